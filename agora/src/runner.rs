@@ -35,7 +35,19 @@ fn fail_name(err: CostError) -> &'static str {
         CostError::QueryNotSupported => "Query not supported",
         CostError::QueryInvalid => "Query invalid",
         CostError::CostModelFail => "Cost model failure",
+        CostError::StatementFail { .. } => "Cost model failure",
         CostError::FailedToParseVariables => "Failed to parse variables",
+        CostError::MissingVariable(_) => "Missing variable",
+        CostError::VariableTypeMismatch { .. } => "Variable type mismatch",
+        CostError::Overflow => "Overflow",
+        CostError::Negative => "Negative",
+        CostError::DepthExceeded { .. } => "Depth exceeded",
+        CostError::NodesExceeded { .. } => "Nodes exceeded",
+        CostError::OperationNotFound => "Operation not found",
+        CostError::AmbiguousOperation => "Ambiguous operation",
+        CostError::NotAllowed(_) => "Not allowed",
+        CostError::UndefinedFragment(_) => "Undefined fragment",
+        CostError::FragmentCycle(_) => "Fragment cycle",
     }
 }
 
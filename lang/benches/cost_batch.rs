@@ -0,0 +1,55 @@
+//! Compares costing a batch of queries one at a time against `cost_batch`,
+//! which costs them in parallel when the `rayon` feature is enabled.
+//!
+//! cargo bench --features rayon
+
+use cost_model::CostModel;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+const MODEL: &str = r#"
+    query { pairs(first: $first, skip: $skip) { id } } when $skip > 1000 => $first * $skip / 100;
+    query { pairs(first: $first) { id } } => $first;
+    query { tokens(symbol: $symbol) } when $symbol == "T1" => 10;
+    default => 1;
+"#;
+
+fn queries(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| match i % 3 {
+            0 => format!("{{ pairs(first: {}, skip: {}) {{ id }} }}", i % 100, i * 10),
+            1 => format!("{{ tokens(symbol: \"T{}\") {{ id }} }}", i % 10),
+            _ => format!("{{ pairs(first: {}) {{ id }} swaps {{ id }} }}", i % 100),
+        })
+        .collect()
+}
+
+fn cost_batch(c: &mut Criterion) {
+    let model: CostModel = MODEL.parse().unwrap();
+    let mut group = c.benchmark_group("cost_batch");
+
+    for count in [100usize, 1000, 10000].iter() {
+        let queries = queries(*count);
+        let queries: Vec<&str> = queries.iter().map(String::as_str).collect();
+
+        group.bench_with_input(
+            BenchmarkId::new("sequential", count),
+            &queries,
+            |b, queries| {
+                b.iter(|| {
+                    queries
+                        .iter()
+                        .map(|query| model.cost(query, ""))
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("batch", count), &queries, |b, queries| {
+            b.iter(|| model.cost_batch(queries))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, cost_batch);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+//! Compares parsing a query which uses fragments once for each cost model
+//! against parsing it once into a `Context` which is shared by all of the models.
+//! The allocations of each are counted before they are measured.
+//!
+//! cargo bench --bench shared_context
+
+use cost_model::{Context, CostError, CostModel};
+use criterion::{criterion_group, criterion_main, Criterion};
+use num_bigint::BigUint;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts every allocation, including those of `realloc`.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// How many allocations `f` makes.
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+const MODELS: [&str; 3] = [
+    "query { pairs(first: $first) { token0 { id } } } => $first;
+     default => 1;",
+    "query { pairs(first: $first) { ... on Pair { token1 { id } } } } => $first * 2;
+     query { tokens } => 5;
+     default => 2;",
+    "query { tokens(where: { owner: $owner }) } when $owner == \"a\" => 10;
+     default => $__selections;",
+];
+
+const QUERY: &str = "
+    {
+        pairs(first: 100) { ...PairFields }
+        tokens(where: { owner: \"a\" }) { ...TokenFields }
+    }
+    fragment PairFields on Pair { id token0 { ...TokenFields } token1 { ...TokenFields } }
+    fragment TokenFields on Token { id symbol name decimals owner { id } }
+";
+
+fn per_model(models: &[CostModel]) -> Vec<Result<BigUint, CostError>> {
+    models.iter().map(|model| model.cost(QUERY, "")).collect()
+}
+
+fn shared(models: &[CostModel]) -> Vec<Result<BigUint, CostError>> {
+    let mut context: Context<&str> = Context::new(QUERY, "").unwrap();
+    models
+        .iter()
+        .map(|model| model.cost_with_context(&mut context))
+        .collect()
+}
+
+fn shared_context(c: &mut Criterion) {
+    let models: Vec<CostModel> = MODELS.iter().map(|m| m.parse().unwrap()).collect();
+
+    let per_model_allocations = allocations(|| drop(per_model(&models)));
+    let shared_allocations = allocations(|| drop(shared(&models)));
+    println!(
+        "allocations: per_model {}, shared {}",
+        per_model_allocations, shared_allocations
+    );
+    assert!(shared_allocations < per_model_allocations);
+
+    let mut group = c.benchmark_group("shared_context");
+    group.bench_function("per_model", |b| b.iter(|| per_model(&models)));
+    group.bench_function("shared", |b| b.iter(|| shared(&models)));
+    group.finish();
+}
+
+criterion_group!(benches, shared_context);
+criterion_main!(benches);
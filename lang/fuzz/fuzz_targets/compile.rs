@@ -0,0 +1,13 @@
+#![no_main]
+use cost_model::CostModel;
+use libfuzzer_sys::fuzz_target;
+
+// Compiling any text should either succeed or fail with an error,
+// and never panic or overflow the stack.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        if let Ok(model) = CostModel::compile(text, "") {
+            let _ = model.cost("{ a(first: 10) { b } }", "");
+        }
+    }
+});
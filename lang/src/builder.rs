@@ -0,0 +1,335 @@
+//! Constructs cost models from code rather than from the text of the DSL.
+//!
+//! A `Document` built here is the same as the one parsed from the equivalent
+//! text, so it costs queries identically once compiled with
+//! `CostModel::from_document`. Only the GraphQL query of a match is parsed.
+
+use crate::expressions::*;
+use crate::language::*;
+use crate::prelude::*;
+use crate::{parser, CompileError};
+use fraction::BigFraction;
+
+/// Collects the statements and definitions of a `Document`, in the order that
+/// they would be written in the text of a cost model.
+#[derive(Debug, Default)]
+pub struct DocumentBuilder<'a> {
+    statements: Vec<Statement<'a>>,
+    /// The text of the predicates of statements added with `query_statement`, by the
+    /// index of the statement. These are parsed once the text is owned by a model.
+    queries: Vec<(usize, String)>,
+    definitions: Vec<(String, LinearExpression)>,
+    scoped_definitions: Vec<(String, String, LinearExpression)>,
+}
+
+impl<'a> DocumentBuilder<'a> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds a statement, like `predicate => cost_expr;`
+    pub fn statement(mut self, predicate: Predicate<'a>, cost_expr: LinearExpression) -> Self {
+        self.statements.push(Statement {
+            doc: None,
+            name: None,
+            predicate,
+            cost_expr,
+            additive: false,
+            scope: None,
+            source: None,
+        });
+        self
+    }
+
+    /// Adds a labelled statement, like `@name("name") predicate => cost_expr;`
+    pub fn named_statement(
+        mut self,
+        name: impl Into<String>,
+        predicate: Predicate<'a>,
+        cost_expr: LinearExpression,
+    ) -> Self {
+        self.statements.push(Statement {
+            doc: None,
+            name: Some(name.into()),
+            predicate,
+            cost_expr,
+            additive: false,
+            scope: None,
+            source: None,
+        });
+        self
+    }
+
+    /// Adds a statement whose cost is added to that of the other matching
+    /// statements, like `predicate += cost_expr;`
+    pub fn additive_statement(
+        mut self,
+        predicate: Predicate<'a>,
+        cost_expr: LinearExpression,
+    ) -> Self {
+        self.statements.push(Statement {
+            doc: None,
+            name: None,
+            predicate,
+            cost_expr,
+            additive: true,
+            scope: None,
+            source: None,
+        });
+        self
+    }
+
+    /// Like `statement`, but the predicate is parsed from text which is built at
+    /// runtime, eg: `query { a(first: $first) } when $first > 10`. The text is owned
+    /// by the model compiled with `CostModel::from_builder`, which reports whether it
+    /// fails to parse.
+    pub fn query_statement(
+        mut self,
+        predicate: impl Into<String>,
+        cost_expr: LinearExpression,
+    ) -> Self {
+        self.queries.push((self.statements.len(), predicate.into()));
+        self.statement(Predicate::default_match(), cost_expr)
+    }
+
+    /// Adds a statement matching every field, like `default => cost_expr;`
+    pub fn default(self, cost_expr: LinearExpression) -> Self {
+        self.statement(Predicate::default_match(), cost_expr)
+    }
+
+    /// Adds a named expression, like `let name = expr;`
+    pub fn definition(mut self, name: impl Into<String>, expr: LinearExpression) -> Self {
+        self.definitions.push((name.into(), expr));
+        self
+    }
+
+    /// Adds the statements and definitions of `scope` within a named scope, like
+    /// `scope name { ... }`. Panics if `scope` has scopes of its own, since scopes
+    /// can't be nested.
+    pub fn scope(mut self, name: impl Into<String>, scope: DocumentBuilder<'a>) -> Self {
+        let name = name.into();
+        assert!(
+            scope.scoped_definitions.is_empty()
+                && scope.statements.iter().all(|s| s.scope.is_none()),
+            "Scopes can't be nested"
+        );
+        let offset = self.statements.len();
+        for (index, predicate) in scope.queries.into_iter() {
+            self.queries.push((offset + index, predicate));
+        }
+        for mut statement in scope.statements.into_iter() {
+            statement.scope = Some(name.clone());
+            self.statements.push(statement);
+        }
+        for (definition, expr) in scope.definitions.into_iter() {
+            self.scoped_definitions
+                .push((name.clone(), definition, expr));
+        }
+        self
+    }
+
+    /// Fails if a statement was added with `query_statement`, since the document
+    /// could not own its text. Use `CostModel::from_builder` instead.
+    pub fn build(self) -> Result<Document<'a>, CompileError> {
+        if !self.queries.is_empty() {
+            return Err(CompileError::QueryStatement);
+        }
+        Ok(self.into_parts().0)
+    }
+
+    /// The document, and the text of the predicates which are yet to be parsed.
+    pub(crate) fn into_parts(self) -> (Document<'a>, Vec<(usize, String)>) {
+        let document = Document {
+            statements: self.statements,
+            definitions: self.definitions,
+            scoped_definitions: self.scoped_definitions,
+        };
+        (document, self.queries)
+    }
+}
+
+impl Statement<'_> {
+    /// The same statement, but with another predicate.
+    pub(crate) fn with_predicate<'b>(self, predicate: Predicate<'b>) -> Statement<'b> {
+        Statement {
+            doc: self.doc,
+            name: self.name,
+            predicate,
+            cost_expr: self.cost_expr,
+            additive: self.additive,
+            scope: self.scope,
+            source: self.source,
+        }
+    }
+}
+
+impl<'a> Predicate<'a> {
+    /// A predicate which matches a field like the GraphQL query, eg: `query { a(first: $first) }`
+    /// Range patterns in the query, eg: `query { a(first: > 100) }`, become a when clause.
+    pub fn query(text: &'a str) -> Result<Self, CompileError> {
+        profile_method!(query);
+
+        parser::parse_graphql_predicate(text)
+            .map_err(|e| CompileError::DocumentParseError(format!("{}", e), e.span()))
+    }
+
+    /// A predicate which matches every field, like `default`
+    pub fn default_match() -> Self {
+        Predicate {
+            match_: Match::Default,
+            when_clause: None,
+        }
+    }
+
+    /// Only matches when the condition is true, like `when condition`. The condition
+    /// of any range patterns in the query is tested first.
+    pub fn when(mut self, condition: Condition) -> Self {
+        let condition =
+            parser::and_conditions(self.when_clause.map(|w| w.condition), Some(condition));
+        self.when_clause = condition.map(|condition| WhenClause { condition });
+        self
+    }
+}
+
+/// A number, eg: `lit(5)`
+pub fn lit(value: impl Into<BigFraction>) -> LinearExpression {
+    LinearExpression::Const(Const::new(value.into()))
+}
+
+/// A capture or global, without the leading `$`, eg: `var("first")`
+pub fn var(name: impl Into<String>) -> LinearExpression {
+    LinearExpression::Variable(Variable::new(name))
+}
+
+fn binary(lhs: LinearExpression, op: AnyLinearOperator, rhs: LinearExpression) -> LinearExpression {
+    LinearExpression::BinaryExpression(Box::new(BinaryExpression::new(lhs, op, rhs)))
+}
+
+pub fn add(lhs: LinearExpression, rhs: LinearExpression) -> LinearExpression {
+    binary(lhs, AnyLinearOperator::Add, rhs)
+}
+
+pub fn sub(lhs: LinearExpression, rhs: LinearExpression) -> LinearExpression {
+    binary(lhs, AnyLinearOperator::Sub, rhs)
+}
+
+pub fn mul(lhs: LinearExpression, rhs: LinearExpression) -> LinearExpression {
+    binary(lhs, AnyLinearOperator::Mul, rhs)
+}
+
+pub fn div(lhs: LinearExpression, rhs: LinearExpression) -> LinearExpression {
+    binary(lhs, AnyLinearOperator::Div, rhs)
+}
+
+pub fn rem(lhs: LinearExpression, rhs: LinearExpression) -> LinearExpression {
+    binary(lhs, AnyLinearOperator::Mod, rhs)
+}
+
+pub fn pow(lhs: LinearExpression, rhs: LinearExpression) -> LinearExpression {
+    binary(lhs, AnyLinearOperator::Pow, rhs)
+}
+
+pub fn neg(expr: LinearExpression) -> LinearExpression {
+    LinearExpression::Neg(Box::new(expr))
+}
+
+/// The number of characters in a captured string, like `len($name)`
+pub fn len(name: impl Into<String>) -> LinearExpression {
+    LinearExpression::Len(StringExpression::Variable(Variable::new(name)))
+}
+
+/// A function of the items of a captured list, like `sum($name)`
+pub fn fold(func: ListFn, name: impl Into<String>) -> LinearExpression {
+    LinearExpression::Fold {
+        func,
+        list: Variable::new(name),
+    }
+}
+
+/// A call to a built-in function, eg: `call(BuiltinFn::Max, vec![var("first"), lit(1)])`
+/// Panics if the function does not accept that many arguments, which the parser
+/// would have rejected.
+pub fn call(func: BuiltinFn, args: Vec<LinearExpression>) -> LinearExpression {
+    assert!(
+        func.accepts(args.len()),
+        "{} does not accept {} arguments",
+        func.name(),
+        args.len()
+    );
+    LinearExpression::Call { func, args }
+}
+
+/// Only the selected branch is evaluated, like `cond ? then : otherwise`
+pub fn cond(
+    cond: Condition,
+    then: LinearExpression,
+    otherwise: LinearExpression,
+) -> LinearExpression {
+    LinearExpression::Conditional {
+        cond: Box::new(cond),
+        then: Box::new(then),
+        otherwise: Box::new(otherwise),
+    }
+}
+
+fn compare(lhs: LinearExpression, op: AnyComparison, rhs: LinearExpression) -> Condition {
+    Condition::Comparison(BinaryExpression::new(lhs, op, rhs))
+}
+
+pub fn eq(lhs: LinearExpression, rhs: LinearExpression) -> Condition {
+    compare(lhs, AnyComparison::Eq, rhs)
+}
+
+pub fn ne(lhs: LinearExpression, rhs: LinearExpression) -> Condition {
+    compare(lhs, AnyComparison::Ne, rhs)
+}
+
+pub fn gt(lhs: LinearExpression, rhs: LinearExpression) -> Condition {
+    compare(lhs, AnyComparison::Gt, rhs)
+}
+
+pub fn lt(lhs: LinearExpression, rhs: LinearExpression) -> Condition {
+    compare(lhs, AnyComparison::Lt, rhs)
+}
+
+pub fn ge(lhs: LinearExpression, rhs: LinearExpression) -> Condition {
+    compare(lhs, AnyComparison::Ge, rhs)
+}
+
+pub fn le(lhs: LinearExpression, rhs: LinearExpression) -> Condition {
+    compare(lhs, AnyComparison::Le, rhs)
+}
+
+/// Only evaluates `rhs` if `lhs` is true, like `lhs && rhs`
+pub fn and(lhs: Condition, rhs: Condition) -> Condition {
+    Condition::Boolean(Box::new(BinaryExpression::new(lhs, AnyBooleanOp::And, rhs)))
+}
+
+/// Only evaluates `rhs` if `lhs` is false, like `lhs || rhs`
+pub fn or(lhs: Condition, rhs: Condition) -> Condition {
+    Condition::Boolean(Box::new(BinaryExpression::new(lhs, AnyBooleanOp::Or, rhs)))
+}
+
+pub fn not(condition: Condition) -> Condition {
+    Condition::Not(Box::new(condition))
+}
+
+/// A boolean literal, eg: `boolean(true)`
+pub fn boolean(value: bool) -> Condition {
+    Condition::Const(Const::new(value))
+}
+
+/// A capture or global which is a boolean, eg: `flag("enabled")`
+pub fn flag(name: impl Into<String>) -> Condition {
+    Condition::Variable(Variable::new(name))
+}
+
+/// Whether the variable has a value of any type, like `defined($name)`
+pub fn defined(name: impl Into<String>) -> Condition {
+    Condition::Defined(Variable::new(name))
+}
+
+/// Whether the value of the variable is null, like `is_null($name)`
+pub fn is_null(name: impl Into<String>) -> Condition {
+    Condition::IsNull(Variable::new(name))
+}
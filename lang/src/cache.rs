@@ -0,0 +1,303 @@
+//! An opt-in cache of the costs of queries, for servers which cost
+//! the same queries many times.
+
+use crate::graphql_utils::{QueryVariables, StaticValue};
+use crate::prelude::*;
+use crate::CostError;
+use graphql_parser::query as q;
+use num_bigint::BigUint;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Counts of lookups in the cache of a `CostModel`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+struct Entry {
+    cost: Result<BigUint, CostError>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    // Keys by the time that they were last used, so that the least recently
+    // used entry is first.
+    recent: BTreeMap<u64, String>,
+    time: u64,
+    stats: CacheStats,
+}
+
+/// A least recently used cache of costs, keyed by normalized queries.
+pub(crate) struct Cache {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Result<BigUint, CostError>> {
+        profile_method!(get);
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.time += 1;
+        match state.entries.get_mut(key) {
+            Some(entry) => {
+                state.recent.remove(&entry.last_used);
+                entry.last_used = state.time;
+                state.recent.insert(state.time, key.to_owned());
+                state.stats.hits += 1;
+                Some(entry.cost.clone())
+            }
+            None => {
+                state.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: String, cost: Result<BigUint, CostError>) {
+        profile_method!(insert);
+
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.time += 1;
+        let entry = Entry {
+            cost,
+            last_used: state.time,
+        };
+        if let Some(replaced) = state.entries.insert(key.clone(), entry) {
+            state.recent.remove(&replaced.last_used);
+        }
+        state.recent.insert(state.time, key);
+
+        while state.entries.len() > self.capacity {
+            // Panic safety: There are as many recent keys as entries
+            let oldest = *state.recent.keys().next().unwrap();
+            let key = state.recent.remove(&oldest).unwrap();
+            state.entries.remove(&key);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+/// Writes the query in a normal form, which is the key to the cache. Queries which
+/// differ only in formatting, in the order of arguments, or in the names of operations,
+/// have the same normal form. The values of variables are written in place of the
+/// variables, so the names of variables with values are ignored, but queries with
+/// different values for their variables do not share an entry. Variables without a
+/// value keep their names, since the errors of such queries report them.
+pub(crate) fn normalize(query: &str, variables: &QueryVariables) -> Result<String, CostError> {
+    profile_fn!(normalize);
+
+    let mut normalizer = Normalizer {
+        variables,
+        strip_aliases: false,
+        renamed: None,
+    };
+    normalizer.query(query)
+}
+
+/// Writes the query in a canonical form, for tools which compare queries. Like the keys
+/// of the cache, formatting, comments, the order of arguments, and the names of
+/// operations are ignored. Aliases are removed as well, so queries which only alias
+/// their fields differently have the same normal form.
+pub fn normalize_query(query: &str) -> Result<String, CostError> {
+    profile_fn!(normalize_query);
+
+    let mut normalizer = Normalizer {
+        variables: &QueryVariables::new(),
+        strip_aliases: true,
+        renamed: None,
+    };
+    normalizer.query(query)
+}
+
+/// A stable hash of the query, for keying caches of costs outside of the cost model.
+/// Queries with the same normal form, as written by `normalize_query`, have the same
+/// fingerprint, as do queries which differ only in the names of their variables. If
+/// `variables` are given, their values are written in place of the variables, so that
+/// the fingerprint also depends on them. The hash is the same on every platform, but
+/// is not made to resist collisions which are crafted on purpose.
+pub fn query_fingerprint(query: &str, variables: Option<&str>) -> Result<u64, CostError> {
+    profile_fn!(query_fingerprint);
+
+    let variables = match variables {
+        Some(variables) => {
+            crate::parse_vars(variables).map_err(|_| CostError::FailedToParseVariables)?
+        }
+        None => QueryVariables::new(),
+    };
+    let mut normalizer = Normalizer {
+        variables: &variables,
+        strip_aliases: true,
+        renamed: Some(HashMap::new()),
+    };
+    let normal = normalizer.query(query)?;
+    Ok(fnv1a(normal.as_bytes()))
+}
+
+/// The 64 bit FNV-1a hash
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+struct Normalizer<'v> {
+    variables: &'v QueryVariables,
+    strip_aliases: bool,
+    /// If set, variables without a value are renamed in the order that they are
+    /// first used, by their index in this map.
+    renamed: Option<HashMap<String, usize>>,
+}
+
+impl Normalizer<'_> {
+    fn query(&mut self, query: &str) -> Result<String, CostError> {
+        profile_method!(query);
+
+        let mut query =
+            q::parse_query::<String>(query).map_err(|_| CostError::FailedToParseQuery)?;
+
+        for definition in query.definitions.iter_mut() {
+            // A query without directives is written in the shorthand for a selection set,
+            // since it would otherwise differ from the shorthand by the `query` keyword.
+            if let q::Definition::Operation(q::OperationDefinition::Query(query)) = definition {
+                if query.directives.is_empty() {
+                    let set = query.selection_set.clone();
+                    *definition =
+                        q::Definition::Operation(q::OperationDefinition::SelectionSet(set));
+                }
+            }
+
+            match definition {
+                q::Definition::Operation(q::OperationDefinition::Query(query)) => {
+                    query.name = None;
+                    query.variable_definitions.clear();
+                    self.directives(&mut query.directives);
+                    self.selection_set(&mut query.selection_set);
+                }
+                q::Definition::Operation(q::OperationDefinition::SelectionSet(set)) => {
+                    self.selection_set(set);
+                }
+                q::Definition::Operation(q::OperationDefinition::Mutation(mutation)) => {
+                    mutation.name = None;
+                    mutation.variable_definitions.clear();
+                    self.directives(&mut mutation.directives);
+                    self.selection_set(&mut mutation.selection_set);
+                }
+                q::Definition::Operation(q::OperationDefinition::Subscription(subscription)) => {
+                    subscription.name = None;
+                    subscription.variable_definitions.clear();
+                    self.directives(&mut subscription.directives);
+                    self.selection_set(&mut subscription.selection_set);
+                }
+                q::Definition::Fragment(fragment) => {
+                    self.directives(&mut fragment.directives);
+                    self.selection_set(&mut fragment.selection_set);
+                }
+            }
+        }
+
+        Ok(query.to_string())
+    }
+
+    // Security: Relying on GraphQL parsing to not have stack overflow here.
+    // See also 01205a6c-4e1a-4b35-8dc6-d400c499d423
+    fn selection_set(&mut self, set: &mut q::SelectionSet<'_, String>) {
+        for selection in set.items.iter_mut() {
+            match selection {
+                q::Selection::Field(field) => {
+                    if self.strip_aliases {
+                        field.alias = None;
+                    }
+                    self.arguments(&mut field.arguments);
+                    self.directives(&mut field.directives);
+                    self.selection_set(&mut field.selection_set);
+                }
+                q::Selection::FragmentSpread(spread) => {
+                    self.directives(&mut spread.directives);
+                }
+                q::Selection::InlineFragment(inline) => {
+                    self.directives(&mut inline.directives);
+                    self.selection_set(&mut inline.selection_set);
+                }
+            }
+        }
+    }
+
+    fn directives(&mut self, directives: &mut [q::Directive<'_, String>]) {
+        for directive in directives.iter_mut() {
+            self.arguments(&mut directive.arguments);
+        }
+    }
+
+    fn arguments<'a>(&mut self, arguments: &mut [(String, q::Value<'a, String>)]) {
+        // Matching does not depend on the order of arguments.
+        arguments.sort_by(|a, b| a.0.cmp(&b.0));
+        for (_, value) in arguments.iter_mut() {
+            self.value(value);
+        }
+    }
+
+    fn value<'a>(&mut self, value: &mut q::Value<'a, String>) {
+        match value {
+            q::Value::Variable(name) => {
+                if let Some(variable) = self.variables.get(name) {
+                    *value = to_value(variable);
+                } else if let Some(renamed) = &mut self.renamed {
+                    let next = renamed.len();
+                    let index = *renamed.entry(name.clone()).or_insert(next);
+                    *name = format!("v{}", index);
+                }
+            }
+            q::Value::List(values) => {
+                for value in values.iter_mut() {
+                    self.value(value);
+                }
+            }
+            q::Value::Object(props) => {
+                for value in props.values_mut() {
+                    self.value(value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn to_value<'a>(value: &StaticValue) -> q::Value<'a, String> {
+    match value {
+        q::Value::Variable(v) => q::Value::Variable(v.clone()),
+        q::Value::Int(i) => q::Value::Int(i.clone()),
+        q::Value::Float(f) => q::Value::Float(*f),
+        q::Value::String(s) => q::Value::String(s.clone()),
+        q::Value::Boolean(b) => q::Value::Boolean(*b),
+        q::Value::Null => q::Value::Null,
+        q::Value::Enum(e) => q::Value::Enum(e.clone()),
+        q::Value::List(l) => q::Value::List(l.iter().map(to_value).collect()),
+        q::Value::Object(o) => {
+            q::Value::Object(o.iter().map(|(k, v)| (k.clone(), to_value(v))).collect())
+        }
+    }
+}
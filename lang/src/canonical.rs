@@ -0,0 +1,235 @@
+//! A canonical form of a `Document`, so that documents which differ only in ways
+//! that do not affect matching or cost compare equal.
+
+use crate::expressions::*;
+use crate::language::*;
+use crate::prelude::*;
+use fraction::BigFraction;
+use graphql_parser::query as q;
+use graphql_parser::Pos;
+
+impl<'a> Document<'a> {
+    /// A copy of the document in a canonical form. Positions in the GraphQL of matches,
+    /// comments, the sources of statements, and the order of arguments and directives
+    /// are ignored. Chains of `+`,
+    /// `*`, `&&` and `||` are made left-associative, which does not change the order in
+    /// which their operands are evaluated, and the constant operands of chains of `+`
+    /// and `*` are folded into one.
+    pub fn canonicalize(&self) -> Document<'a> {
+        profile_method!(canonicalize);
+
+        Document {
+            statements: self.statements.iter().map(statement).collect(),
+            definitions: self
+                .definitions
+                .iter()
+                .map(|(name, expr)| (name.clone(), linear_expression(expr)))
+                .collect(),
+            scoped_definitions: self
+                .scoped_definitions
+                .iter()
+                .map(|(scope, name, expr)| (scope.clone(), name.clone(), linear_expression(expr)))
+                .collect(),
+        }
+    }
+
+    /// Whether the documents are equal in their canonical forms. See `canonicalize`.
+    pub fn canonical_eq(&self, other: &Document<'_>) -> bool {
+        profile_method!(canonical_eq);
+
+        self.canonicalize() == other.canonicalize()
+    }
+}
+
+fn statement<'a>(statement: &Statement<'a>) -> Statement<'a> {
+    let match_ = match &statement.predicate.match_ {
+        Match::GraphQL(field, definitions) => {
+            let mut field = field.clone();
+            normalize_field(&mut field);
+            let mut definitions = definitions.clone();
+            definitions.sort_by(|a, b| a.name.cmp(b.name));
+            for definition in definitions.iter_mut() {
+                definition.position = Pos::default();
+            }
+            Match::GraphQL(field, definitions)
+        }
+        Match::Default => Match::Default,
+    };
+    let when_clause = statement
+        .predicate
+        .when_clause
+        .as_ref()
+        .map(|when_clause| WhenClause {
+            condition: condition(&when_clause.condition),
+        });
+    Statement {
+        doc: None,
+        name: statement.name.clone(),
+        predicate: Predicate {
+            match_,
+            when_clause,
+        },
+        cost_expr: linear_expression(&statement.cost_expr),
+        additive: statement.additive,
+        scope: statement.scope.clone(),
+        source: None,
+    }
+}
+
+// Security: Relying on GraphQL parsing to not have stack overflow here.
+// See also 01205a6c-4e1a-4b35-8dc6-d400c499d423
+fn normalize_field<'a>(field: &mut q::Field<'a, &'a str>) {
+    field.position = Pos::default();
+    // Matching does not depend on the order of arguments or directives.
+    field.arguments.sort_by(|a, b| a.0.cmp(b.0));
+    normalize_directives(&mut field.directives);
+    normalize_selection_set(&mut field.selection_set);
+}
+
+fn normalize_directives<'a>(directives: &mut Vec<q::Directive<'a, &'a str>>) {
+    directives.sort_by(|a, b| a.name.cmp(b.name));
+    for directive in directives.iter_mut() {
+        directive.position = Pos::default();
+        directive.arguments.sort_by(|a, b| a.0.cmp(b.0));
+    }
+}
+
+fn normalize_selection_set<'a>(set: &mut q::SelectionSet<'a, &'a str>) {
+    set.span = (Pos::default(), Pos::default());
+    for selection in set.items.iter_mut() {
+        match selection {
+            q::Selection::Field(field) => normalize_field(field),
+            q::Selection::FragmentSpread(spread) => {
+                spread.position = Pos::default();
+                normalize_directives(&mut spread.directives);
+            }
+            q::Selection::InlineFragment(inline) => {
+                inline.position = Pos::default();
+                normalize_directives(&mut inline.directives);
+                normalize_selection_set(&mut inline.selection_set);
+            }
+        }
+    }
+}
+
+/// Collects the operands of a chain of the same operator, in the order that they are evaluated.
+fn linear_operands<'e>(
+    expr: &'e LinearExpression,
+    op: AnyLinearOperator,
+    operands: &mut Vec<&'e LinearExpression>,
+) {
+    match expr {
+        LinearExpression::BinaryExpression(binary) if binary.op == op => {
+            linear_operands(&binary.lhs, op, operands);
+            linear_operands(&binary.rhs, op, operands);
+        }
+        expr => operands.push(expr),
+    }
+}
+
+fn linear_expression(expr: &LinearExpression) -> LinearExpression {
+    match expr {
+        LinearExpression::BinaryExpression(binary)
+            if matches!(binary.op, AnyLinearOperator::Add | AnyLinearOperator::Mul) =>
+        {
+            let mut operands = Vec::new();
+            linear_operands(expr, binary.op, &mut operands);
+
+            // Constants can not fail to evaluate, so moving them to the front
+            // does not change the result or the error of the chain.
+            let mut constant: Option<Const<BigFraction>> = None;
+            let mut rest = Vec::new();
+            for operand in operands.into_iter().map(linear_expression) {
+                match (operand, &mut constant) {
+                    (LinearExpression::Const(c), None) => constant = Some(c),
+                    (LinearExpression::Const(c), Some(folded)) => {
+                        match binary.op.exec(folded.value.clone(), c.value.clone()) {
+                            Ok(value) => folded.value = value,
+                            Err(_) => rest.push(LinearExpression::Const(c)),
+                        }
+                    }
+                    (operand, _) => rest.push(operand),
+                }
+            }
+            constant
+                .map(LinearExpression::Const)
+                .into_iter()
+                .chain(rest)
+                .fold(None, |lhs, rhs| {
+                    Some(match lhs {
+                        Some(lhs) => LinearExpression::BinaryExpression(Box::new(
+                            BinaryExpression::new(lhs, binary.op, rhs),
+                        )),
+                        None => rhs,
+                    })
+                })
+                // Panic safety: A chain has at least two operands
+                .unwrap()
+        }
+        LinearExpression::BinaryExpression(binary) => {
+            LinearExpression::BinaryExpression(Box::new(BinaryExpression::new(
+                linear_expression(&binary.lhs),
+                binary.op,
+                linear_expression(&binary.rhs),
+            )))
+        }
+        LinearExpression::Call { func, args } => LinearExpression::Call {
+            func: *func,
+            args: args.iter().map(linear_expression).collect(),
+        },
+        LinearExpression::Neg(expr) => LinearExpression::Neg(Box::new(linear_expression(expr))),
+        LinearExpression::Conditional {
+            cond,
+            then,
+            otherwise,
+        } => LinearExpression::Conditional {
+            cond: Box::new(condition(cond)),
+            then: Box::new(linear_expression(then)),
+            otherwise: Box::new(linear_expression(otherwise)),
+        },
+        LinearExpression::Const(_)
+        | LinearExpression::Variable(_)
+        | LinearExpression::Len(_)
+        | LinearExpression::Fold { .. }
+        | LinearExpression::Error(_) => expr.clone(),
+    }
+}
+
+/// Collects the operands of a chain of the same operator, in the order that they are evaluated.
+fn boolean_operands<'e>(
+    condition: &'e Condition,
+    op: AnyBooleanOp,
+    operands: &mut Vec<&'e Condition>,
+) {
+    match condition {
+        Condition::Boolean(binary) if binary.op == op => {
+            boolean_operands(&binary.lhs, op, operands);
+            boolean_operands(&binary.rhs, op, operands);
+        }
+        condition => operands.push(condition),
+    }
+}
+
+fn condition(cond: &Condition) -> Condition {
+    match cond {
+        Condition::Boolean(binary) => {
+            // Both operators short circuit from left to right, so keeping the order
+            // of the operands keeps which of them are evaluated.
+            let mut operands = Vec::new();
+            boolean_operands(cond, binary.op, &mut operands);
+            let mut operands = operands.into_iter().map(condition);
+            // Panic safety: A chain has at least two operands
+            let first = operands.next().unwrap();
+            operands.fold(first, |lhs, rhs| {
+                Condition::Boolean(Box::new(BinaryExpression::new(lhs, binary.op, rhs)))
+            })
+        }
+        Condition::Comparison(comparison) => Condition::Comparison(BinaryExpression::new(
+            linear_expression(&comparison.lhs),
+            comparison.op,
+            linear_expression(&comparison.rhs),
+        )),
+        Condition::Not(cond) => Condition::Not(Box::new(condition(cond))),
+        cond => cond.clone(),
+    }
+}
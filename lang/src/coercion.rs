@@ -1,11 +1,14 @@
 use graphql_parser::query as q;
 
+use crate::graphql_utils::{IntoStaticValue as _, StaticValue};
 use fraction::BigFraction;
 use q::Value::*;
 
 /// This is like TryInto, but more liberal
 pub trait Coerce<T> {
     type Error;
+    /// How values of `T` are described in errors, eg: `Number`
+    const EXPECTED: &'static str;
     fn coerce(&self) -> Result<T, Self::Error>;
 }
 
@@ -17,6 +20,7 @@ pub trait Coerce<T> {
 
 impl<'t, Text: q::Text<'t>> Coerce<bool> for q::Value<'t, Text> {
     type Error = ();
+    const EXPECTED: &'static str = "Boolean";
     fn coerce(&self) -> Result<bool, Self::Error> {
         match self {
             Boolean(b) => Ok(*b),
@@ -30,15 +34,62 @@ impl<'t, Text: q::Text<'t>> Coerce<bool> for q::Value<'t, Text> {
     }
 }
 
+/// Whether a value is null. Coercing to this never fails, so that
+/// `is_null` can tell null apart from values such as `0` or `false`,
+/// which null otherwise coerces to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IsNull(pub bool);
+
+impl<'t, Text: q::Text<'t>> Coerce<IsNull> for q::Value<'t, Text> {
+    type Error = ();
+    const EXPECTED: &'static str = "Null";
+    fn coerce(&self) -> Result<IsNull, Self::Error> {
+        Ok(IsNull(matches!(self, Null)))
+    }
+}
+
 impl<'t, Text: q::Text<'t>> Coerce<BigFraction> for q::Value<'t, Text> {
     type Error = ();
+    const EXPECTED: &'static str = "Number";
     fn coerce(&self) -> Result<BigFraction, Self::Error> {
         match self {
             Boolean(b) => Ok(if *b { 1.into() } else { 0.into() }),
             Null => Ok(0.into()),
             Int(i) => Ok(i.as_i64().ok_or(())?.into()),
             String(s) => crate::parse_real(s).map_err(|_| ()),
-            List(_) | Object(_) | Variable(_) | Float(_) | Enum(_) => Err(()),
+            // A float is the decimal number which it was written as, which is the
+            // shortest decimal that converts to the same float. So 0.1 is exactly 1/10.
+            Float(f) => crate::parse_real(&f.to_string()).map_err(|_| ()),
+            // So that costs can scale with the number of items in a list argument.
+            List(l) => Ok((l.len() as i64).into()),
+            Object(_) | Variable(_) | Enum(_) => Err(()),
+        }
+    }
+}
+
+impl<'t, Text: q::Text<'t>> Coerce<std::string::String> for q::Value<'t, Text> {
+    type Error = ();
+    const EXPECTED: &'static str = "String";
+    fn coerce(&self) -> Result<std::string::String, Self::Error> {
+        match self {
+            String(s) => Ok(s.clone()),
+            // An enum is represented by its name, so that it can be compared to a string.
+            Enum(e) => Ok(e.as_ref().to_owned()),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The items of a list, which may be of any type. Null is an empty list,
+/// so that an argument which is explicitly null adds nothing.
+impl<'t, Text: q::Text<'t>> Coerce<Vec<StaticValue>> for q::Value<'t, Text> {
+    type Error = ();
+    const EXPECTED: &'static str = "List";
+    fn coerce(&self) -> Result<Vec<StaticValue>, Self::Error> {
+        match self {
+            List(l) => Ok(l.iter().map(|item| item.to_graphql()).collect()),
+            Null => Ok(Vec::new()),
+            _ => Err(()),
         }
     }
 }
@@ -3,6 +3,8 @@ use crate::prelude::*;
 use crate::{Captures, CostError};
 use graphql_parser::query as q;
 
+/// A query which has been parsed, with its fragments, so that it may be costed by
+/// many models. See `CostModel::cost_with_context`.
 pub struct Context<'a, T: q::Text<'a>> {
     pub operations: Vec<q::OperationDefinition<'a, T>>,
     pub fragments: Vec<q::FragmentDefinition<'a, T>>,
@@ -16,8 +18,19 @@ impl<'a, T: q::Text<'a>> Context<'a, T> {
 
         let variables =
             crate::parse_vars(variables).map_err(|_| CostError::FailedToParseVariables)?;
+        Self::with_variables(query, variables)
+    }
+
+    /// Like `new`, but for variables which have already been parsed.
+    pub fn with_variables(query: &'a str, variables: QueryVariables) -> Result<Self, CostError> {
+        profile_method!(with_variables);
+
         let query = q::parse_query::<T>(query).map_err(|_| CostError::FailedToParseQuery)?;
-        let (operations, fragments) = crate::split_definitions(query.definitions);
+        let (operations, mut fragments) = crate::split_definitions(query.definitions);
+        // Sorted once, so that each model which costs the query finds fragments by
+        // binary search. The sort is stable, so the first of fragments with the same
+        // name is still found first.
+        fragments.sort_by(|a, b| a.name.as_ref().cmp(b.name.as_ref()));
 
         Ok(Self {
             captures: Captures::new(),
@@ -26,6 +39,12 @@ impl<'a, T: q::Text<'a>> Context<'a, T> {
             operations,
         })
     }
+
+    /// What was captured by the last statement which was tried against
+    /// the query. This is useful for debugging cost models.
+    pub fn captures(&self) -> &Captures {
+        &self.captures
+    }
 }
 
 impl<'a, T: q::Text<'a> + Clone> Clone for Context<'a, T> {
@@ -0,0 +1,480 @@
+//! Prints a parsed Document back out as the text of a cost model. The
+//! text re-parses to an equivalent Document, with parentheses inserted
+//! only where they are necessary to preserve the structure of expressions.
+
+use crate::expressions::*;
+use crate::language::*;
+use crate::prelude::*;
+use fraction::{BigFraction, GenericFraction, Sign};
+use graphql_parser::query as q;
+use num_bigint::BigUint;
+use num_traits::{Pow as _, Zero as _};
+use std::collections::HashSet;
+use std::fmt;
+
+impl fmt::Display for Document<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        for (name, expr) in self.definitions.iter() {
+            writeln!(f, "let {} = {};", name, expr)?;
+        }
+
+        // The statements of a scope are printed in runs, which keeps their order.
+        // The definitions of each scope are printed in its first block.
+        let mut opened = HashSet::new();
+        let mut current = None;
+        for statement in self.statements.iter() {
+            let scope = statement.scope.as_deref();
+            if scope != current {
+                if current.is_some() {
+                    writeln!(f, "}}")?;
+                }
+                if let Some(scope) = scope {
+                    self.open_scope(f, scope, &mut opened)?;
+                }
+                current = scope;
+            }
+            writeln!(f, "{}", statement)?;
+        }
+        if current.is_some() {
+            writeln!(f, "}}")?;
+        }
+        // Scopes which have definitions, but no statements
+        for (scope, _, _) in self.scoped_definitions.iter() {
+            if !opened.contains(scope.as_str()) {
+                self.open_scope(f, scope, &mut opened)?;
+                writeln!(f, "}}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Document<'a> {
+    /// Prints the start of a block of the scope, with its definitions if it has not
+    /// been opened before.
+    fn open_scope<'d>(
+        &'d self,
+        f: &mut fmt::Formatter<'_>,
+        scope: &'d str,
+        opened: &mut HashSet<&'d str>,
+    ) -> fmt::Result {
+        writeln!(f, "scope {} {{", scope)?;
+        if opened.insert(scope) {
+            for (_, name, expr) in self.scoped_definitions.iter().filter(|d| d.0 == scope) {
+                writeln!(f, "let {} = {};", name, expr)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Statement<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        if let Some(doc) = &self.doc {
+            for line in doc.split('\n') {
+                writeln!(f, "#{}", line)?;
+            }
+        }
+        if let Some(name) = &self.name {
+            write!(f, "@name(")?;
+            write_string(f, name)?;
+            write!(f, ") ")?;
+        }
+        let arrow = if self.additive { "+=" } else { "=>" };
+        write!(f, "{} {} {};", self.predicate, arrow, self.cost_expr)
+    }
+}
+
+impl fmt::Display for Predicate<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        write!(f, "{}", self.match_)?;
+        if let Some(when_clause) = &self.when_clause {
+            write!(f, " when {}", when_clause.condition)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Match<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        match self {
+            Match::GraphQL(field, definitions) => {
+                write!(f, "query ")?;
+                if !definitions.is_empty() {
+                    write!(f, "(")?;
+                    for (i, definition) in definitions.iter().enumerate() {
+                        if i != 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "${}: ", definition.name)?;
+                        write_type(f, &definition.var_type)?;
+                        if let Some(value) = &definition.default_value {
+                            write!(f, " = {}", value)?;
+                        }
+                    }
+                    write!(f, ") ")?;
+                }
+                write!(f, "{{ {} }}", field.to_string().trim())
+            }
+            Match::Default => write!(f, "default"),
+        }
+    }
+}
+
+fn write_type(f: &mut fmt::Formatter<'_>, var_type: &q::Type<'_, &str>) -> fmt::Result {
+    match var_type {
+        q::Type::NamedType(name) => write!(f, "{}", name),
+        q::Type::ListType(inner) => {
+            write!(f, "[")?;
+            write_type(f, inner)?;
+            write!(f, "]")
+        }
+        q::Type::NonNullType(inner) => {
+            write_type(f, inner)?;
+            write!(f, "!")
+        }
+    }
+}
+
+// The parser applies each linear operator in a separate pass, so each
+// operator is effectively its own level of precedence.
+fn linear_precedence(op: AnyLinearOperator) -> u8 {
+    match op {
+        AnyLinearOperator::BitOr => 1,
+        AnyLinearOperator::BitAnd => 2,
+        AnyLinearOperator::Shr => 3,
+        AnyLinearOperator::Shl => 4,
+        AnyLinearOperator::Sub => 5,
+        AnyLinearOperator::Add => 6,
+        AnyLinearOperator::Mod => 7,
+        AnyLinearOperator::Div => 8,
+        AnyLinearOperator::Mul => 9,
+        AnyLinearOperator::Pow => 10,
+    }
+}
+
+const LEAF_PRECEDENCE: u8 = u8::MAX;
+
+fn linear_op_tag(op: AnyLinearOperator) -> &'static str {
+    match op {
+        AnyLinearOperator::Add => "+",
+        AnyLinearOperator::Sub => "-",
+        AnyLinearOperator::Mul => "*",
+        AnyLinearOperator::Div => "/",
+        AnyLinearOperator::Mod => "%",
+        AnyLinearOperator::Pow => "^",
+        AnyLinearOperator::Shl => "<<",
+        AnyLinearOperator::Shr => ">>",
+        AnyLinearOperator::BitAnd => "&",
+        AnyLinearOperator::BitOr => "|",
+    }
+}
+
+impl LinearExpression {
+    fn precedence(&self) -> u8 {
+        match self {
+            LinearExpression::BinaryExpression(binary) => linear_precedence(binary.op),
+            // Fractions which can't be written as a decimal are written as a division.
+            LinearExpression::Const(c) if decimal_places(&c.value).is_none() => {
+                linear_precedence(AnyLinearOperator::Div)
+            }
+            // Errors are written as an expression which fails in the same way.
+            LinearExpression::Error(e) if variable_name(e).is_none() => {
+                linear_precedence(AnyLinearOperator::Div)
+            }
+            // A conditional binds more loosely than any operator.
+            LinearExpression::Conditional { .. } => 0,
+            _ => LEAF_PRECEDENCE,
+        }
+    }
+}
+
+/// Writes `child`, surrounded by parentheses if it would otherwise be parsed
+/// differently as an operand of an operator with the given precedence.
+fn write_operand(
+    f: &mut fmt::Formatter<'_>,
+    child: &impl fmt::Display,
+    child_precedence: u8,
+    parent_precedence: u8,
+    needs_parens_if_equal: bool,
+) -> fmt::Result {
+    if child_precedence < parent_precedence
+        || (child_precedence == parent_precedence && needs_parens_if_equal)
+    {
+        write!(f, "({})", child)
+    } else {
+        write!(f, "{}", child)
+    }
+}
+
+impl fmt::Display for LinearExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        match self {
+            LinearExpression::Const(c) => write_real(f, &c.value),
+            LinearExpression::Variable(var) => write!(f, "${}", var.name()),
+            LinearExpression::BinaryExpression(binary) => {
+                let precedence = linear_precedence(binary.op);
+                // Only ^ is right-associative.
+                let right_assoc = binary.op == AnyLinearOperator::Pow;
+                write_operand(
+                    f,
+                    &binary.lhs,
+                    binary.lhs.precedence(),
+                    precedence,
+                    right_assoc,
+                )?;
+                write!(f, " {} ", linear_op_tag(binary.op))?;
+                write_operand(
+                    f,
+                    &binary.rhs,
+                    binary.rhs.precedence(),
+                    precedence,
+                    !right_assoc,
+                )
+            }
+            LinearExpression::Call { func, args } => {
+                write!(f, "{}(", func.name())?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            LinearExpression::Neg(expr) => match expr.as_ref() {
+                LinearExpression::Variable(_)
+                | LinearExpression::Call { .. }
+                | LinearExpression::Len(_)
+                | LinearExpression::Fold { .. }
+                | LinearExpression::Neg(_) => write!(f, "-{}", expr),
+                _ => write!(f, "-({})", expr),
+            },
+            LinearExpression::Len(expr) => write!(f, "len({})", expr),
+            LinearExpression::Fold { func, list } => write!(f, "{}(${})", func.name(), list.name()),
+            LinearExpression::Conditional {
+                cond,
+                then,
+                otherwise,
+            } => write!(f, "{} ? {} : {}", cond, then, otherwise),
+            LinearExpression::Error(e) => write_error(f, e),
+        }
+    }
+}
+
+fn comparison_tag(op: AnyComparison) -> &'static str {
+    match op {
+        AnyComparison::Eq => "==",
+        AnyComparison::Ne => "!=",
+        AnyComparison::Gt => ">",
+        AnyComparison::Lt => "<",
+        AnyComparison::Ge => ">=",
+        AnyComparison::Le => "<=",
+    }
+}
+
+fn boolean_precedence(op: AnyBooleanOp) -> u8 {
+    match op {
+        AnyBooleanOp::Or => 1,
+        AnyBooleanOp::And => 2,
+    }
+}
+
+fn boolean_op_tag(op: AnyBooleanOp) -> &'static str {
+    match op {
+        AnyBooleanOp::And => "&&",
+        AnyBooleanOp::Or => "||",
+    }
+}
+
+impl Condition {
+    fn precedence(&self) -> u8 {
+        match self {
+            Condition::Boolean(binary) => boolean_precedence(binary.op),
+            _ => LEAF_PRECEDENCE,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        match self {
+            Condition::Comparison(c) => {
+                write!(f, "{} {} {}", c.lhs, comparison_tag(c.op), c.rhs)
+            }
+            Condition::StringComparison(c) => {
+                write!(f, "{} {} {}", c.lhs, comparison_tag(c.op), c.rhs)
+            }
+            Condition::In(membership) => write!(f, "{}", membership),
+            #[cfg(feature = "regex")]
+            Condition::Regex { var, pattern } => {
+                write!(f, "${} =~ ", var.name())?;
+                write_string(f, pattern.0.as_str())
+            }
+            Condition::Boolean(binary) => {
+                let precedence = boolean_precedence(binary.op);
+                write_operand(f, &binary.lhs, binary.lhs.precedence(), precedence, false)?;
+                write!(f, " {} ", boolean_op_tag(binary.op))?;
+                write_operand(f, &binary.rhs, binary.rhs.precedence(), precedence, true)
+            }
+            Condition::Not(condition) => match condition.as_ref() {
+                Condition::Not(_) | Condition::Variable(_) | Condition::Const(_) => {
+                    write!(f, "!{}", condition)
+                }
+                _ => write!(f, "!({})", condition),
+            },
+            Condition::IsNull(var) => write!(f, "is_null(${})", var.name()),
+            Condition::Defined(var) => write!(f, "defined(${})", var.name()),
+            Condition::Variable(var) => write!(f, "${}", var.name()),
+            Condition::Const(c) => write!(f, "{}", c.value),
+            Condition::Error(e) => match variable_name(e) {
+                Some(name) => write!(f, "${}", name),
+                None => {
+                    write_error(f, e)?;
+                    write!(f, " == 0")
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Display for StringExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        match self {
+            StringExpression::Const(c) => write_string(f, &c.value),
+            StringExpression::Variable(var) => write!(f, "${}", var.name()),
+            // Only variables produce errors in string expressions.
+            StringExpression::Error(e) => write!(f, "${}", variable_name(e).unwrap_or("")),
+        }
+    }
+}
+
+impl fmt::Display for Membership {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        match self {
+            Membership::Reals { var, list } => {
+                write!(f, "${} in [", var.name())?;
+                for (i, value) in list.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_real(f, value)?;
+                }
+            }
+            Membership::Strings { var, list } => {
+                write!(f, "${} in [", var.name())?;
+                for (i, value) in list.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_string(f, value)?;
+                }
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// The name of the variable responsible for an error, if any.
+fn variable_name(error: &EvalError) -> Option<&str> {
+    match error {
+        EvalError::MissingVariable(name) => Some(name),
+        EvalError::TypeMismatch { name } => Some(name),
+        EvalError::VariableTypeMismatch { name, .. } => Some(name),
+        _ => None,
+    }
+}
+
+/// Writes a linear expression which fails with the same error.
+fn write_error(f: &mut fmt::Formatter<'_>, error: &EvalError) -> fmt::Result {
+    match error {
+        EvalError::MissingVariable(name)
+        | EvalError::TypeMismatch { name }
+        | EvalError::VariableTypeMismatch { name, .. } => write!(f, "${}", name),
+        EvalError::DivideByZero => write!(f, "1 / 0"),
+        EvalError::Overflow => write!(f, "2 ^ {}", MAX_EXPONENT + 1),
+        EvalError::NotAnInteger => write!(f, "2 ^ 0.5"),
+        EvalError::InvalidArgument => write!(f, "log2(0)"),
+    }
+}
+
+/// The number of decimal places needed to write the value,
+/// or None if the decimal expansion does not terminate.
+fn decimal_places(value: &BigFraction) -> Option<u32> {
+    let ratio = match value {
+        GenericFraction::Rational(_, ratio) => ratio,
+        GenericFraction::Infinity(_) | GenericFraction::NaN => return None,
+    };
+
+    // A decimal terminates exactly when the denominator has no prime factors but 2 and 5.
+    let mut denom = ratio.denom().clone();
+    let mut places = [0u32; 2];
+    for (factor, count) in [2u32, 5u32].iter().zip(places.iter_mut()) {
+        let factor = BigUint::from(*factor);
+        while (&denom % &factor).is_zero() {
+            denom /= &factor;
+            *count += 1;
+        }
+    }
+    if denom == BigUint::from(1u32) {
+        Some(places[0].max(places[1]))
+    } else {
+        None
+    }
+}
+
+fn write_real(f: &mut fmt::Formatter<'_>, value: &BigFraction) -> fmt::Result {
+    let (sign, ratio) = match value {
+        GenericFraction::Rational(sign, ratio) => (sign, ratio),
+        // These can't be parsed, so write something which fails to evaluate like them.
+        GenericFraction::Infinity(_) | GenericFraction::NaN => {
+            return write_error(f, &EvalError::DivideByZero)
+        }
+    };
+    if matches!(sign, Sign::Minus) && !ratio.numer().is_zero() {
+        write!(f, "-")?;
+    }
+
+    let places = match decimal_places(value) {
+        Some(places) => places,
+        None => return write!(f, "{} / {}", ratio.numer(), ratio.denom()),
+    };
+    let scaled = ratio.numer() * BigUint::from(10u32).pow(places) / ratio.denom();
+    let digits = scaled.to_string();
+    let places = places as usize;
+    if places == 0 {
+        write!(f, "{}", digits)
+    } else {
+        let digits = format!("{:0>width$}", digits, width = places + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - places);
+        write!(f, "{}.{}", whole, fraction)
+    }
+}
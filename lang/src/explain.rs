@@ -0,0 +1,51 @@
+use crate::prelude::*;
+use fraction::BigFraction;
+use num_bigint::BigUint;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Why a query costs what it does. See `CostModel::explain`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostExplanation {
+    /// The statements which costed the query, in the order of the top-level fields
+    /// that they matched.
+    pub matches: Vec<StatementMatch>,
+    /// The cost of the query, in wei
+    pub cost: BigUint,
+}
+
+/// A statement which costed a top-level field of the query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementMatch {
+    /// The index of the statement, in the order that the statements were declared
+    pub statement: usize,
+    pub name: Option<String>,
+    /// The cost expression of the statement, as it would be written in the cost model
+    pub cost_expr: String,
+    /// What the statement captured, including built-in captures. See `Captures::debug_snapshot`.
+    pub captures: BTreeMap<String, String>,
+    /// The cost of the field, in GRT
+    pub cost: BigFraction,
+}
+
+impl fmt::Display for CostExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        for m in self.matches.iter() {
+            write!(f, "Statement {}", m.statement)?;
+            if let Some(name) = &m.name {
+                write!(f, " ({:?})", name)?;
+            }
+            match m.captures.get("__alias") {
+                Some(alias) => writeln!(f, " matched field {}", alias.trim_matches('"'))?,
+                None => writeln!(f, " matched")?,
+            }
+            writeln!(f, "  cost: {} => {} GRT", m.cost_expr, m.cost)?;
+            for (name, value) in m.captures.iter() {
+                writeln!(f, "  ${} = {}", name, value)?;
+            }
+        }
+        write!(f, "Total: {} wei", self.cost)
+    }
+}
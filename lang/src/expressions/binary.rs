@@ -1,9 +1,12 @@
+use super::*;
+
 // TODO: The simplest way to make this recursion free would be
 // to use a stack machine for execution.
 /// An expression like 1 + 1 consisting of
 /// a left-hand-side expression, an operator, and
 /// a right-hand-side expression.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BinaryExpression<Op, LHS, RHS = LHS> {
     pub(crate) lhs: LHS,
     pub(crate) op: Op,
@@ -20,8 +23,8 @@ impl<Op, LHS, RHS> BinaryExpression<Op, LHS, RHS> {
 pub trait BinaryOperator<T> {
     type Type;
     #[inline(always)]
-    fn short_circuit(&self, _lhs: &T) -> Result<Option<Self::Type>, ()> {
+    fn short_circuit(&self, _lhs: &T) -> Result<Option<Self::Type>, EvalError> {
         Ok(None)
     }
-    fn exec(&self, lhs: T, rhs: T) -> Result<Self::Type, ()>;
+    fn exec(&self, lhs: T, rhs: T) -> Result<Self::Type, EvalError>;
 }
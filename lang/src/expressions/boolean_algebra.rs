@@ -3,14 +3,20 @@
 use super::*;
 
 macro_rules! boolean_op {
-    ($($Name:ident: $op:tt,)+) => {
+    ($($Name:ident: $op:tt, $decides:literal,)+) => {
         $(
             #[derive(Debug, PartialEq, Eq, Copy, Clone)]
             pub struct $Name;
 
             impl BinaryOperator<bool> for $Name {
                 type Type = bool;
-                fn exec(&self, lhs: bool, rhs: bool) -> Result<bool, ()> {
+                // When the left-hand side decides the value,
+                // the right-hand side is not evaluated.
+                #[inline(always)]
+                fn short_circuit(&self, lhs: &bool) -> Result<Option<bool>, EvalError> {
+                    Ok(if *lhs == $decides { Some($decides) } else { None })
+                }
+                fn exec(&self, lhs: bool, rhs: bool) -> Result<bool, EvalError> {
                     Ok(lhs $op rhs)
                 }
             }
@@ -24,6 +30,7 @@ macro_rules! boolean_op {
         )+
 
         #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
         pub enum AnyBooleanOp {
             $(
                 $Name,
@@ -32,7 +39,14 @@ macro_rules! boolean_op {
 
         impl BinaryOperator<bool> for AnyBooleanOp {
             type Type = bool;
-            fn exec(&self, lhs: bool, rhs: bool) -> Result<bool, ()> {
+            fn short_circuit(&self, lhs: &bool) -> Result<Option<bool>, EvalError> {
+                match self {
+                    $(
+                        Self::$Name => $Name.short_circuit(lhs),
+                    )+
+                }
+            }
+            fn exec(&self, lhs: bool, rhs: bool) -> Result<bool, EvalError> {
                 match self {
                     $(
                         Self::$Name => $Name.exec(lhs, rhs),
@@ -44,6 +58,6 @@ macro_rules! boolean_op {
 }
 
 boolean_op![
-    And: &&,
-    Or: ||,
+    And: &&, false,
+    Or: ||, true,
 ];
@@ -8,7 +8,7 @@ macro_rules! comparisons {
 
             impl<T: $T> BinaryOperator<T> for $Name {
                 type Type = bool;
-                fn exec(&self, lhs: T, rhs: T) -> Result<Self::Type, ()> {
+                fn exec(&self, lhs: T, rhs: T) -> Result<Self::Type, EvalError> {
                     Ok(lhs $op rhs)
                 }
             }
@@ -22,6 +22,7 @@ macro_rules! comparisons {
         )+
 
         #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
         pub enum AnyComparison {
             $(
                 $Name,
@@ -32,7 +33,7 @@ macro_rules! comparisons {
             T: PartialEq + PartialOrd
         {
             type Type = bool;
-            fn exec(&self, lhs: T, rhs: T) -> Result<Self::Type, ()> {
+            fn exec(&self, lhs: T, rhs: T) -> Result<Self::Type, EvalError> {
                 match self {
                     $(Self::$Name => $Name.exec(lhs, rhs),)+
                 }
@@ -0,0 +1,55 @@
+use crate::prelude::*;
+use std::{error, fmt};
+
+/// The reason that evaluating an expression failed.
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EvalError {
+    /// A variable was neither captured nor substituted by a global.
+    MissingVariable(String),
+    /// A variable was found, but could not be coerced to the expected type.
+    TypeMismatch {
+        name: String,
+    },
+    /// A variable was captured from a query variable, which could not be
+    /// coerced to the type that the variable is used as.
+    VariableTypeMismatch {
+        /// The name of the query variable
+        name: String,
+        expected: String,
+        got: String,
+    },
+    DivideByZero,
+    Overflow,
+    /// An operation which is only defined for whole numbers was given a fraction.
+    NotAnInteger,
+    /// A function or operator was given arguments outside of its domain.
+    InvalidArgument,
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        use EvalError::*;
+        match self {
+            MissingVariable(name) => write!(f, "Missing variable ${}", name),
+            TypeMismatch { name } => write!(f, "Variable ${} has an unexpected type", name),
+            VariableTypeMismatch {
+                name,
+                expected,
+                got,
+            } => write!(
+                f,
+                "Query variable ${} is used as a {}, but is a {}",
+                name, expected, got
+            ),
+            DivideByZero => write!(f, "Divide by zero"),
+            Overflow => write!(f, "Overflow"),
+            NotAnInteger => write!(f, "Expected a whole number"),
+            InvalidArgument => write!(f, "Invalid argument"),
+        }
+    }
+}
+
+impl error::Error for EvalError {}
@@ -6,6 +6,9 @@ use fraction::BigFraction;
 pub enum Atom<'a, Expr, Op> {
     Expr(&'a Expr),
     Op(Op),
+    /// The right-hand side of an operator which may short circuit,
+    /// to be scheduled unless the operator decides the value from the left.
+    Rhs(Op, &'a Expr),
 }
 pub struct Stack<'a, E, O, V, C> {
     queue: Vec<Atom<'a, E, O>>,
@@ -19,20 +22,57 @@ pub type LinearStack<'a, 'c> =
 pub type CondStack<'a, 'c> = Stack<'a, Condition, AnyBooleanOp, bool, LinearStack<'a, 'c>>;
 
 pub trait Schedule<'e, Stack> {
-    fn schedule(&'e self, stack: &mut Stack) -> Result<(), ()>;
+    fn schedule(&'e self, stack: &mut Stack) -> Result<(), EvalError>;
 }
 
 impl<'a, 'c> Schedule<'a, LinearStack<'a, 'c>> for LinearExpression {
-    fn schedule(&'a self, stack: &mut LinearStack<'a, 'c>) -> Result<(), ()> {
+    fn schedule(&'a self, stack: &mut LinearStack<'a, 'c>) -> Result<(), EvalError> {
         match self {
             LinearExpression::Const(c) => stack.push_value(c.eval()),
             LinearExpression::Variable(v) => stack.push_value(v.eval(stack.context)?),
-            LinearExpression::Error(e) => return Err(*e),
+            LinearExpression::Error(e) => return Err(e.clone()),
             LinearExpression::BinaryExpression(bin) => {
                 stack.queue.push(Atom::Op(bin.op));
                 stack.push_expr(&bin.rhs);
                 stack.push_expr(&bin.lhs);
             }
+            LinearExpression::Call { func, args } => {
+                // Like comparisons in conditions, arguments execute on a nested stack frame.
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args.iter() {
+                    values.push(stack.execute(arg)?);
+                }
+                stack.push_value(func.exec(values)?);
+            }
+            LinearExpression::Neg(expr) => {
+                let value = stack.execute(&**expr)?;
+                stack.push_value(-value);
+            }
+            LinearExpression::Len(expr) => {
+                let value = expr.eval(stack.context)?;
+                stack.push_value((value.chars().count() as i64).into());
+            }
+            LinearExpression::Fold { func, list } => {
+                let items = list.eval(stack.context)?;
+                let value = func.exec(&items).map_err(|item| {
+                    list.type_mismatch(stack.context, "Number", value_kind(item))
+                })?;
+                stack.push_value(value);
+            }
+            LinearExpression::Conditional {
+                cond,
+                then,
+                otherwise,
+            } => {
+                // The condition executes on a nested stack frame. Only the selected
+                // branch is scheduled, so errors in the other branch are not raised.
+                let mut conditions = CondStack::new(LinearStack::new(stack.context));
+                if conditions.execute(&**cond)? {
+                    stack.push_expr(&**then);
+                } else {
+                    stack.push_expr(&**otherwise);
+                }
+            }
         }
 
         Ok(())
@@ -40,22 +80,45 @@ impl<'a, 'c> Schedule<'a, LinearStack<'a, 'c>> for LinearExpression {
 }
 
 impl<'a, 'c> Schedule<'a, CondStack<'a, 'c>> for Condition {
-    fn schedule(&'a self, stack: &mut CondStack<'a, 'c>) -> Result<(), ()> {
+    fn schedule(&'a self, stack: &mut CondStack<'a, 'c>) -> Result<(), EvalError> {
         match self {
             Condition::Const(c) => stack.push_value(c.eval()),
             Condition::Variable(v) => stack.push_value(v.eval(stack.context.context)?),
+            Condition::IsNull(v) => stack.push_value(v.eval(stack.context.context)?.0),
+            Condition::Defined(v) => stack.push_value(stack.context.context.contains(v.name())),
             Condition::Comparison(c) => {
                 let lhs = stack.context.execute(&c.lhs)?;
                 let rhs = stack.context.execute(&c.rhs)?;
                 let value = c.op.exec(lhs, rhs)?;
                 stack.push_value(value);
             }
-            Condition::Error(e) => return Err(*e),
+            Condition::StringComparison(c) => {
+                let lhs = c.lhs.eval(stack.context.context)?;
+                let rhs = c.rhs.eval(stack.context.context)?;
+                let value = c.op.exec(lhs, rhs)?;
+                stack.push_value(value);
+            }
+            Condition::In(membership) => stack.push_value(membership.eval(stack.context.context)?),
+            #[cfg(feature = "regex")]
+            Condition::Regex { var, pattern } => {
+                let value = match stack.context.context.get_as::<String>(var.name()) {
+                    Ok(Some(value)) => pattern.0.is_match(&value),
+                    _ => false,
+                };
+                stack.push_value(value);
+            }
+            Condition::Error(e) => return Err(e.clone()),
             Condition::Boolean(bin) => {
-                stack.queue.push(Atom::Op(bin.op));
-                stack.push_expr(&bin.rhs);
+                // The right-hand side is only scheduled once the left-hand side
+                // has a value, so that eg: `defined($a) && $a > 1` does not fail.
+                stack.queue.push(Atom::Rhs(bin.op, &bin.rhs));
                 stack.push_expr(&bin.lhs);
             }
+            Condition::Not(condition) => {
+                // Like comparisons, the negated condition executes on a nested stack frame.
+                let value = stack.execute(&**condition)?;
+                stack.push_value(!value);
+            }
         }
         Ok(())
     }
@@ -81,7 +144,7 @@ impl<'a, Expr, Op, V, C> Stack<'a, Expr, Op, V, C> {
 }
 
 impl<'a, Expr, Op, V, C> Stack<'a, Expr, Op, V, C> {
-    pub fn execute(&mut self, expr: &'a Expr) -> Result<V, ()>
+    pub fn execute(&mut self, expr: &'a Expr) -> Result<V, EvalError>
     where
         Expr: Schedule<'a, Self>,
         Op: BinaryOperator<V, Type = V>,
@@ -95,6 +158,8 @@ impl<'a, Expr, Op, V, C> Stack<'a, Expr, Op, V, C> {
         let values_len = self.values.len();
         self.push_expr(expr);
 
+        // Panic safety: Each expression leaves exactly one value on the stack, so
+        // the values of both operands are there by the time their operator runs.
         while self.queue.len() > len {
             let next = self.queue.pop().unwrap();
 
@@ -108,6 +173,19 @@ impl<'a, Expr, Op, V, C> Stack<'a, Expr, Op, V, C> {
                     let value = op.exec(lhs, rhs)?;
                     self.values.push(value);
                 }
+                Atom::Rhs(op, rhs) => {
+                    let lhs = self.values.last().unwrap();
+                    match op.short_circuit(lhs)? {
+                        Some(value) => {
+                            self.values.pop();
+                            self.values.push(value);
+                        }
+                        None => {
+                            self.queue.push(Atom::Op(op));
+                            self.push_expr(rhs);
+                        }
+                    }
+                }
             }
         }
         assert!(self.values.len() == values_len + 1);
@@ -0,0 +1,171 @@
+use super::*;
+use crate::coercion::Coerce as _;
+use crate::graphql_utils::StaticValue;
+use crate::prelude::*;
+use fraction::{BigFraction, GenericFraction, Sign};
+use num_bigint::BigUint;
+use num_traits::Zero as _;
+
+/// A function which may be called from within a rational expression.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BuiltinFn {
+    Min,
+    Max,
+    /// The floor of the base 2 logarithm
+    Log2,
+    /// The floor of the base 10 logarithm
+    Log10,
+    /// The absolute value
+    Abs,
+    /// The quotient of two values, rounded up to a whole number
+    CeilDiv,
+}
+
+impl BuiltinFn {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuiltinFn::Min => "min",
+            BuiltinFn::Max => "max",
+            BuiltinFn::Log2 => "log2",
+            BuiltinFn::Log10 => "log10",
+            BuiltinFn::Abs => "abs",
+            BuiltinFn::CeilDiv => "ceil_div",
+        }
+    }
+
+    /// The minimum and (if any) maximum number of arguments accepted.
+    pub fn arity(&self) -> (usize, Option<usize>) {
+        match self {
+            BuiltinFn::Min | BuiltinFn::Max => (2, None),
+            BuiltinFn::Log2 | BuiltinFn::Log10 | BuiltinFn::Abs => (1, Some(1)),
+            BuiltinFn::CeilDiv => (2, Some(2)),
+        }
+    }
+
+    pub fn accepts(&self, count: usize) -> bool {
+        let (min, max) = self.arity();
+        count >= min && max.map_or(true, |max| count <= max)
+    }
+
+    pub fn exec(&self, args: Vec<BigFraction>) -> Result<BigFraction, EvalError> {
+        profile_method!(exec);
+
+        if !self.accepts(args.len()) {
+            return Err(EvalError::InvalidArgument);
+        }
+
+        let mut args = args.into_iter();
+        let first = args.next().ok_or(EvalError::InvalidArgument)?;
+        match self {
+            BuiltinFn::Min => Ok(args.fold(first, |min, v| if v < min { v } else { min })),
+            BuiltinFn::Max => Ok(args.fold(first, |max, v| if v > max { v } else { max })),
+            BuiltinFn::Log2 => floor_log(&first, 2),
+            BuiltinFn::Log10 => floor_log(&first, 10),
+            BuiltinFn::Abs => Ok(abs(first)),
+            BuiltinFn::CeilDiv => {
+                let second = args.next().ok_or(EvalError::InvalidArgument)?;
+                ceil_div(first, second)
+            }
+        }
+    }
+}
+
+/// A function of the items of a list, which may be called from within a rational
+/// expression with a capture of a list, eg: `sum($amounts)`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ListFn {
+    /// The sum of the items
+    Sum,
+    /// The number of items
+    Count,
+}
+
+impl ListFn {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ListFn::Sum => "sum",
+            ListFn::Count => "count",
+        }
+    }
+
+    /// Fails with the first item which is used as a number but can't be coerced to one.
+    pub fn exec<'v>(&self, items: &'v [StaticValue]) -> Result<BigFraction, &'v StaticValue> {
+        profile_method!(exec);
+
+        match self {
+            ListFn::Sum => {
+                let mut sum = BigFraction::from(0);
+                for item in items.iter() {
+                    let value: BigFraction = item.coerce().map_err(|()| item)?;
+                    sum = sum + value;
+                }
+                Ok(sum)
+            }
+            ListFn::Count => Ok((items.len() as i64).into()),
+        }
+    }
+}
+
+fn abs(value: BigFraction) -> BigFraction {
+    match value {
+        GenericFraction::Rational(_, ratio) => GenericFraction::Rational(Sign::Plus, ratio),
+        GenericFraction::Infinity(_) => GenericFraction::Infinity(Sign::Plus),
+        GenericFraction::NaN => GenericFraction::NaN,
+    }
+}
+
+fn ceil_div(dividend: BigFraction, divisor: BigFraction) -> Result<BigFraction, EvalError> {
+    match &divisor {
+        GenericFraction::Rational(_, ratio) if ratio.numer().is_zero() => {
+            return Err(EvalError::DivideByZero)
+        }
+        _ => {}
+    }
+    let one = BigUint::from(1u32);
+    match dividend / divisor {
+        GenericFraction::Rational(Sign::Plus, ratio) => {
+            Ok(BigFraction::new(ratio.ceil().to_integer(), one))
+        }
+        // Rounding a negative value up rounds its magnitude down
+        GenericFraction::Rational(Sign::Minus, ratio) => {
+            Ok(BigFraction::new_neg(ratio.floor().to_integer(), one))
+        }
+        GenericFraction::Infinity(_) | GenericFraction::NaN => Err(EvalError::Overflow),
+    }
+}
+
+/// The floor of the logarithm of a positive value.
+fn floor_log(value: &BigFraction, base: u32) -> Result<BigFraction, EvalError> {
+    let ratio = match value {
+        GenericFraction::Rational(Sign::Plus, ratio) if !ratio.numer().is_zero() => ratio,
+        GenericFraction::Rational(_, _) => return Err(EvalError::InvalidArgument),
+        GenericFraction::Infinity(_) | GenericFraction::NaN => return Err(EvalError::Overflow),
+    };
+    let base = BigUint::from(base);
+    let one = BigUint::from(1u32);
+
+    if ratio.numer() >= ratio.denom() {
+        // Since powers of the base are whole, base^k <= x exactly when base^k <= floor(x).
+        let int = ratio.to_integer();
+        let mut power = one.clone();
+        let mut k = 0u64;
+        while &power * &base <= int {
+            power *= &base;
+            k += 1;
+        }
+        Ok(BigFraction::new(BigUint::from(k), one))
+    } else {
+        // For x < 1, floor(log(x)) is -ceil(log(1/x)). Again since powers of the base
+        // are whole, base^k >= 1/x exactly when base^k >= ceil(1/x).
+        let int = ratio.recip().ceil().to_integer();
+        let mut power = one.clone();
+        let mut k = 0u64;
+        while power < int {
+            power *= &base;
+            k += 1;
+        }
+        Ok(BigFraction::new_neg(BigUint::from(k), one))
+    }
+}
@@ -1,17 +1,31 @@
 use super::*;
-use std::ops;
+use fraction::{BigFraction, GenericFraction, Sign};
+use num_bigint::{BigInt, BigUint, Sign as IntSign};
+use num_traits::{Pow as _, ToPrimitive as _, Zero as _};
+
+/// The largest exponent accepted by the `^` operator. Larger exponents
+/// fail with `EvalError::Overflow` rather than risk exhausting memory.
+pub const MAX_EXPONENT: u32 = 256;
+
+/// The largest shift accepted by the `<<` operator, for the same reason as `MAX_EXPONENT`.
+pub const MAX_SHIFT: usize = 256;
+
+/// The most bits that the numerator or denominator of the result of the `^` operator
+/// may have. Without it, nesting `^` grows the result exponentially even within
+/// `MAX_EXPONENT`, eg: `((2 ^ 256) ^ 256) ^ 256`.
+pub const MAX_POW_BITS: u64 = 1 << 20;
 
 macro_rules! linear_op {
-    ($($Name:ident: $op:tt,)+) => {
+    ($($Name:ident: |$lhs:ident, $rhs:ident| $exec:expr,)+) => {
         $(
             #[derive(Debug, PartialEq, Eq, Copy, Clone)]
             pub struct $Name;
 
-            impl<T: ops::$Name> BinaryOperator<T> for $Name {
-                type Type = T::Output;
+            impl BinaryOperator<BigFraction> for $Name {
+                type Type = BigFraction;
                 #[inline(always)]
-                fn exec(&self, lhs: T, rhs: T) -> Result<Self::Type, ()> {
-                    Ok(lhs $op rhs)
+                fn exec(&self, $lhs: BigFraction, $rhs: BigFraction) -> Result<Self::Type, EvalError> {
+                    $exec
                 }
             }
 
@@ -24,17 +38,16 @@ macro_rules! linear_op {
         )+
 
         #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
         pub enum AnyLinearOperator {
             $(
                 $Name,
             )+
         }
 
-        impl<T> BinaryOperator<T> for AnyLinearOperator where
-            $(T: ops::$Name<Output=T>,)+
-        {
-            type Type = T;
-            fn exec(&self, lhs: T, rhs: T) -> Result<Self::Type, ()> {
+        impl BinaryOperator<BigFraction> for AnyLinearOperator {
+            type Type = BigFraction;
+            fn exec(&self, lhs: BigFraction, rhs: BigFraction) -> Result<Self::Type, EvalError> {
                 match self {
                     $(Self::$Name => $Name.exec(lhs, rhs),)+
                 }
@@ -44,9 +57,125 @@ macro_rules! linear_op {
     }
 }
 
+fn is_zero(value: &BigFraction) -> bool {
+    match value {
+        GenericFraction::Rational(_, ratio) => ratio.numer().is_zero(),
+        GenericFraction::Infinity(_) | GenericFraction::NaN => false,
+    }
+}
+
+/// Rounds toward zero
+fn trunc(value: &BigFraction) -> BigFraction {
+    match value {
+        GenericFraction::Rational(sign, ratio) => {
+            let int = ratio.to_integer();
+            let one = BigUint::from(1u32);
+            match sign {
+                Sign::Plus => BigFraction::new(int, one),
+                Sign::Minus => BigFraction::new_neg(int, one),
+            }
+        }
+        other => other.clone(),
+    }
+}
+
+fn to_integer(value: &BigFraction) -> Result<(Sign, BigUint), EvalError> {
+    match value {
+        GenericFraction::Rational(sign, ratio) if ratio.is_integer() => {
+            Ok((*sign, ratio.to_integer()))
+        }
+        GenericFraction::Rational(_, _) => Err(EvalError::NotAnInteger),
+        GenericFraction::Infinity(_) | GenericFraction::NaN => Err(EvalError::Overflow),
+    }
+}
+
+/// Bitwise operators act on the two's complement of whole numbers.
+fn to_bigint(value: &BigFraction) -> Result<BigInt, EvalError> {
+    let (sign, int) = to_integer(value)?;
+    let sign = match sign {
+        Sign::Plus => IntSign::Plus,
+        Sign::Minus => IntSign::Minus,
+    };
+    Ok(BigInt::from_biguint(sign, int))
+}
+
+fn from_bigint(value: BigInt) -> BigFraction {
+    let (sign, bytes) = value.to_bytes_le();
+    let int = BigUint::from_bytes_le(&bytes);
+    let one = BigUint::from(1u32);
+    match sign {
+        IntSign::Minus => BigFraction::new_neg(int, one),
+        IntSign::NoSign | IntSign::Plus => BigFraction::new(int, one),
+    }
+}
+
+fn shift_count(value: &BigFraction) -> Result<usize, EvalError> {
+    let (sign, count) = to_integer(value)?;
+    if matches!(sign, Sign::Minus) && !count.is_zero() {
+        return Err(EvalError::InvalidArgument);
+    }
+    // Shifting right by more bits than the value has is the same for any count
+    Ok(count.to_usize().unwrap_or(usize::MAX))
+}
+
+fn pow(base: BigFraction, exponent: u32) -> Result<BigFraction, EvalError> {
+    match base {
+        GenericFraction::Rational(sign, ratio) => {
+            let bits = ratio.numer().bits().max(ratio.denom().bits()) as u64;
+            if bits.saturating_mul(exponent as u64) > MAX_POW_BITS {
+                return Err(EvalError::Overflow);
+            }
+            let numer = ratio.numer().clone().pow(exponent);
+            let denom = ratio.denom().clone().pow(exponent);
+            if matches!(sign, Sign::Minus) && exponent % 2 == 1 {
+                Ok(BigFraction::new_neg(numer, denom))
+            } else {
+                Ok(BigFraction::new(numer, denom))
+            }
+        }
+        GenericFraction::Infinity(_) | GenericFraction::NaN => Err(EvalError::Overflow),
+    }
+}
+
 linear_op![
-    Add: +,
-    Sub: -,
-    Mul: *,
-    Div: /,
+    Add: |lhs, rhs| Ok(lhs + rhs),
+    Sub: |lhs, rhs| Ok(lhs - rhs),
+    Mul: |lhs, rhs| Ok(lhs * rhs),
+    Div: |lhs, rhs| {
+        if is_zero(&rhs) {
+            return Err(EvalError::DivideByZero);
+        }
+        Ok(lhs / rhs)
+    },
+    // Like the remainder operator on integers, the result takes the sign of the lhs.
+    Mod: |lhs, rhs| {
+        if is_zero(&rhs) {
+            return Err(EvalError::DivideByZero);
+        }
+        let quotient = trunc(&(lhs.clone() / rhs.clone()));
+        Ok(lhs - rhs * quotient)
+    },
+    Pow: |lhs, rhs| {
+        let (sign, exponent) = to_integer(&rhs)?;
+        if matches!(sign, Sign::Minus) && !exponent.is_zero() {
+            return Err(EvalError::Overflow);
+        }
+        match exponent.to_u32() {
+            Some(exponent) if exponent <= MAX_EXPONENT => pow(lhs, exponent),
+            _ => Err(EvalError::Overflow),
+        }
+    },
+    Shl: |lhs, rhs| {
+        let count = shift_count(&rhs)?;
+        if count > MAX_SHIFT {
+            return Err(EvalError::Overflow);
+        }
+        Ok(from_bigint(to_bigint(&lhs)? << count))
+    },
+    Shr: |lhs, rhs| {
+        let count = shift_count(&rhs)?;
+        Ok(from_bigint(to_bigint(&lhs)? >> count))
+    },
+    BitAnd: |lhs, rhs| Ok(from_bigint(to_bigint(&lhs)? & to_bigint(&rhs)?)),
+    BitOr: |lhs, rhs| Ok(from_bigint(to_bigint(&lhs)? | to_bigint(&rhs)?)),
 ];
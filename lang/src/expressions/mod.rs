@@ -1,12 +1,16 @@
 pub mod binary;
 pub mod boolean_algebra;
 pub mod comparisons;
+pub mod errors;
 pub mod expr_stack;
+pub mod functions;
 pub mod linears;
 pub mod primitives;
 pub(self) use crate::language::Captures;
 pub use binary::*;
 pub use boolean_algebra::*;
 pub use comparisons::*;
+pub use errors::*;
+pub use functions::*;
 pub use linears::*;
 pub use primitives::*;
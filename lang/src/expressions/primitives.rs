@@ -38,11 +38,33 @@ impl<T> Variable<T>
 where
     StaticValue: Coerce<T>,
 {
-    pub fn eval(&self, captures: &Captures) -> Result<T, ()> {
-        if let Some(Ok(v)) = captures.get_as(&self.name) {
-            Ok(v)
-        } else {
-            Err(())
+    pub fn eval(&self, captures: &Captures) -> Result<T, EvalError> {
+        match captures.get_as(&self.name) {
+            Ok(Some(v)) => Ok(v),
+            Ok(None) => Err(EvalError::MissingVariable(self.name.clone())),
+            Err(e) => {
+                Err(self.type_mismatch(captures, <StaticValue as Coerce<T>>::EXPECTED, e.found))
+            }
+        }
+    }
+
+    /// The error for a capture, or part of one, which is not of the type that it
+    /// is used as. It names the query variable which supplied the capture, if any.
+    pub(crate) fn type_mismatch(
+        &self,
+        captures: &Captures,
+        expected: &str,
+        got: &str,
+    ) -> EvalError {
+        match captures.variable(&self.name) {
+            Some(variable) => EvalError::VariableTypeMismatch {
+                name: variable.to_owned(),
+                expected: expected.to_owned(),
+                got: got.to_owned(),
+            },
+            None => EvalError::TypeMismatch {
+                name: self.name.clone(),
+            },
         }
     }
 }
@@ -1,54 +1,21 @@
 // TODO: This is all copy-pasted from graph-node. Needs to move to a common lib.
-use crate::prelude::*;
 use graphql_parser::query as q;
-use serde::{
-    self,
-    ser::{SerializeMap, SerializeSeq},
-    Deserialize, Deserializer, Serialize, Serializer,
-};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::HashMap;
 use std::convert::TryInto as _;
 
 // TODO: (Performance) may want to do zero-copy here later.
 pub type StaticValue = q::Value<'static, String>;
 
-/// Variable value for a GraphQL query.
-#[derive(Clone, Debug, Deserialize)]
-struct DeserializableGraphQlValue(#[serde(with = "GraphQLValue")] StaticValue);
-
-#[derive(Clone, Debug, Serialize)]
-struct SerializableGraphQlValue<'a>(#[serde(with = "GraphQLValue")] &'a StaticValue);
-
-fn deserialize_variables<'de, D>(deserializer: D) -> Result<HashMap<String, StaticValue>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    profile_fn!(deserialize_variables);
-    let pairs: BTreeMap<String, DeserializableGraphQlValue> =
-        Deserialize::deserialize(deserializer)?;
-    Ok(pairs.into_iter().map(|(k, v)| (k, v.0)).collect())
-}
-
-fn serialize_variables<S>(vars: &HashMap<String, StaticValue>, ser: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    profile_fn!(serialize_variables);
-
-    let mut seq = ser.serialize_map(Some(vars.len()))?;
-    for (key, value) in vars.iter() {
-        seq.serialize_key(key)?;
-        seq.serialize_value(&SerializableGraphQlValue(value))?;
-    }
-    seq.end()
-}
-
 /// Variable values for a GraphQL query.
-#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct QueryVariables(
-    #[serde(
-        deserialize_with = "deserialize_variables",
-        serialize_with = "serialize_variables"
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            deserialize_with = "serde_values::deserialize_variables",
+            serialize_with = "serde_values::serialize_variables"
+        )
     )]
     pub HashMap<String, StaticValue>,
 );
@@ -61,87 +28,155 @@ impl QueryVariables {
     pub fn get(&self, name: &str) -> Option<&StaticValue> {
         self.0.get(name)
     }
-}
 
-#[derive(Serialize, Deserialize)]
-#[serde(untagged, remote = "StaticValue")]
-enum GraphQLValue {
-    #[serde(
-        deserialize_with = "deserialize_number",
-        serialize_with = "serialize_number"
-    )]
-    Int(q::Number),
-    Float(f64),
-    String(String),
-    Boolean(bool),
-    Null,
-    Enum(String),
-    #[serde(
-        deserialize_with = "deserialize_list",
-        serialize_with = "serialize_list"
-    )]
-    List(Vec<StaticValue>),
-    #[serde(
-        deserialize_with = "deserialize_object",
-        serialize_with = "serialize_object"
-    )]
-    Object(BTreeMap<String, StaticValue>),
-    Variable(String),
+    /// The variables of a JSON object, such as the variables sent with a query.
+    pub fn from_json(object: serde_json::Map<String, serde_json::Value>) -> Self {
+        QueryVariables(
+            object
+                .into_iter()
+                .map(|(name, value)| (name, value.to_graphql()))
+                .collect(),
+        )
+    }
 }
 
-fn deserialize_number<'de, D>(deserializer: D) -> Result<q::Number, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let i: i32 = Deserialize::deserialize(deserializer)?;
-    Ok(q::Number::from(i))
-}
+/// Serde support for `QueryVariables`. The variables of a query are parsed
+/// with `QueryVariables::from_json`, which does not need it.
+#[cfg(feature = "serde")]
+mod serde_values {
+    use super::StaticValue;
+    use crate::prelude::*;
+    use graphql_parser::query as q;
+    use serde::{
+        self,
+        ser::{Error as _, SerializeMap, SerializeSeq},
+        Deserialize, Deserializer, Serialize, Serializer,
+    };
+    use std::collections::{BTreeMap, HashMap};
+    use std::convert::TryInto as _;
 
-fn serialize_number<S>(number: &q::Number, ser: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    ser.serialize_i32(number.as_i64().unwrap().try_into().unwrap())
-}
+    /// Variable value for a GraphQL query.
+    #[derive(Clone, Debug, Deserialize)]
+    struct DeserializableGraphQlValue(#[serde(with = "GraphQLValue")] StaticValue);
 
-fn deserialize_list<'de, D>(deserializer: D) -> Result<Vec<StaticValue>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let values: Vec<DeserializableGraphQlValue> = Deserialize::deserialize(deserializer)?;
-    Ok(values.into_iter().map(|v| v.0).collect())
-}
+    #[derive(Clone, Debug, Serialize)]
+    struct SerializableGraphQlValue<'a>(#[serde(with = "GraphQLValue")] &'a StaticValue);
 
-fn serialize_list<S>(list: &Vec<StaticValue>, ser: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    let mut seq = ser.serialize_seq(Some(list.len()))?;
-    for value in list.iter() {
-        seq.serialize_element(&SerializableGraphQlValue(value))?;
+    pub(super) fn deserialize_variables<'de, D>(
+        deserializer: D,
+    ) -> Result<HashMap<String, StaticValue>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        profile_fn!(deserialize_variables);
+        let pairs: BTreeMap<String, DeserializableGraphQlValue> =
+            Deserialize::deserialize(deserializer)?;
+        Ok(pairs.into_iter().map(|(k, v)| (k, v.0)).collect())
     }
-    seq.end()
-}
 
-fn deserialize_object<'de, D>(deserializer: D) -> Result<BTreeMap<String, StaticValue>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let pairs: BTreeMap<String, DeserializableGraphQlValue> =
-        Deserialize::deserialize(deserializer)?;
-    Ok(pairs.into_iter().map(|(k, v)| (k, v.0)).collect())
-}
+    pub(super) fn serialize_variables<S>(
+        vars: &HashMap<String, StaticValue>,
+        ser: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        profile_fn!(serialize_variables);
+
+        let mut seq = ser.serialize_map(Some(vars.len()))?;
+        for (key, value) in vars.iter() {
+            seq.serialize_key(key)?;
+            seq.serialize_value(&SerializableGraphQlValue(value))?;
+        }
+        seq.end()
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged, remote = "StaticValue")]
+    enum GraphQLValue {
+        #[serde(
+            deserialize_with = "deserialize_number",
+            serialize_with = "serialize_number"
+        )]
+        Int(q::Number),
+        Float(f64),
+        String(String),
+        Boolean(bool),
+        Null,
+        Enum(String),
+        #[serde(
+            deserialize_with = "deserialize_list",
+            serialize_with = "serialize_list"
+        )]
+        List(Vec<StaticValue>),
+        #[serde(
+            deserialize_with = "deserialize_object",
+            serialize_with = "serialize_object"
+        )]
+        Object(BTreeMap<String, StaticValue>),
+        Variable(String),
+    }
+
+    fn deserialize_number<'de, D>(deserializer: D) -> Result<q::Number, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let i: i32 = Deserialize::deserialize(deserializer)?;
+        Ok(q::Number::from(i))
+    }
+
+    fn serialize_number<S>(number: &q::Number, ser: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let number: Option<i32> = number.as_i64().and_then(|n| n.try_into().ok());
+        match number {
+            Some(number) => ser.serialize_i32(number),
+            None => Err(S::Error::custom("Int is out of range")),
+        }
+    }
+
+    fn deserialize_list<'de, D>(deserializer: D) -> Result<Vec<StaticValue>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let values: Vec<DeserializableGraphQlValue> = Deserialize::deserialize(deserializer)?;
+        Ok(values.into_iter().map(|v| v.0).collect())
+    }
+
+    fn serialize_list<S>(list: &Vec<StaticValue>, ser: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = ser.serialize_seq(Some(list.len()))?;
+        for value in list.iter() {
+            seq.serialize_element(&SerializableGraphQlValue(value))?;
+        }
+        seq.end()
+    }
+
+    fn deserialize_object<'de, D>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, StaticValue>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pairs: BTreeMap<String, DeserializableGraphQlValue> =
+            Deserialize::deserialize(deserializer)?;
+        Ok(pairs.into_iter().map(|(k, v)| (k, v.0)).collect())
+    }
 
-fn serialize_object<S>(obj: &BTreeMap<String, StaticValue>, ser: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    let mut seq = ser.serialize_map(Some(obj.len()))?;
-    for (key, value) in obj.iter() {
-        seq.serialize_key(key)?;
-        seq.serialize_value(&SerializableGraphQlValue(value))?;
+    fn serialize_object<S>(obj: &BTreeMap<String, StaticValue>, ser: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = ser.serialize_map(Some(obj.len()))?;
+        for (key, value) in obj.iter() {
+            seq.serialize_key(key)?;
+            seq.serialize_value(&SerializableGraphQlValue(value))?;
+        }
+        seq.end()
     }
-    seq.end()
 }
 
 pub trait IntoStaticValue {
@@ -154,6 +189,18 @@ impl IntoStaticValue for i32 {
     }
 }
 
+/// Numbers which fit in an `Int` are one. Larger numbers are written out as a `String`,
+/// which is coerced to a number without losing precision.
+impl IntoStaticValue for u64 {
+    fn to_graphql(self) -> StaticValue {
+        let int: Option<i32> = self.try_into().ok();
+        match int {
+            Some(int) => int.to_graphql(),
+            None => StaticValue::String(self.to_string()),
+        }
+    }
+}
+
 impl IntoStaticValue for bool {
     fn to_graphql(self) -> StaticValue {
         StaticValue::Boolean(self)
@@ -194,6 +241,32 @@ impl<'a, T: q::Text<'a>> IntoStaticValue for &'_ q::Value<'a, T> {
     }
 }
 
+/// Numbers which fit in an `Int` are one, and other numbers are a `Float`.
+impl IntoStaticValue for serde_json::Value {
+    fn to_graphql(self) -> StaticValue {
+        match self {
+            serde_json::Value::Null => StaticValue::Null,
+            serde_json::Value::Bool(b) => StaticValue::Boolean(b),
+            serde_json::Value::Number(n) => {
+                let int: Option<i32> = n.as_i64().and_then(|n| n.try_into().ok());
+                match (int, n.as_f64()) {
+                    (Some(int), _) => int.to_graphql(),
+                    (None, Some(float)) => StaticValue::Float(float),
+                    // Only reachable with the arbitrary_precision feature of serde_json
+                    (None, None) => StaticValue::String(n.to_string()),
+                }
+            }
+            serde_json::Value::String(s) => StaticValue::String(s),
+            serde_json::Value::Array(a) => {
+                StaticValue::List(a.into_iter().map(IntoStaticValue::to_graphql).collect())
+            }
+            serde_json::Value::Object(o) => {
+                StaticValue::Object(o.into_iter().map(|(k, v)| (k, v.to_graphql())).collect())
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     //! GraphQL parsing must not overflow the stack.
@@ -1,20 +1,30 @@
-use crate::coercion::Coerce;
+use crate::coercion::{Coerce, IsNull};
 use crate::expressions::expr_stack::*;
 use crate::expressions::*;
 use crate::graphql_utils::{IntoStaticValue, QueryVariables, StaticValue};
-use crate::matching::{get_capture_names_field, match_query};
+use crate::matching::{
+    get_capture_names_field, match_query, MatchError, UnresolvedDirectives, WILDCARD_FIELD,
+};
 use crate::prelude::*;
+use crate::CompileError;
 use fraction::BigFraction;
 use graphql_parser::query as q;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 
 #[derive(Debug, PartialEq)]
 pub struct Document<'a> {
     pub statements: Vec<Statement<'a>>,
+    /// Named expressions declared with `let`, which statements refer to like globals.
+    pub definitions: Vec<(String, LinearExpression)>,
+    /// Named expressions declared with `let` within a `scope` block, as the name of the
+    /// scope, the name of the definition and its expression. Statements of the scope
+    /// refer to these before `definitions`.
+    pub scoped_definitions: Vec<(String, String, LinearExpression)>,
 }
 
 enum Visit<'a, 't> {
-    Document(&'a mut Document<'t>),
     Statement(&'a mut Statement<'t>),
     Predicate(&'a mut Predicate<'t>),
     LinearExpression(&'a mut LinearExpression),
@@ -22,24 +32,102 @@ enum Visit<'a, 't> {
     WhenClause(&'a mut WhenClause),
     Condition(&'a mut Condition),
     Field(&'a q::Field<'t, &'t str>),
+    /// Restores the scope whose definitions are visible, after visiting a definition
+    /// from another scope.
+    Scope(Option<&'a str>),
 }
 
-pub fn substitute_globals(document: &mut Document, globals: &QueryVariables) -> Result<(), ()> {
-    let mut queue = Vec::new();
-    queue.push(Visit::Document(document));
+/// The named expressions which variables may refer to.
+struct Definitions<'d> {
+    global: HashMap<&'d str, &'d LinearExpression>,
+    /// By the name of the scope, then of the definition
+    scoped: HashMap<&'d str, HashMap<&'d str, &'d LinearExpression>>,
+}
+
+impl<'d> Definitions<'d> {
+    fn new(
+        global: &'d [(String, LinearExpression)],
+        scoped: &'d [(String, String, LinearExpression)],
+    ) -> Self {
+        // Later definitions replace earlier ones with the same name.
+        let mut definitions = Definitions {
+            global: global
+                .iter()
+                .map(|(name, expr)| (name.as_str(), expr))
+                .collect(),
+            scoped: HashMap::new(),
+        };
+        for (scope, name, expr) in scoped.iter() {
+            definitions
+                .scoped
+                .entry(scope.as_str())
+                .or_default()
+                .insert(name.as_str(), expr);
+        }
+        definitions
+    }
+
+    /// The scope with the name, if it has any definitions.
+    fn scope(&self, name: Option<&str>) -> Option<&'d str> {
+        self.scoped.get_key_value(name?).map(|(scope, _)| *scope)
+    }
+
+    /// The definition which the name refers to from within the scope, and the scope
+    /// that it was declared in. Definitions of the scope hide those of the document.
+    fn get(
+        &self,
+        scope: Option<&str>,
+        name: &str,
+    ) -> Option<(&'d LinearExpression, Option<&'d str>)> {
+        if let Some((scope, definitions)) = scope.and_then(|scope| self.scoped.get_key_value(scope))
+        {
+            if let Some(expr) = definitions.get(name) {
+                return Some((*expr, Some(*scope)));
+            }
+        }
+        self.global.get(name).map(|expr| (*expr, None))
+    }
+}
+
+/// How many times definitions may be substituted into the statements of a document.
+/// Each substitution copies the expression of a definition, so a chain of definitions
+/// which each refer to the previous one twice would otherwise grow exponentially.
+pub const MAX_SUBSTITUTIONS: usize = 1 << 20;
+
+/// Substitutes definitions and globals into the statements of the document.
+/// Definitions must not be cyclic. See `cyclic_definition`.
+pub fn substitute_globals(
+    document: &mut Document,
+    globals: &QueryVariables,
+) -> Result<(), CompileError> {
+    let Document {
+        statements,
+        definitions,
+        scoped_definitions,
+    } = document;
+    let definitions = Definitions::new(definitions, scoped_definitions);
+    let mut queue: Vec<_> = statements.iter_mut().map(Visit::Statement).collect();
     let mut capture_names = Vec::new();
+    // The scope whose definitions are visible to the expression being visited
+    let mut scope = None;
+    let mut substitutions = 0;
 
     // Security: Uses a visit queue to avoid stack overflow
     while let Some(next) = queue.pop() {
         match next {
-            Visit::Document(doc) => doc.substitute_globals(&mut queue),
             Visit::Statement(statement) => {
+                scope = definitions.scope(statement.scope.as_deref());
                 statement.substitute_globals(&mut capture_names, &mut queue)
             }
             Visit::Predicate(predicate) => predicate.substitute_globals(&mut queue),
-            Visit::LinearExpression(linear_expression) => {
-                linear_expression.substitute_globals(&mut queue, &mut capture_names, globals)
-            }
+            Visit::LinearExpression(linear_expression) => linear_expression.substitute_globals(
+                &mut queue,
+                &mut capture_names,
+                &definitions,
+                &mut scope,
+                &mut substitutions,
+                globals,
+            )?,
             Visit::Match(match_) => {
                 match_.get_capture_names(&mut queue);
             }
@@ -47,42 +135,261 @@ pub fn substitute_globals(document: &mut Document, globals: &QueryVariables) ->
             // Security: Relying on GraphQL parsing to not have stack overflow here.
             // Could refactor like the above to unlimit depth.
             // See also 01205a6c-4e1a-4b35-8dc6-d400c499d423
-            Visit::Field(field) => get_capture_names_field(field, &mut capture_names)?,
+            Visit::Field(field) => get_capture_names_field(field, &mut capture_names)
+                .map_err(|()| CompileError::Unknown)?,
             Visit::Condition(condition) => {
                 condition.substitute_globals(&mut queue, &capture_names, globals)
             }
+            Visit::Scope(previous) => scope = previous,
         }
     }
 
     Ok(())
 }
 
-impl<'t> Document<'t> {
-    fn substitute_globals<'a, 'b: 'a>(&'b mut self, queue: &'a mut Vec<Visit<'b, 't>>) {
-        for statement in self.statements.iter_mut() {
-            queue.push(Visit::Statement(statement));
+/// An expression within a statement. See `Statement::for_each_node`.
+#[derive(Clone, Copy)]
+enum Node<'a> {
+    LinearExpression(&'a LinearExpression),
+    Condition(&'a Condition),
+    StringExpression(&'a StringExpression),
+}
+
+/// The name of a definition which refers to itself, either directly or through
+/// other definitions, if there is one. Substituting it would never finish.
+pub(crate) fn cyclic_definition<'d>(document: &'d Document) -> Option<&'d str> {
+    profile_fn!(cyclic_definition);
+
+    enum Walk<'a> {
+        LinearExpression(&'a LinearExpression),
+        Condition(&'a Condition),
+    }
+
+    // Every definition, with the scope that it was declared in, if any.
+    let definitions: Vec<(Option<&str>, &str, &LinearExpression)> = document
+        .definitions
+        .iter()
+        .map(|(name, expr)| (None, name.as_str(), expr))
+        .chain(
+            document
+                .scoped_definitions
+                .iter()
+                .map(|(scope, name, expr)| (Some(scope.as_str()), name.as_str(), expr)),
+        )
+        .collect();
+
+    // Later definitions replace earlier ones with the same name.
+    let indexes: HashMap<(Option<&str>, &str), usize> = definitions
+        .iter()
+        .enumerate()
+        .map(|(i, (scope, name, _))| ((*scope, *name), i))
+        .collect();
+    // Definitions of the scope hide those of the document. See `Definitions::get`.
+    let resolve = |scope: Option<&str>, name: &str| {
+        scope
+            .and_then(|scope| indexes.get(&(Some(scope), name)))
+            .or_else(|| indexes.get(&(None, name)))
+            .copied()
+    };
+
+    // The definitions referred to by each definition.
+    // Security: Uses a visit queue to avoid stack overflow.
+    let references: Vec<Vec<usize>> = definitions
+        .iter()
+        .map(|&(scope, _, expr)| {
+            let mut references = Vec::new();
+            let mut queue = vec![Walk::LinearExpression(expr)];
+            while let Some(next) = queue.pop() {
+                match next {
+                    Walk::LinearExpression(expr) => match expr {
+                        LinearExpression::Variable(var) => {
+                            references.extend(resolve(scope, var.name()));
+                        }
+                        LinearExpression::BinaryExpression(binary) => {
+                            queue.push(Walk::LinearExpression(&binary.lhs));
+                            queue.push(Walk::LinearExpression(&binary.rhs));
+                        }
+                        LinearExpression::Call { args, .. } => {
+                            queue.extend(args.iter().map(Walk::LinearExpression));
+                        }
+                        LinearExpression::Neg(expr) => queue.push(Walk::LinearExpression(&**expr)),
+                        LinearExpression::Conditional {
+                            cond,
+                            then,
+                            otherwise,
+                        } => {
+                            queue.push(Walk::Condition(&**cond));
+                            queue.push(Walk::LinearExpression(&**then));
+                            queue.push(Walk::LinearExpression(&**otherwise));
+                        }
+                        LinearExpression::Const(_)
+                        | LinearExpression::Len(_)
+                        | LinearExpression::Fold { .. }
+                        | LinearExpression::Error(_) => {}
+                    },
+                    Walk::Condition(condition) => match condition {
+                        Condition::Comparison(comparison) => {
+                            queue.push(Walk::LinearExpression(&comparison.lhs));
+                            queue.push(Walk::LinearExpression(&comparison.rhs));
+                        }
+                        Condition::Boolean(boolean) => {
+                            queue.push(Walk::Condition(&boolean.lhs));
+                            queue.push(Walk::Condition(&boolean.rhs));
+                        }
+                        Condition::Not(condition) => queue.push(Walk::Condition(&**condition)),
+                        Condition::StringComparison(_)
+                        | Condition::In(_)
+                        | Condition::IsNull(_)
+                        | Condition::Defined(_)
+                        | Condition::Variable(_)
+                        | Condition::Const(_)
+                        | Condition::Error(_) => {}
+                        #[cfg(feature = "regex")]
+                        Condition::Regex { .. } => {}
+                    },
+                }
+            }
+            references
+        })
+        .collect();
+
+    // A depth first search for a definition which is reached again while
+    // its own references are still being searched.
+    #[derive(Copy, Clone, PartialEq)]
+    enum State {
+        Unvisited,
+        Searching,
+        Done,
+    }
+    let mut states = vec![State::Unvisited; definitions.len()];
+    for start in 0..definitions.len() {
+        if states[start] != State::Unvisited {
+            continue;
+        }
+        states[start] = State::Searching;
+        // Each definition being searched, and the next of its references to search.
+        let mut stack = vec![(start, 0)];
+        while let Some((current, next)) = stack.last_mut() {
+            match references[*current].get(*next) {
+                Some(&reference) => {
+                    *next += 1;
+                    match states[reference] {
+                        State::Unvisited => {
+                            states[reference] = State::Searching;
+                            stack.push((reference, 0));
+                        }
+                        State::Searching => return Some(definitions[reference].1),
+                        State::Done => {}
+                    }
+                }
+                None => {
+                    states[*current] = State::Done;
+                    stack.pop();
+                }
+            }
         }
     }
+    None
+}
+
+/// The reason that a statement failed to produce a cost for a query.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum StatementError {
+    /// The query contained something that the predicate could not be matched against.
+    Match(MatchError),
+    Eval(EvalError),
+}
+
+impl From<EvalError> for StatementError {
+    fn from(err: EvalError) -> Self {
+        StatementError::Eval(err)
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Statement<'a> {
+    /// The comments written before the statement, without their markers.
+    /// These are printed with the statement, but do not affect matching or cost.
+    pub doc: Option<String>,
+    /// An optional label for the statement, for attributing costs in metrics.
+    pub name: Option<String>,
     pub predicate: Predicate<'a>,
     pub cost_expr: LinearExpression,
+    /// Whether the cost is added to that of the other matching statements, as
+    /// written with `+=` instead of `=>`. Such a statement does not stop the
+    /// search for the first matching statement which is not additive.
+    pub additive: bool,
+    /// The `scope` block that the statement was declared in, if any. The statement
+    /// refers to the definitions of its scope before those of the document, but
+    /// otherwise matches and costs queries like any other statement.
+    pub scope: Option<String>,
+    /// The name of the source that the statement was read from, for a model
+    /// compiled with `CostModel::from_sources`. This does not affect matching or cost.
+    pub source: Option<String>,
 }
 
 impl<'s> Statement<'s> {
+    /// How narrowly the statement selects queries, for choosing between statements
+    /// which all match. Each named field and each argument of the match count as
+    /// one, as does a when clause. A default match with no when clause is zero.
+    pub fn specificity(&self) -> u32 {
+        profile_method!(specificity);
+
+        // Security: Relying on GraphQL parsing to not have stack overflow here.
+        // See also 01205a6c-4e1a-4b35-8dc6-d400c499d423
+        fn field<'a>(field: &q::Field<'a, &'a str>) -> u32 {
+            let name = if field.name == WILDCARD_FIELD { 0 } else { 1 };
+            name + field.arguments.len() as u32 + set(&field.selection_set)
+        }
+        fn set<'a>(set: &q::SelectionSet<'a, &'a str>) -> u32 {
+            set.items
+                .iter()
+                .map(|item| match item {
+                    q::Selection::Field(f) => field(f),
+                    q::Selection::InlineFragment(inline) => set(&inline.selection_set),
+                    q::Selection::FragmentSpread(_) => 0,
+                })
+                .sum()
+        }
+
+        let fields = match &self.predicate.match_ {
+            Match::GraphQL(f, _) => field(f),
+            Match::Default => 0,
+        };
+        let when_clause = if self.predicate.when_clause.is_some() {
+            1
+        } else {
+            0
+        };
+        fields + when_clause
+    }
+
+    /// Compares how narrowly the statements select queries. See `specificity`.
+    /// Statements which are equally specific compare equal, so choosing between
+    /// them must fall back to another order, such as the order of declaration.
+    pub fn cmp_specificity(&self, other: &Statement<'_>) -> Ordering {
+        profile_method!(cmp_specificity);
+
+        self.specificity().cmp(&other.specificity())
+    }
+
     pub fn try_cost<'a, 't: 'a, T: q::Text<'t>>(
         &self,
         query: &'a q::Field<'t, T>,
         fragments: &'a [q::FragmentDefinition<'t, T>],
         variables: &QueryVariables,
+        unresolved_directives: UnresolvedDirectives,
+        argument_aliases: &HashMap<String, String>,
         captures: &mut Captures,
-    ) -> Result<Option<BigFraction>, ()> {
-        if !self
-            .predicate
-            .match_with_vars(query, fragments, variables, captures)?
-        {
+    ) -> Result<Option<BigFraction>, StatementError> {
+        if !self.predicate.match_with_vars(
+            query,
+            fragments,
+            variables,
+            unresolved_directives,
+            argument_aliases,
+            captures,
+        )? {
             return Ok(None);
         }
 
@@ -109,11 +416,141 @@ impl<'s> Statement<'s> {
         queue.push(Visit::LinearExpression(&mut self.cost_expr));
         queue.push(Visit::Predicate(&mut self.predicate));
     }
+
+    /// The names of the variables which the match of the statement captures, from
+    /// the arguments of its fields and directives and the variables it defines to
+    /// give captures a default value. Built-in captures, which are available to
+    /// every statement, are not included.
+    pub fn captured_variables(&self) -> HashSet<String> {
+        profile_method!(captured_variables);
+
+        let mut names = Vec::new();
+        if let Match::GraphQL(field, definitions) = &self.predicate.match_ {
+            // This only fails for matches which are rejected when compiling,
+            // such as those which capture the same name twice.
+            let _ = get_capture_names_field(field, &mut names);
+            names.extend(definitions.iter().map(|definition| definition.name));
+        }
+        names.into_iter().map(str::to_owned).collect()
+    }
+
+    /// The names of variables used by the statement which are neither captured by
+    /// its match nor defined in the globals, in the order that they first appear.
+    /// Globals must have been substituted first, which leaves an error in place of
+    /// each of these variables.
+    pub(crate) fn unbound_variables(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        self.for_each_node(|node| {
+            let error = match node {
+                Node::LinearExpression(LinearExpression::Error(e))
+                | Node::Condition(Condition::Error(e))
+                | Node::StringExpression(StringExpression::Error(e)) => e,
+                _ => return,
+            };
+            if let EvalError::MissingVariable(name) = error {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+            }
+        });
+        names
+    }
+
+    /// Whether the cost or the when clause of the statement uses the variable, such
+    /// as a built-in capture.
+    pub(crate) fn uses_variable(&self, name: &str) -> bool {
+        let mut used = false;
+        self.for_each_node(|node| {
+            let variable = match node {
+                Node::LinearExpression(LinearExpression::Variable(var)) => var.name(),
+                Node::LinearExpression(LinearExpression::Fold { list, .. }) => list.name(),
+                Node::Condition(Condition::In(membership)) => membership.name(),
+                #[cfg(feature = "regex")]
+                Node::Condition(Condition::Regex { var, .. }) => var.name(),
+                Node::Condition(Condition::IsNull(var)) => var.name(),
+                Node::Condition(Condition::Defined(var)) => var.name(),
+                Node::Condition(Condition::Variable(var)) => var.name(),
+                Node::StringExpression(StringExpression::Variable(var)) => var.name(),
+                _ => return,
+            };
+            used |= variable == name;
+        });
+        used
+    }
+
+    /// Visits each expression of the when clause and the cost of the statement, in
+    /// the order that they are written.
+    fn for_each_node<'s>(&'s self, mut visit: impl FnMut(Node<'s>)) {
+        // Security: Uses a visit queue to avoid stack overflow.
+        // Items are pushed in reverse so that they are visited in the order written.
+        let mut queue = vec![Node::LinearExpression(&self.cost_expr)];
+        if let Some(when_clause) = &self.predicate.when_clause {
+            queue.push(Node::Condition(&when_clause.condition));
+        }
+        while let Some(next) = queue.pop() {
+            visit(next);
+            match next {
+                Node::LinearExpression(expr) => match expr {
+                    LinearExpression::BinaryExpression(binary) => {
+                        queue.push(Node::LinearExpression(&binary.rhs));
+                        queue.push(Node::LinearExpression(&binary.lhs));
+                    }
+                    LinearExpression::Call { args, .. } => {
+                        queue.extend(args.iter().rev().map(Node::LinearExpression));
+                    }
+                    LinearExpression::Neg(expr) => queue.push(Node::LinearExpression(&**expr)),
+                    LinearExpression::Len(expr) => queue.push(Node::StringExpression(expr)),
+                    LinearExpression::Conditional {
+                        cond,
+                        then,
+                        otherwise,
+                    } => {
+                        queue.push(Node::LinearExpression(&**otherwise));
+                        queue.push(Node::LinearExpression(&**then));
+                        queue.push(Node::Condition(&**cond));
+                    }
+                    LinearExpression::Error(_)
+                    | LinearExpression::Const(_)
+                    | LinearExpression::Variable(_)
+                    | LinearExpression::Fold { .. } => {}
+                },
+                Node::Condition(condition) => match condition {
+                    Condition::Comparison(comparison) => {
+                        queue.push(Node::LinearExpression(&comparison.rhs));
+                        queue.push(Node::LinearExpression(&comparison.lhs));
+                    }
+                    Condition::StringComparison(comparison) => {
+                        queue.push(Node::StringExpression(&comparison.rhs));
+                        queue.push(Node::StringExpression(&comparison.lhs));
+                    }
+                    Condition::Boolean(boolean) => {
+                        queue.push(Node::Condition(&boolean.rhs));
+                        queue.push(Node::Condition(&boolean.lhs));
+                    }
+                    Condition::Not(condition) => queue.push(Node::Condition(&**condition)),
+                    Condition::Error(_)
+                    | Condition::In(_)
+                    | Condition::IsNull(_)
+                    | Condition::Defined(_)
+                    | Condition::Variable(_)
+                    | Condition::Const(_) => {}
+                    #[cfg(feature = "regex")]
+                    Condition::Regex { .. } => {}
+                },
+                Node::StringExpression(_) => {}
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Match<'a> {
-    GraphQL(q::Field<'a, &'a str>),
+    /// The field to match, and the variables defined by the query, which
+    /// give default values to captures of arguments that a query leaves out.
+    GraphQL(
+        q::Field<'a, &'a str>,
+        Vec<q::VariableDefinition<'a, &'a str>>,
+    ),
     Default,
 }
 
@@ -123,11 +560,31 @@ impl<'m> Match<'m> {
         item: &'a q::Field<'t, T>,
         fragments: &'a [q::FragmentDefinition<'t, T>],
         variables: &QueryVariables,
+        unresolved_directives: UnresolvedDirectives,
+        argument_aliases: &HashMap<String, String>,
         captures: &mut Captures,
-    ) -> Result<bool, ()> {
+    ) -> Result<bool, StatementError> {
         match self {
-            Self::GraphQL(selection) => {
-                match_query(selection, item, fragments, variables, captures)
+            Self::GraphQL(selection, definitions) => {
+                let mut defaults = QueryVariables::new();
+                for definition in definitions.iter() {
+                    if let Some(value) = &definition.default_value {
+                        defaults
+                            .0
+                            .insert(definition.name.to_owned(), value.to_graphql());
+                    }
+                }
+                match_query(
+                    selection,
+                    item,
+                    fragments,
+                    variables,
+                    &defaults,
+                    unresolved_directives,
+                    argument_aliases,
+                    captures,
+                )
+                .map_err(StatementError::Match)
             }
             Self::Default => Ok(true),
         }
@@ -135,7 +592,7 @@ impl<'m> Match<'m> {
 
     fn get_capture_names<'a, 'b: 'a>(&'b self, queue: &'a mut Vec<Visit<'b, 'm>>) {
         match self {
-            Self::GraphQL(selection) => queue.push(Visit::Field(selection)),
+            Self::GraphQL(selection, _) => queue.push(Visit::Field(selection)),
             Self::Default => {}
         }
     }
@@ -153,14 +610,20 @@ impl<'p> Predicate<'p> {
         item: &'a q::Field<'t, T>,
         fragments: &'a [q::FragmentDefinition<'t, T>],
         variables: &QueryVariables,
+        unresolved_directives: UnresolvedDirectives,
+        argument_aliases: &HashMap<String, String>,
         captures: &mut Captures,
-    ) -> Result<bool, ()> {
+    ) -> Result<bool, StatementError> {
         captures.clear();
 
-        if !self
-            .match_
-            .match_with_vars(item, fragments, variables, captures)?
-        {
+        if !self.match_.match_with_vars(
+            item,
+            fragments,
+            variables,
+            unresolved_directives,
+            argument_aliases,
+            captures,
+        )? {
             return Ok(false);
         }
 
@@ -200,11 +663,30 @@ impl WhenClause {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum LinearExpression {
     Const(Const<BigFraction>),
     Variable(Variable<BigFraction>),
     BinaryExpression(Box<BinaryExpression<AnyLinearOperator, LinearExpression>>),
-    Error(()),
+    Call {
+        func: BuiltinFn,
+        args: Vec<LinearExpression>,
+    },
+    Neg(Box<LinearExpression>),
+    /// The number of characters in a string, eg: `len($id)`
+    Len(StringExpression),
+    /// A function of the items of a captured list, eg: `sum($amounts)`
+    Fold {
+        func: ListFn,
+        list: Variable<Vec<StaticValue>>,
+    },
+    /// Only the branch selected by the condition is evaluated, eg: `$x > 1 ? $x : 1`
+    Conditional {
+        cond: Box<Condition>,
+        then: Box<LinearExpression>,
+        otherwise: Box<LinearExpression>,
+    },
+    Error(EvalError),
 }
 
 impl LinearExpression {
@@ -212,18 +694,37 @@ impl LinearExpression {
         &'b mut self,
         queue: &'a mut Vec<Visit<'b, '_>>,
         capture_names: &[&str],
+        definitions: &Definitions<'b>,
+        scope: &mut Option<&'b str>,
+        substitutions: &mut usize,
         globals: &QueryVariables,
-    ) {
+    ) -> Result<(), CompileError> {
         use LinearExpression::*;
         match self {
-            Const(_) | Error(()) => {}
+            Const(_) | Error(_) => {}
             Variable(var) => {
                 // Duplicated code
                 // See also 9195a627-cfa1-4bd4-81bb-b9fc90867e8c
                 let name = var.name();
                 // Captures shadow globals
-                if capture_names.contains(&name) {
-                    return;
+                if is_capture(name, capture_names) {
+                    return Ok(());
+                }
+                // Definitions shadow globals. The expression of the definition is
+                // visited in turn, since it may use captures, globals and definitions.
+                // It refers to the definitions of the scope that it was declared in.
+                if let Some((definition, definition_scope)) = definitions.get(*scope, name) {
+                    *substitutions += 1;
+                    if *substitutions > MAX_SUBSTITUTIONS {
+                        return Err(CompileError::TooManySubstitutions(name.to_owned()));
+                    }
+                    *self = definition.clone();
+                    if definition_scope != *scope {
+                        queue.push(Visit::Scope(*scope));
+                        *scope = definition_scope;
+                    }
+                    queue.push(Visit::LinearExpression(self));
+                    return Ok(());
                 }
                 // If it's not a capture, it must be a global.
                 // But if we can't find it, the expr will always be an error so jump straight there.
@@ -232,24 +733,214 @@ impl LinearExpression {
                     Some(Ok(value)) => {
                         LinearExpression::Const(crate::expressions::Const::new(value))
                     }
-                    _ => LinearExpression::Error(()),
+                    Some(Err(())) => LinearExpression::Error(EvalError::TypeMismatch {
+                        name: name.to_owned(),
+                    }),
+                    None => LinearExpression::Error(EvalError::MissingVariable(name.to_owned())),
                 }
             }
             BinaryExpression(binary_expression) => {
                 queue.push(Visit::LinearExpression(&mut binary_expression.lhs));
                 queue.push(Visit::LinearExpression(&mut binary_expression.rhs));
             }
+            Call { args, .. } => {
+                for arg in args.iter_mut() {
+                    queue.push(Visit::LinearExpression(arg));
+                }
+            }
+            Neg(expr) => queue.push(Visit::LinearExpression(&mut **expr)),
+            Len(expr) => expr.substitute_globals(capture_names, globals),
+            Fold { func, list } => {
+                // Duplicated code
+                // See also 9195a627-cfa1-4bd4-81bb-b9fc90867e8c
+                let name = list.name();
+                // Captures shadow globals
+                if is_capture(name, capture_names) {
+                    return Ok(());
+                }
+                let value = globals.get(name).map(|v| {
+                    let items: Vec<StaticValue> = v.coerce()?;
+                    func.exec(&items).map_err(|_| ())
+                });
+                *self = match value {
+                    Some(Ok(value)) => {
+                        LinearExpression::Const(crate::expressions::Const::new(value))
+                    }
+                    Some(Err(())) => LinearExpression::Error(EvalError::TypeMismatch {
+                        name: name.to_owned(),
+                    }),
+                    None => LinearExpression::Error(EvalError::MissingVariable(name.to_owned())),
+                }
+            }
+            Conditional {
+                cond,
+                then,
+                otherwise,
+            } => {
+                queue.push(Visit::Condition(&mut **cond));
+                queue.push(Visit::LinearExpression(&mut **then));
+                queue.push(Visit::LinearExpression(&mut **otherwise));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StringExpression {
+    Const(Const<String>),
+    Variable(Variable<String>),
+    Error(EvalError),
+}
+
+impl StringExpression {
+    pub fn eval(&self, captures: &Captures) -> Result<String, EvalError> {
+        match self {
+            StringExpression::Const(c) => Ok(c.eval()),
+            StringExpression::Variable(v) => v.eval(captures),
+            StringExpression::Error(e) => Err(e.clone()),
+        }
+    }
+
+    fn substitute_globals(&mut self, capture_names: &[&str], globals: &QueryVariables) {
+        if let StringExpression::Variable(var) = self {
+            // Duplicated code
+            // See also 9195a627-cfa1-4bd4-81bb-b9fc90867e8c
+            let name = var.name();
+            // Captures shadow globals
+            if is_capture(name, capture_names) {
+                return;
+            }
+            *self = match globals.get(name).map(|v| v.coerce()) {
+                Some(Ok(value)) => StringExpression::Const(crate::expressions::Const::new(value)),
+                Some(Err(())) => StringExpression::Error(EvalError::TypeMismatch {
+                    name: name.to_owned(),
+                }),
+                None => StringExpression::Error(EvalError::MissingVariable(name.to_owned())),
+            }
         }
     }
 }
 
+/// A literal list that a variable may be tested for membership in.
+/// All elements of a list have the same type.
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Membership {
+    Reals {
+        var: Variable<BigFraction>,
+        #[cfg_attr(feature = "serde", serde(with = "crate::serialize::reals"))]
+        list: Vec<BigFraction>,
+    },
+    Strings {
+        var: Variable<String>,
+        list: Vec<String>,
+    },
+}
+
+impl Membership {
+    fn name(&self) -> &str {
+        match self {
+            Membership::Reals { var, .. } => var.name(),
+            Membership::Strings { var, .. } => var.name(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Membership::Reals { list, .. } => list.is_empty(),
+            Membership::Strings { list, .. } => list.is_empty(),
+        }
+    }
+
+    pub fn eval(&self, captures: &Captures) -> Result<bool, EvalError> {
+        // Nothing is in an empty list, regardless of whether the variable exists.
+        if self.is_empty() {
+            return Ok(false);
+        }
+        match self {
+            Membership::Reals { var, list } => Ok(list.contains(&var.eval(captures)?)),
+            Membership::Strings { var, list } => Ok(list.contains(&var.eval(captures)?)),
+        }
+    }
+
+    fn substitute_globals(
+        &self,
+        capture_names: &[&str],
+        globals: &QueryVariables,
+    ) -> Option<Condition> {
+        // Duplicated code
+        // See also 9195a627-cfa1-4bd4-81bb-b9fc90867e8c
+        let name = self.name();
+        // Captures shadow globals
+        if is_capture(name, capture_names) {
+            return None;
+        }
+        if self.is_empty() {
+            return Some(Condition::Const(crate::expressions::Const::new(false)));
+        }
+        let value = match globals.get(name) {
+            Some(value) => value,
+            None => {
+                return Some(Condition::Error(EvalError::MissingVariable(
+                    name.to_owned(),
+                )))
+            }
+        };
+        let contains = match self {
+            Membership::Reals { list, .. } => {
+                Coerce::<BigFraction>::coerce(value).map(|v| list.contains(&v))
+            }
+            Membership::Strings { list, .. } => {
+                Coerce::<String>::coerce(value).map(|v| list.contains(&v))
+            }
+        };
+        Some(match contains {
+            Ok(contains) => Condition::Const(crate::expressions::Const::new(contains)),
+            Err(()) => Condition::Error(EvalError::TypeMismatch {
+                name: name.to_owned(),
+            }),
+        })
+    }
+}
+
+/// A compiled regular expression. Patterns are compared by their source text.
+#[cfg(feature = "regex")]
+#[derive(Debug, Clone)]
+pub struct Pattern(pub regex::Regex);
+
+#[cfg(feature = "regex")]
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Eq for Pattern {}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Condition {
     Comparison(BinaryExpression<AnyComparison, LinearExpression>),
+    StringComparison(BinaryExpression<AnyComparison, StringExpression>),
+    In(Membership),
+    #[cfg(feature = "regex")]
+    Regex {
+        var: Variable<String>,
+        pattern: Pattern,
+    },
     Boolean(Box<BinaryExpression<AnyBooleanOp, Condition>>),
+    Not(Box<Condition>),
+    /// Whether the value of the variable is null, eg: `is_null($first)`
+    IsNull(Variable<IsNull>),
+    /// Whether the variable has a value of any type, eg: `defined($first)`.
+    /// Unlike other conditions, this does not fail if the variable is missing.
+    Defined(Variable<()>),
     Variable(Variable<bool>),
     Const(Const<bool>),
-    Error(()),
+    Error(EvalError),
 }
 
 impl Condition {
@@ -265,16 +956,65 @@ impl Condition {
                 queue.push(Visit::LinearExpression(&mut comparison.lhs));
                 queue.push(Visit::LinearExpression(&mut comparison.rhs));
             }
+            StringComparison(comparison) => {
+                comparison.lhs.substitute_globals(capture_names, globals);
+                comparison.rhs.substitute_globals(capture_names, globals);
+            }
+            In(membership) => {
+                if let Some(condition) = membership.substitute_globals(capture_names, globals) {
+                    *self = condition;
+                }
+            }
+            #[cfg(feature = "regex")]
+            Regex { var, pattern } => {
+                let name = var.name();
+                // Captures shadow globals
+                if is_capture(name, capture_names) {
+                    return;
+                }
+                // A global which is missing or is not a string never matches,
+                // the same as for a capture.
+                let value = match globals.get(name).map(Coerce::<String>::coerce) {
+                    Some(Ok(value)) => pattern.0.is_match(&value),
+                    _ => false,
+                };
+                *self = Condition::Const(crate::expressions::Const::new(value));
+            }
             Boolean(boolean) => {
                 queue.push(Visit::Condition(&mut boolean.lhs));
                 queue.push(Visit::Condition(&mut boolean.rhs));
             }
+            Not(condition) => queue.push(Visit::Condition(&mut **condition)),
+            IsNull(var) => {
+                let name = var.name();
+                // Captures shadow globals
+                if is_capture(name, capture_names) {
+                    return;
+                }
+                *self = match globals.get(name).map(Coerce::<IsNull>::coerce) {
+                    Some(Ok(IsNull(value))) => {
+                        Condition::Const(crate::expressions::Const::new(value))
+                    }
+                    Some(Err(())) | None => {
+                        Condition::Error(EvalError::MissingVariable(name.to_owned()))
+                    }
+                }
+            }
+            Defined(var) => {
+                let name = var.name();
+                // Captures shadow globals
+                if is_capture(name, capture_names) {
+                    return;
+                }
+                let value = globals.get(name).is_some();
+                *self = Condition::Const(crate::expressions::Const::new(value));
+            }
             Variable(var) => {
                 // Duplicated code
                 // See also 9195a627-cfa1-4bd4-81bb-b9fc90867e8c
                 let name = var.name();
                 // Captures shadow globals
-                if capture_names.contains(&name) {
+                if is_capture(name, capture_names) {
                     return;
                 }
                 // If it's not a capture, it must be a global.
@@ -282,7 +1022,10 @@ impl Condition {
                 // TODO: (Performance) This means that later in the code we can assume the variable will be there.
                 *self = match globals.get(name).map(|v| v.coerce()) {
                     Some(Ok(value)) => Condition::Const(crate::expressions::Const::new(value)),
-                    _ => Condition::Error(()),
+                    Some(Err(())) => Condition::Error(EvalError::TypeMismatch {
+                        name: name.to_owned(),
+                    }),
+                    None => Condition::Error(EvalError::MissingVariable(name.to_owned())),
                 }
             }
             Const(_) | Error(_) => {}
@@ -290,9 +1033,22 @@ impl Condition {
     }
 }
 
+/// Names of captures starting with this prefix are reserved for built-in captures,
+/// which are available to every statement without being captured by the match.
+pub const BUILTIN_CAPTURE_PREFIX: &str = "__";
+
+/// Whether a variable will be supplied by the captures rather than by globals.
+fn is_capture(name: &str, capture_names: &[&str]) -> bool {
+    capture_names.contains(&name) || name.starts_with(BUILTIN_CAPTURE_PREFIX)
+}
+
 #[derive(Default, Debug)]
 pub struct Captures {
     values: HashMap<String, StaticValue>,
+    // Built-in captures apply to the whole query, so are not cleared between statements.
+    builtins: HashMap<String, StaticValue>,
+    // The query variable which supplied each capture, for those that were supplied by one.
+    variables: HashMap<String, String>,
 }
 
 impl Captures {
@@ -304,20 +1060,178 @@ impl Captures {
         self.values.insert(name.into(), value.to_graphql());
     }
 
-    pub fn get_as<T>(
-        &self,
-        name: impl AsRef<str>,
-    ) -> Option<Result<T, <StaticValue as Coerce<T>>::Error>>
+    /// Like `insert`, but records that the value was supplied by the query variable,
+    /// so that errors can refer to the variable.
+    pub fn insert_from_variable(
+        &mut self,
+        name: impl Into<String>,
+        variable: impl Into<String>,
+        value: impl IntoStaticValue,
+    ) {
+        let name = name.into();
+        self.variables.insert(name.clone(), variable.into());
+        self.insert(name, value);
+    }
+
+    /// The name of the query variable which supplied the capture, if any.
+    pub fn variable(&self, name: impl AsRef<str>) -> Option<&str> {
+        self.variables.get(name.as_ref()).map(String::as_str)
+    }
+
+    fn get(&self, name: &str) -> Option<&StaticValue> {
+        self.values.get(name).or_else(|| self.builtins.get(name))
+    }
+
+    /// The capture converted to `T`, or None if there is no capture with the name.
+    pub fn get_as<T>(&self, name: impl AsRef<str>) -> Result<Option<T>, CaptureTypeError>
     where
         StaticValue: Coerce<T>,
     {
         profile_fn!(get_as);
 
-        self.values.get(name.as_ref()).map(Coerce::coerce)
+        let name = name.as_ref();
+        match self.get(name) {
+            Some(value) => match value.coerce() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(CaptureTypeError {
+                    name: name.to_owned(),
+                    expected: std::any::type_name::<T>(),
+                    found: value_kind(value),
+                }),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Whether there is a capture with the name, of any type.
+    pub fn contains(&self, name: impl AsRef<str>) -> bool {
+        self.get(name.as_ref()).is_some()
+    }
+
+    /// A copy of the capture as it was captured, without converting it.
+    pub fn get_cloned(&self, name: impl AsRef<str>) -> Option<StaticValue> {
+        profile_fn!(get_cloned);
+
+        self.get(name.as_ref()).cloned()
     }
 
+    /// Sets a built-in capture, which is kept between statements.
+    /// The name should start with `BUILTIN_CAPTURE_PREFIX`.
+    pub fn insert_builtin(&mut self, name: impl Into<String>, value: impl IntoStaticValue) {
+        let name = name.into();
+        debug_assert!(name.starts_with(BUILTIN_CAPTURE_PREFIX));
+        self.builtins.insert(name, value.to_graphql());
+    }
+
+    /// Clears the captures of a statement, leaving built-in captures in place.
     pub fn clear(&mut self) {
-        self.values.clear()
+        self.values.clear();
+        self.variables.clear();
+    }
+
+    /// Every capture, including built-in captures, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, CaptureValue<'_>)> {
+        profile_method!(iter);
+
+        // Captures of the statement hide built-in captures with the same name.
+        let values = &self.values;
+        let builtins = self
+            .builtins
+            .iter()
+            .filter(move |(name, _)| !values.contains_key(*name));
+        values
+            .iter()
+            .chain(builtins)
+            .map(|(name, value)| (name.as_str(), CaptureValue::from(value)))
+    }
+
+    /// Every capture written as a GraphQL value, sorted by name.
+    /// This is a stable view of the captures for logging and tests.
+    pub fn debug_snapshot(&self) -> BTreeMap<String, String> {
+        profile_method!(debug_snapshot);
+
+        let mut snapshot: BTreeMap<_, _> = self
+            .builtins
+            .iter()
+            .map(|(name, value)| (name.clone(), value.to_string()))
+            .collect();
+        snapshot.extend(
+            self.values
+                .iter()
+                .map(|(name, value)| (name.clone(), value.to_string())),
+        );
+        snapshot
+    }
+}
+
+/// A capture which could not be converted to the type that it was used as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureTypeError {
+    pub name: String,
+    /// The name of the Rust type which the capture was converted to.
+    pub expected: &'static str,
+    /// The kind of GraphQL value which was captured, eg: `Enum`.
+    pub found: &'static str,
+}
+
+impl fmt::Display for CaptureTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Capture ${} is a {} which cannot be converted to {}",
+            self.name, self.found, self.expected
+        )
+    }
+}
+
+impl std::error::Error for CaptureTypeError {}
+
+pub(crate) fn value_kind(value: &StaticValue) -> &'static str {
+    match value {
+        q::Value::Null => "Null",
+        q::Value::Boolean(_) => "Boolean",
+        q::Value::Int(_) => "Int",
+        q::Value::Float(_) => "Float",
+        q::Value::String(_) => "String",
+        q::Value::Enum(_) => "Enum",
+        q::Value::Variable(_) => "Variable",
+        q::Value::List(_) => "List",
+        q::Value::Object(_) => "Object",
+    }
+}
+
+/// The value of a capture, for inspecting what a statement captured.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaptureValue<'a> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(&'a str),
+    Enum(&'a str),
+    Variable(&'a str),
+    List(Vec<CaptureValue<'a>>),
+    Object(BTreeMap<&'a str, CaptureValue<'a>>),
+}
+
+impl<'a> From<&'a StaticValue> for CaptureValue<'a> {
+    fn from(value: &'a StaticValue) -> Self {
+        match value {
+            q::Value::Null => CaptureValue::Null,
+            q::Value::Boolean(b) => CaptureValue::Bool(*b),
+            // Panic safety: graphql-parser stores every Int as an i64
+            q::Value::Int(i) => CaptureValue::Int(i.as_i64().unwrap()),
+            q::Value::Float(f) => CaptureValue::Float(*f),
+            q::Value::String(s) => CaptureValue::String(s),
+            q::Value::Enum(e) => CaptureValue::Enum(e),
+            q::Value::Variable(v) => CaptureValue::Variable(v),
+            q::Value::List(l) => CaptureValue::List(l.iter().map(CaptureValue::from).collect()),
+            q::Value::Object(o) => CaptureValue::Object(
+                o.iter()
+                    .map(|(k, v)| (k.as_str(), CaptureValue::from(v)))
+                    .collect(),
+            ),
+        }
     }
 }
 
@@ -348,4 +1262,16 @@ pub(crate) mod test_helpers {
             v
         }
     }
+
+    impl<T0: IntoStaticValue, T1: IntoStaticValue, T2: IntoStaticValue>
+        From<((&'_ str, T0), (&'_ str, T1), (&'_ str, T2))> for Captures
+    {
+        fn from(value: ((&'_ str, T0), (&'_ str, T1), (&'_ str, T2))) -> Captures {
+            let mut v = Captures::new();
+            v.insert((value.0).0, (value.0).1.to_graphql());
+            v.insert((value.1).0, (value.1).1.to_graphql());
+            v.insert((value.2).0, (value.2).1.to_graphql());
+            v
+        }
+    }
 }
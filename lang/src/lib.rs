@@ -1,16 +1,27 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod builder;
+mod cache;
+mod canonical;
 mod coercion;
 mod context;
+mod display;
+mod explain;
 mod expressions;
 mod graphql_utils;
 mod language;
+mod lint;
 mod matching;
 #[macro_use]
 mod parse_errors;
 mod parser;
+mod range_patterns;
 mod repeat;
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(any(test, feature = "wasm"))]
+mod wasm;
 
 pub(crate) mod prelude;
 use prelude::*;
@@ -19,9 +30,22 @@ use fraction::{BigFraction, GenericFraction, Sign};
 use graphql_parser::query as q;
 use language::*;
 use num_bigint::BigUint;
-use std::{error, fmt};
+use num_traits::ToPrimitive as _;
+use std::{collections::HashMap, error, fmt, str::FromStr};
 
+pub use builder::DocumentBuilder;
+pub use cache::{normalize_query, query_fingerprint, CacheStats};
 pub use context::Context;
+pub use explain::{CostExplanation, StatementMatch};
+pub use expressions::{BuiltinFn, EvalError, ListFn};
+pub use language::{
+    CaptureTypeError, CaptureValue, Captures, Condition, Document, LinearExpression, Predicate,
+    Statement, MAX_SUBSTITUTIONS,
+};
+pub use lint::LintWarning;
+pub use matching::UnresolvedDirectives;
+pub use parse_errors::Span;
+pub use parser::{StatementIter, DEFAULT_MAX_NESTING};
 // Hack for indexer selection
 pub use graphql_utils::QueryVariables;
 
@@ -35,6 +59,77 @@ pub struct CostModel {
     // must be owned by this struct and be dropped after `document`.
     #[allow(dead_code)]
     text: String,
+    max_cost: Option<BigUint>,
+    max_depth: Option<usize>,
+    max_nodes: Option<u64>,
+    default_fan_out: u64,
+    max_fan_out: Option<u64>,
+    field_base_cost: Option<FieldBaseCost>,
+    multiplier: Option<BigFraction>,
+    arithmetic_mode: ArithmeticMode,
+    negative_cost_policy: NegativeCostPolicy,
+    unresolved_directives: UnresolvedDirectives,
+    argument_aliases: HashMap<String, String>,
+    cache: Option<cache::Cache>,
+    fast_path: Option<FastPath>,
+    strict: bool,
+    /// Whether any statement uses `$__depth`, which is only measured if it is needed
+    uses_depth: bool,
+    /// Whether any statement uses `$__nodes`, which is only estimated if it is needed
+    uses_nodes: bool,
+    /// Whether any statement uses `$__count` or `$__total_first`, which are only
+    /// counted if they are needed
+    uses_counts: bool,
+}
+
+/// Queries which `CostModel::cost_fast` costs without matching any statement.
+struct FastPath {
+    max_fields: u64,
+    /// In wei
+    cost: BigUint,
+}
+
+/// A cost which is added for every field of a query, on top of the cost of the statements.
+struct FieldBaseCost {
+    /// In GRT, like the costs of statements
+    cost: BigFraction,
+    count_introspection: bool,
+}
+
+/// What to do with a cost which is over the maximum cost of a `CostModel`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ArithmeticMode {
+    /// Fail with `CostError::Overflow`.
+    Checked,
+    /// Use the maximum cost instead.
+    Saturating,
+}
+
+impl Default for ArithmeticMode {
+    fn default() -> Self {
+        ArithmeticMode::Saturating
+    }
+}
+
+/// What to do with the cost of a top-level field which is negative, such as from a
+/// formula with a subtraction. The cost of a field is that of the statement which
+/// costed it plus any additive statements, so additive statements may still be
+/// used as discounts.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NegativeCostPolicy {
+    /// Subtract the cost from that of the other fields of the query. A query whose
+    /// total cost is negative still costs 0.
+    Allow,
+    /// Cost the field as 0.
+    ClampToZero,
+    /// Fail with `CostError::Negative`.
+    Error,
+}
+
+impl Default for NegativeCostPolicy {
+    fn default() -> Self {
+        NegativeCostPolicy::ClampToZero
+    }
 }
 
 unsafe impl Send for CostModel {}
@@ -61,6 +156,50 @@ pub enum CostError {
     QueryNotSupported,
     QueryNotCosted,
     CostModelFail,
+    /// A statement which matched the query failed to evaluate.
+    /// The statement is given by its index.
+    StatementFail {
+        statement: usize,
+        error: EvalError,
+    },
+    /// A statement needed the value of a query variable which was not provided.
+    MissingVariable(String),
+    /// The value of a query variable was not of the type that a statement used it as,
+    /// such as a string which is not a number used in arithmetic.
+    VariableTypeMismatch {
+        name: String,
+        expected: String,
+        got: String,
+    },
+    /// The cost is over the maximum cost, with `ArithmeticMode::Checked`,
+    /// or does not fit in the `u64` returned by `cost_u64`.
+    Overflow,
+    /// The cost is negative, which `cost_u64` treats as an error, as does a
+    /// `CostModel` with `NegativeCostPolicy::Error` for the cost of a field.
+    Negative,
+    /// The query is nested more deeply than the maximum depth of the `CostModel`.
+    DepthExceeded {
+        depth: usize,
+        limit: usize,
+    },
+    /// The query fetches more nodes than the maximum of the `CostModel`, as estimated
+    /// for `$__nodes`.
+    NodesExceeded {
+        nodes: u64,
+        limit: u64,
+    },
+    /// The document has no operation with the requested name.
+    OperationNotFound,
+    /// No operation name was given, but the document has more than one operation.
+    AmbiguousOperation,
+    /// With `CostModel::strict`, the top-level field, given by its alias or name,
+    /// matched no statement other than a default statement.
+    NotAllowed(String),
+    /// The query spreads a fragment, given by its name, which it does not define.
+    UndefinedFragment(String),
+    /// A fragment of the query, given by its name, includes itself, directly or
+    /// through other fragments.
+    FragmentCycle(String),
 }
 
 lazy_static! {
@@ -84,6 +223,130 @@ impl fmt::Display for CostError {
             QueryInvalid => write!(f, "Query invalid"),
             QueryNotCosted => write!(f, "Query not costed"),
             CostModelFail => write!(f, "Cost model failure"),
+            StatementFail { statement, error } => {
+                write!(
+                    f,
+                    "Cost model failure in statement {}: {}",
+                    statement, error
+                )
+            }
+            MissingVariable(name) => write!(f, "Missing variable ${}", name),
+            UndefinedFragment(name) => write!(f, "Undefined fragment {}", name),
+            FragmentCycle(name) => write!(f, "Fragment {} includes itself", name),
+            VariableTypeMismatch {
+                name,
+                expected,
+                got,
+            } => write!(
+                f,
+                "Variable ${} is used as a {}, but is a {}",
+                name, expected, got
+            ),
+            Overflow => write!(f, "Cost is over the maximum cost"),
+            Negative => write!(f, "Cost is negative"),
+            DepthExceeded { depth, limit } => {
+                write!(
+                    f,
+                    "Query depth {} is over the maximum depth {}",
+                    depth, limit
+                )
+            }
+            NodesExceeded { nodes, limit } => {
+                write!(
+                    f,
+                    "Query fetches {} nodes, over the maximum of {}",
+                    nodes, limit
+                )
+            }
+            OperationNotFound => write!(f, "Operation not found"),
+            AmbiguousOperation => write!(f, "Operation name required"),
+            NotAllowed(field) => write!(f, "Field {} is not allowed", field),
+        }
+    }
+}
+
+/// Why `CostModel::assess` rejected a query.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RejectReason {
+    /// The query is nested more deeply than the maximum depth of the `CostModel`.
+    DepthExceeded { depth: usize, limit: usize },
+    /// The query fetches more nodes than the maximum of the `CostModel`.
+    NodesExceeded { nodes: u64, limit: u64 },
+    /// The cost of the query is over the maximum cost of the `CostModel`. Costing
+    /// stops once the cost is over, so the cost of the whole query is not known.
+    CostExceeded { limit: BigUint },
+    /// The query is not valid GraphQL.
+    ParseError,
+    /// The query could not be costed for another reason, such as no statement
+    /// matching one of its fields.
+    CostError(CostError),
+}
+
+impl From<CostError> for RejectReason {
+    fn from(error: CostError) -> Self {
+        match error {
+            CostError::DepthExceeded { depth, limit } => {
+                RejectReason::DepthExceeded { depth, limit }
+            }
+            CostError::NodesExceeded { nodes, limit } => {
+                RejectReason::NodesExceeded { nodes, limit }
+            }
+            CostError::FailedToParseQuery => RejectReason::ParseError,
+            error => RejectReason::CostError(error),
+        }
+    }
+}
+
+impl error::Error for RejectReason {}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        match self {
+            RejectReason::DepthExceeded { depth, limit } => {
+                write!(
+                    f,
+                    "Query depth {} is over the maximum depth {}",
+                    depth, limit
+                )
+            }
+            RejectReason::NodesExceeded { nodes, limit } => {
+                write!(
+                    f,
+                    "Query fetches {} nodes, over the maximum of {}",
+                    nodes, limit
+                )
+            }
+            RejectReason::CostExceeded { limit } => {
+                write!(f, "Cost is over the maximum cost of {} wei", limit)
+            }
+            RejectReason::ParseError => CostError::FailedToParseQuery.fmt(f),
+            RejectReason::CostError(error) => error.fmt(f),
+        }
+    }
+}
+
+/// A problem with a cost model which can be found without costing a query.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ValidationError {
+    /// A variable which is neither captured by the statement's match
+    /// nor defined in the globals. The statement is given by its index.
+    UnboundVariable { statement: usize, name: String },
+}
+
+impl error::Error for ValidationError {}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        match self {
+            ValidationError::UnboundVariable { statement, name } => write!(
+                f,
+                "Statement {} uses ${}, which is not captured or defined in globals",
+                statement, name
+            ),
         }
     }
 }
@@ -95,7 +358,33 @@ pub(crate) fn parse_vars(vars: &str) -> Result<QueryVariables, serde_json::Error
     if ["{}", "null", ""].contains(&vars) {
         Ok(graphql_utils::QueryVariables::new())
     } else {
-        serde_json::from_str(vars)
+        let vars: serde_json::Map<String, serde_json::Value> = serde_json::from_str(vars)?;
+        Ok(QueryVariables::from_json(vars))
+    }
+}
+
+pub(crate) fn parse_json_vars(vars: &serde_json::Value) -> Result<QueryVariables, CostError> {
+    profile_fn!(parse_json_vars);
+
+    match vars {
+        serde_json::Value::Null => Ok(graphql_utils::QueryVariables::new()),
+        serde_json::Value::Object(vars) => Ok(QueryVariables::from_json(vars.clone())),
+        _ => Err(CostError::FailedToParseVariables),
+    }
+}
+
+/// The error for a fragment spread whose fragment the query does not define.
+fn undefined_fragment<'a, T: q::Text<'a>>(spread: &q::FragmentSpread<'a, T>) -> CostError {
+    CostError::UndefinedFragment(spread.fragment_name.as_ref().to_owned())
+}
+
+/// Converts an error from matching the query outside of any statement.
+fn match_error_to_cost_error(err: matching::MatchError) -> CostError {
+    match err {
+        matching::MatchError::MissingVariable(name) => CostError::MissingVariable(name),
+        matching::MatchError::UndefinedFragment(name) => CostError::UndefinedFragment(name),
+        matching::MatchError::FragmentCycle(name) => CostError::FragmentCycle(name),
+        _ => CostError::QueryNotSupported,
     }
 }
 
@@ -103,8 +392,18 @@ pub(crate) fn parse_vars(vars: &str) -> Result<QueryVariables, serde_json::Error
 // by using another rental struct here.
 #[derive(Debug)]
 pub enum CompileError {
-    DocumentParseError(String),
+    DocumentParseError(String, Span),
     GlobalsParseError(serde_json::error::Error),
+    /// The named definition refers to itself, either directly or through other definitions.
+    CyclicDefinition(String),
+    /// Definitions were substituted more than `MAX_SUBSTITUTIONS` times, the last of
+    /// them being the named definition.
+    TooManySubstitutions(String),
+    /// A `DocumentBuilder` with statements added by `query_statement` was built into
+    /// a document, which could not own their text. See `CostModel::from_builder`.
+    QueryStatement,
+    /// The error occurred in the named source. See `CostModel::from_sources`.
+    InSource(String, Box<CompileError>),
     // TODO: Get rid of this by making all the errors known
     Unknown,
 }
@@ -114,7 +413,7 @@ impl fmt::Display for CompileError {
         profile_method!(fmt);
 
         match self {
-            CompileError::DocumentParseError(inner) => {
+            CompileError::DocumentParseError(inner, _) => {
                 writeln!(f, "Failed to parse cost model.")?;
                 write!(f, "{}", inner)?;
             }
@@ -122,6 +421,29 @@ impl fmt::Display for CompileError {
                 writeln!(f, "Failed to parse globals.")?;
                 write!(f, "{}", inner)?;
             }
+            CompileError::CyclicDefinition(name) => {
+                writeln!(f, "Failed to compile cost model.")?;
+                write!(f, "The definition of {} refers to itself.", name)?;
+            }
+            CompileError::TooManySubstitutions(name) => {
+                writeln!(f, "Failed to compile cost model.")?;
+                write!(
+                    f,
+                    "Definitions are substituted too many times, at {}.",
+                    name
+                )?;
+            }
+            CompileError::QueryStatement => {
+                writeln!(f, "Failed to compile cost model.")?;
+                write!(
+                    f,
+                    "Statements added with query_statement must be compiled with from_builder."
+                )?;
+            }
+            CompileError::InSource(source, inner) => {
+                writeln!(f, "In {}:", source)?;
+                write!(f, "{}", inner)?;
+            }
             CompileError::Unknown => {
                 writeln!(f, "Unknown error.")?;
             }
@@ -133,7 +455,61 @@ impl fmt::Display for CompileError {
 
 impl std::error::Error for CompileError {}
 
+impl CompileError {
+    /// Where in the text of the cost model the error occurred, if known. For an
+    /// error in one of several sources, this is within the text of that source.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CompileError::DocumentParseError(_, span) => Some(*span),
+            CompileError::InSource(_, inner) => inner.span(),
+            CompileError::GlobalsParseError(_)
+            | CompileError::CyclicDefinition(_)
+            | CompileError::TooManySubstitutions(_)
+            | CompileError::QueryStatement
+            | CompileError::Unknown => None,
+        }
+    }
+}
+
 impl CostModel {
+    /// A model with the default settings. `document` is a boxed `Document`, which may
+    /// borrow from `text`.
+    fn new(text: String, document: *const u8) -> Self {
+        let mut model = CostModel {
+            document,
+            text,
+            max_cost: None,
+            max_depth: None,
+            max_nodes: None,
+            default_fan_out: 1,
+            max_fan_out: None,
+            field_base_cost: None,
+            multiplier: None,
+            arithmetic_mode: ArithmeticMode::default(),
+            negative_cost_policy: NegativeCostPolicy::default(),
+            unresolved_directives: UnresolvedDirectives::default(),
+            argument_aliases: HashMap::new(),
+            cache: None,
+            fast_path: None,
+            strict: false,
+            uses_depth: false,
+            uses_nodes: false,
+            uses_counts: false,
+        };
+        model.uses_depth = model.uses_variable("__depth");
+        model.uses_nodes = model.uses_variable("__nodes");
+        model.uses_counts = model.uses_variable("__count") || model.uses_variable("__total_first");
+        model
+    }
+
+    /// Whether the cost or the when clause of any statement uses the variable.
+    fn uses_variable(&self, name: &str) -> bool {
+        self.document()
+            .statements
+            .iter()
+            .any(|statement| statement.uses_variable(name))
+    }
+
     pub fn document(&self) -> &Document {
         unsafe { &*(self.document as *const Document) }
     }
@@ -141,22 +517,663 @@ impl CostModel {
     pub fn compile(text: impl Into<String>, globals: &str) -> Result<Self, CompileError> {
         profile_method!(compile);
 
+        Self::compile_with_max_nesting(text, globals, DEFAULT_MAX_NESTING)
+    }
+
+    /// Like `compile`, but with a limit on how deeply expressions may be nested other
+    /// than `DEFAULT_MAX_NESTING`. This keeps adversarial text from overflowing the
+    /// stack while it is parsed.
+    pub fn compile_with_max_nesting(
+        text: impl Into<String>,
+        globals: &str,
+        max_nesting: usize,
+    ) -> Result<Self, CompileError> {
+        profile_method!(compile_with_max_nesting);
+
         let text = text.into();
-        let mut document = parser::parse_document(&text)
-            .map_err(|e| CompileError::DocumentParseError(format!("{}", e)))?;
+        let mut document = parser::parse_document_with_max_nesting(&text, max_nesting)?;
         let globals = parse_vars(globals).map_err(CompileError::GlobalsParseError)?;
-        substitute_globals(&mut document, &globals).map_err(|_| CompileError::Unknown)?;
+        substitute_globals(&mut document, &globals)?;
         let document = Box::into_raw(Box::new(document)) as *const u8;
-        Ok(CostModel { document, text })
+        Ok(CostModel::new(text, document))
+    }
+
+    /// Compiles a document which was built with `DocumentBuilder` rather than parsed.
+    /// It costs queries the same as the document parsed from the equivalent text.
+    pub fn from_document(document: Document<'static>, globals: &str) -> Result<Self, CompileError> {
+        profile_method!(from_document);
+
+        let document = Self::compile_document(document, globals)?;
+        Ok(CostModel::new(String::new(), document))
+    }
+
+    /// Like `from_document`, but also parses the predicates of statements which were
+    /// added with `DocumentBuilder::query_statement`. The model owns their text.
+    pub fn from_builder(builder: DocumentBuilder<'_>, globals: &str) -> Result<Self, CompileError> {
+        profile_method!(from_builder);
+
+        let (document, queries) = builder.into_parts();
+        let mut texts = vec![None; document.statements.len()];
+        for (index, query) in queries.into_iter() {
+            texts[index] = Some(query);
+        }
+
+        // The document may only borrow from the text of the model, so the matches of
+        // the other statements are printed and parsed again along with the queries.
+        let mut text = String::new();
+        let mut parts = Vec::with_capacity(texts.len());
+        for (mut statement, query) in document.statements.into_iter().zip(texts) {
+            let predicate = std::mem::replace(&mut statement.predicate, Predicate::default_match());
+            let query = match (query, predicate.match_) {
+                (Some(query), _) => Some(query),
+                (None, match_ @ Match::GraphQL(..)) => Some(match_.to_string()),
+                (None, Match::Default) => None,
+            };
+            let range = query.map(|query| {
+                let start = text.len();
+                text.push_str(&query);
+                start..text.len()
+            });
+            parts.push((statement, range, predicate.when_clause));
+        }
+
+        let mut statements = Vec::with_capacity(parts.len());
+        for (statement, range, when_clause) in parts.into_iter() {
+            let predicate = match range {
+                Some(range) => {
+                    let mut predicate = parser::parse_predicate(&text[range]).map_err(|e| {
+                        CompileError::DocumentParseError(format!("{}", e), e.span())
+                    })?;
+                    predicate.when_clause = when_clause.or(predicate.when_clause);
+                    predicate
+                }
+                None => Predicate {
+                    match_: Match::Default,
+                    when_clause,
+                },
+            };
+            statements.push(statement.with_predicate(predicate));
+        }
+        let document = Document {
+            statements,
+            definitions: document.definitions,
+            scoped_definitions: document.scoped_definitions,
+        };
+        let document = Self::compile_document(document, globals)?;
+        Ok(CostModel::new(text, document))
+    }
+
+    /// Checks the definitions of a document and substitutes globals into it. Like in
+    /// `compile`, the document may borrow from text which is then moved into the model.
+    fn compile_document(mut document: Document, globals: &str) -> Result<*const u8, CompileError> {
+        if let Some(name) = cyclic_definition(&document) {
+            return Err(CompileError::CyclicDefinition(name.to_owned()));
+        }
+        let globals = parse_vars(globals).map_err(CompileError::GlobalsParseError)?;
+        substitute_globals(&mut document, &globals)?;
+        Ok(Box::into_raw(Box::new(document)) as *const u8)
     }
 
+    /// Compiles a cost model which is split across several named sources, eg: files,
+    /// as though their text were concatenated in order. Each statement records the
+    /// name of its source, and an error in a source is reported with its name. A
+    /// scope must be closed in the same source that opens it.
+    pub fn from_sources(sources: &[(String, &str)]) -> Result<Self, CompileError> {
+        profile_method!(from_sources);
+
+        let mut text = String::new();
+        let ranges: Vec<_> = sources
+            .iter()
+            .map(|(_, source)| {
+                let start = text.len();
+                text.push_str(source);
+                start..text.len()
+            })
+            .collect();
+
+        let mut document = Document {
+            statements: Vec::new(),
+            definitions: Vec::new(),
+            scoped_definitions: Vec::new(),
+        };
+        for ((name, _), range) in sources.iter().zip(ranges) {
+            let parsed = parser::parse_document(&text[range])
+                .map_err(|e| CompileError::InSource(name.clone(), Box::new(e)))?;
+            document
+                .statements
+                .extend(parsed.statements.into_iter().map(|mut statement| {
+                    statement.source = Some(name.clone());
+                    statement
+                }));
+            document.definitions.extend(parsed.definitions);
+            document
+                .scoped_definitions
+                .extend(parsed.scoped_definitions);
+        }
+        // A definition may refer to those of other sources.
+        if let Some(name) = cyclic_definition(&document) {
+            return Err(CompileError::CyclicDefinition(name.to_owned()));
+        }
+        substitute_globals(&mut document, &QueryVariables::new())?;
+        let document = Box::into_raw(Box::new(document)) as *const u8;
+        Ok(CostModel::new(text, document))
+    }
+
+    /// Like `from_str`, but reports the errors of all statements which fail to
+    /// parse rather than only the first. After an error, parsing resumes after the
+    /// next `;`.
+    pub fn parse_collecting(text: &str) -> Result<Self, Vec<CompileError>> {
+        profile_method!(parse_collecting);
+
+        let errors: Vec<_> = StatementIter::recovering(text)
+            .filter_map(Result::err)
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        CostModel::compile(text, "").map_err(|e| vec![e])
+    }
+
+    /// Caps the cost of any query at `max_cost`, in wei. This is a safety valve
+    /// for formulas which may grow very large on adversarial inputs. The cap is
+    /// applied to the total cost of the query, after all of its fields have been
+    /// costed.
+    pub fn with_max_cost(mut self, max_cost: BigUint) -> Self {
+        self.max_cost = Some(max_cost);
+        self
+    }
+
+    /// Rejects queries which are nested more deeply than `max_depth` with
+    /// `CostError::DepthExceeded`, without evaluating any statements. Depth is counted
+    /// the same way as for `$__depth`, after expanding fragments.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Rejects queries which fetch more than `max_nodes` nodes in total with
+    /// `CostError::NodesExceeded`, without evaluating any statements. The nodes of
+    /// each top-level field are estimated the same way as for `$__nodes`, and summed
+    /// over the whole query.
+    pub fn with_max_nodes(mut self, max_nodes: u64) -> Self {
+        self.max_nodes = Some(max_nodes);
+        self
+    }
+
+    /// Sets the number of nodes which `$__nodes` assumes are fetched by a field with
+    /// a selection set but no `first` argument, for each node of its parent. The
+    /// default is 1.
+    pub fn with_default_fan_out(mut self, fan_out: u64) -> Self {
+        self.default_fan_out = fan_out;
+        self
+    }
+
+    /// Caps the number of nodes which `$__nodes` assumes are fetched by a field for
+    /// each node of its parent, so that a huge `first` argument does not make the
+    /// estimate unbounded. The cap is applied at every level, before multiplying by
+    /// the nodes of the parent. By default, there is no cap.
+    pub fn with_max_fanout(mut self, max_fan_out: u64) -> Self {
+        self.max_fan_out = Some(max_fan_out);
+        self
+    }
+
+    /// Adds `base_cost`, in wei, to the cost of a query for each of its fields, after
+    /// expanding fragments. Fields at every depth are counted, not only top-level
+    /// fields. Introspection fields such as `__typename`, and the fields that they
+    /// select, are only counted if `count_introspection` is true.
+    pub fn with_field_base_cost(mut self, base_cost: BigUint, count_introspection: bool) -> Self {
+        self.field_base_cost = Some(FieldBaseCost {
+            cost: BigFraction::new(base_cost, wei_to_grt()),
+            count_introspection,
+        });
+        self
+    }
+
+    /// Scales the cost of every query by `multiplier`, such as `1.5` for surge pricing,
+    /// without editing the statements. The cost in GRT, including any field base cost,
+    /// is scaled before it is rounded up to wei. Budgets and the maximum cost apply to
+    /// the scaled cost, so `within_budget` compares the budget with what `cost` returns
+    /// and the maximum cost still caps it.
+    pub fn with_multiplier(mut self, multiplier: BigFraction) -> Self {
+        self.multiplier = Some(multiplier);
+        self
+    }
+
+    /// Sets whether a cost which is over the maximum cost fails or is capped.
+    /// This is useful when costs are later converted to a smaller integer type,
+    /// such as with `with_max_cost(u64::MAX.into())`. By default, costs are capped.
+    pub fn with_arithmetic_mode(mut self, mode: ArithmeticMode) -> Self {
+        self.arithmetic_mode = mode;
+        self
+    }
+
+    /// Sets what to do with the cost of a top-level field which is negative. This
+    /// applies before the multiplier and the field base cost. By default, the field
+    /// costs 0.
+    pub fn with_negative_cost_policy(mut self, policy: NegativeCostPolicy) -> Self {
+        self.negative_cost_policy = policy;
+        self
+    }
+
+    /// Sets what to do with a selection of the query whose `@skip` or `@include`
+    /// directive uses a variable which was not provided. By default, the selection
+    /// is kept.
+    pub fn with_unresolved_directives(mut self, unresolved: UnresolvedDirectives) -> Self {
+        self.unresolved_directives = unresolved;
+        self
+    }
+
+    /// Matches an argument of the query named `from` as though it were named `to`, so
+    /// that statements written for one version of a schema also match queries for
+    /// another, such as a version which renamed `first` to `limit`. This applies to
+    /// the arguments of every field when matching statements, but not to those of
+    /// directives, nor to the estimates of `$__nodes` and `$__total_first`. Aliases
+    /// are not chained, so an argument is renamed at most once.
+    pub fn with_argument_alias(mut self, from: &str, to: &str) -> Self {
+        self.argument_aliases.insert(from.to_owned(), to.to_owned());
+        self
+    }
+
+    /// Remembers the costs of up to `capacity` of the most recently costed queries,
+    /// so that costing the same query again is cheap. Queries are the same if they
+    /// differ only in formatting, in the names of operations, or in the names of
+    /// variables which have the same values. The cache is used by `cost` and
+    /// `cost_with_variables`.
+    pub fn with_cache(mut self, capacity: usize) -> Self {
+        self.cache = Some(cache::Cache::new(capacity));
+        self
+    }
+
+    /// Enables the fast path of `cost_fast`, which costs a query with at most
+    /// `max_fields` fields and no list arguments at `cost` wei, without matching
+    /// any statement.
+    pub fn with_fast_path(mut self, max_fields: u64, cost: BigUint) -> Self {
+        self.fast_path = Some(FastPath { max_fields, cost });
+        self
+    }
+
+    /// Uses the cost model as an allowlist, so that a query is only costed if each
+    /// of its top-level fields matches a statement with a GraphQL match. A field
+    /// which matches no statement, or only a default statement, fails with
+    /// `CostError::NotAllowed`. Default statements still add to the cost of a
+    /// field which is allowed, such as with `default += 1;`. The fast path of
+    /// `cost_fast` is not used, since it does not match statements.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// The hits and misses of the cache, if the model has one.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Checks the statements of the cost model for variables which would always fail
+    /// to evaluate because they are neither captured nor defined in the globals.
+    /// This includes variables which are intentionally left undefined to ban a query.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        profile_method!(validate);
+
+        let mut errors = Vec::new();
+        for (index, statement) in self.document().statements.iter().enumerate() {
+            for name in statement.unbound_variables() {
+                errors.push(ValidationError::UnboundVariable {
+                    statement: index,
+                    name: name.to_owned(),
+                });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Costs the query with the variables given as JSON. This does not panic for any
+    /// query or variables, nor for any model which compiled. Invalid input and
+    /// expressions which can't be evaluated fail with a `CostError` instead.
     pub fn cost(&self, query: &str, variables: &str) -> Result<BigUint, CostError> {
         profile_method!(cost);
 
+        if let Some(cache) = &self.cache {
+            let variables = parse_vars(variables).map_err(|_| CostError::FailedToParseVariables)?;
+            return self.cost_cached(cache, query, variables);
+        }
+
         let mut context: Context<&str> = Context::new(query, variables)?;
         self.cost_with_context(&mut context)
     }
 
+    /// Like `cost`, but takes the variables of the query as JSON which
+    /// has already been parsed.
+    pub fn cost_with_variables(
+        &self,
+        query: &str,
+        variables: &serde_json::Value,
+    ) -> Result<BigUint, CostError> {
+        profile_method!(cost_with_variables);
+
+        let variables = parse_json_vars(variables)?;
+        if let Some(cache) = &self.cache {
+            return self.cost_cached(cache, query, variables);
+        }
+        let mut context: Context<&str> = Context::with_variables(query, variables)?;
+        self.cost_with_context(&mut context)
+    }
+
+    fn cost_cached(
+        &self,
+        cache: &cache::Cache,
+        query: &str,
+        variables: QueryVariables,
+    ) -> Result<BigUint, CostError> {
+        profile_method!(cost_cached);
+
+        // Queries which fail to parse are not cached.
+        let key = cache::normalize(query, &variables)?;
+        if let Some(cost) = cache.get(&key) {
+            return cost;
+        }
+        let mut context: Context<&str> = Context::with_variables(query, variables)?;
+        let cost = self.cost_with_context(&mut context);
+        cache.insert(key, cost.clone());
+        cost
+    }
+
+    /// Like `cost`, but each top-level field is costed as the sum of every statement
+    /// which matches it rather than only the first. This allows for models made of
+    /// a base cost plus surcharges. A field which matches no statements is not costed.
+    pub fn cost_additive(&self, query: &str, variables: &str) -> Result<BigUint, CostError> {
+        profile_method!(cost_additive);
+
+        let mut context: Context<&str> = Context::new(query, variables)?;
+        let cost =
+            self.cost_fract_with_context(&mut context, MatchMode::All, None, |_, _, _| {})?;
+        self.to_cost(cost)
+    }
+
+    /// Like `cost`, but each top-level field is costed by the most specific statement
+    /// which matches it rather than the first, so that statements may be declared in
+    /// any order. See `Statement::specificity`. Statements which are equally specific
+    /// are tried in the order that they were declared.
+    pub fn cost_most_specific(&self, query: &str, variables: &str) -> Result<BigUint, CostError> {
+        profile_method!(cost_most_specific);
+
+        let mut context: Context<&str> = Context::new(query, variables)?;
+        let cost = self.cost_fract_with_context(
+            &mut context,
+            MatchMode::MostSpecific,
+            None,
+            |_, _, _| {},
+        )?;
+        self.to_cost(cost)
+    }
+
+    /// Like `cost`, but also returns the index of the statement which costed each
+    /// top-level field of the query, followed by any additive statements which also
+    /// matched it. Indexes are zero-based and in the order that the statements were
+    /// declared in the cost model.
+    pub fn cost_with_statements(
+        &self,
+        query: &str,
+        variables: &str,
+    ) -> Result<(BigUint, Vec<usize>), CostError> {
+        profile_method!(cost_with_statements);
+
+        let mut context: Context<&str> = Context::new(query, variables)?;
+        let mut statements = Vec::new();
+        let cost =
+            self.cost_fract_with_context(&mut context, MatchMode::First, None, |index, _, _| {
+                statements.push(index)
+            })?;
+        Ok((self.to_cost(cost)?, statements))
+    }
+
+    /// What each statement would cost the query, in the order that the statements were
+    /// declared, for reports such as which statements are used by a set of queries. A
+    /// statement which matches several top-level fields costs the sum of them, and is
+    /// None if it matches none of them. Fields which match no statements are not an error.
+    pub fn cost_all(
+        &self,
+        query: &str,
+        variables: &str,
+    ) -> Result<Vec<Option<BigUint>>, CostError> {
+        profile_method!(cost_all);
+
+        let mut context: Context<&str> = Context::new(query, variables)?;
+        let mut costs: Vec<Option<BigFraction>> = vec![None; self.document().statements.len()];
+        self.cost_fract_with_context(&mut context, MatchMode::Each, None, |index, cost, _| {
+            let sum = costs[index].get_or_insert_with(|| BigFraction::from(0));
+            *sum += cost.clone();
+        })?;
+        costs
+            .into_iter()
+            .map(|cost| cost.map(|cost| self.to_cost(cost)).transpose())
+            .collect()
+    }
+
+    /// Like `cost`, but for callers which need the cost in wei as a `u64`. This fails with
+    /// `CostError::Overflow` if the cost does not fit, and with `CostError::Negative` if
+    /// the total is negative, rather than costing the query as 0.
+    pub fn cost_u64(&self, query: &str, variables: &str) -> Result<u64, CostError> {
+        profile_method!(cost_u64);
+
+        let mut context: Context<&str> = Context::new(query, variables)?;
+        let cost =
+            self.cost_fract_with_context(&mut context, MatchMode::First, None, |_, _, _| {})?;
+        if cost < BigFraction::from(0) {
+            return Err(CostError::Negative);
+        }
+        self.to_cost(cost)?.to_u64().ok_or(CostError::Overflow)
+    }
+
+    /// Like `cost`, but explains the cost with the statement which costed each top-level
+    /// field, what it captured and what it cost. The explanation displays as a report.
+    pub fn explain(&self, query: &str, variables: &str) -> Result<CostExplanation, CostError> {
+        profile_method!(explain);
+
+        self.explain_with_mode(query, variables, MatchMode::First)
+    }
+
+    /// Like `explain`, but for `cost_additive`, so every statement which
+    /// contributed to the cost of each top-level field is included.
+    pub fn explain_additive(
+        &self,
+        query: &str,
+        variables: &str,
+    ) -> Result<CostExplanation, CostError> {
+        profile_method!(explain_additive);
+
+        self.explain_with_mode(query, variables, MatchMode::All)
+    }
+
+    fn explain_with_mode(
+        &self,
+        query: &str,
+        variables: &str,
+        mode: MatchMode,
+    ) -> Result<CostExplanation, CostError> {
+        let mut context: Context<&str> = Context::new(query, variables)?;
+        let statements = &self.document().statements;
+        let mut matches = Vec::new();
+        let cost =
+            self.cost_fract_with_context(&mut context, mode, None, |index, cost, captures| {
+                let statement = &statements[index];
+                matches.push(StatementMatch {
+                    statement: index,
+                    name: statement.name.clone(),
+                    cost_expr: statement.cost_expr.to_string(),
+                    captures: captures.debug_snapshot(),
+                    cost: cost.clone(),
+                })
+            })?;
+        Ok(CostExplanation {
+            matches,
+            cost: self.to_cost(cost)?,
+        })
+    }
+
+    /// Like `cost_with_statements`, but returns the name of each statement,
+    /// or None for statements which are not named.
+    pub fn cost_with_statement_names(
+        &self,
+        query: &str,
+        variables: &str,
+    ) -> Result<(BigUint, Vec<Option<&str>>), CostError> {
+        profile_method!(cost_with_statement_names);
+
+        let (cost, indexes) = self.cost_with_statements(query, variables)?;
+        let statements = &self.document().statements;
+        let names = indexes
+            .into_iter()
+            .map(|index| statements[index].name.as_deref())
+            .collect();
+        Ok((cost, names))
+    }
+
+    /// Costs one of the operations of a document, which has no variables. The operation
+    /// is selected by its name, or may be omitted if the document has only one operation.
+    /// Fragments of the document are shared by all of its operations.
+    pub fn cost_operation(
+        &self,
+        document: &str,
+        operation_name: Option<&str>,
+    ) -> Result<BigUint, CostError> {
+        profile_method!(cost_operation);
+
+        let mut context: Context<&str> = Context::new(document, "")?;
+        let operations = std::mem::take(&mut context.operations);
+        context.operations = vec![select_operation(operations, operation_name)?];
+        self.cost_with_context(&mut context)
+    }
+
+    /// Like `cost` for a query which has no variables, but trades accuracy for speed.
+    /// If the model has a fast path, configured with `with_fast_path`, a query which
+    /// is small enough costs a fixed amount rather than what its statements would
+    /// cost. A query is small enough if it has no more than the configured number of
+    /// fields, after expanding fragments, and none of them have an argument which is
+    /// or may be a list. Other queries are costed in full, as are all queries if the
+    /// model has no fast path.
+    pub fn cost_fast(&self, query: &str) -> Result<BigUint, CostError> {
+        profile_method!(cost_fast);
+
+        let fast_path = match &self.fast_path {
+            Some(fast_path) if !self.strict => fast_path,
+            _ => return self.cost(query, ""),
+        };
+
+        let mut context: Context<&str> = Context::new(query, "")?;
+        let mut fields = 0u64;
+        let mut small = true;
+        for operation in context.operations.iter() {
+            let top_level_fields = get_top_level_fields(
+                operation,
+                &context.fragments,
+                &context.variables,
+                self.unresolved_directives,
+            )?;
+            for field in top_level_fields {
+                match fast_path_fields(field, &context.fragments)? {
+                    Some(count) => fields = fields.saturating_add(count),
+                    None => small = false,
+                }
+            }
+        }
+        if small && fields <= fast_path.max_fields {
+            return Ok(fast_path.cost.clone());
+        }
+        self.cost_with_context(&mut context)
+    }
+
+    /// Costs each of the queries, which have no variables. With the `rayon` feature,
+    /// the queries are costed in parallel. The costs are in the same order as the queries.
+    pub fn cost_batch(&self, queries: &[&str]) -> Vec<Result<BigUint, CostError>> {
+        profile_method!(cost_batch);
+
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            queries
+                .par_iter()
+                .map(|query| self.cost(query, ""))
+                .collect()
+        }
+        #[cfg(not(feature = "rayon"))]
+        {
+            queries.iter().map(|query| self.cost(query, "")).collect()
+        }
+    }
+
+    /// Costs the query, which has no variables, on the blocking thread pool of the
+    /// current Tokio runtime, so that costing a large query does not block the
+    /// executor. Requires the `tokio` feature. Panics if called outside of a runtime.
+    #[cfg(feature = "tokio")]
+    pub fn cost_spawn_blocking(
+        self: std::sync::Arc<Self>,
+        query: String,
+    ) -> impl std::future::Future<Output = Result<BigUint, CostError>> {
+        profile_method!(cost_spawn_blocking);
+
+        let task = tokio::task::spawn_blocking(move || self.cost(&query, ""));
+        async move {
+            match task.await {
+                Ok(result) => result,
+                Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                // The task is only cancelled if the runtime shuts down before it starts.
+                Err(_) => Err(CostError::CostModelFail),
+            }
+        }
+    }
+
+    /// Whether the cost of the query is at most `budget`, in wei. Costing stops as soon
+    /// as the running total of the query's top-level fields is over budget, so the rest
+    /// of the query is not costed. This means that a negative cost for a later field
+    /// can't bring the query back within budget.
+    pub fn within_budget(
+        &self,
+        query: &str,
+        variables: &str,
+        budget: &BigUint,
+    ) -> Result<bool, CostError> {
+        profile_method!(within_budget);
+
+        let mut context: Context<&str> = Context::new(query, variables)?;
+        let cost = self.cost_fract_with_context(
+            &mut context,
+            MatchMode::First,
+            Some(budget),
+            |_, _, _| {},
+        )?;
+        Ok(self.to_cost(cost)? <= *budget)
+    }
+
+    /// Decides whether a gateway should serve the query, which has no variables, giving
+    /// the reason if not. The maximum depth and nodes are checked before any statements
+    /// are evaluated, and costing stops as soon as the cost is over the maximum cost,
+    /// so an astronomically large cost is never fully computed. Unlike `cost`, a cost
+    /// over the maximum is rejected rather than capped. See `with_max_depth`,
+    /// `with_max_nodes` and `with_max_cost`.
+    pub fn assess(&self, query: &str) -> Result<BigUint, RejectReason> {
+        profile_method!(assess);
+
+        let mut context: Context<&str> = Context::new(query, "")?;
+        let cost = self.cost_fract_with_context(
+            &mut context,
+            MatchMode::First,
+            self.max_cost.as_ref(),
+            |_, _, _| {},
+        )?;
+        let cost = self.uncapped_cost(cost)?;
+        match &self.max_cost {
+            Some(limit) if cost > *limit => Err(RejectReason::CostExceeded {
+                limit: limit.clone(),
+            }),
+            _ => Ok(cost),
+        }
+    }
+
     /// This may be more efficient when costing a single query against multiple models
     pub fn cost_with_context<'a, T: q::Text<'a>>(
         &self,
@@ -164,50 +1181,317 @@ impl CostModel {
     ) -> Result<BigUint, CostError> {
         profile_method!(cost_with_context);
 
+        let result = self.cost_fract_with_context(context, MatchMode::First, None, |_, _, _| {})?;
+        self.to_cost(result)
+    }
+
+    /// Converts a total cost in GRT to an in-range value in wei.
+    fn to_cost(&self, fract: BigFraction) -> Result<BigUint, CostError> {
+        let cost = self.uncapped_cost(fract)?;
+        match &self.max_cost {
+            Some(max_cost) if cost > *max_cost => match self.arithmetic_mode {
+                ArithmeticMode::Checked => Err(CostError::Overflow),
+                ArithmeticMode::Saturating => Ok(max_cost.clone()),
+            },
+            _ => Ok(cost),
+        }
+    }
+
+    /// Converts a total cost in GRT to wei, without applying the maximum cost.
+    fn uncapped_cost(&self, mut fract: BigFraction) -> Result<BigUint, CostError> {
+        if let Some(multiplier) = &self.multiplier {
+            fract *= multiplier.clone();
+        }
+        fract_to_cost(fract).map_err(|()| CostError::CostModelFail)
+    }
+
+    /// Sums the cost of each top-level field in GRT, calling `on_match` with the
+    /// index, cost and captures of the statement which costed each field. The `mode` decides which
+    /// of the matching statements cost a field. If there is a `budget`, this stops
+    /// early once the total is over it.
+    fn cost_fract_with_context<'a, T: q::Text<'a>>(
+        &self,
+        context: &mut Context<'a, T>,
+        mode: MatchMode,
+        budget: Option<&BigUint>,
+        mut on_match: impl FnMut(usize, &BigFraction, &Captures),
+    ) -> Result<BigFraction, CostError> {
+        profile_method!(cost_fract_with_context);
+
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("cost", mode = ?mode).entered();
+
         let mut result = BigFraction::from(0);
 
-        for operation in context.operations.iter() {
-            profile_section!(operation_definition);
+        let statements = &self.document().statements;
+        // Additive statements are tried even after a statement has been chosen
+        let any_additive = statements.iter().any(|statement| statement.additive);
+        // TODO: (Performance) The order could be computed once, when compiling
+        let mut order = Vec::new();
+        if mode == MatchMode::MostSpecific {
+            order.extend(0..statements.len());
+            // The most specific first, with ties in the order of declaration. This is a
+            // total order, so the same statement is chosen every time.
+            order.sort_by(|&a, &b| {
+                statements[b]
+                    .cmp_specificity(&statements[a])
+                    .then(a.cmp(&b))
+            });
+        }
 
+        // The depth and nodes of every operation are checked before any statements
+        // are evaluated, so that deeply nested or wide queries are rejected cheaply.
+        let mut operations = Vec::with_capacity(context.operations.len());
+        let mut total_nodes = 0u64;
+        for operation in context.operations.iter() {
             // TODO: (Performance) We could move the search for top level fields
             // into the Context. But, then it would have to be self-referential
-            let top_level_fields =
-                get_top_level_fields(operation, &context.fragments, &context.variables)?;
+            let top_level_fields = get_top_level_fields(
+                operation,
+                &context.fragments,
+                &context.variables,
+                self.unresolved_directives,
+            )?;
+            // The depth, which is only measured if needed
+            let mut depth = None;
+            if self.uses_depth || self.max_depth.is_some() {
+                depth = Some(query_depth(&top_level_fields, &context.fragments)?);
+            }
+            if let (Some(depth), Some(limit)) = (depth, self.max_depth) {
+                if depth > limit {
+                    return Err(CostError::DepthExceeded { depth, limit });
+                }
+            }
+            // The nodes of each top-level field, which are only estimated if needed
+            let mut nodes = Vec::new();
+            if self.uses_nodes || self.max_nodes.is_some() {
+                nodes = field_nodes(
+                    &top_level_fields,
+                    &context.fragments,
+                    &context.variables,
+                    self.default_fan_out,
+                    self.max_fan_out,
+                )?;
+            }
+            if let Some(limit) = self.max_nodes {
+                total_nodes = nodes
+                    .iter()
+                    .fold(total_nodes, |total, nodes| total.saturating_add(*nodes));
+                if total_nodes > limit {
+                    return Err(CostError::NodesExceeded {
+                        nodes: total_nodes,
+                        limit,
+                    });
+                }
+            }
+            operations.push((operation, top_level_fields, depth, nodes));
+        }
 
-            for top_level_field in top_level_fields.into_iter() {
+        for (operation, top_level_fields, depth, nodes) in operations.into_iter() {
+            profile_section!(operation_definition);
+
+            context
+                .captures
+                .insert_builtin("__selections", top_level_fields.len() as u64);
+            if let Some(depth) = depth {
+                context.captures.insert_builtin("__depth", depth as u64);
+            }
+            context
+                .captures
+                .insert_builtin("__operation", operation_type(operation).to_owned());
+
+            // How many times each field is selected, and how many items are requested
+            // by all of them with `first`, for batches of the same field
+            let mut counts: HashMap<&str, (usize, i64)> = HashMap::new();
+            if self.uses_counts {
+                for &field in top_level_fields.iter() {
+                    let (count, total_first) = counts.entry(field.name.as_ref()).or_default();
+                    *count += 1;
+                    // A negative page is empty
+                    let first = first_argument(field, &context.variables).unwrap_or(0);
+                    *total_first = total_first.saturating_add(first.max(0));
+                }
+            }
+
+            for (index, top_level_field) in top_level_fields.into_iter().enumerate() {
                 profile_section!(operation_field);
 
+                // The response key of the field, which is its alias if it has one.
+                let alias: &str = top_level_field
+                    .alias
+                    .as_ref()
+                    .unwrap_or(&top_level_field.name)
+                    .as_ref();
+                context.captures.insert_builtin("__alias", alias.to_owned());
+                if let Some(&(count, total_first)) = counts.get(top_level_field.name.as_ref()) {
+                    context.captures.insert_builtin("__count", count as u64);
+                    // The total is not negative, because negative pages are empty
+                    context
+                        .captures
+                        .insert_builtin("__total_first", total_first as u64);
+                }
+                context
+                    .captures
+                    .insert_builtin("__introspection", is_introspection(&top_level_field));
+                let deferred = matching::deferred(&top_level_field.directives, &context.variables);
+                context.captures.insert_builtin("__deferred", deferred);
+                if let Some(&nodes) = nodes.get(index) {
+                    context.captures.insert_builtin("__nodes", nodes);
+                }
+
+                #[cfg(feature = "tracing")]
+                let _span = tracing::debug_span!("field", alias).entered();
+
                 let mut this_cost = None;
+                let mut chosen = false;
+                let mut allowed = false;
 
-                for statement in &self.document().statements {
+                for position in 0..statements.len() {
                     profile_section!(field_statement);
 
-                    match statement.try_cost(
+                    let index = if order.is_empty() {
+                        position
+                    } else {
+                        order[position]
+                    };
+                    let statement = &statements[index];
+                    if chosen && !statement.additive {
+                        continue;
+                    }
+
+                    let statement_cost = statement.try_cost(
                         &top_level_field,
                         &context.fragments,
                         &context.variables,
+                        self.unresolved_directives,
+                        &self.argument_aliases,
                         &mut context.captures,
-                    ) {
+                    );
+                    #[cfg(feature = "tracing")]
+                    tracing::trace!(
+                        statement = index,
+                        name = statement.name.as_deref(),
+                        matched = matches!(statement_cost, Ok(Some(_))),
+                        captures = ?context.captures.debug_snapshot(),
+                        "Tried statement"
+                    );
+
+                    match statement_cost {
                         Ok(None) => continue,
-                        Ok(cost) => {
-                            this_cost = cost;
-                            break;
+                        Ok(Some(cost)) => {
+                            on_match(index, &cost, &context.captures);
+                            allowed |= matches!(statement.predicate.match_, Match::GraphQL(..));
+                            this_cost = Some(match this_cost {
+                                Some(sum) => sum + cost,
+                                None => cost,
+                            });
+                            if !statement.additive
+                                && matches!(mode, MatchMode::First | MatchMode::MostSpecific)
+                            {
+                                if !any_additive {
+                                    break;
+                                }
+                                chosen = true;
+                            }
+                        }
+                        Err(StatementError::Match(matching::MatchError::MissingVariable(name))) => {
+                            return Err(CostError::MissingVariable(name))
+                        }
+                        Err(StatementError::Match(matching::MatchError::UndefinedFragment(
+                            name,
+                        ))) => return Err(CostError::UndefinedFragment(name)),
+                        Err(StatementError::Match(matching::MatchError::FragmentCycle(name))) => {
+                            return Err(CostError::FragmentCycle(name))
+                        }
+                        Err(StatementError::Eval(EvalError::VariableTypeMismatch {
+                            name,
+                            expected,
+                            got,
+                        })) => {
+                            return Err(CostError::VariableTypeMismatch {
+                                name,
+                                expected,
+                                got,
+                            })
+                        }
+                        Err(StatementError::Eval(error)) => {
+                            return Err(CostError::StatementFail {
+                                statement: index,
+                                error,
+                            })
                         }
                         Err(_) => return Err(CostError::CostModelFail),
                     }
                 }
-                if let Some(this_cost) = this_cost {
-                    result += this_cost;
-                } else {
-                    return Err(CostError::QueryNotCosted);
+                #[cfg(feature = "tracing")]
+                tracing::debug!(
+                    cost = ?this_cost.as_ref().map(ToString::to_string),
+                    "Costed field"
+                );
+                if self.strict && !allowed {
+                    return Err(CostError::NotAllowed(alias.to_owned()));
+                }
+                let this_cost = match this_cost {
+                    Some(cost) if cost < BigFraction::from(0) => match self.negative_cost_policy {
+                        NegativeCostPolicy::Allow => Some(cost),
+                        NegativeCostPolicy::ClampToZero => Some(BigFraction::from(0)),
+                        NegativeCostPolicy::Error => return Err(CostError::Negative),
+                    },
+                    this_cost => this_cost,
+                };
+                match this_cost {
+                    Some(this_cost) => result += this_cost,
+                    None if mode == MatchMode::Each => {}
+                    None => return Err(CostError::QueryNotCosted),
+                }
+                if let Some(base) = &self.field_base_cost {
+                    let fields = field_count(
+                        &top_level_field,
+                        &context.fragments,
+                        base.count_introspection,
+                    )?;
+                    result += base.cost.clone() * BigFraction::from(fields);
+                }
+
+                if let Some(budget) = budget {
+                    // The cost is not capped, so that a budget of the maximum cost stops early
+                    if matches!(self.uncapped_cost(result.clone()), Ok(cost) if cost > *budget) {
+                        return Ok(result);
+                    }
                 }
             }
         }
 
-        // Convert to an in-range value
-        fract_to_cost(result).map_err(|()| CostError::CostModelFail)
+        #[cfg(feature = "tracing")]
+        tracing::debug!(cost = %result, "Costed query");
+        Ok(result)
     }
 }
+
+/// Which of the statements that match a top-level field are used to cost it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum MatchMode {
+    /// The first, in the order that they were declared
+    First,
+    /// The sum of all of them
+    All,
+    /// The one with the highest specificity
+    MostSpecific,
+    /// Each of them, as for `All`, but fields which match none are not an error
+    Each,
+}
+
+/// Compiles a cost model which does not use any globals.
+impl FromStr for CostModel {
+    type Err = CompileError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        profile_method!(from_str);
+
+        CostModel::compile(text, "")
+    }
+}
+
 pub fn fract_to_cost(fract: BigFraction) -> Result<BigUint, ()> {
     profile_fn!(fract_to_cost);
 
@@ -216,8 +1500,8 @@ pub fn fract_to_cost(fract: BigFraction) -> Result<BigUint, ()> {
             Sign::Plus => {
                 // Convert to wei
                 ratio *= wei_to_grt();
-                // Rounds toward 0
-                let mut int = ratio.to_integer();
+                // Rounds up, so that fractions of a wei are never free
+                let mut int = ratio.ceil().to_integer();
                 if int > *MAX_COST {
                     int = MAX_COST.clone()
                 };
@@ -261,10 +1545,303 @@ pub(crate) fn split_definitions<'a, T: q::Text<'a>>(
     (operations, fragments)
 }
 
+/// The operation with the given name. Without a name, the only operation.
+fn select_operation<'a, T: q::Text<'a>>(
+    operations: Vec<q::OperationDefinition<'a, T>>,
+    name: Option<&str>,
+) -> Result<q::OperationDefinition<'a, T>, CostError> {
+    profile_fn!(select_operation);
+
+    let mut operations = operations.into_iter().filter(|op| match name {
+        Some(name) => operation_name(op) == Some(name),
+        None => true,
+    });
+    let operation = operations.next().ok_or(CostError::OperationNotFound)?;
+    if operations.next().is_some() {
+        return Err(CostError::AmbiguousOperation);
+    }
+    Ok(operation)
+}
+
+fn operation_name<'a, 'o, T: q::Text<'a>>(
+    op: &'o q::OperationDefinition<'a, T>,
+) -> Option<&'o str> {
+    let name = match op {
+        q::OperationDefinition::Query(query) => &query.name,
+        q::OperationDefinition::Mutation(mutation) => &mutation.name,
+        q::OperationDefinition::Subscription(subscription) => &subscription.name,
+        q::OperationDefinition::SelectionSet(_) => return None,
+    };
+    name.as_ref().map(|name| name.as_ref())
+}
+
+/// The keyword for the type of the operation. The shorthand for
+/// a selection set is a query.
+fn operation_type<'a, T: q::Text<'a>>(op: &q::OperationDefinition<'a, T>) -> &'static str {
+    match op {
+        q::OperationDefinition::Query(_) | q::OperationDefinition::SelectionSet(_) => "query",
+        q::OperationDefinition::Mutation(_) => "mutation",
+        q::OperationDefinition::Subscription(_) => "subscription",
+    }
+}
+
+/// Whether the top-level field is part of an introspection query,
+/// such as `__schema` or `__type`.
+fn is_introspection<'a, T: q::Text<'a>>(field: &q::Field<'a, T>) -> bool {
+    field.name.as_ref().starts_with("__")
+}
+
+/// The deepest nesting of selection sets in the operation, after expanding fragments.
+/// The selection set of the operation itself is depth 1, so `{ a { b } }` is depth 2.
+fn query_depth<'f, 'a, T: q::Text<'a>>(
+    top_level_fields: &[&'f q::Field<'a, T>],
+    fragments: &'f [q::FragmentDefinition<'a, T>],
+) -> Result<usize, CostError> {
+    profile_fn!(query_depth);
+
+    // Fragments are part of the selection set which contains them, so do not add to the depth.
+    fn depth<'f, 'a, T: q::Text<'a>>(
+        fields: &mut Fields<'f, 'a, T, usize>,
+        field: &'f q::Field<'a, T>,
+    ) -> Result<usize, CostError> {
+        Ok(fields.measure(&field.selection_set, &mut depth)? + 1)
+    }
+
+    let mut fields = Fields::new(fragments, 0, usize::max);
+    let mut deepest = 1;
+    for &field in top_level_fields.iter() {
+        deepest = depth(&mut fields, field)?.max(deepest);
+    }
+    Ok(deepest)
+}
+
+/// The number of fields selected by the top-level field, including itself, after
+/// expanding fragments.
+fn field_count<'f, 'a, T: q::Text<'a>>(
+    top_level_field: &'f q::Field<'a, T>,
+    fragments: &'f [q::FragmentDefinition<'a, T>],
+    count_introspection: bool,
+) -> Result<u64, CostError> {
+    profile_fn!(field_count);
+
+    fn count<'f, 'a, T: q::Text<'a>>(
+        fields: &mut Fields<'f, 'a, T, u64>,
+        field: &'f q::Field<'a, T>,
+        count_introspection: bool,
+    ) -> Result<u64, CostError> {
+        if !count_introspection && is_introspection(field) {
+            return Ok(0);
+        }
+        let selected = fields.measure(&field.selection_set, &mut |fields, child| {
+            count(fields, child, count_introspection)
+        })?;
+        Ok(selected.saturating_add(1))
+    }
+
+    count(
+        &mut Fields::new(fragments, 0, u64::saturating_add),
+        top_level_field,
+        count_introspection,
+    )
+}
+
+/// The number of fields of the top-level field, after expanding fragments, or None
+/// if any of them has an argument which is or may be a list. See `CostModel::cost_fast`.
+fn fast_path_fields<'f, 'a, T: q::Text<'a>>(
+    top_level_field: &'f q::Field<'a, T>,
+    fragments: &'f [q::FragmentDefinition<'a, T>],
+) -> Result<Option<u64>, CostError> {
+    profile_fn!(fast_path_fields);
+
+    // The value of a variable is not known without the variables of the query.
+    fn may_be_list<'a, T: q::Text<'a>>(value: &q::Value<'a, T>) -> bool {
+        match value {
+            q::Value::List(_) | q::Value::Variable(_) => true,
+            q::Value::Object(object) => object.values().any(may_be_list),
+            _ => false,
+        }
+    }
+
+    fn count<'f, 'a, T: q::Text<'a>>(
+        fields: &mut Fields<'f, 'a, T, Option<u64>>,
+        field: &'f q::Field<'a, T>,
+    ) -> Result<Option<u64>, CostError> {
+        if field.arguments.iter().any(|(_, value)| may_be_list(value)) {
+            return Ok(None);
+        }
+        let selected = fields.measure(&field.selection_set, &mut count)?;
+        Ok(selected.map(|selected| selected.saturating_add(1)))
+    }
+
+    // A selection set has no count if any of its fields has none
+    fn add(total: Option<u64>, count: Option<u64>) -> Option<u64> {
+        Some(total?.saturating_add(count?))
+    }
+
+    count(&mut Fields::new(fragments, Some(0), add), top_level_field)
+}
+
+/// An estimate of the number of nodes which each top-level field fetches, after
+/// expanding fragments. A field with a selection set fetches as many nodes as its
+/// `first` argument for each node of its parent, or `default_fan_out` nodes if it
+/// has no `first` argument, but no more than `max_fan_out`. The nodes of sibling
+/// fields are summed, and fields without a selection set are not counted.
+fn field_nodes<'f, 'a, T: q::Text<'a>>(
+    top_level_fields: &[&'f q::Field<'a, T>],
+    fragments: &'f [q::FragmentDefinition<'a, T>],
+    variables: &QueryVariables,
+    default_fan_out: u64,
+    max_fan_out: Option<u64>,
+) -> Result<Vec<u64>, CostError> {
+    profile_fn!(field_nodes);
+
+    let fan_out = |field: &q::Field<'a, T>| {
+        let fan_out = match first_argument(field, variables) {
+            // A negative page is empty
+            Some(count) => count.max(0) as u64,
+            None => default_fan_out,
+        };
+        match max_fan_out {
+            Some(max_fan_out) => fan_out.min(max_fan_out),
+            None => fan_out,
+        }
+    };
+
+    // The nodes of a field for each node of its parent, which are multiplied by the
+    // nodes of the parent. Measured this way, a fragment has the same nodes wherever
+    // it is spread, so it is only measured once.
+    fn nodes<'f, 'a, T: q::Text<'a>>(
+        fields: &mut Fields<'f, 'a, T, u64>,
+        field: &'f q::Field<'a, T>,
+        fan_out: &impl Fn(&q::Field<'a, T>) -> u64,
+    ) -> Result<u64, CostError> {
+        if field.selection_set.items.is_empty() {
+            return Ok(0);
+        }
+        let selected = fields.measure(&field.selection_set, &mut |fields, child| {
+            nodes(fields, child, fan_out)
+        })?;
+        // Each node which the field fetches fetches the nodes of its selection set
+        Ok(fan_out(field).saturating_mul(selected.saturating_add(1)))
+    }
+
+    let mut fields = Fields::new(fragments, 0, u64::saturating_add);
+    top_level_fields
+        .iter()
+        .map(|&field| nodes(&mut fields, field, &fan_out))
+        .collect()
+}
+
+/// The value of the `first` argument of the field, if it is a whole number which is
+/// either written in the query or supplied by a variable.
+fn first_argument<'a, T: q::Text<'a>>(
+    field: &q::Field<'a, T>,
+    variables: &QueryVariables,
+) -> Option<i64> {
+    let first = field
+        .arguments
+        .iter()
+        .find(|(name, _)| name.as_ref() == "first")
+        .map(|(_, value)| value);
+    match first {
+        Some(q::Value::Variable(var)) => match variables.get(var.as_ref()) {
+            Some(q::Value::Int(i)) => i.as_i64(),
+            _ => None,
+        },
+        Some(q::Value::Int(i)) => i.as_i64(),
+        _ => None,
+    }
+}
+
+/// Measures the fields selected by a query, expanding fragments so that a spread selects
+/// the fields of its fragment in its place. A measure of a query, such as its depth,
+/// combines the measures of the fields of each selection set. Each fragment is measured
+/// once and its measure reused for each spread of it, so that a fragment which is spread
+/// many times, directly or through other fragments, is not walked many times. A spread
+/// of a fragment which is not defined fails with `CostError::UndefinedFragment`, and a
+/// fragment which spreads itself fails with `CostError::FragmentCycle`.
+struct Fields<'f, 'a, T: q::Text<'a>, M> {
+    fragments: &'f [q::FragmentDefinition<'a, T>],
+    /// The measure of an empty selection set
+    empty: M,
+    /// Combines the measures of two selections of the same selection set
+    combine: fn(M, M) -> M,
+    /// The fragments which are being measured, to find cycles
+    expanding: Vec<&'f str>,
+    /// The fragments which have been measured
+    measured: HashMap<&'f str, M>,
+}
+
+impl<'f, 'a, T: q::Text<'a>, M: Clone> Fields<'f, 'a, T, M> {
+    fn new(
+        fragments: &'f [q::FragmentDefinition<'a, T>],
+        empty: M,
+        combine: fn(M, M) -> M,
+    ) -> Self {
+        Self {
+            fragments,
+            empty,
+            combine,
+            expanding: Vec::new(),
+            measured: HashMap::new(),
+        }
+    }
+
+    /// The measure of the selection set, where `f` measures each of its fields. `f` may
+    /// measure the selection set of the field in turn, which keeps expanding the same
+    /// fragments, so that cycles through nested fields are found.
+    // Security: Relying on GraphQL parsing to not have stack overflow here.
+    // See also 01205a6c-4e1a-4b35-8dc6-d400c499d423
+    fn measure<F>(&mut self, set: &'f q::SelectionSet<'a, T>, f: &mut F) -> Result<M, CostError>
+    where
+        F: FnMut(&mut Self, &'f q::Field<'a, T>) -> Result<M, CostError>,
+    {
+        let mut acc = self.empty.clone();
+        for item in set.items.iter() {
+            let measure = match item {
+                q::Selection::Field(field) => f(self, field)?,
+                q::Selection::FragmentSpread(spread) => self.fragment(spread, f)?,
+                q::Selection::InlineFragment(inline) => self.measure(&inline.selection_set, f)?,
+            };
+            acc = (self.combine)(acc, measure);
+        }
+        Ok(acc)
+    }
+
+    /// The measure of the fragment which is spread, which is only measured the first
+    /// time that it is spread.
+    fn fragment<F>(
+        &mut self,
+        spread: &'f q::FragmentSpread<'a, T>,
+        f: &mut F,
+    ) -> Result<M, CostError>
+    where
+        F: FnMut(&mut Self, &'f q::Field<'a, T>) -> Result<M, CostError>,
+    {
+        let fragments = self.fragments;
+        let fragment = matching::find_fragment(fragments, spread.fragment_name.as_ref())
+            .ok_or_else(|| undefined_fragment(spread))?;
+        let name = fragment.name.as_ref();
+        if let Some(measure) = self.measured.get(name) {
+            return Ok(measure.clone());
+        }
+        // A fragment which includes itself would never finish expanding.
+        if self.expanding.contains(&name) {
+            return Err(CostError::FragmentCycle(name.to_owned()));
+        }
+        self.expanding.push(name);
+        let measure = self.measure(&fragment.selection_set, f)?;
+        self.expanding.pop();
+        self.measured.insert(name, measure.clone());
+        Ok(measure)
+    }
+}
+
 fn get_top_level_fields<'a, 's, T: q::Text<'s>>(
     op: &'a q::OperationDefinition<'s, T>,
     fragments: &'a [q::FragmentDefinition<'s, T>],
     variables: &QueryVariables,
+    unresolved_directives: UnresolvedDirectives,
 ) -> Result<Vec<&'a q::Field<'s, T>>, CostError> {
     profile_fn!(get_top_level_fields);
 
@@ -272,57 +1849,72 @@ fn get_top_level_fields<'a, 's, T: q::Text<'s>>(
         set: &'a1 q::SelectionSet<'s1, T>,
         fragments: &'a1 [q::FragmentDefinition<'s1, T>],
         variables: &QueryVariables,
+        unresolved_directives: UnresolvedDirectives,
         result: &mut Vec<&'a1 q::Field<'s1, T>>,
+        expanding: &mut Vec<&'a1 str>,
     ) -> Result<(), CostError> {
         profile_fn!(get_top_level_fields_from_set);
 
+        let exclude = |directives: &[q::Directive<'s1, T>]| {
+            matching::exclude(directives, variables, unresolved_directives)
+                .map_err(match_error_to_cost_error)
+        };
+
         for item in set.items.iter() {
             match item {
                 q::Selection::Field(field) => {
-                    if !matching::exclude(&field.directives, variables)
-                        .map_err(|()| CostError::QueryNotSupported)?
-                    {
+                    // Other directives of the field are matched by statements
+                    let excluded = matching::exclude_standard(
+                        &field.directives,
+                        variables,
+                        unresolved_directives,
+                    )
+                    .map_err(match_error_to_cost_error)?;
+                    if !excluded {
                         result.push(field)
                     }
                 }
                 q::Selection::FragmentSpread(fragment_spread) => {
                     // Find the fragment from the fragment declarations
-                    let fragment = fragments
-                        .iter()
-                        .find(|frag| frag.name == fragment_spread.fragment_name);
+                    let fragment =
+                        matching::find_fragment(fragments, fragment_spread.fragment_name.as_ref());
                     let fragment = if let Some(fragment) = fragment {
                         fragment
                     } else {
-                        return Err(CostError::QueryInvalid);
+                        return Err(undefined_fragment(fragment_spread));
                     };
 
                     // Exclude the fragment if either the fragment itself or the spread
                     // has a directive indicating that.
-                    if matching::exclude(&fragment_spread.directives, variables)
-                        .map_err(|()| CostError::QueryNotSupported)?
-                    {
+                    if exclude(&fragment_spread.directives)? {
                         continue;
                     }
 
-                    if matching::exclude(&fragment.directives, variables)
-                        .map_err(|()| CostError::QueryNotSupported)?
-                    {
+                    if exclude(&fragment.directives)? {
                         continue;
                     }
 
+                    // A fragment which includes itself would never finish expanding.
+                    let name = fragment.name.as_ref();
+                    if expanding.contains(&name) {
+                        return Err(CostError::FragmentCycle(name.to_owned()));
+                    }
+
                     // Treat each field within the fragment as a top level field
                     // TODO: (Security) Recursion
+                    expanding.push(name);
                     get_top_level_fields_from_set(
                         &fragment.selection_set,
                         fragments,
                         variables,
+                        unresolved_directives,
                         result,
+                        expanding,
                     )?;
+                    expanding.pop();
                 }
                 q::Selection::InlineFragment(inline_fragment) => {
-                    if matching::exclude(&inline_fragment.directives, variables)
-                        .map_err(|()| CostError::QueryNotSupported)?
-                    {
+                    if exclude(&inline_fragment.directives)? {
                         continue;
                     }
 
@@ -330,7 +1922,9 @@ fn get_top_level_fields<'a, 's, T: q::Text<'s>>(
                         &inline_fragment.selection_set,
                         fragments,
                         variables,
+                        unresolved_directives,
                         result,
+                        expanding,
                     )?;
                 }
             }
@@ -338,22 +1932,39 @@ fn get_top_level_fields<'a, 's, T: q::Text<'s>>(
         Ok(())
     }
 
-    let mut result = Vec::new();
-
-    match op {
-        q::OperationDefinition::Query(query) => {
-            if query.directives.len() != 0 {
-                return Err(CostError::QueryNotSupported);
-            }
-            get_top_level_fields_from_set(&query.selection_set, fragments, variables, &mut result)?;
+    let (directives, set) = match op {
+        q::OperationDefinition::Query(query) => (&query.directives[..], &query.selection_set),
+        q::OperationDefinition::Mutation(mutation) => {
+            (&mutation.directives[..], &mutation.selection_set)
         }
-        q::OperationDefinition::SelectionSet(set) => {
-            get_top_level_fields_from_set(set, fragments, variables, &mut result)?;
-        }
-        q::OperationDefinition::Mutation(_) | q::OperationDefinition::Subscription(_) => {
-            return Err(CostError::QueryNotSupported);
+        q::OperationDefinition::Subscription(subscription) => {
+            (&subscription.directives[..], &subscription.selection_set)
         }
+        q::OperationDefinition::SelectionSet(set) => (&[][..], set),
+    };
+    if directives.len() != 0 {
+        return Err(CostError::QueryNotSupported);
+    }
+    // Checked up front, since matching only expands the fragments that a statement
+    // gets to. Each fragment which is spread is checked once, and fragments which are
+    // defined but never spread are ignored.
+    fn check<'f, 'a, T: q::Text<'a>>(
+        fields: &mut Fields<'f, 'a, T, ()>,
+        field: &'f q::Field<'a, T>,
+    ) -> Result<(), CostError> {
+        fields.measure(&field.selection_set, &mut check)
     }
+    Fields::new(fragments, (), |(), ()| ()).measure(set, &mut check)?;
+
+    let mut result = Vec::new();
+    get_top_level_fields_from_set(
+        set,
+        fragments,
+        variables,
+        unresolved_directives,
+        &mut result,
+        &mut Vec::new(),
+    )?;
 
     Ok(result)
 }
@@ -0,0 +1,146 @@
+//! Checks of a compiled cost model for likely mistakes which do not stop it from
+//! compiling, such as statements which can never be chosen.
+
+use crate::language::*;
+use crate::matching::{WILDCARD_FIELD, WILDCARD_VALUE};
+use crate::prelude::*;
+use crate::CostModel;
+use graphql_parser::query as q;
+use std::fmt;
+
+/// A likely mistake in a cost model. See `CostModel::lint`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LintWarning {
+    /// The statement at `index` is never chosen, because the earlier statement at
+    /// `shadowed_by` matches every field that it does. Statements are given by the
+    /// order that they were declared.
+    UnreachableStatement { index: usize, shadowed_by: usize },
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        profile_method!(fmt);
+
+        match self {
+            LintWarning::UnreachableStatement { index, shadowed_by } => write!(
+                f,
+                "Statement {} is unreachable, because statement {} matches every field that it does",
+                index, shadowed_by
+            ),
+        }
+    }
+}
+
+impl CostModel {
+    /// Finds likely mistakes in the model, assuming that each field is costed by the
+    /// first statement which matches it, as by `cost`. A statement is unreachable when
+    /// an earlier statement, which has no when clause, matches a subset of the same
+    /// fields, arguments, directives and selections. This check is conservative, so
+    /// for `cost` a warning is always right, but not every unreachable statement is
+    /// found. Additive statements are never unreachable. `cost_additive` and `cost_all`
+    /// use every statement which matches a field, so an unreachable statement still
+    /// costs fields for them, and the order of `cost_most_specific` is not considered.
+    pub fn lint(&self) -> Vec<LintWarning> {
+        profile_method!(lint);
+
+        let statements = &self.document().statements;
+        let mut warnings = Vec::new();
+        for (index, statement) in statements.iter().enumerate() {
+            // Additive statements are tried even after a statement has been chosen
+            if statement.additive {
+                continue;
+            }
+            let shadowed_by = statements[..index].iter().position(|earlier| {
+                !earlier.additive && subsumes(&earlier.predicate, &statement.predicate)
+            });
+            if let Some(shadowed_by) = shadowed_by {
+                warnings.push(LintWarning::UnreachableStatement { index, shadowed_by });
+            }
+        }
+        warnings
+    }
+}
+
+/// Whether every field which `later` matches is also matched by `earlier`.
+fn subsumes<'a>(earlier: &Predicate<'a>, later: &Predicate<'a>) -> bool {
+    if earlier.when_clause.is_some() {
+        return false;
+    }
+    match (&earlier.match_, &later.match_) {
+        (Match::Default, _) => true,
+        (Match::GraphQL(..), Match::Default) => false,
+        (Match::GraphQL(earlier, earlier_defs), Match::GraphQL(later, later_defs)) => Subsumes {
+            earlier_defs,
+            later_defs,
+        }
+        .field(earlier, later),
+    }
+}
+
+struct Subsumes<'d, 'a> {
+    earlier_defs: &'d [q::VariableDefinition<'a, &'a str>],
+    later_defs: &'d [q::VariableDefinition<'a, &'a str>],
+}
+
+fn has_default<'a>(definitions: &[q::VariableDefinition<'a, &'a str>], capture: &str) -> bool {
+    definitions
+        .iter()
+        .any(|definition| definition.name == capture && definition.default_value.is_some())
+}
+
+// Security: Relying on GraphQL parsing to not have stack overflow here.
+// See also 01205a6c-4e1a-4b35-8dc6-d400c499d423
+impl<'d, 'a> Subsumes<'d, 'a> {
+    fn field(&self, earlier: &q::Field<'a, &'a str>, later: &q::Field<'a, &'a str>) -> bool {
+        if earlier.name != WILDCARD_FIELD && earlier.name != later.name {
+            return false;
+        }
+        // A predicate without an alias matches a field with any alias
+        if earlier.alias.is_some() && earlier.alias != later.alias {
+            return false;
+        }
+        let directives = earlier.directives.iter().all(|e_directive| {
+            later.directives.iter().any(|l_directive| {
+                e_directive.name == l_directive.name
+                    && self.arguments(&e_directive.arguments, &l_directive.arguments)
+            })
+        });
+        let selections = earlier.selection_set.items.iter().all(|e_item| {
+            match e_item {
+            q::Selection::Field(e_field) => later.selection_set.items.iter().any(|l_item| {
+                matches!(l_item, q::Selection::Field(l_field) if self.field(e_field, l_field))
+            }),
+            // Fragments are not compared, which is conservative
+            _ => false,
+        }
+        });
+        directives && selections && self.arguments(&earlier.arguments, &later.arguments)
+    }
+
+    fn arguments(
+        &self,
+        earlier: &[(&'a str, q::Value<'a, &'a str>)],
+        later: &[(&'a str, q::Value<'a, &'a str>)],
+    ) -> bool {
+        earlier.iter().all(|(name, e_value)| {
+            match later.iter().find(|(l_name, _)| l_name == name) {
+                Some((_, l_value)) => self.value(e_value, l_value),
+                None => false,
+            }
+        })
+    }
+
+    fn value(&self, earlier: &q::Value<'a, &'a str>, later: &q::Value<'a, &'a str>) -> bool {
+        match (earlier, later) {
+            // A capture with a default also matches a field which leaves out the argument
+            (q::Value::Variable(e_capture), q::Value::Variable(l_capture))
+                if has_default(self.later_defs, l_capture) =>
+            {
+                has_default(self.earlier_defs, e_capture)
+            }
+            (q::Value::Variable(_), _) => true,
+            (q::Value::Enum(wildcard), _) if *wildcard == WILDCARD_VALUE => true,
+            (earlier, later) => earlier == later,
+        }
+    }
+}
@@ -4,35 +4,83 @@ use crate::prelude::*;
 use graphql_parser::query as q;
 use single::Single as _;
 use std::borrow::Borrow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+
+/// The reason that a query could not be matched against a predicate.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MatchError {
+    /// The query or predicate uses a feature that matching does not support,
+    /// such as an unknown directive.
+    Unsupported,
+    /// The query spreads a fragment which it does not define.
+    UndefinedFragment(String),
+    /// The query uses a variable which was not provided.
+    MissingVariable(String),
+    /// A fragment includes itself, directly or through other fragments.
+    FragmentCycle(String),
+}
+
+/// What to do with a selection whose `@skip` or `@include` directive
+/// depends on a variable which was not provided.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UnresolvedDirectives {
+    /// Keep the selection, as though the directive were not there.
+    Keep,
+    /// Leave out the selection, as though the directive excluded it.
+    Exclude,
+    /// Fail with `MatchError::MissingVariable`.
+    Fail,
+}
+
+impl Default for UnresolvedDirectives {
+    fn default() -> Self {
+        UnresolvedDirectives::Keep
+    }
+}
+
+/// A field name in a predicate which matches a field with any name.
+pub(crate) const WILDCARD_FIELD: &str = "_";
+
+/// An argument value in a predicate which matches any value, without capturing it.
+/// This parses as an enum value.
+pub(crate) const WILDCARD_VALUE: &str = "_";
 
 struct MatchingContext<'var, 'cap, 'frag, 'fragt: 'frag, TF: q::Text<'fragt>> {
     fragments: &'frag [q::FragmentDefinition<'fragt, TF>],
     variables: &'var QueryVariables,
+    /// Default values for captures of arguments which the query leaves out
+    defaults: &'var QueryVariables,
+    unresolved_directives: UnresolvedDirectives,
+    /// Names of arguments of the query to match as though they had another name
+    argument_aliases: &'var HashMap<String, String>,
     captures: &'cap mut Captures,
+    /// The names of fragments currently being expanded
+    expanding: Vec<&'frag str>,
 }
 
 fn match_selections<'l, 'r, 'c, TL: q::Text<'l>, TR: q::Text<'r>, TC: q::Text<'c>>(
     predicate: &q::Selection<'l, TL>,
     query: &q::Selection<'r, TR>,
     context: &mut MatchingContext<'_, '_, '_, 'c, TC>,
-) -> Result<bool, ()> {
+) -> Result<bool, MatchError> {
     profile_fn!(match_selections);
 
     match (predicate, query) {
         // A fragment spread on the lhs has nothing to draw the fragment contents from.
-        (q::Selection::FragmentSpread(_), _) => return Err(()),
+        (q::Selection::FragmentSpread(_), _) => return Err(MatchError::Unsupported),
         (q::Selection::Field(predicate), q::Selection::Field(query)) => {
             match_fields(predicate, query, context)
         }
         (_, q::Selection::FragmentSpread(fragment_spread)) => {
-            if exclude(&fragment_spread.directives, context.variables)? {
+            if exclude(
+                &fragment_spread.directives,
+                context.variables,
+                context.unresolved_directives,
+            )? {
                 return Ok(false);
             }
-            let fragment_definition = context
-                .fragments
-                .iter()
-                .find(|def| def.name.as_ref() == fragment_spread.fragment_name.as_ref());
+            let fragment_definition =
+                find_fragment(context.fragments, fragment_spread.fragment_name.as_ref());
             if let Some(fragment_definition) = fragment_definition {
                 // TODO: Check the spec... what if there are 2 fragment definitions
                 // with opposing directives? Does it mean "include the fragment" if such,
@@ -40,49 +88,67 @@ fn match_selections<'l, 'r, 'c, TL: q::Text<'l>, TR: q::Text<'r>, TC: q::Text<'c
                 // same name might be valid. In the other, not. If the former, we would want
                 // to move the check for excluding a fragment to find and then if there
                 // is no fragment with a matching name then treat it as empty?
-                if exclude(&fragment_definition.directives, context.variables)? {
+                if exclude(
+                    &fragment_definition.directives,
+                    context.variables,
+                    context.unresolved_directives,
+                )? {
                     return Ok(false);
                 }
 
                 // TODO: A fragment definition always has a type condition. So,
                 // this sometimes needs to match an inline fragment?
 
-                any_ok(
+                // Fragments are expanded as they are matched. Fragments being expanded
+                // are tracked, because one which includes itself would never finish.
+                let name = fragment_definition.name.as_ref();
+                if context.expanding.contains(&name) {
+                    return Err(MatchError::FragmentCycle(name.to_owned()));
+                }
+                context.expanding.push(name);
+                let result = any_ok(
                     fragment_definition.selection_set.items.iter(),
                     |selection| match_selections(predicate, selection, context),
-                )
+                );
+                context.expanding.pop();
+                result
             } else {
-                return Err(());
+                return Err(MatchError::UndefinedFragment(
+                    fragment_spread.fragment_name.as_ref().to_owned(),
+                ));
             }
         }
         (_, q::Selection::InlineFragment(q_inline)) => {
-            if exclude(&q_inline.directives, context.variables)? {
+            if exclude(
+                &q_inline.directives,
+                context.variables,
+                context.unresolved_directives,
+            )? {
                 return Ok(false);
             }
-            if let Some(q_type) = &q_inline.type_condition {
-                // If the fragment has a type condition, then match a fragment
-                // with the same type condition as the predicate
-                if let q::Selection::InlineFragment(p_inline) = predicate {
-                    if let Some(p_type) = &p_inline.type_condition {
-                        match (p_type, q_type) {
-                            (q::TypeCondition::On(p_type), q::TypeCondition::On(q_type)) => {
-                                if p_type.as_ref() == q_type.as_ref() {
-                                    // Two fragments with the same type condition.
-                                    return match_selection_sets(
-                                        &p_inline.selection_set,
-                                        &q_inline.selection_set,
-                                        context,
-                                    );
-                                }
-                            }
+            match (predicate, &q_inline.type_condition) {
+                // A type condition in the predicate only matches a fragment
+                // with the same type condition.
+                (q::Selection::InlineFragment(p_inline), Some(q_type)) => {
+                    match (&p_inline.type_condition, q_type) {
+                        (Some(q::TypeCondition::On(p_type)), q::TypeCondition::On(q_type))
+                            if p_type.as_ref() == q_type.as_ref() =>
+                        {
+                            match_selection_sets(
+                                &p_inline.selection_set,
+                                &q_inline.selection_set,
+                                context,
+                            )
                         }
+                        _ => Ok(false),
                     }
                 }
-                return Ok(false);
-            } else {
-                any_ok(&q_inline.selection_set.items, |item| {
+                // Otherwise, the fragment is flattened. Its selections are matched as
+                // though they were written in place of the fragment, whatever its type
+                // condition, just as for the contents of a named fragment.
+                _ => any_ok(&q_inline.selection_set.items, |item| {
                     match_selections(predicate, item, context)
-                })
+                }),
             }
         }
         (q::Selection::InlineFragment(inline_fragment), q::Selection::Field(_)) => {
@@ -90,7 +156,7 @@ fn match_selections<'l, 'r, 'c, TL: q::Text<'l>, TR: q::Text<'r>, TC: q::Text<'c
             // but I'm not sure I want to think about what bringing substitutions in here would
             // mean fully as of yet.
             if inline_fragment.directives.len() != 0 {
-                return Err(());
+                return Err(MatchError::Unsupported);
             }
             if inline_fragment.type_condition.is_some() {
                 return Ok(false);
@@ -104,11 +170,27 @@ fn match_selections<'l, 'r, 'c, TL: q::Text<'l>, TR: q::Text<'r>, TC: q::Text<'c
             // when error conditions arise. We could also cache things like capture names during pre-processing.
             // There's probably no reason to support an inline fragment with no type condition
             // in the predicate though.
-            return Err(());
+            return Err(MatchError::Unsupported);
         }
     }
 }
 
+/// The first definition of the fragment with the name. A `Context` sorts its fragments
+/// by name once, so that every model which costs the query with it finds them by
+/// binary search. Fragments which are out of order are still found.
+pub(crate) fn find_fragment<'f, 'a, T: q::Text<'a>>(
+    fragments: &'f [q::FragmentDefinition<'a, T>],
+    name: &str,
+) -> Option<&'f q::FragmentDefinition<'a, T>> {
+    let index = fragments.partition_point(|fragment| fragment.name.as_ref() < name);
+    match fragments.get(index) {
+        Some(fragment) if fragment.name.as_ref() == name => Some(fragment),
+        _ => fragments
+            .iter()
+            .find(|fragment| fragment.name.as_ref() == name),
+    }
+}
+
 fn get_capture_names_selection<'l>(
     predicate: &q::Selection<'l, &'l str>,
     names: &mut Vec<&'l str>,
@@ -162,8 +244,11 @@ pub fn match_query<'l, 'r, 'f, 'tf: 'f, TF: q::Text<'tf>, TL: q::Text<'l>, TR: q
     query: &q::Field<'r, TR>,
     fragments: &'f [q::FragmentDefinition<'tf, TF>],
     variables: &QueryVariables,
+    defaults: &QueryVariables,
+    unresolved_directives: UnresolvedDirectives,
+    argument_aliases: &HashMap<String, String>,
     captures: &mut Captures,
-) -> Result<bool, ()> {
+) -> Result<bool, MatchError> {
     profile_fn!(match_query);
 
     // TODO: (Security) Prevent stackoverflow by using
@@ -171,7 +256,11 @@ pub fn match_query<'l, 'r, 'f, 'tf: 'f, TF: q::Text<'tf>, TL: q::Text<'l>, TR: q
     let mut context = MatchingContext {
         fragments,
         variables,
+        defaults,
+        unresolved_directives,
+        argument_aliases,
         captures,
+        expanding: Vec::new(),
     };
     match_fields(predicate, query, &mut context)
 }
@@ -199,7 +288,7 @@ fn any_ok<T: IntoIterator, Err>(
 fn get_if_argument<'a, T: q::Text<'a>>(
     directive: &q::Directive<'a, T>,
     variables: &QueryVariables,
-) -> Result<bool, ()> {
+) -> Result<bool, MatchError> {
     profile_fn!(get_if_argument);
 
     match directive.arguments.iter().single() {
@@ -207,11 +296,12 @@ fn get_if_argument<'a, T: q::Text<'a>>(
             q::Value::Boolean(b) => Ok(*b),
             q::Value::Variable(name) => match variables.get(name.as_ref()) {
                 Some(q::Value::Boolean(b)) => Ok(*b),
-                _ => Err(()),
+                Some(_) => Err(MatchError::Unsupported),
+                None => Err(MatchError::MissingVariable(name.as_ref().to_owned())),
             },
-            _ => Err(()),
+            _ => Err(MatchError::Unsupported),
         },
-        _ => Err(()),
+        _ => Err(MatchError::Unsupported),
     }
 }
 
@@ -223,59 +313,159 @@ fn get_if_argument<'a, T: q::Text<'a>>(
 pub fn exclude<'a, T: q::Text<'a>>(
     directives: &[q::Directive<'a, T>],
     variables: &QueryVariables,
-) -> Result<bool, ()> {
+    unresolved_directives: UnresolvedDirectives,
+) -> Result<bool, MatchError> {
     profile_fn!(exclude);
 
     for directive in directives.iter() {
-        match directive.name.as_ref() {
-            "skip" => {
-                if get_if_argument(directive, variables)? {
-                    return Ok(true);
-                }
-            }
-            "include" => {
-                if !get_if_argument(directive, variables)? {
-                    return Ok(true);
-                }
-            }
-            _ => return Err(()),
+        if excludes(directive, variables, unresolved_directives)? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Like `exclude`, but ignores directives other than `@skip` and `@include`,
+/// which are left to be matched against predicates.
+pub fn exclude_standard<'a, T: q::Text<'a>>(
+    directives: &[q::Directive<'a, T>],
+    variables: &QueryVariables,
+    unresolved_directives: UnresolvedDirectives,
+) -> Result<bool, MatchError> {
+    profile_fn!(exclude_standard);
+
+    for directive in directives.iter() {
+        let standard = matches!(directive.name.as_ref(), "skip" | "include");
+        if standard && excludes(directive, variables, unresolved_directives)? {
+            return Ok(true);
         }
     }
 
     Ok(false)
 }
 
+/// Whether a `@defer` or `@stream` directive asks for the selection to be delivered
+/// incrementally. Their `if` argument is true by default, including when it is a
+/// variable which was not provided.
+pub fn deferred<'a, T: q::Text<'a>>(
+    directives: &[q::Directive<'a, T>],
+    variables: &QueryVariables,
+) -> bool {
+    profile_fn!(deferred);
+
+    directives
+        .iter()
+        .filter(|directive| matches!(directive.name.as_ref(), "defer" | "stream"))
+        .any(|directive| {
+            let condition = directive
+                .arguments
+                .iter()
+                .find(|(name, _)| name.as_ref() == "if")
+                .map(|(_, value)| value);
+            match condition {
+                Some(q::Value::Boolean(b)) => *b,
+                Some(q::Value::Variable(name)) => {
+                    !matches!(variables.get(name.as_ref()), Some(q::Value::Boolean(false)))
+                }
+                _ => true,
+            }
+        })
+}
+
+/// Whether one `@skip` or `@include` directive leaves out its selection.
+fn excludes<'a, T: q::Text<'a>>(
+    directive: &q::Directive<'a, T>,
+    variables: &QueryVariables,
+    unresolved_directives: UnresolvedDirectives,
+) -> Result<bool, MatchError> {
+    profile_fn!(excludes);
+
+    let skip_if = match directive.name.as_ref() {
+        "skip" => true,
+        "include" => false,
+        // Incremental delivery changes when a selection is sent, but not whether it is.
+        "defer" | "stream" => return Ok(false),
+        _ => return Err(MatchError::Unsupported),
+    };
+    let condition = match get_if_argument(directive, variables) {
+        Err(MatchError::MissingVariable(_))
+            if unresolved_directives == UnresolvedDirectives::Keep =>
+        {
+            return Ok(false)
+        }
+        Err(MatchError::MissingVariable(_))
+            if unresolved_directives == UnresolvedDirectives::Exclude =>
+        {
+            return Ok(true)
+        }
+        result => result?,
+    };
+    Ok(condition == skip_if)
+}
+
 fn match_fields<'l, 'r, 'c, TL: q::Text<'l>, TR: q::Text<'r>, TC: q::Text<'c>>(
     predicate: &q::Field<'l, TL>,
     query: &q::Field<'r, TR>,
     context: &mut MatchingContext<'_, '_, '_, 'c, TC>,
-) -> Result<bool, ()> {
+) -> Result<bool, MatchError> {
     profile_fn!(match_fields);
 
-    if predicate.name.as_ref() != query.name.as_ref() {
+    let predicate_name = predicate.name.as_ref();
+    if predicate_name != WILDCARD_FIELD && predicate_name != query.name.as_ref() {
         return Ok(false);
     }
 
-    if predicate.directives.len() != 0 {
-        return Err(());
+    // A predicate without an alias matches a field with any alias,
+    // or none at all.
+    if let Some(p_alias) = &predicate.alias {
+        match &query.alias {
+            Some(q_alias) if p_alias.as_ref() == q_alias.as_ref() => {}
+            _ => return Ok(false),
+        }
     }
 
-    if predicate.directives.len() != 0 {
-        return Err(());
+    // If a directive says that a field should not be included,
+    // then it won't be counted toward a match. Directives which are
+    // named by the predicate are matched below instead.
+    for directive in query.directives.iter() {
+        let name = directive.name.as_ref();
+        if predicate.directives.iter().any(|p| p.name.as_ref() == name) {
+            continue;
+        }
+        if excludes(directive, context.variables, context.unresolved_directives)? {
+            return Ok(false);
+        }
     }
 
-    // If a directive says that a field should not be included,
-    // then it won't be counted toward a match.
-    if exclude(&query.directives, context.variables)? {
-        return Ok(false);
+    for p_directive in predicate.directives.iter() {
+        if !any_ok(query.directives.iter(), |q_directive| {
+            match_directives(p_directive, q_directive, context)
+        })? {
+            return Ok(false);
+        }
     }
 
+    let aliases = context.argument_aliases;
     for p_argument in predicate.arguments.iter() {
         let p_argument = (p_argument.0.as_ref(), &p_argument.1);
         if !any_ok(query.arguments.iter(), |q_argument| {
-            let q_argument = (q_argument.0.as_ref(), &q_argument.1);
+            let q_argument = (argument_name(aliases, q_argument.0.as_ref()), &q_argument.1);
             match_named_value(p_argument, q_argument, context)
         })? {
+            // An argument which the query leaves out is captured from its default, if any.
+            if let q::Value::Variable(var) = p_argument.1 {
+                let omitted = !query
+                    .arguments
+                    .iter()
+                    .any(|(name, _)| argument_name(aliases, name.as_ref()) == p_argument.0);
+                if let Some(default) = context.defaults.get(var.as_ref()) {
+                    if omitted {
+                        context.captures.insert(var.as_ref(), default.clone());
+                        continue;
+                    }
+                }
+            }
             return Ok(false);
         }
     }
@@ -284,16 +474,47 @@ fn match_fields<'l, 'r, 'c, TL: q::Text<'l>, TR: q::Text<'r>, TC: q::Text<'c>>(
         return Ok(false);
     }
 
-    // TODO: Support alias?
-
     return Ok(true);
 }
 
+/// The name that an argument of the query is matched by, which is its alias if
+/// it has one. Aliases are not followed any further.
+fn argument_name<'a>(aliases: &'a HashMap<String, String>, name: &'a str) -> &'a str {
+    aliases.get(name).map(String::as_str).unwrap_or(name)
+}
+
+/// Matches a directive of the predicate, such as `@paginate(limit: $limit)`, against a
+/// directive of the query with the same name. Arguments are matched, and captured,
+/// like the arguments of a field.
+fn match_directives<'l, 'r, 'c, TL: q::Text<'l>, TR: q::Text<'r>, TC: q::Text<'c>>(
+    predicate: &q::Directive<'l, TL>,
+    query: &q::Directive<'r, TR>,
+    context: &mut MatchingContext<'_, '_, '_, 'c, TC>,
+) -> Result<bool, MatchError> {
+    profile_fn!(match_directives);
+
+    if predicate.name.as_ref() != query.name.as_ref() {
+        return Ok(false);
+    }
+
+    for p_argument in predicate.arguments.iter() {
+        let p_argument = (p_argument.0.as_ref(), &p_argument.1);
+        if !any_ok(query.arguments.iter(), |q_argument| {
+            let q_argument = (q_argument.0.as_ref(), &q_argument.1);
+            match_named_value(p_argument, q_argument, context)
+        })? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
 fn match_selection_sets<'l, 'r, 'c, TL: q::Text<'l>, TR: q::Text<'r>, TC: q::Text<'c>>(
     predicate: &q::SelectionSet<'l, TL>,
     query: &q::SelectionSet<'r, TR>,
     context: &mut MatchingContext<'_, '_, '_, 'c, TC>,
-) -> Result<bool, ()> {
+) -> Result<bool, MatchError> {
     profile_fn!(match_selection_sets);
 
     for p_selection in predicate.items.iter() {
@@ -315,6 +536,11 @@ pub fn get_capture_names_field<'l>(
     for (_, value) in predicate.arguments.iter() {
         get_capture_names_value(value, names)?;
     }
+    for directive in predicate.directives.iter() {
+        for (_, value) in directive.arguments.iter() {
+            get_capture_names_value(value, names)?;
+        }
+    }
 
     get_capture_names_selection_set(&predicate.selection_set, names)
 }
@@ -332,7 +558,7 @@ fn match_named_value<
     predicate: (&str, VP),
     query: (&str, VQ),
     context: &mut MatchingContext<'_, '_, '_, 'c, TC>,
-) -> Result<bool, ()> {
+) -> Result<bool, MatchError> {
     profile_fn!(match_named_value);
 
     if predicate.0 != query.0 {
@@ -346,18 +572,29 @@ fn match_value<'l, 'r, 'c, TR: q::Text<'r>, TL: q::Text<'l>, TC: q::Text<'c>>(
     predicate: &q::Value<'l, TL>,
     query: &q::Value<'r, TR>,
     context: &mut MatchingContext<'_, '_, '_, 'c, TC>,
-) -> Result<bool, ()> {
+) -> Result<bool, MatchError> {
     profile_fn!(match_value);
     use q::Value::*;
 
     match (predicate, query) {
+        (Variable(capture), Variable(var)) => {
+            if let Some(value) = context.variables.get(var.as_ref()) {
+                context
+                    .captures
+                    .insert_from_variable(capture.as_ref(), var.as_ref(), value);
+                Ok(true)
+            } else {
+                Err(MatchError::MissingVariable(var.as_ref().to_owned()))
+            }
+        }
         (_, Variable(var)) => {
-            if let Some(var) = context.variables.get(var.as_ref()) {
-                match_value(predicate, var, context)
+            if let Some(value) = context.variables.get(var.as_ref()) {
+                match_value(predicate, value, context)
             } else {
-                Err(())
+                Err(MatchError::MissingVariable(var.as_ref().to_owned()))
             }
         }
+        (Enum(p), _) if p.as_ref() == WILDCARD_VALUE => Ok(true),
         // TODO: Performance: Borrow keys in Captures
         (Variable(var), q) => {
             context.captures.insert(var.as_ref(), q.to_graphql());
@@ -412,7 +649,7 @@ fn match_list<'l, 'r, 'c, TR: q::Text<'r>, TL: q::Text<'l>, TC: q::Text<'c>>(
     predicate: &Vec<q::Value<'l, TL>>,
     query: &Vec<q::Value<'r, TR>>,
     context: &mut MatchingContext<'_, '_, '_, 'c, TC>,
-) -> Result<bool, ()> {
+) -> Result<bool, MatchError> {
     profile_fn!(match_list);
 
     if predicate.len() != query.len() {
@@ -431,7 +668,7 @@ fn match_object<'l, 'r, 'c, TR: q::Text<'r>, TL: q::Text<'l>, TC: q::Text<'c>>(
     predicate: &BTreeMap<TL::Value, q::Value<'l, TL>>,
     query: &BTreeMap<TR::Value, q::Value<'r, TR>>,
     context: &mut MatchingContext<'_, '_, '_, 'c, TC>,
-) -> Result<bool, ()> {
+) -> Result<bool, MatchError> {
     profile_fn!(match_object);
 
     for p_arg in predicate.iter() {
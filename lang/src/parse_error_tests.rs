@@ -7,6 +7,59 @@ fn assert_err_text(model: &str, text: &str) {
     assert_eq!(text, &display);
 }
 
+#[test]
+fn span() {
+    let model = "query { a } => 1;\ndefault => 1 + 2);";
+    let err = CostModel::compile(model, "{}").unwrap_err();
+    // From the start of the second statement to the unexpected )
+    assert_eq!(err.span(), Some(Span { start: 18, end: 34 }));
+
+    let err = CostModel::compile("default => 1;", "{").unwrap_err();
+    assert_eq!(err.span(), None);
+}
+
+#[test]
+fn parse_collecting() {
+    let model = "query { a } => 1;\nquery { b } => ;\nquery { c } => 3;\n";
+    let mut statements = StatementIter::recovering(model);
+    assert!(matches!(statements.next(), Some(Ok(_))));
+    let err = statements.next().unwrap().unwrap_err();
+    assert_eq!(&model[err.span().unwrap().start..][..11], "query { b }");
+    assert!(matches!(statements.next(), Some(Ok(_))));
+    assert!(statements.next().is_none());
+
+    let errors = CostModel::parse_collecting(model).unwrap_err();
+    assert_eq!(errors.len(), 1);
+
+    let model = "query { a } => ;\nquery { b } => 2;\ndefault => 1 + ;\n";
+    assert_eq!(CostModel::parse_collecting(model).unwrap_err().len(), 2);
+
+    assert!(CostModel::parse_collecting("query { a } => 1;\ndefault => 2;").is_ok());
+}
+
+#[test]
+fn variable_defaults() {
+    let error = |model: &str| format!("{}", CostModel::compile(model, "{}").unwrap_err());
+
+    assert!(CostModel::compile("query ($a: Int = 1) { a(b: $a) } => $a;", "{}").is_ok());
+    assert!(error("query ($a: Int) { a(b: $a) } => $a;")
+        .contains("Variable $a in the GraphQL query must have a default value."));
+    for model in [
+        "query ($a: Int = \"1\") { a(b: $a) } => $a;",
+        "query ($a: Int! = null) { a(b: $a) } => $a;",
+        "query ($a: [Int] = [1.5]) { a(b: $a) } => 1;",
+        "query ($a: Boolean = 1) { a(b: $a) } => 1;",
+    ]
+    .iter()
+    {
+        assert!(
+            error(model).contains("The default value of variable $a does not match its type."),
+            "{}",
+            model
+        );
+    }
+}
+
 #[test]
 fn mismatched_close_paren() {
     let model = "default => 1 + 2);";
@@ -110,3 +163,12 @@ fn bad_graphql() {
     ";
     assert_err_text(model, expect);
 }
+
+#[cfg(feature = "regex")]
+#[test]
+fn invalid_pattern() {
+    let model = "query { a(id: $id) } when $id =~ \"(\" => 1;";
+    let err = CostModel::compile(model, "{}").unwrap_err();
+    let display = format!("{}", err);
+    assert!(display.contains("Invalid regular expression."));
+}
@@ -1,3 +1,4 @@
+use crate::expressions::BuiltinFn;
 use crate::prelude::*;
 use crate::repeat::repeat;
 use graphql_parser::query::ParseError as GraphQLParseError;
@@ -12,6 +13,7 @@ use std::ops::Deref;
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ErrorContext {
     Statement,
+    Definition,
     Predicate,
     RationalExpression,
     WhenClause,
@@ -21,6 +23,9 @@ pub enum ErrorContext {
     RealNumber,
     GraphQLQuery,
     Comparison,
+    Call,
+    StringLiteral,
+    List,
 }
 
 impl fmt::Display for ErrorContext {
@@ -31,6 +36,7 @@ impl fmt::Display for ErrorContext {
         use ErrorContext::*;
         match self {
             Statement => write!(f, "statement"),
+            Definition => write!(f, "definition"),
             Predicate => write!(f, "predicate"),
             RationalExpression => write!(f, "rational expression"),
             WhenClause => write!(f, "when clause"),
@@ -40,6 +46,9 @@ impl fmt::Display for ErrorContext {
             RealNumber => write!(f, "number"),
             GraphQLQuery => write!(f, "query"),
             Comparison => write!(f, "comparison"),
+            Call => write!(f, "function call"),
+            StringLiteral => write!(f, "string"),
+            List => write!(f, "list"),
         }
     }
 }
@@ -54,9 +63,18 @@ pub enum ValidationError<I> {
     FailedToParseGraphQL(GraphQLParseError),
     ExpectedQueryOperationDefinition,
     MatchingQueryNameIsUnsupported(I),
-    VariablesAreUnsupported,
+    VariableWithoutDefault(I),
+    DefaultTypeMismatch(I),
     DirectivesAreUnsupported,
     SelectionSetMustContainSingleField,
+    WrongArgumentCount(BuiltinFn),
+    MixedListTypes,
+    /// Expressions are nested more deeply than the limit, which is given.
+    NestingTooDeep(usize),
+    /// A match has more range patterns than the limit, eg: `first: > 100`
+    TooManyRangePatterns,
+    #[cfg(feature = "regex")]
+    InvalidPattern(regex::Error),
 }
 
 impl fmt::Display for ValidationError<&'_ str> {
@@ -83,8 +101,20 @@ impl fmt::Display for ValidationError<&'_ str> {
             MatchingQueryNameIsUnsupported(name) => {
                 writeln!(f, "Matching a query name is unsupported. Got: {}", name)?;
             }
-            VariablesAreUnsupported => {
-                writeln!(f, "Defining variables in the GraphQL query is unsupported.")?;
+            VariableWithoutDefault(name) => {
+                writeln!(
+                    f,
+                    "Variable ${} in the GraphQL query must have a default value.",
+                    name
+                )?;
+                writeln!(f, "Note that variables may only be defined in order to give a default value to a capture.")?;
+            }
+            DefaultTypeMismatch(name) => {
+                writeln!(
+                    f,
+                    "The default value of variable ${} does not match its type.",
+                    name
+                )?;
             }
             DirectivesAreUnsupported => {
                 writeln!(
@@ -100,6 +130,35 @@ impl fmt::Display for ValidationError<&'_ str> {
                 )?;
                 writeln!(f, "Note that when multiple fields exist in the query, they will be costed individually and summed.")?;
             }
+            WrongArgumentCount(func) => {
+                write!(f, "Wrong number of arguments to {}. Expected ", func.name())?;
+                match func.arity() {
+                    (min, None) => writeln!(f, "at least {}.", min)?,
+                    (min, Some(max)) if min == max => writeln!(f, "exactly {}.", min)?,
+                    (min, Some(max)) => writeln!(f, "between {} and {}.", min, max)?,
+                }
+            }
+            MixedListTypes => {
+                writeln!(
+                    f,
+                    "A list must contain either only numbers or only strings."
+                )?;
+            }
+            NestingTooDeep(max) => {
+                writeln!(f, "Expressions are nested more than {} deep.", max)?;
+            }
+            TooManyRangePatterns => {
+                writeln!(
+                    f,
+                    "A match may have at most {} range patterns.",
+                    crate::range_patterns::MAX_RANGE_PATTERNS
+                )?;
+            }
+            #[cfg(feature = "regex")]
+            InvalidPattern(inner) => {
+                writeln!(f, "Invalid regular expression.")?;
+                writeln!(f, "{}", inner)?;
+            }
         }
         Ok(())
     }
@@ -303,6 +362,13 @@ impl<I> From<ValidationAtom<I>> for ErrorAggregator<I> {
     }
 }
 
+/// A range of bytes within the text of a cost model.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 /// In order to finally impl Error and print a useful indication
 /// of where in the document something went wrong, we need to
 /// capture the original input as well. So, that's what this does.
@@ -322,6 +388,23 @@ impl<'a> AgoraParseError<&'a str> {
     pub fn new(input: &'a str, aggregator: ErrorAggregator<&'a str>) -> Self {
         Self { input, aggregator }
     }
+
+    /// Where the error occurred. The span starts at the outermost part of the
+    /// input which failed to parse, usually a statement, and ends where parsing
+    /// failed within it.
+    pub fn span(&self) -> Span {
+        profile_method!(span);
+
+        let end = self.input.len() - self.aggregator.unparsed_input_len();
+        let start = match &self.aggregator {
+            ErrorAggregator::Context(context, _) => self.input.len() - context.input.len(),
+            _ => end,
+        };
+        Span {
+            start: start.min(end),
+            end,
+        }
+    }
     fn pos<E>(&self, atom: &ErrorAtom<&'a str, E>) -> Pos<'a> {
         profile_fn!(pos);
 
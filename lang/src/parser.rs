@@ -2,39 +2,105 @@ use crate::parse_errors::{
     ErrorAggregator, ErrorAtom as ErrAtom, ErrorContext, ExpectationError, ValidationError,
 };
 use crate::prelude::*;
-use crate::{expressions::*, language::*, parse_errors::*};
+use crate::{expressions::*, language::*, parse_errors::*, range_patterns, CompileError};
 use fraction::BigFraction;
 use graphql_parser::query as q;
 use nom::{
     branch::alt,
-    bytes::complete::{is_not, take_while1},
+    bytes::complete::{take_until, take_while, take_while1},
     character::complete::{alpha1, alphanumeric1, char, digit1},
     combinator::{map, opt, recognize},
     error::ParseError as NomParseError,
     multi::many0,
-    sequence::{pair, tuple},
-    sequence::{preceded, terminated},
+    sequence::{delimited, preceded, terminated, tuple},
     Compare, Err as NomErr, IResult as NomIResult, InputLength, InputTake, InputTakeAtPosition,
 };
 use num_bigint::BigUint;
 use num_traits::Pow as _;
 use single::Single as _;
+use std::cell::Cell;
 
 // Change Nom default error type from (I, ErrorKind) to ErrorAggregator<I>
 type IResult<I, O, E = ErrorAggregator<I>> = NomIResult<I, O, E>;
 
-fn graphql_query<'a>(input: &'a str) -> IResult<&'a str, q::Field<'a, &'a str>> {
+/// Whether a default value can be given for a variable of the type.
+fn default_matches_type<'a>(
+    var_type: &q::Type<'a, &'a str>,
+    value: &q::Value<'a, &'a str>,
+) -> bool {
+    match (var_type, value) {
+        (_, q::Value::Variable(_)) => false,
+        (q::Type::NonNullType(_), q::Value::Null) => false,
+        (q::Type::NonNullType(inner), value) => default_matches_type(inner, value),
+        (_, q::Value::Null) => true,
+        (q::Type::ListType(inner), q::Value::List(values)) => values
+            .iter()
+            .all(|value| default_matches_type(inner, value)),
+        (q::Type::ListType(_), _) => false,
+        (q::Type::NamedType(name), value) => match (*name, value) {
+            ("Int", q::Value::Int(_)) => true,
+            ("Float", q::Value::Int(_)) | ("Float", q::Value::Float(_)) => true,
+            ("String", q::Value::String(_)) => true,
+            ("Boolean", q::Value::Boolean(_)) => true,
+            ("ID", q::Value::String(_)) | ("ID", q::Value::Int(_)) => true,
+            ("Int", _) | ("Float", _) | ("String", _) | ("Boolean", _) | ("ID", _) => false,
+            // Enums and input objects are not checked
+            _ => true,
+        },
+    }
+}
+
+fn into_query<'a>(definition: q::Definition<'a, &'a str>) -> Option<q::Query<'a, &'a str>> {
+    match definition {
+        q::Definition::Operation(q::OperationDefinition::Query(query)) => Some(query),
+        _ => None,
+    }
+}
+
+/// The field of a match, the variables defined by the query, and the condition
+/// implied by its range patterns, if any.
+type GraphQLQuery<'a> = (
+    q::Field<'a, &'a str>,
+    Vec<q::VariableDefinition<'a, &'a str>>,
+    Option<Condition>,
+);
+
+fn graphql_query<'a>(input: &'a str) -> IResult<&'a str, GraphQLQuery<'a>> {
     profile_fn!(graphql_query);
 
     with_context(ErrorContext::GraphQLQuery, |input: &str| {
         tag("query")(input)?;
         fail_fast(|input: &'a str| {
-            let (query, input) = q::consume_definition::<'a, &'a str>(input)
-                .map_err(|e| ErrAtom::new(input, ValidationError::FailedToParseGraphQL(e)))?;
+            // Range patterns are not GraphQL, so a copy without them is parsed instead.
+            // See also range_patterns.rs
+            let masked = range_patterns::mask(input);
+            let (query, input) = match &masked {
+                None => {
+                    let (definition, rest) =
+                        q::consume_definition::<'a, &'a str>(input).map_err(|e| {
+                            ErrAtom::new(input, ValidationError::FailedToParseGraphQL(e))
+                        })?;
+                    (into_query(definition), rest)
+                }
+                Some(masked) => {
+                    let (definition, rest) =
+                        q::consume_definition::<&str>(masked.text()).map_err(|e| {
+                            ErrAtom::new(input, ValidationError::FailedToParseGraphQL(e))
+                        })?;
+                    let original = input;
+                    let input = &original[masked.text().len() - rest.len()..];
+                    ensure!(
+                        masked.pattern_count() <= range_patterns::MAX_RANGE_PATTERNS,
+                        ErrAtom::new(input, ValidationError::TooManyRangePatterns)
+                    );
+                    let query = into_query(definition).map(|query| masked.rebase(query, original));
+                    (query, input)
+                }
+            };
 
             let query = match query {
-                q::Definition::Operation(q::OperationDefinition::Query(query)) => query,
-                _ => ErrAtom::err(input, ValidationError::ExpectedQueryOperationDefinition)?,
+                Some(query) => query,
+                None => ErrAtom::err(input, ValidationError::ExpectedQueryOperationDefinition)?,
             };
 
             ensure!(
@@ -45,10 +111,19 @@ fn graphql_query<'a>(input: &'a str) -> IResult<&'a str, q::Field<'a, &'a str>>
                 )
             );
 
-            ensure!(
-                query.variable_definitions.len() == 0,
-                ErrAtom::new(input, ValidationError::VariablesAreUnsupported)
-            );
+            // Variables may only be defined to give a default value to a capture.
+            for definition in query.variable_definitions.iter() {
+                match &definition.default_value {
+                    None => ErrAtom::err(
+                        input,
+                        ValidationError::VariableWithoutDefault(definition.name),
+                    )?,
+                    Some(value) => ensure!(
+                        default_matches_type(&definition.var_type, value),
+                        ErrAtom::new(input, ValidationError::DefaultTypeMismatch(definition.name))
+                    ),
+                }
+            }
 
             ensure!(
                 query.directives.len() == 0,
@@ -56,7 +131,10 @@ fn graphql_query<'a>(input: &'a str) -> IResult<&'a str, q::Field<'a, &'a str>>
             );
 
             match query.selection_set.items.into_iter().single() {
-                Ok(q::Selection::Field(field)) => Ok((input, field)),
+                Ok(q::Selection::Field(field)) => {
+                    let condition = masked.map(|masked| masked.condition());
+                    Ok((input, (field, query.variable_definitions, condition)))
+                }
                 _ => ErrAtom::err(input, ValidationError::SelectionSetMustContainSingleField)?,
             }
         })(input)
@@ -179,6 +257,30 @@ impl<Leaf, Branch: PartialEq<Branch>> FlatTree<Leaf, Branch> {
 
         Ok((input, out))
     }
+
+    /// Like collapse, but joins right-to-left for right-associative operators.
+    fn collapse_right(
+        self,
+        input: &str,
+        kind: impl Into<Branch>,
+        mut join: impl FnMut(Leaf, Branch, Leaf) -> Leaf,
+    ) -> IResult<&str, Self> {
+        profile_method!(collapse_right);
+
+        let FlatTree {
+            mut leaves,
+            mut branches,
+        } = self;
+        leaves.reverse();
+        branches.reverse();
+        let reversed = FlatTree { leaves, branches };
+
+        let (input, mut out) = reversed.collapse(input, kind, |rhs, op, lhs| join(lhs, op, rhs))?;
+        out.leaves.reverse();
+        out.branches.reverse();
+
+        Ok((input, out))
+    }
 }
 
 enum ParenOrLeaf<T> {
@@ -285,6 +387,8 @@ fn parse_tree_with_parens<Leaf, Branch>(
 fn condition(input: &str) -> IResult<&str, Condition> {
     profile_fn!(condition);
 
+    let _nested = Nested::enter(input)?;
+
     fn try_collapse(
         input: &str,
         tree: FlatTree<Condition, AnyBooleanOp>,
@@ -301,11 +405,36 @@ fn condition(input: &str) -> IResult<&str, Condition> {
     }
 
     fn condition_atom(input: &str) -> IResult<&str, Condition> {
-        alt((
+        let comparisons = or_quietly(
             map(comparison, Condition::Comparison),
+            map(string_comparison, Condition::StringComparison),
+        );
+        let comparisons = or_quietly(comparisons, map(membership, Condition::In));
+        #[cfg(feature = "regex")]
+        let comparisons = or_quietly(comparisons, regex_condition);
+
+        let atom = alt((
+            comparisons,
             map(variable, Condition::Variable),
             map(const_bool, Condition::Const),
-        ))(input)
+        ));
+        let atom = or_quietly(atom, is_null);
+        let atom = or_quietly(atom, defined);
+        or_quietly(atom, negation)(input)
+    }
+
+    // Negation binds tighter than any boolean operator, so it applies
+    // only to the atom or parenthesized condition which follows it.
+    fn negation(input: &str) -> IResult<&str, Condition> {
+        let _nested = Nested::enter(input)?;
+        let (input, _) = tuple((tag("!"), opt(whitespace)))(input)?;
+        let parens = delimited(
+            tuple((tag("("), opt(whitespace))),
+            condition,
+            tuple((opt(whitespace), tag(")"))),
+        );
+        let (input, condition) = alt((parens, condition_atom))(input)?;
+        Ok((input, Condition::Not(Box::new(condition))))
     }
 
     fn condition_op(input: &str) -> IResult<&str, AnyBooleanOp> {
@@ -337,6 +466,198 @@ fn comparison(input: &str) -> IResult<&str, BinaryExpression<AnyComparison, Line
     })(input)
 }
 
+fn string_comparison(
+    input: &str,
+) -> IResult<&str, BinaryExpression<AnyComparison, StringExpression>> {
+    profile_fn!(string_comparison);
+
+    with_context(ErrorContext::Comparison, |input: &str| {
+        let (input, lhs) = string_expression(input)?;
+        // Strings are ordered lexicographically
+        let (input, op) = surrounded_by(
+            opt(whitespace),
+            alt((
+                |input| binary_operator(input, "==", Eq),
+                |input| binary_operator(input, "!=", Ne),
+                |input| binary_operator(input, ">=", Ge),
+                |input| binary_operator(input, "<=", Le),
+                |input| binary_operator(input, ">", Gt),
+                |input| binary_operator(input, "<", Lt),
+            )),
+        )(input)?;
+        let (input, rhs) = string_expression(input)?;
+
+        Ok((input, BinaryExpression::new(lhs, op, rhs)))
+    })(input)
+}
+
+enum ListElement {
+    Real(BigFraction),
+    String(String),
+}
+
+fn list_elements(input: &str) -> IResult<&str, (Vec<BigFraction>, Vec<String>)> {
+    profile_fn!(list_elements);
+
+    let mut reals = Vec::new();
+    let mut strings = Vec::new();
+    let mut input = input;
+    loop {
+        let (i, element) = alt((
+            map(real, ListElement::Real),
+            map(string_literal, ListElement::String),
+        ))(input)?;
+        match element {
+            ListElement::Real(real) => reals.push(real),
+            ListElement::String(string) => strings.push(string),
+        }
+        let (i, comma) = opt(surrounded_by(opt(whitespace), tag(",")))(i)?;
+        input = i;
+        if comma.is_none() {
+            break;
+        }
+    }
+    Ok((input, (reals, strings)))
+}
+
+fn membership<'a>(input: &'a str) -> IResult<&'a str, Membership> {
+    profile_fn!(membership);
+
+    let (input, var) = variable::<String>(input)?;
+    let (input, _) = tuple((whitespace, tag("in"), opt(whitespace)))(input)?;
+
+    with_context(ErrorContext::List, move |start: &'a str| {
+        let (input, _) = tag("[")(start)?;
+        fail_fast(|input: &'a str| -> IResult<&'a str, Membership> {
+            let (input, _) = opt(whitespace)(input)?;
+            let (input, (reals, strings)) = match opt(tag("]"))(input)? {
+                (input, Some(_)) => (input, (Vec::new(), Vec::new())),
+                (input, None) => {
+                    let (input, elements) = list_elements(input)?;
+                    let (input, _) = tuple((opt(whitespace), tag("]")))(input)?;
+                    (input, elements)
+                }
+            };
+
+            ensure!(
+                reals.is_empty() || strings.is_empty(),
+                ErrAtom::new(start, ValidationError::MixedListTypes)
+            );
+
+            let membership = if strings.is_empty() {
+                Membership::Reals {
+                    var: Variable::new(var.name()),
+                    list: reals,
+                }
+            } else {
+                Membership::Strings {
+                    var: var.clone(),
+                    list: strings,
+                }
+            };
+            Ok((input, membership))
+        })(input)
+    })(input)
+}
+
+#[cfg(feature = "regex")]
+fn regex_condition<'a>(input: &'a str) -> IResult<&'a str, Condition> {
+    profile_fn!(regex_condition);
+
+    let (input, var) = variable(input)?;
+    let (input, _) = surrounded_by(opt(whitespace), tag("=~"))(input)?;
+
+    // The pattern is compiled once here rather than each time it is evaluated.
+    fail_fast(|start: &'a str| -> IResult<&'a str, Condition> {
+        let (input, source) = string_literal(start)?;
+        let pattern = regex::Regex::new(&source)
+            .map_err(|e| ErrAtom::new(start, ValidationError::InvalidPattern(e)))?;
+        Ok((
+            input,
+            Condition::Regex {
+                var: var.clone(),
+                pattern: Pattern(pattern),
+            },
+        ))
+    })(input)
+}
+
+fn string_expression(input: &str) -> IResult<&str, StringExpression> {
+    profile_fn!(string_expression);
+
+    alt((
+        map(string_literal, |s| StringExpression::Const(Const::new(s))),
+        map(variable, StringExpression::Variable),
+    ))(input)
+}
+
+/// The number of characters in a string, eg: `len($id)`
+fn length(input: &str) -> IResult<&str, LinearExpression> {
+    profile_fn!(length);
+
+    map(
+        delimited(
+            tuple((tag("len("), opt(whitespace))),
+            string_expression,
+            tuple((opt(whitespace), tag(")"))),
+        ),
+        LinearExpression::Len,
+    )(input)
+}
+
+fn list_fn(input: &str) -> IResult<&str, ListFn> {
+    profile_fn!(list_fn);
+
+    alt((
+        map(tag("sum"), |_| ListFn::Sum),
+        map(tag("count"), |_| ListFn::Count),
+    ))(input)
+}
+
+/// A function of the items of a captured list, eg: `sum($amounts)`
+fn fold(input: &str) -> IResult<&str, LinearExpression> {
+    profile_fn!(fold);
+
+    map(
+        tuple((
+            terminated(list_fn, tuple((tag("("), opt(whitespace)))),
+            terminated(variable, tuple((opt(whitespace), tag(")")))),
+        )),
+        |(func, list)| LinearExpression::Fold { func, list },
+    )(input)
+}
+
+/// A double quoted string. The only supported escapes are `\"` and `\\`.
+fn string_literal<'a>(input: &'a str) -> IResult<&'a str, String> {
+    profile_fn!(string_literal);
+
+    with_context(ErrorContext::StringLiteral, |input: &'a str| {
+        let (input, _) = tag("\"")(input)?;
+        fail_fast(|input: &'a str| -> IResult<&'a str, String> {
+            let mut value = String::new();
+            let mut chars = input.char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => return Ok((&input[i + 1..], value)),
+                    '\\' => match chars.next() {
+                        Some((_, '"')) => value.push('"'),
+                        Some((_, '\\')) => value.push('\\'),
+                        _ => ErrAtom::err(
+                            &input[i + 1..],
+                            ExpectationError::Or(Box::new((
+                                ExpectationError::Tag("\""),
+                                ExpectationError::Tag("\\"),
+                            ))),
+                        )?,
+                    },
+                    c => value.push(c),
+                }
+            }
+            Err(ErrAtom::new(&input[input.len()..], ExpectationError::Tag("\"")).into())
+        })(input)
+    })(input)
+}
+
 fn identifier(input: &str) -> IResult<&str, &str> {
     profile_fn!(identifier);
 
@@ -349,6 +670,40 @@ fn identifier(input: &str) -> IResult<&str, &str> {
     )(input)
 }
 
+/// Whether a variable is null, eg: `is_null($first)`
+fn is_null(input: &str) -> IResult<&str, Condition> {
+    profile_fn!(is_null);
+
+    preceded(
+        tag("is_null("),
+        fail_fast(map(
+            delimited(
+                opt(whitespace),
+                variable,
+                tuple((opt(whitespace), tag(")"))),
+            ),
+            Condition::IsNull,
+        )),
+    )(input)
+}
+
+/// Whether a variable has a value, eg: `defined($first)`
+fn defined(input: &str) -> IResult<&str, Condition> {
+    profile_fn!(defined);
+
+    preceded(
+        tag("defined("),
+        fail_fast(map(
+            delimited(
+                opt(whitespace),
+                variable,
+                tuple((opt(whitespace), tag(")"))),
+            ),
+            Condition::Defined,
+        )),
+    )(input)
+}
+
 fn variable<T>(input: &str) -> IResult<&str, Variable<T>> {
     profile_fn!(variable);
 
@@ -376,6 +731,61 @@ where
     }
 }
 
+/// The deepest that parsers which call themselves may be nested by default, eg: for
+/// `-(-(-($a)))`. Parentheses around an operand do not count, since they are parsed
+/// without recursion.
+pub const DEFAULT_MAX_NESTING: usize = 128;
+
+thread_local! {
+    // The depth of the parsers which have been entered, and the most which may be.
+    static NESTING: Cell<(usize, usize)> = Cell::new((0, DEFAULT_MAX_NESTING));
+}
+
+/// Entered by each parser which may call itself, so that adversarial text fails
+/// to parse instead of overflowing the stack. Leaves the parser when dropped.
+struct Nested;
+
+impl Nested {
+    fn enter(input: &str) -> Result<Self, NomErr<ErrorAggregator<&str>>> {
+        NESTING.with(|nesting| {
+            let (depth, max) = nesting.get();
+            if depth >= max {
+                let err = ErrAtom::new(input, ValidationError::NestingTooDeep(max));
+                return Err(NomErr::Failure(err.into()));
+            }
+            nesting.set((depth + 1, max));
+            Ok(Nested)
+        })
+    }
+}
+
+impl Drop for Nested {
+    fn drop(&mut self) {
+        NESTING.with(|nesting| {
+            let (depth, max) = nesting.get();
+            nesting.set((depth - 1, max));
+        })
+    }
+}
+
+/// Tries `primary`, then `fallback`. If both fail, only the error from `primary`
+/// is kept. This allows extending the grammar without adding noise about the
+/// extension to errors for inputs that never attempted to use it.
+fn or_quietly<I, O, F, G>(primary: F, fallback: G) -> impl Fn(I) -> IResult<I, O>
+where
+    I: Clone,
+    F: Fn(I) -> IResult<I, O>,
+    G: Fn(I) -> IResult<I, O>,
+{
+    move |input: I| match primary(input.clone()) {
+        Err(NomErr::Error(e)) => match fallback(input) {
+            Err(NomErr::Error(_)) => Err(NomErr::Error(e)),
+            other => other,
+        },
+        other => other,
+    }
+}
+
 pub fn real(input: &str) -> IResult<&str, BigFraction> {
     with_context(ErrorContext::RealNumber, |input: &str| {
         profile_fn!(real);
@@ -416,6 +826,8 @@ fn any_boolean_operator(input: &str) -> IResult<&str, AnyBooleanOp> {
 fn linear_expression(input: &str) -> IResult<&str, LinearExpression> {
     profile_fn!(linear_expression);
 
+    let _nested = Nested::enter(input)?;
+
     fn try_collapse(
         input: &str,
         tree: FlatTree<LinearExpression, AnyLinearOperator>,
@@ -429,8 +841,8 @@ fn linear_expression(input: &str) -> IResult<&str, LinearExpression> {
                 // TODO: (Performance) Do not do constant propagation in
                 // the parsing pass, but instead after global substitution
                 // Constant propagation
-                (LinearExpression::Error(()), _) => LinearExpression::Error(()),
-                (_, LinearExpression::Error(())) => LinearExpression::Error(()),
+                (LinearExpression::Error(e), _) => LinearExpression::Error(e),
+                (_, LinearExpression::Error(e)) => LinearExpression::Error(e),
                 (LinearExpression::Const(lhs), LinearExpression::Const(rhs)) => {
                     match op.exec(lhs.value, rhs.value) {
                         Ok(value) => LinearExpression::Const(Const::new(value)),
@@ -442,10 +854,16 @@ fn linear_expression(input: &str) -> IResult<&str, LinearExpression> {
                 ))),
             }
         }
+        let (input, tree) = tree.collapse_right(input, Pow, join)?;
         let (input, tree) = tree.collapse(input, Mul, join)?;
         let (input, tree) = tree.collapse(input, Div, join)?;
+        let (input, tree) = tree.collapse(input, Mod, join)?;
         let (input, tree) = tree.collapse(input, Add, join)?;
-        let (input, mut tree) = tree.collapse(input, Sub, join)?;
+        let (input, tree) = tree.collapse(input, Sub, join)?;
+        let (input, tree) = tree.collapse(input, Shl, join)?;
+        let (input, tree) = tree.collapse(input, Shr, join)?;
+        let (input, tree) = tree.collapse(input, BitAnd, join)?;
+        let (input, mut tree) = tree.collapse(input, BitOr, join)?;
         assert!(tree.leaves.len() == 1);
         assert!(tree.branches.len() == 0);
 
@@ -453,10 +871,36 @@ fn linear_expression(input: &str) -> IResult<&str, LinearExpression> {
     }
 
     fn linear_expression_leaf(input: &str) -> IResult<&str, LinearExpression> {
-        alt((
-            map(real, |r| LinearExpression::Const(Const::new(r))),
-            map(variable, LinearExpression::Variable),
-        ))(input)
+        let leaf = or_quietly(
+            alt((
+                map(real, |r| LinearExpression::Const(Const::new(r))),
+                map(variable, LinearExpression::Variable),
+                length,
+                fold,
+            )),
+            call,
+        );
+        or_quietly(leaf, negation)(input)
+    }
+
+    // Like negative numbers, negation binds tighter than any binary operator,
+    // so it applies only to the leaf or parenthesized expression which follows it.
+    fn negation(input: &str) -> IResult<&str, LinearExpression> {
+        let _nested = Nested::enter(input)?;
+        let (input, _) = tag("-")(input)?;
+        let parens = delimited(
+            tuple((tag("("), opt(whitespace))),
+            linear_expression,
+            tuple((opt(whitespace), tag(")"))),
+        );
+        let (input, expr) = alt((parens, linear_expression_leaf))(input)?;
+        let expr = match expr {
+            // Constant propagation
+            LinearExpression::Const(c) => LinearExpression::Const(Const::new(-c.value)),
+            LinearExpression::Error(e) => LinearExpression::Error(e),
+            expr => LinearExpression::Neg(Box::new(expr)),
+        };
+        Ok((input, expr))
     }
 
     fn any_linear_binary_operator(input: &str) -> IResult<&str, AnyLinearOperator> {
@@ -467,6 +911,14 @@ fn linear_expression(input: &str) -> IResult<&str, LinearExpression> {
                 |input| binary_operator(input, "-", Sub),
                 |input| binary_operator(input, "*", Mul),
                 |input| binary_operator(input, "/", Div),
+                |input| binary_operator(input, "%", Mod),
+                |input| binary_operator(input, "^", Pow),
+                |input| binary_operator(input, "<<", Shl),
+                |input| binary_operator(input, ">>", Shr),
+                // Requiring whitespace after the operator keeps these
+                // from matching the start of `&&` and `||`.
+                |input| binary_operator(input, "&", BitAnd),
+                |input| binary_operator(input, "|", BitOr),
             )),
         )(input)
     }
@@ -481,6 +933,91 @@ fn linear_expression(input: &str) -> IResult<&str, LinearExpression> {
     })(input)
 }
 
+/// A rational expression, or a conditional which selects between two of them,
+/// eg: `$first > 100 ? $first * 2 : $first`. The conditional binds more loosely
+/// than any operator, so it may only be the whole of the cost of a statement or a
+/// branch of another conditional, optionally surrounded by parentheses.
+fn conditional_expression(input: &str) -> IResult<&str, LinearExpression> {
+    profile_fn!(conditional_expression);
+
+    let _nested = Nested::enter(input)?;
+
+    // Until the `?` is found, anything which fails to parse is reported
+    // as an error in a rational expression instead of in a condition.
+    let cond = terminated(condition, surrounded_by(whitespace, tag("?")));
+    let (input, cond) = match cond(input) {
+        Ok(ok) => ok,
+        Err(_) => {
+            let parens = delimited(
+                tuple((tag("("), opt(whitespace))),
+                conditional_expression,
+                tuple((opt(whitespace), tag(")"))),
+            );
+            return or_quietly(linear_expression, parens)(input);
+        }
+    };
+
+    let (input, (then, _, otherwise)) = fail_fast(tuple((
+        conditional_expression,
+        surrounded_by(whitespace, tag(":")),
+        conditional_expression,
+    )))(input)?;
+
+    let expr = match cond {
+        // Constant propagation
+        Condition::Const(c) if c.value => then,
+        Condition::Const(_) => otherwise,
+        cond => LinearExpression::Conditional {
+            cond: Box::new(cond),
+            then: Box::new(then),
+            otherwise: Box::new(otherwise),
+        },
+    };
+    Ok((input, expr))
+}
+
+fn builtin_fn(input: &str) -> IResult<&str, BuiltinFn> {
+    profile_fn!(builtin_fn);
+
+    alt((
+        map(tag("min"), |_| BuiltinFn::Min),
+        map(tag("max"), |_| BuiltinFn::Max),
+        map(tag("log2"), |_| BuiltinFn::Log2),
+        map(tag("log10"), |_| BuiltinFn::Log10),
+        map(tag("abs"), |_| BuiltinFn::Abs),
+        map(tag("ceil_div"), |_| BuiltinFn::CeilDiv),
+    ))(input)
+}
+
+fn call<'a>(input: &'a str) -> IResult<&'a str, LinearExpression> {
+    profile_fn!(call);
+
+    with_context(ErrorContext::Call, |start: &'a str| {
+        let (input, func) = terminated(builtin_fn, tag("("))(start)?;
+        fail_fast(move |input: &'a str| {
+            let (mut input, _) = opt(whitespace)(input)?;
+            let mut args = Vec::new();
+            loop {
+                let (i, arg) = linear_expression(input)?;
+                args.push(arg);
+                let (i, comma) = opt(surrounded_by(opt(whitespace), tag(",")))(i)?;
+                input = i;
+                if comma.is_none() {
+                    break;
+                }
+            }
+            let (input, _) = tuple((opt(whitespace), tag(")")))(input)?;
+
+            ensure!(
+                func.accepts(args.len()),
+                ErrAtom::new(start, ValidationError::WrongArgumentCount(func))
+            );
+
+            Ok((input, LinearExpression::Call { func, args }))
+        })(input)
+    })(input)
+}
+
 fn binary_operator<'a, O>(
     input: &'a str,
     tag_: &'static str,
@@ -491,18 +1028,33 @@ fn binary_operator<'a, O>(
     map(tag(tag_), |_| op.into())(input)
 }
 
-fn match_(input: &str) -> IResult<&str, Match> {
+/// A match, and the condition implied by its range patterns, if any.
+fn match_(input: &str) -> IResult<&str, (Match, Option<Condition>)> {
     profile_fn!(match_);
 
     with_context(
         ErrorContext::Match,
         alt((
-            map(tag("default"), |_| Match::Default),
-            map(graphql_query, |graphql| Match::GraphQL(graphql)),
+            map(tag("default"), |_| (Match::Default, None)),
+            map(graphql_query, |(field, definitions, condition)| {
+                (Match::GraphQL(field, definitions), condition)
+            }),
         )),
     )(input)
 }
 
+/// Both conditions, in order, where either may be missing.
+pub(crate) fn and_conditions(lhs: Option<Condition>, rhs: Option<Condition>) -> Option<Condition> {
+    match (lhs, rhs) {
+        (Some(lhs), Some(rhs)) => Some(Condition::Boolean(Box::new(BinaryExpression::new(
+            lhs,
+            AnyBooleanOp::And,
+            rhs,
+        )))),
+        (lhs, rhs) => lhs.or(rhs),
+    }
+}
+
 fn predicate(input: &str) -> IResult<&str, Predicate> {
     profile_fn!(predicate);
 
@@ -510,7 +1062,7 @@ fn predicate(input: &str) -> IResult<&str, Predicate> {
         // Whitespace is optional here because graphql_query is greedy and takes it.
         // Shouldn't be a problem though for ambiguity since `default=> 1` or `query { a }=> 1`
         // both seem unambiguous and readable.
-        let (input, match_) = terminated(match_, opt(whitespace))(input)?;
+        let (input, (match_, implicit)) = terminated(match_, opt(whitespace))(input)?;
 
         // TODO: The use of opt here makes error messages less informative.
         // The when_clause call has all the information we need to say something like
@@ -551,14 +1103,59 @@ fn predicate(input: &str) -> IResult<&str, Predicate> {
         // extending items that were just parsed.
         let (input, when_clause) = opt(terminated(when_clause, whitespace))(input)?;
 
+        // The condition of range patterns is tested before the when clause.
+        let condition = and_conditions(implicit, when_clause.map(|w| w.condition));
         let predicate = Predicate {
             match_,
-            when_clause,
+            when_clause: condition.map(|condition| WhenClause { condition }),
         };
         Ok((input, predicate))
     })(input)
 }
 
+/// A label for a statement, eg: `@name("pagination")`
+fn statement_name(input: &str) -> IResult<&str, String> {
+    profile_fn!(statement_name);
+
+    preceded(
+        tag("@name("),
+        fail_fast(delimited(
+            opt(whitespace),
+            string_literal,
+            tuple((opt(whitespace), tag(")"))),
+        )),
+    )(input)
+}
+
+/// A comment, eg: `# text` to the end of the line or `/* text */`.
+/// Returns the text of the comment without its markers.
+fn comment(input: &str) -> IResult<&str, &str> {
+    profile_fn!(comment);
+
+    alt((
+        preceded(char('#'), take_while(|c| c != '\n')),
+        delimited(tag("/*"), take_until("*/"), tag("*/")),
+    ))(input)
+}
+
+/// Whitespace and comments, which may come between statements.
+/// Returns the text of each comment.
+fn comments(input: &str) -> IResult<&str, Vec<&str>> {
+    profile_fn!(comments);
+
+    let (input, items) = many0(alt((map(whitespace, |_| None), map(comment, Some))))(input)?;
+    Ok((input, items.into_iter().flatten().collect()))
+}
+
+/// Joins the comments before a statement, so that they can be printed with it.
+fn doc(comments: Vec<&str>) -> Option<String> {
+    if comments.is_empty() {
+        None
+    } else {
+        Some(comments.join("\n"))
+    }
+}
+
 fn statement(input: &str) -> IResult<&str, Statement> {
     profile_fn!(statement);
 
@@ -569,53 +1166,319 @@ fn statement(input: &str) -> IResult<&str, Statement> {
         // but that made it impossible to comment default matches.
         // Handling it here also allows us to do a query check in the
         // graphql parser which enables better error handling.
-        let (input, _) = many0(alt((whitespace, recognize(pair(char('#'), is_not("\n"))))))(input)?;
+        // The comments are kept as the doc of the statement, for printing.
+        let (input, mut leading) = comments(input)?;
+        // The name labels the statement, but does not affect matching or cost.
+        let (input, name) = opt(statement_name)(input)?;
+        let (input, after_name) = comments(input)?;
+        leading.extend(after_name);
         let (input, predicate) = predicate(input)?;
-        let (input, _) = tuple((tag("=>"), whitespace))(input)?;
-        let (input, cost_expr) = linear_expression(input)?;
+        let (input, additive) = terminated(
+            or_quietly(map(tag("=>"), |_| false), map(tag("+="), |_| true)),
+            whitespace,
+        )(input)?;
+        let (input, cost_expr) = conditional_expression(input)?;
         let (input, _) = tag(";")(input)?;
         let (input, _) = opt(whitespace)(input)?;
 
         let statement = Statement {
+            doc: doc(leading),
+            name,
             predicate,
             cost_expr,
+            additive,
+            scope: None,
+            source: None,
         };
         Ok((input, statement))
     })(input)
 }
 
-fn document<'a>(mut input: &'a str) -> Result<Document<'a>, ErrorAggregator<&'a str>> {
-    profile_fn!(document);
+/// A named expression which statements may refer to, eg: `let page_cost = $first * 2;`
+fn definition(input: &str) -> IResult<&str, (String, LinearExpression)> {
+    profile_fn!(definition);
+
+    with_context(ErrorContext::Definition, |input| {
+        let (input, _) = comments(input)?;
+        let (input, _) = tuple((tag("let"), whitespace))(input)?;
+        fail_fast(|input| {
+            let (input, name) = identifier(input)?;
+            let (input, _) = surrounded_by(opt(whitespace), tag("="))(input)?;
+            let (input, expr) = conditional_expression(input)?;
+            let (input, _) = tag(";")(input)?;
+            let (input, _) = opt(whitespace)(input)?;
+            Ok((input, (name.to_owned(), expr)))
+        })(input)
+    })(input)
+}
+
+/// The start of a block of statements and definitions in a named scope,
+/// eg: `scope tokens {`
+fn scope_start(input: &str) -> IResult<&str, &str> {
+    profile_fn!(scope_start);
+
+    let (input, _) = comments(input)?;
+    let (input, _) = tuple((tag("scope"), whitespace))(input)?;
+    fail_fast(|input| {
+        let (input, name) = identifier(input)?;
+        let (input, _) = surrounded_by(opt(whitespace), tag("{"))(input)?;
+        let (input, _) = opt(whitespace)(input)?;
+        Ok((input, name))
+    })(input)
+}
+
+/// The end of a scope, eg: `}`
+fn scope_end(input: &str) -> IResult<&str, ()> {
+    profile_fn!(scope_end);
+
+    let (input, _) = comments(input)?;
+    let (input, _) = tag("}")(input)?;
+    let (input, _) = opt(whitespace)(input)?;
+    Ok((input, ()))
+}
+
+enum Item<'a> {
+    Statement(Statement<'a>),
+    /// A definition, and the scope that it was declared in
+    Definition(Option<String>, String, LinearExpression),
+    ScopeStart(String),
+    ScopeEnd,
+}
+
+/// Scopes may not be nested, so only the end of the scope is expected
+/// within one, and only the start of one outside of them.
+fn item<'a>(in_scope: bool) -> impl Fn(&'a str) -> IResult<&'a str, Item<'a>> {
+    move |input| {
+        profile_fn!(item);
 
-    // This function breaks the pattern of using IResult because we assume
-    // that you need to parse to the end, which means you need to retain the
-    // final error. (Otherwise you need to try to parse a statement on the
-    // remaining error again to retrieve it.)
-    let mut statements = Vec::new();
-    while input.len() != 0 {
-        match statement(input) {
-            Ok((remaining, statement)) => {
-                statements.push(statement);
-                input = remaining
+        let scope = |input: &'a str| {
+            if in_scope {
+                map(scope_end, |_| Item::ScopeEnd)(input)
+            } else {
+                map(scope_start, |name| Item::ScopeStart(name.to_owned()))(input)
             }
-            Err(e) => match e {
-                NomErr::Error(e) => return Err(e),
-                NomErr::Failure(e) => return Err(e),
-                NomErr::Incomplete(_) => unreachable!("Incomplete input"),
-            },
+        };
+        or_quietly(
+            map(statement, Item::Statement),
+            or_quietly(
+                map(definition, |(name, expr)| {
+                    Item::Definition(None, name, expr)
+                }),
+                scope,
+            ),
+        )(input)
+    }
+}
+
+/// Parses the statements of a cost model one at a time, so that large models
+/// can be processed as they are parsed. The statements before an error are parsed
+/// successfully, and there are no more statements after an error unless the
+/// iterator was created with `recovering`. Definitions are parsed, but are not
+/// returned by the iterator. Statements within a `scope` block are returned with
+/// the name of their scope.
+pub struct StatementIter<'a> {
+    // The whole text of the cost model, which errors are reported against.
+    text: &'a str,
+    input: &'a str,
+    failed: bool,
+    recover: bool,
+    // The scope which the input is within, if any
+    scope: Option<String>,
+}
+
+impl<'a> StatementIter<'a> {
+    pub fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            input: text,
+            failed: false,
+            recover: false,
+            scope: None,
+        }
+    }
+
+    /// Like `new`, but after an error parsing resumes after the next `;`, so that
+    /// the errors of every statement may be reported.
+    pub fn recovering(text: &'a str) -> Self {
+        Self {
+            recover: true,
+            ..Self::new(text)
+        }
+    }
+
+    fn resynchronize(&mut self, error: &CompileError) {
+        profile_method!(resynchronize);
+
+        // Panic safety: Parse errors always have a span within the text
+        let end = error.span().unwrap().end;
+        match self.text[end..].find(';') {
+            Some(i) => self.input = self.text[end + i + 1..].trim_start(),
+            None => self.failed = true,
         }
     }
-    Ok(Document { statements })
 }
 
-pub fn parse_document(input: &str) -> Result<Document, AgoraParseError<&str>> {
+impl<'a> StatementIter<'a> {
+    fn next_item(&mut self) -> Option<Result<Item<'a>, CompileError>> {
+        profile_method!(next_item);
+
+        if self.failed || (self.input.len() == 0 && self.scope.is_none()) {
+            return None;
+        }
+        // Comments after the last statement are not attached to any statement.
+        // A scope which is still open at the end is expected to be closed.
+        let mut unclosed = false;
+        if let Ok((remaining, _)) = comments(self.input) {
+            if remaining.is_empty() {
+                if self.scope.is_none() {
+                    return None;
+                }
+                unclosed = true;
+            }
+        }
+        let result = if unclosed {
+            map(scope_end, |_| Item::ScopeEnd)(self.input)
+        } else {
+            item(self.scope.is_some())(self.input)
+        };
+        match result {
+            Ok((remaining, item)) => {
+                self.input = remaining;
+                let item = match item {
+                    Item::Statement(mut statement) => {
+                        statement.scope = self.scope.clone();
+                        Item::Statement(statement)
+                    }
+                    Item::Definition(_, name, expr) => {
+                        Item::Definition(self.scope.clone(), name, expr)
+                    }
+                    Item::ScopeStart(name) => {
+                        self.scope = Some(name.clone());
+                        Item::ScopeStart(name)
+                    }
+                    Item::ScopeEnd => {
+                        self.scope = None;
+                        Item::ScopeEnd
+                    }
+                };
+                Some(Ok(item))
+            }
+            Err(NomErr::Error(e)) | Err(NomErr::Failure(e)) => {
+                // Mapping from ErrorAggregator to AgoraParseError,
+                // which requires the 'original' input.
+                let e = AgoraParseError::new(self.text, e);
+                let e = CompileError::DocumentParseError(format!("{}", e), e.span());
+                if self.recover {
+                    self.resynchronize(&e);
+                } else {
+                    self.failed = true;
+                }
+                Some(Err(e))
+            }
+            Err(NomErr::Incomplete(_)) => unreachable!("Incomplete input"),
+        }
+    }
+}
+
+impl<'a> Iterator for StatementIter<'a> {
+    type Item = Result<Statement<'a>, CompileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        profile_method!(next);
+
+        loop {
+            match self.next_item()? {
+                Ok(Item::Statement(statement)) => return Some(Ok(statement)),
+                Ok(Item::Definition(..)) | Ok(Item::ScopeStart(_)) | Ok(Item::ScopeEnd) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+pub fn parse_document(input: &str) -> Result<Document, CompileError> {
     profile_fn!(parse_document);
 
-    // Mapping from ErrorAggregator to AgoraParseError,
-    // which requires the 'original' input.
-    match document(input) {
-        Ok(doc) => Ok(doc),
-        Err(e) => Err(AgoraParseError::new(input, e)),
+    parse_document_with_max_nesting(input, DEFAULT_MAX_NESTING)
+}
+
+/// Like `parse_document`, but with a limit on nesting other than `DEFAULT_MAX_NESTING`.
+/// Text which is nested more deeply fails to parse.
+pub fn parse_document_with_max_nesting(
+    input: &str,
+    max_nesting: usize,
+) -> Result<Document, CompileError> {
+    profile_fn!(parse_document_with_max_nesting);
+
+    // Restores the previous limit when dropped, even on panic.
+    struct Limit(usize);
+    impl Drop for Limit {
+        fn drop(&mut self) {
+            let previous = self.0;
+            NESTING.with(|nesting| nesting.set((nesting.get().0, previous)));
+        }
+    }
+    let _limit = NESTING.with(|nesting| {
+        let (depth, previous) = nesting.get();
+        nesting.set((depth, max_nesting));
+        Limit(previous)
+    });
+
+    let mut document = Document {
+        statements: Vec::new(),
+        definitions: Vec::new(),
+        scoped_definitions: Vec::new(),
+    };
+    let mut items = StatementIter::new(input);
+    while let Some(item) = items.next_item() {
+        match item? {
+            Item::Statement(statement) => document.statements.push(statement),
+            Item::Definition(None, name, expr) => document.definitions.push((name, expr)),
+            Item::Definition(Some(scope), name, expr) => {
+                document.scoped_definitions.push((scope, name, expr))
+            }
+            Item::ScopeStart(_) | Item::ScopeEnd => {}
+        }
+    }
+    if let Some(name) = cyclic_definition(&document) {
+        return Err(CompileError::CyclicDefinition(name.to_owned()));
+    }
+    Ok(document)
+}
+
+/// Parses a GraphQL query which matches a single field, eg: `query { a }`. The
+/// predicate only has a when clause if the query has range patterns.
+pub fn parse_graphql_predicate<'a>(
+    input: &'a str,
+) -> Result<Predicate<'a>, AgoraParseError<&'a str>> {
+    profile_fn!(parse_graphql_predicate);
+
+    match graphql_query(input) {
+        Ok((rest, (field, definitions, condition))) if rest.trim().is_empty() => Ok(Predicate {
+            match_: Match::GraphQL(field, definitions),
+            when_clause: condition.map(|condition| WhenClause { condition }),
+        }),
+        Ok((rest, _)) => Err(AgoraParseError::new(
+            input,
+            ErrAtom::new(rest, ExpectationError::TODO).into(),
+        )),
+        Err(NomErr::Error(e)) | Err(NomErr::Failure(e)) => Err(AgoraParseError::new(input, e)),
+        Err(NomErr::Incomplete(_)) => unreachable!("Incomplete input"),
+    }
+}
+
+/// Parses the predicate of a statement, eg: `query { a(first: $first) } when $first > 10`
+pub fn parse_predicate<'a>(input: &'a str) -> Result<Predicate<'a>, AgoraParseError<&'a str>> {
+    profile_fn!(parse_predicate);
+
+    match predicate(input) {
+        Ok((rest, predicate)) if rest.trim().is_empty() => Ok(predicate),
+        Ok((rest, _)) => Err(AgoraParseError::new(
+            input,
+            ErrAtom::new(rest, ExpectationError::TODO).into(),
+        )),
+        Err(NomErr::Error(e)) | Err(NomErr::Failure(e)) => Err(AgoraParseError::new(input, e)),
+        Err(NomErr::Incomplete(_)) => unreachable!("Incomplete input"),
     }
 }
 
@@ -673,6 +1536,37 @@ mod tests {
         assert!(when_clause("when .").is_err());
     }
 
+    #[test]
+    fn compare_variables() {
+        assert_clause("when $a > $b", true, (("a", 3), ("b", 2)));
+        assert_clause("when $a > $b", false, (("a", 2), ("b", 2)));
+        assert_clause("when $a == $b", false, (("a", 1), ("b", 2)));
+        assert_clause("when $a <= $b", true, (("a", 2), ("b", 2)));
+        assert_clause("when $a <= $b", false, (("a", 3), ("b", 2)));
+
+        // Either side may be the one which is missing
+        let (_, clause) = when_clause("when $a < $b").unwrap();
+        let cases: Vec<(Captures, &str)> = vec![(("a", 1).into(), "b"), (("b", 1).into(), "a")];
+        for (captures, missing) in cases {
+            let stack = LinearStack::new(&captures);
+            let mut stack = CondStack::new(stack);
+            assert_eq!(
+                stack.execute(&clause.condition),
+                Err(EvalError::MissingVariable(missing.to_owned()))
+            );
+        }
+    }
+
+    #[test]
+    fn compare_expressions() {
+        let v = || (("a", 3), ("b", 4), ("c", 11));
+        assert_clause("when $a * $b >= $c + 1", true, v());
+        assert_clause("when $a * $b > $c + 1", false, v());
+        assert_clause("when $c - $a * $b == -1", true, v());
+        assert_clause("when ($a + $b) * 2 < $c", false, v());
+        assert_clause("when $a ^ 2 + $b ^ 2 == 25 && $c % $a == 2", true, v());
+    }
+
     #[test]
     fn boolean_precedence() {
         assert_clause("when true || 1 == 0 && false", true, ());
@@ -685,6 +1579,21 @@ mod tests {
         assert_clause("when false && (false || true)", false, ());
     }
 
+    #[test]
+    fn negation() {
+        assert_clause("when !($x > 5)", true, ("x", 2));
+        assert_clause("when !$x > 5", false, ("x", 6));
+        assert_clause("when !!$a", true, ("a", true));
+        assert_clause("when ! !(1 == 1)", true, ());
+        assert_clause("when !(true && $a)", true, ("a", false));
+
+        // Negation binds tighter than && and ||
+        assert_clause("when !$a && $b", false, (("a", false), ("b", false)));
+        assert_clause("when !($a && $b)", true, (("a", false), ("b", false)));
+        assert_clause("when !true || true", true, ());
+        assert_clause("when (!true) || true", true, ());
+    }
+
     #[test]
     fn when_parens() {
         assert_clause("when ($a != $a)", false, ("a", 1));
@@ -721,8 +1630,8 @@ mod tests {
         query { users(name: \"Bob\") { tokens } } => 999999;
         ";
 
-        //println!("{}", document(file).unwrap_err());
-        assert!(document(file).is_ok())
+        //println!("{}", parse_document(file).unwrap_err());
+        assert!(parse_document(file).is_ok())
     }
 
     #[test]
@@ -778,6 +1687,52 @@ mod tests {
         assert_eq!(result, Ok(true));
     }
 
+    #[test]
+    fn nesting_too_deep_does_not_stack_overflow() {
+        const DEPTH: usize = 5000;
+        let texts = [
+            format!("default => {}$a{};", "-(".repeat(DEPTH), ")".repeat(DEPTH)),
+            format!("default => {}$a{};", "-".repeat(DEPTH), ""),
+            format!(
+                "default => {}$a{};",
+                "max(".repeat(DEPTH),
+                ", 1)".repeat(DEPTH)
+            ),
+            format!(
+                "default => {}1{};",
+                "($a ? ".repeat(DEPTH),
+                " : 2)".repeat(DEPTH)
+            ),
+            format!(
+                "default when {}$a{} => 1;",
+                "!(".repeat(DEPTH),
+                ")".repeat(DEPTH)
+            ),
+        ];
+        for text in texts.iter() {
+            match parse_document(text) {
+                Err(CompileError::DocumentParseError(message, _)) => {
+                    assert!(message.contains("nested more than 128 deep"), "{}", message)
+                }
+                other => panic!("Expected a parse error, got {:?}", other),
+            }
+        }
+
+        // Parentheses alone do not count towards the limit
+        let text = format!(
+            "default => {}$a{};",
+            "(1 + ".repeat(DEPTH),
+            ")".repeat(DEPTH)
+        );
+        assert!(parse_document(&text).is_ok());
+
+        let text = format!("default => {}$a{};", "-(".repeat(10), ")".repeat(10));
+        assert!(parse_document(&text).is_ok());
+        assert!(parse_document_with_max_nesting(&text, 10).is_err());
+        // The limit is restored afterwards
+        assert!(parse_document(&text).is_ok());
+    }
+
     #[test]
     fn paren_condition() {
         let text = "when (1 != 1)";
@@ -804,6 +1759,342 @@ mod tests {
         assert_clause(text, false, ());
     }
 
+    #[test]
+    fn divide_by_captured_zero() {
+        let (rest, expr) = linear_expression("10 / $zero").unwrap();
+        assert!(rest.len() == 0);
+        let captures = ("zero", 0).into();
+        let mut stack = LinearStack::new(&captures);
+        assert_eq!(stack.execute(&expr), Err(EvalError::DivideByZero));
+    }
+
+    #[test]
+    fn modulo() {
+        assert_expr("$x % 3", 1, ("x", 7));
+        assert_expr("$x % 3", -1, ("x", -7));
+        assert_expr("$x % -3", 1, ("x", 7));
+        assert_expr("7.5 % 2", BigFraction::new(3u32, 2u32), ());
+        assert_expr("1 + 10 % 4", 3, ());
+    }
+
+    #[test]
+    fn modulo_by_zero() {
+        let (_, expr) = linear_expression("$x % 0").unwrap();
+        let captures = ("x", 7).into();
+        let mut stack = LinearStack::new(&captures);
+        assert_eq!(stack.execute(&expr), Err(EvalError::DivideByZero));
+    }
+
+    #[test]
+    fn exponent() {
+        assert_expr("2 ^ $depth", 1024, ("depth", 10));
+        assert_expr("2 * 3 ^ 2", 18, ());
+        assert_expr("2 ^ 3 ^ 2", 512, ());
+        assert_expr("(2 ^ 3) ^ 2", 64, ());
+        assert_expr("0.5 ^ 2", BigFraction::new(1u32, 4u32), ());
+        assert_expr("-2 ^ 3", -8, ());
+    }
+
+    #[test]
+    fn exponent_out_of_range() {
+        let (_, expr) = linear_expression("2 ^ $e").unwrap();
+        for e in &[-1, MAX_EXPONENT as i32 + 1] {
+            let captures = ("e", *e).into();
+            let mut stack = LinearStack::new(&captures);
+            assert_eq!(stack.execute(&expr), Err(EvalError::Overflow));
+        }
+    }
+
+    #[test]
+    fn power_too_large() {
+        let (_, expr) = linear_expression("(($x ^ 256) ^ 256) ^ 256").unwrap();
+        let captures = ("x", 2).into();
+        let mut stack = LinearStack::new(&captures);
+        assert_eq!(stack.execute(&expr), Err(EvalError::Overflow));
+    }
+
+    #[test]
+    fn bitwise() {
+        assert_expr("$flags & 4", 4, ("flags", 5));
+        assert_expr("$flags & 4", 0, ("flags", 3));
+        assert_expr("$flags | 8", 13, ("flags", 5));
+        assert_expr("1 << $n", 1024, ("n", 10));
+        assert_expr("$x >> 2", 250, ("x", 1000));
+        assert_expr("1 + 1 << 2", 8, ());
+        assert_expr("6 & 3 | 8", 10, ());
+        assert_expr("$a & 1 << 2", 4, ("a", 5));
+        // Two's complement
+        assert_expr("-6 & 3", 2, ());
+        assert_expr("-1 | 4", -1, ());
+    }
+
+    #[test]
+    fn bitwise_errors() {
+        let (_, expr) = linear_expression("1 << $n").unwrap();
+        for (n, err) in &[
+            (-1, EvalError::InvalidArgument),
+            (MAX_SHIFT as i32 + 1, EvalError::Overflow),
+        ] {
+            let captures = ("n", *n).into();
+            let mut stack = LinearStack::new(&captures);
+            assert_eq!(stack.execute(&expr), Err(err.clone()));
+        }
+
+        let (_, expr) = linear_expression("$x / 2 & 1").unwrap();
+        let captures = ("x", 1).into();
+        let mut stack = LinearStack::new(&captures);
+        assert_eq!(stack.execute(&expr), Err(EvalError::NotAnInteger));
+    }
+
+    #[test]
+    fn conditional() {
+        let eval = |s: &str, v: Captures| {
+            let (rest, expr) = conditional_expression(s).unwrap();
+            assert!(rest.len() == 0);
+            LinearStack::new(&v).execute(&expr)
+        };
+        let s = "$first > 100 ? $first * 2 : $first";
+        assert_eq!(eval(s, ("first", 200).into()), Ok(400.into()));
+        assert_eq!(eval(s, ("first", 50).into()), Ok(50.into()));
+        let s = "($first > 100 ? $first * 2 : $first)";
+        assert_eq!(eval(s, ("first", 200).into()), Ok(400.into()));
+
+        // Right-associative
+        let s = "$a > 1 ? $a > 2 ? 3 : 2 : 1";
+        for a in 1..=3 {
+            assert_eq!(eval(s, ("a", a).into()), Ok(a.into()));
+        }
+
+        // Only the selected branch is evaluated
+        let s = "$d == 0 ? 0 : 1 / $d";
+        assert_eq!(eval(s, ("d", 0).into()), Ok(0.into()));
+        assert_eq!(eval(s, ("d", 4).into()), Ok(BigFraction::new(1u32, 4u32)));
+        assert_eq!(eval("true ? 1 : 1 / 0", ().into()), Ok(1.into()));
+        assert_eq!(
+            eval("$a ? 1 : 2", ().into()),
+            Err(EvalError::MissingVariable("a".to_owned()))
+        );
+
+        assert!(conditional_expression("$a > 1 ? 2").is_err());
+        assert!(conditional_expression("$a > 1 ? 2 : ").is_err());
+    }
+
+    #[test]
+    fn min_max() {
+        assert_expr("max($first, 1)", 1, ("first", 0));
+        assert_expr("max($first, 1)", 20, ("first", 20));
+        assert_expr("min($first, 500)", 500, ("first", 1000));
+        assert_expr(
+            "min( 10, $a * 2 , 3 + $b ) + max(1, 2, 3)",
+            8,
+            (("a", 4), ("b", 2)),
+        );
+        assert_expr("(max(1, min(2, 3)) * 2)", 4, ());
+    }
+
+    #[test]
+    fn logarithms() {
+        assert_expr("log2(1024)", 10, ());
+        assert_expr("log2(1)", 0, ());
+        assert_expr("log2(1000)", 9, ());
+        assert_expr("log2(0.25)", -2, ());
+        assert_expr("log2(0.3)", -2, ());
+        assert_expr("log10(1000)", 3, ());
+        assert_expr("log10(999)", 2, ());
+        assert_expr("log2($first) + 1", 7, ("first", 100));
+
+        let (_, expr) = linear_expression("log2($a)").unwrap();
+        let zero = ("a", 0).into();
+        let negative = ("a", -8).into();
+        assert_eq!(
+            Err(EvalError::InvalidArgument),
+            LinearStack::new(&zero).execute(&expr)
+        );
+        assert_eq!(
+            Err(EvalError::InvalidArgument),
+            LinearStack::new(&negative).execute(&expr)
+        );
+        assert!(linear_expression("log2(1, 2)").is_err());
+    }
+
+    #[test]
+    fn absolute_value() {
+        assert_expr("abs(5)", 5, ());
+        assert_expr("abs(-5)", 5, ());
+        assert_expr("abs($a - $b)", 3, (("a", 2), ("b", 5)));
+        assert_expr("abs(1 / -2)", BigFraction::new(1u8, 2u8), ());
+        assert!(linear_expression("abs(1, 2)").is_err());
+    }
+
+    #[test]
+    fn ceil_div() {
+        assert_expr("ceil_div(100, 25)", 4, ());
+        assert_expr("ceil_div(101, 25)", 5, ());
+        assert_expr("ceil_div($n, $page)", 1, (("n", 1), ("page", 100)));
+        assert_expr("ceil_div(0, 3)", 0, ());
+        assert_expr("ceil_div(-7, 2)", -3, ());
+        assert_expr("ceil_div(1 / 2, 1)", 1, ());
+
+        let (_, expr) = linear_expression("ceil_div($n, $page)").unwrap();
+        let zero = (("n", 10), ("page", 0)).into();
+        assert_eq!(
+            Err(EvalError::DivideByZero),
+            LinearStack::new(&zero).execute(&expr)
+        );
+        assert!(linear_expression("ceil_div(1)").is_err());
+        assert!(linear_expression("ceil_div(1, 2, 3)").is_err());
+    }
+
+    #[test]
+    fn len() {
+        assert_expr("len($s)", 5, ("s", "hello".to_owned()));
+        assert_expr("len( $s ) * 2", 4, ("s", "né".to_owned()));
+        assert_expr("len(\"abc\")", 3, ());
+        assert_expr("-len($s)", -1, ("s", "a".to_owned()));
+
+        let (_, expr) = linear_expression("len($s)").unwrap();
+        let number = ("s", 1).into();
+        assert!(LinearStack::new(&number).execute(&expr).is_err());
+        assert!(linear_expression("len(1)").is_err());
+        assert!(linear_expression("len($a, $b)").is_err());
+    }
+
+    #[test]
+    fn fold() {
+        let list = |items: &[i32]| -> q::Value<'static, String> {
+            q::Value::List(items.iter().map(|&i| q::Value::Int(i.into())).collect())
+        };
+        assert_expr("sum($l)", 6, ("l", list(&[1, 2, 3])));
+        assert_expr("count( $l ) * 2", 6, ("l", list(&[1, 2, 3])));
+        assert_expr("sum($l)", 0, ("l", list(&[])));
+        assert_expr("-sum($l) + count($l)", -1, ("l", list(&[1, 1])));
+        assert_expr("count($l)", 0, ("l", q::Value::Null));
+
+        let (_, expr) = linear_expression("sum($l)").unwrap();
+        let number = ("l", 1).into();
+        assert!(LinearStack::new(&number).execute(&expr).is_err());
+        assert!(linear_expression("sum(1)").is_err());
+        assert!(linear_expression("count($a, $b)").is_err());
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_expr("-$x", -3, ("x", 3));
+        assert_expr("-$x", 3, ("x", -3));
+        assert_expr("--$x", 3, ("x", 3));
+        assert_expr("-(1 + 2)", -3, ());
+        assert_expr("-abs($x)", -3, ("x", -3));
+        assert_expr("1 - -$x", 4, ("x", 3));
+
+        // Negation binds tighter than any binary operator
+        assert_expr("-$x + 1", -2, ("x", 3));
+        assert_expr("-$x * 2", -6, ("x", 3));
+        assert_expr("-$x ^ 2", 9, ("x", 3));
+        assert_expr("-($x ^ 2)", -9, ("x", 3));
+    }
+
+    #[test]
+    fn min_max_require_two_args() {
+        assert!(linear_expression("min(1)").is_err());
+        assert!(linear_expression("max($a)").is_err());
+        assert!(linear_expression("max()").is_err());
+    }
+
+    #[test]
+    fn unbound_variable() {
+        let (rest, expr) = linear_expression("$a + $missing").unwrap();
+        assert!(rest.len() == 0);
+        let captures = ("a", 1).into();
+        let mut stack = LinearStack::new(&captures);
+        assert_eq!(
+            stack.execute(&expr),
+            Err(EvalError::MissingVariable("missing".to_owned()))
+        );
+    }
+
+    #[test]
+    fn string_comparisons() {
+        assert_clause(
+            "when $token == \"USDC\"",
+            true,
+            ("token", "USDC".to_owned()),
+        );
+        assert_clause(
+            "when $token != \"USDC\"",
+            false,
+            ("token", "USDC".to_owned()),
+        );
+        assert_clause(
+            "when \"DAI\" == $token",
+            false,
+            ("token", "USDC".to_owned()),
+        );
+        assert_clause(
+            "when $s == \"say \\\"hi\\\"\"",
+            true,
+            ("s", "say \"hi\"".to_owned()),
+        );
+        assert!(when_clause("when $token == \"USDC").is_err());
+    }
+
+    #[test]
+    fn string_ordering() {
+        let id = |id: &str| ("id", id.to_owned());
+        assert_clause("when $id < \"0x8\"", true, id("0x7f"));
+        assert_clause("when $id < \"0x8\"", false, id("0x80"));
+        assert_clause("when $id >= \"b\"", true, id("b"));
+        assert_clause("when $id > \"b\"", false, id("b"));
+        assert_clause("when \"b\" <= $id", true, id("ba"));
+        // Lexicographic, not numeric
+        assert_clause("when $id > \"9\"", false, id("10"));
+        assert_clause("when \"a\" < \"b\"", true, ());
+        // Captures which are not strings fail to evaluate
+        let v = ("id", 1).into();
+        let (_, clause) = when_clause("when $id > \"0\"").unwrap();
+        let mut stack = CondStack::new(LinearStack::new(&v));
+        assert_eq!(
+            stack.execute(&clause.condition),
+            Err(EvalError::TypeMismatch {
+                name: "id".to_owned()
+            })
+        );
+        // A string can't be compared to a number
+        assert!(when_clause("when \"a\" < 1").is_err());
+        assert!(when_clause("when 1 >= \"a\"").is_err());
+    }
+
+    #[test]
+    fn membership_conditions() {
+        let s = "when $network in [\"mainnet\", \"arbitrum\"]";
+        assert_clause(s, true, ("network", "mainnet".to_owned()));
+        assert_clause(s, true, ("network", "arbitrum".to_owned()));
+        assert_clause(s, false, ("network", "rinkeby".to_owned()));
+
+        let s = "when $first in [10, 100, 1000]";
+        assert_clause(s, true, ("first", 100));
+        assert_clause(s, false, ("first", 50));
+
+        assert_clause("when $first in []", false, ("first", 1));
+        assert_clause("when $first in []", false, ());
+    }
+
+    #[test]
+    fn membership_requires_one_type() {
+        assert!(when_clause("when $a in [1, \"b\"]").is_err());
+        assert!(when_clause("when $a in [1, 2").is_err());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex_conditions() {
+        let s = "when $id =~ \"^0x[0-9a-f]+$\"";
+        assert_clause(s, true, ("id", "0xbeef".to_owned()));
+        assert_clause(s, false, ("id", "beef".to_owned()));
+        assert_clause(s, false, ("id", 1));
+        assert_clause(s, false, ());
+        assert!(when_clause("when $id =~ \"[\"").is_err());
+    }
+
     #[test]
     fn using_fract() {
         let text = "when 4 * 1.25 == $five";
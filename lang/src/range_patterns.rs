@@ -0,0 +1,340 @@
+//! Range patterns in the arguments of a match, eg: `query { users(first: > 100) }`.
+//!
+//! A range pattern is not GraphQL, so the match can't be given to the GraphQL parser
+//! as written. Instead, each pattern is replaced by a variable of the same length in
+//! a copy of the match, which is parsed in its place. The parsed query is then moved
+//! back onto the text of the cost model, with those variables renamed to captures
+//! that the statement tests with an implicit when clause.
+
+use crate::expressions::*;
+use crate::language::*;
+use crate::parser::real;
+use crate::prelude::*;
+use fraction::BigFraction;
+use graphql_parser::query as q;
+
+/// The most range patterns that a single match may have.
+pub const MAX_RANGE_PATTERNS: usize = 16;
+
+/// The names of the captures of range patterns, in the order that they are written.
+/// These have the prefix of built-in captures so that they do not hide captures
+/// which are written in the match.
+const RANGE_CAPTURES: [&str; MAX_RANGE_PATTERNS] = [
+    "__range0",
+    "__range1",
+    "__range2",
+    "__range3",
+    "__range4",
+    "__range5",
+    "__range6",
+    "__range7",
+    "__range8",
+    "__range9",
+    "__range10",
+    "__range11",
+    "__range12",
+    "__range13",
+    "__range14",
+    "__range15",
+];
+
+struct RangePattern {
+    /// Where the pattern starts and ends in the text
+    start: usize,
+    end: usize,
+    op: AnyComparison,
+    value: BigFraction,
+}
+
+/// A copy of the GraphQL of a match in which range patterns have been replaced
+/// with variables.
+pub struct Masked {
+    text: String,
+    patterns: Vec<RangePattern>,
+}
+
+/// Finds the range patterns in the GraphQL at the start of the input, which ends with
+/// the selection set of the query. Returns None if there are no range patterns, so
+/// that the input may be parsed as it is.
+pub fn mask(input: &str) -> Option<Masked> {
+    profile_fn!(mask);
+
+    let bytes = input.as_bytes();
+    let mut patterns = Vec::new();
+    let mut depth = 0;
+    let mut end = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' if input[i..].starts_with("\"\"\"") => {
+                i = input[i + 3..].find("\"\"\"").map(|j| i + j + 6)?;
+                continue;
+            }
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+            }
+            b'#' => {
+                i = input[i..].find('\n').map(|j| i + j)?;
+            }
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    end = Some(i + 1);
+                    break;
+                }
+            }
+            // Only arguments of the selection set, not the types of variables.
+            b':' if depth > 0 => {
+                if let Some(pattern) = range_pattern(input, i + 1) {
+                    i = pattern.end;
+                    patterns.push(pattern);
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    let end = end?;
+    if patterns.is_empty() {
+        return None;
+    }
+
+    // Each pattern is at least 2 bytes long, and is replaced by eg: `$__`, which is a
+    // variable whose name is the same number of underscores.
+    let mut text = String::with_capacity(end);
+    let mut copied = 0;
+    for pattern in patterns.iter() {
+        text.push_str(&input[copied..pattern.start]);
+        text.push('$');
+        text.extend(std::iter::repeat('_').take(pattern.end - pattern.start - 1));
+        copied = pattern.end;
+    }
+    text.push_str(&input[copied..end]);
+
+    Some(Masked { text, patterns })
+}
+
+/// Parses a range pattern following the `:` of an argument, eg: ` >= 100`
+fn range_pattern(input: &str, after_colon: usize) -> Option<RangePattern> {
+    let rest = &input[after_colon..];
+    let start = after_colon + (rest.len() - rest.trim_start().len());
+    let rest = &input[start..];
+
+    let (op, len) = if rest.starts_with(">=") {
+        (AnyComparison::Ge, 2)
+    } else if rest.starts_with("<=") {
+        (AnyComparison::Le, 2)
+    } else if rest.starts_with('>') {
+        (AnyComparison::Gt, 1)
+    } else if rest.starts_with('<') {
+        (AnyComparison::Lt, 1)
+    } else {
+        return None;
+    };
+
+    let rest = rest[len..].trim_start();
+    let (after, value) = real(rest).ok()?;
+    // Eg: `> 1a` is not a number
+    if matches!(after.chars().next(), Some(c) if c.is_alphanumeric() || c == '_' || c == '.') {
+        return None;
+    }
+    Some(RangePattern {
+        start,
+        end: input.len() - after.len(),
+        op,
+        value,
+    })
+}
+
+impl Masked {
+    /// The copy of the GraphQL to parse, which ends with the selection set of the query
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn pattern_count(&self) -> usize {
+        self.patterns.len()
+    }
+
+    /// The condition which the captures of the patterns must meet, eg: `$__range0 > 100`
+    pub fn condition(&self) -> Condition {
+        profile_method!(condition);
+
+        let mut conditions =
+            self.patterns
+                .iter()
+                .zip(RANGE_CAPTURES.iter())
+                .map(|(pattern, name)| {
+                    Condition::Comparison(BinaryExpression::new(
+                        LinearExpression::Variable(Variable::new(*name)),
+                        pattern.op,
+                        LinearExpression::Const(Const::new(pattern.value.clone())),
+                    ))
+                });
+        // Panic safety: There is at least one pattern
+        let first = conditions.next().unwrap();
+        conditions.fold(first, |lhs, rhs| {
+            Condition::Boolean(Box::new(BinaryExpression::new(lhs, AnyBooleanOp::And, rhs)))
+        })
+    }
+
+    /// Moves a query parsed from the text of the copy onto the original input, which
+    /// has the same text outside of the patterns.
+    pub fn rebase<'c, 'a>(
+        &'c self,
+        query: q::Query<'c, &'c str>,
+        original: &'a str,
+    ) -> q::Query<'a, &'a str> {
+        profile_method!(rebase);
+
+        let rebase = Rebase {
+            masked: self,
+            original,
+        };
+        q::Query {
+            position: query.position,
+            name: query.name.map(|name| rebase.text(name)),
+            variable_definitions: query
+                .variable_definitions
+                .into_iter()
+                .map(|definition| q::VariableDefinition {
+                    position: definition.position,
+                    name: rebase.text(definition.name),
+                    var_type: rebase.var_type(definition.var_type),
+                    default_value: definition.default_value.map(|value| rebase.value(value)),
+                })
+                .collect(),
+            directives: rebase.directives(query.directives),
+            selection_set: rebase.selection_set(query.selection_set),
+        }
+    }
+}
+
+struct Rebase<'c, 'a> {
+    masked: &'c Masked,
+    original: &'a str,
+}
+
+// Security: Relying on GraphQL parsing to not have stack overflow here.
+// See also 01205a6c-4e1a-4b35-8dc6-d400c499d423
+impl<'c, 'a> Rebase<'c, 'a> {
+    fn offset(&self, text: &'c str) -> usize {
+        text.as_ptr() as usize - self.masked.text.as_ptr() as usize
+    }
+
+    fn text(&self, text: &'c str) -> &'a str {
+        // Panic safety: The GraphQL parser borrows all text from its input,
+        // which is no longer than the original.
+        let offset = self.offset(text);
+        &self.original[offset..offset + text.len()]
+    }
+
+    fn value(&self, value: q::Value<'c, &'c str>) -> q::Value<'a, &'a str> {
+        match value {
+            q::Value::Variable(name) => {
+                // The name follows the `$` which replaced the start of the pattern
+                let start = self.offset(name) - 1;
+                match self
+                    .masked
+                    .patterns
+                    .iter()
+                    .position(|pattern| pattern.start == start)
+                {
+                    Some(index) => q::Value::Variable(RANGE_CAPTURES[index]),
+                    None => q::Value::Variable(self.text(name)),
+                }
+            }
+            q::Value::Int(i) => q::Value::Int(i),
+            q::Value::Float(f) => q::Value::Float(f),
+            q::Value::String(s) => q::Value::String(s),
+            q::Value::Boolean(b) => q::Value::Boolean(b),
+            q::Value::Null => q::Value::Null,
+            q::Value::Enum(e) => q::Value::Enum(self.text(e)),
+            q::Value::List(l) => q::Value::List(l.into_iter().map(|v| self.value(v)).collect()),
+            q::Value::Object(o) => q::Value::Object(
+                o.into_iter()
+                    .map(|(k, v)| (self.text(k), self.value(v)))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn var_type(&self, var_type: q::Type<'c, &'c str>) -> q::Type<'a, &'a str> {
+        match var_type {
+            q::Type::NamedType(name) => q::Type::NamedType(self.text(name)),
+            q::Type::ListType(inner) => q::Type::ListType(Box::new(self.var_type(*inner))),
+            q::Type::NonNullType(inner) => q::Type::NonNullType(Box::new(self.var_type(*inner))),
+        }
+    }
+
+    fn arguments(
+        &self,
+        arguments: Vec<(&'c str, q::Value<'c, &'c str>)>,
+    ) -> Vec<(&'a str, q::Value<'a, &'a str>)> {
+        arguments
+            .into_iter()
+            .map(|(name, value)| (self.text(name), self.value(value)))
+            .collect()
+    }
+
+    fn directives(
+        &self,
+        directives: Vec<q::Directive<'c, &'c str>>,
+    ) -> Vec<q::Directive<'a, &'a str>> {
+        directives
+            .into_iter()
+            .map(|directive| q::Directive {
+                position: directive.position,
+                name: self.text(directive.name),
+                arguments: self.arguments(directive.arguments),
+            })
+            .collect()
+    }
+
+    fn selection_set(&self, set: q::SelectionSet<'c, &'c str>) -> q::SelectionSet<'a, &'a str> {
+        q::SelectionSet {
+            span: set.span,
+            items: set
+                .items
+                .into_iter()
+                .map(|selection| match selection {
+                    q::Selection::Field(field) => q::Selection::Field(q::Field {
+                        position: field.position,
+                        alias: field.alias.map(|alias| self.text(alias)),
+                        name: self.text(field.name),
+                        arguments: self.arguments(field.arguments),
+                        directives: self.directives(field.directives),
+                        selection_set: self.selection_set(field.selection_set),
+                    }),
+                    q::Selection::FragmentSpread(spread) => {
+                        q::Selection::FragmentSpread(q::FragmentSpread {
+                            position: spread.position,
+                            fragment_name: self.text(spread.fragment_name),
+                            directives: self.directives(spread.directives),
+                        })
+                    }
+                    q::Selection::InlineFragment(inline) => {
+                        q::Selection::InlineFragment(q::InlineFragment {
+                            position: inline.position,
+                            type_condition: inline.type_condition.map(
+                                |condition| match condition {
+                                    q::TypeCondition::On(name) => {
+                                        q::TypeCondition::On(self.text(name))
+                                    }
+                                },
+                            ),
+                            directives: self.directives(inline.directives),
+                            selection_set: self.selection_set(inline.selection_set),
+                        })
+                    }
+                })
+                .collect(),
+        }
+    }
+}
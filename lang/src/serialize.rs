@@ -0,0 +1,276 @@
+//! Serialization of compiled cost models, for tooling which would rather
+//! ship a precompiled model than parse the text of the model at startup.
+//!
+//! Numbers are serialized as strings (eg: "-1/3") so that no precision is lost,
+//! and the GraphQL query of each match is serialized as its text.
+
+use crate::expressions::{Const, Variable};
+use crate::language::*;
+use crate::prelude::*;
+use crate::{parser, CostModel};
+use fraction::{BigFraction, GenericFraction, Sign};
+use num_bigint::BigUint;
+use num_traits::Zero as _;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+fn real_to_string(value: &BigFraction) -> String {
+    match value {
+        GenericFraction::Rational(sign, ratio) => {
+            let sign = if matches!(sign, Sign::Minus) { "-" } else { "" };
+            format!("{}{}/{}", sign, ratio.numer(), ratio.denom())
+        }
+        GenericFraction::Infinity(Sign::Plus) => "inf".to_owned(),
+        GenericFraction::Infinity(Sign::Minus) => "-inf".to_owned(),
+        GenericFraction::NaN => "NaN".to_owned(),
+    }
+}
+
+fn real_from_str<E: de::Error>(text: &str) -> Result<BigFraction, E> {
+    match text {
+        "inf" => return Ok(GenericFraction::Infinity(Sign::Plus)),
+        "-inf" => return Ok(GenericFraction::Infinity(Sign::Minus)),
+        "NaN" => return Ok(GenericFraction::NaN),
+        _ => {}
+    }
+
+    let invalid = || E::custom(format!("Invalid number: {}", text));
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, text),
+    };
+    let mut parts = unsigned.splitn(2, '/');
+    let numer: BigUint = parts
+        .next()
+        .and_then(|numer| numer.parse().ok())
+        .ok_or_else(invalid)?;
+    let denom: BigUint = parts
+        .next()
+        .and_then(|denom| denom.parse().ok())
+        .ok_or_else(invalid)?;
+    if denom.is_zero() {
+        return Err(invalid());
+    }
+
+    if negative {
+        Ok(BigFraction::new_neg(numer, denom))
+    } else {
+        Ok(BigFraction::new(numer, denom))
+    }
+}
+
+/// For use with `#[serde(with = "...")]` on lists of numbers.
+pub(crate) mod reals {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        values: &[BigFraction],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(values.iter().map(real_to_string))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<BigFraction>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|text| real_from_str(text))
+            .collect()
+    }
+}
+
+impl Serialize for Const<BigFraction> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&real_to_string(&self.value))
+    }
+}
+
+impl<'de> Deserialize<'de> for Const<BigFraction> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        real_from_str(&text).map(Const::new)
+    }
+}
+
+impl Serialize for Const<bool> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Const<bool> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        bool::deserialize(deserializer).map(Const::new)
+    }
+}
+
+impl Serialize for Const<String> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Const<String> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Const::new)
+    }
+}
+
+impl<T> Serialize for Variable<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Variable<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Variable::new)
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+#[cfg(feature = "regex")]
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        regex::Regex::new(&text)
+            .map(Pattern)
+            .map_err(de::Error::custom)
+    }
+}
+
+#[derive(Serialize)]
+struct SerializeStatement<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    doc: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    /// The text of the GraphQL query to match, or None for a default statement.
+    query: Option<String>,
+    when: Option<&'a Condition>,
+    cost: &'a LinearExpression,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    additive: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct DeserializeStatement {
+    #[serde(default)]
+    doc: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    query: Option<String>,
+    when: Option<Condition>,
+    cost: LinearExpression,
+    #[serde(default)]
+    additive: bool,
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(default)]
+    source: Option<String>,
+}
+
+impl Serialize for Statement<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        profile_method!(serialize);
+
+        let query = match &self.predicate.match_ {
+            Match::GraphQL(..) => Some(self.predicate.match_.to_string()),
+            Match::Default => None,
+        };
+        let when = self
+            .predicate
+            .when_clause
+            .as_ref()
+            .map(|when_clause| &when_clause.condition);
+
+        SerializeStatement {
+            doc: self.doc.as_deref(),
+            name: self.name.as_deref(),
+            query,
+            when,
+            cost: &self.cost_expr,
+            additive: self.additive,
+            scope: self.scope.as_deref(),
+            source: self.source.as_deref(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl Serialize for Document<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.statements.serialize(serializer)
+    }
+}
+
+/// Serializes the compiled document, which has had globals substituted.
+impl Serialize for CostModel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.document().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CostModel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        profile_method!(deserialize);
+
+        let statements = Vec::<DeserializeStatement>::deserialize(deserializer)?;
+
+        // The matches in the document reference the text owned by the model,
+        // so all of the queries are collected into that text before parsing them.
+        let mut text = String::new();
+        let ranges: Vec<_> = statements
+            .iter()
+            .map(|statement| {
+                statement.query.as_ref().map(|query| {
+                    let start = text.len();
+                    text.push_str(query);
+                    start..text.len()
+                })
+            })
+            .collect();
+
+        // Definitions were substituted into the statements before serializing.
+        let mut document = Document {
+            statements: Vec::with_capacity(statements.len()),
+            definitions: Vec::new(),
+            scoped_definitions: Vec::new(),
+        };
+        for (statement, range) in statements.into_iter().zip(ranges) {
+            let predicate = match range {
+                Some(range) => {
+                    parser::parse_graphql_predicate(&text[range]).map_err(de::Error::custom)?
+                }
+                None => Predicate::default_match(),
+            };
+            let condition =
+                parser::and_conditions(predicate.when_clause.map(|w| w.condition), statement.when);
+            document.statements.push(Statement {
+                doc: statement.doc,
+                name: statement.name,
+                predicate: Predicate {
+                    match_: predicate.match_,
+                    when_clause: condition.map(|condition| WhenClause { condition }),
+                },
+                cost_expr: statement.cost,
+                additive: statement.additive,
+                scope: statement.scope,
+                source: statement.source,
+            });
+        }
+
+        let document = Box::into_raw(Box::new(document)) as *const u8;
+        Ok(CostModel::new(text, document))
+    }
+}
@@ -2,6 +2,8 @@ use crate::prelude::*;
 use crate::repeat::repeat;
 use crate::*;
 use num_bigint::BigUint;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 
 trait IntoTestResult {
     fn into(self) -> Result<BigUint, CostError>;
@@ -35,6 +37,12 @@ impl IntoModel for &str {
     }
 }
 
+impl IntoModel for CostModel {
+    fn into(self) -> CostModel {
+        self
+    }
+}
+
 impl IntoModel for (&str, &str) {
     fn into(self) -> CostModel {
         CostModel::compile(self.0, self.1).unwrap()
@@ -114,161 +122,2926 @@ fn sums_top_levels() {
 }
 
 #[test]
-fn var_substitutions() {
-    let query = "query pairs($skip: Int!) { pairs(skip: $skip) { id } }";
-    let variables = "{\"skip\":1}";
-    let model = "query { pairs(skip: $k) } => $k;";
+fn var_substitutions() {
+    let query = "query pairs($skip: Int!) { pairs(skip: $skip) { id } }";
+    let variables = "{\"skip\":1}";
+    let model = "query { pairs(skip: $k) } => $k;";
+
+    test(model, (query, variables), 1);
+}
+
+#[test]
+fn model_from_str() {
+    let model: CostModel = "
+        query { a(first: $first) } => $first;
+        query { b { c } } => 2;
+        default => 3;
+    "
+    .parse()
+    .unwrap();
+
+    let cost = |query| model.cost(query, "");
+    assert_eq!(cost("{ a(first: 5) }"), IntoTestResult::into(5));
+    assert_eq!(cost("{ b { c d } }"), IntoTestResult::into(2));
+    assert_eq!(cost("{ b }"), IntoTestResult::into(3));
+    assert_eq!(cost("{ a(first: 7) b { c } }"), IntoTestResult::into(9));
+}
+
+#[test]
+fn earliest_statement_wins() {
+    let model: CostModel = "
+        query { a(first: $first) } when $first > 10 => 1;
+        query { a } => 2;
+        query { a(first: 100) } => 3;
+        default => 4;
+    "
+    .parse()
+    .unwrap();
+
+    let (cost, statements) = model.cost_with_statements("{ a(first: 100) }", "").unwrap();
+    assert_eq!(cost, BigUint::from(1u32) * wei_to_grt());
+    assert_eq!(statements, vec![0]);
+
+    let (_, statements) = model
+        .cost_with_statements("{ a(first: 5) b a(first: 11) }", "")
+        .unwrap();
+    assert_eq!(statements, vec![1, 3, 0]);
+}
+
+#[test]
+fn statement_names() {
+    let model: CostModel = "
+        @name(\"pagination\") query { a(first: $first) } when $first > 10 => 1;
+        # Comments may come before or after the name
+        @name( \"plain a\" )
+        # Another comment
+        query { a } => 2;
+        default => 4;
+    "
+    .parse()
+    .unwrap();
+
+    let (cost, names) = model
+        .cost_with_statement_names("{ a(first: 100) a b }", "")
+        .unwrap();
+    assert_eq!(cost, BigUint::from(7u32) * wei_to_grt());
+    assert_eq!(names, vec![Some("pagination"), Some("plain a"), None]);
+
+    // The name does not affect matching or cost
+    let unnamed: CostModel = "
+        query { a(first: $first) } when $first > 10 => 1;
+        query { a } => 2;
+        default => 4;
+    "
+    .parse()
+    .unwrap();
+    for query in ["{ a(first: 100) }", "{ a b }", "{ c }"].iter() {
+        assert_eq!(model.cost(query, ""), unnamed.cost(query, ""));
+    }
+
+    let printed = format!("{}", model.document());
+    assert!(printed.starts_with("@name(\"pagination\") query { a(first: $first) }"));
+    let reparsed = parser::parse_document(&printed).unwrap();
+    let names: Vec<_> = reparsed
+        .statements
+        .iter()
+        .map(|s| s.name.as_deref())
+        .collect();
+    assert_eq!(names, vec![Some("pagination"), Some("plain a"), None]);
+
+    assert!(parser::parse_document("@name(pagination) default => 1;").is_err());
+}
+
+#[test]
+fn comments() {
+    let text = "
+        # Pages are cheap
+        /* unless they
+           are large */
+        query { a(first: $first) } when $first > 10 => $first;
+        query { a } => 2; # Trails the statement above
+        @name(\"fallback\") #
+        default => 4;
+        /* Not attached to any statement */
+        # Nor is this
+    ";
+    let model: CostModel = text.parse().unwrap();
+    let uncommented: CostModel = "
+        query { a(first: $first) } when $first > 10 => $first;
+        query { a } => 2;
+        @name(\"fallback\") default => 4;
+    "
+    .parse()
+    .unwrap();
+    for query in ["{ a(first: 100) }", "{ a b }", "{ c }"].iter() {
+        assert_eq!(model.cost(query, ""), uncommented.cost(query, ""));
+    }
+
+    let docs = |document: &Document| -> Vec<Option<String>> {
+        document.statements.iter().map(|s| s.doc.clone()).collect()
+    };
+    let expected = vec![
+        Some(" Pages are cheap\n unless they\n           are large ".to_owned()),
+        None,
+        Some(" Trails the statement above\n".to_owned()),
+    ];
+    let document = parser::parse_document(text).unwrap();
+    assert_eq!(docs(&document), expected);
+
+    let printed = format!("{}", document);
+    assert!(printed.starts_with("# Pages are cheap\n# unless they\n#           are large \nquery"));
+    let reparsed = parser::parse_document(&printed).unwrap();
+    assert_eq!(docs(&reparsed), expected);
+    assert_eq!(printed, format!("{}", reparsed));
+
+    assert!(parser::parse_document("/* unterminated default => 1;").is_err());
+}
+
+#[test]
+fn additive_statements() {
+    let model: CostModel = "
+        query { pairs } => 1;
+        query { pairs(first: $first) } when $first > 100 => 5;
+        query { tokens } => 2;
+    "
+    .parse()
+    .unwrap();
+
+    let cost = |query: &str| model.cost_additive(query, "");
+    let expect = |grt: u64| Ok(BigUint::from(grt) * wei_to_grt());
+
+    assert_eq!(cost("{ pairs(first: 10) }"), expect(1));
+    assert_eq!(cost("{ pairs(first: 1000) }"), expect(6));
+    assert_eq!(cost("{ pairs(first: 1000) tokens }"), expect(8));
+    assert_eq!(cost("{ swaps }"), Err(CostError::QueryNotCosted));
+
+    // Only the first match is used by cost
+    assert_eq!(model.cost("{ pairs(first: 1000) }", ""), expect(1));
+
+    // A statement which matches a directive is summed with one which matches the
+    // selection of the same field
+    let model: CostModel = "
+        query { pairs { id } } => 10;
+        query { pairs @cached(ttl: $ttl) } => $ttl;
+    "
+    .parse()
+    .unwrap();
+    let cost = |query: &str| model.cost_additive(query, "");
+    assert_eq!(cost("{ pairs @cached(ttl: 5) { id } }"), expect(15));
+    assert_eq!(cost("{ pairs { id } }"), expect(10));
+    assert_eq!(cost("{ pairs @cached(ttl: 5) }"), expect(5));
+
+    // As they are by cost, when the directive is matched by an additive statement
+    let model = "
+        query { pairs { id } } => 10;
+        query { pairs @cached(ttl: $ttl) } += $ttl;
+    ";
+    test(model, "{ pairs @cached(ttl: 5) { id } }", 15);
+    test(model, "{ pairs { id } }", 10);
+}
+
+#[test]
+fn additive_keyword() {
+    let model = "
+        query { pairs(skip: $skip) } when $skip > 2000 += 3;
+        query { pairs } => 10;
+        query { pairs { reserveUSD } } += 5;
+        query { pairs } => 1000;
+        query { tokens } += 7;
+    ";
+
+    // The first statement with => is summed with every matching statement with +=
+    test(model, "{ pairs { id } }", 10);
+    test(model, "{ pairs(skip: 5000) { id } }", 13);
+    test(model, "{ pairs { reserveUSD } }", 15);
+    test(model, "{ pairs(skip: 5000) { reserveUSD } }", 18);
+    test(model, "{ pairs(skip: 5000) { reserveUSD } tokens }", 25);
+    // A field which only matches statements with += is costed at their sum
+    test(model, "{ tokens }", 7);
+    test(model, "{ swaps }", CostError::QueryNotCosted);
+
+    let model: CostModel = model.parse().unwrap();
+    assert_eq!(
+        model.cost_with_statements("{ pairs(skip: 5000) { reserveUSD } }", ""),
+        Ok((BigUint::from(18u32) * wei_to_grt(), vec![0, 1, 2]))
+    );
+    // Every matching statement is summed by cost_additive
+    assert_eq!(
+        model.cost_additive("{ pairs { reserveUSD } }", ""),
+        Ok(BigUint::from(1015u32) * wei_to_grt())
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serialize_round_trip() {
+    let model = "
+        query { pairs(first: $first, where: { token: $token }) } when $token == \"USDC\" => 0.5 * $first;
+        query { pairs(first: $first) } when $first in [10, 20] && $LOAD > 1 => $first / 3;
+        query { tokens { id } } => max($LOAD, 2) ^ 2;
+        query ($first: Int = 5) { swaps(first: $first) } => $first;
+        query { swaps } += 0.5;
+        default => -1 + 2;
+    ";
+    let model = CostModel::compile(model, "{\"LOAD\": 3}").unwrap();
+    let json = serde_json::to_string(&model).unwrap();
+    let deserialized: CostModel = serde_json::from_str(&json).unwrap();
+
+    let queries = [
+        "{ pairs(first: 7, where: { token: \"USDC\" }) }",
+        "{ pairs(first: 20) }",
+        "{ pairs(first: 30) }",
+        "{ tokens { id symbol } }",
+        "{ swaps }",
+        "{ swaps(first: 2) }",
+    ];
+    for query in queries.iter() {
+        assert_eq!(model.cost(query, ""), deserialized.cost(query, ""));
+    }
+    assert_eq!(json, serde_json::to_string(&deserialized).unwrap());
+}
+
+#[test]
+fn display_round_trip() {
+    let models = [
+        "query { a } => 1;",
+        "default => 0.001;",
+        "default => 1 / 3;",
+        "query { a(first: $first) } => $first * 0.25 - 1;",
+        "query { a(first: $first) } => $first - ($first - 1) - $first + 1;",
+        "query { a(x: $x, y: $y) } => ($x + $y) * ($x - $y) / 2 % 7;",
+        "query { a(x: $x) } => 2 ^ 3 ^ $x + (2 ^ $x) ^ 3;",
+        "query { a(x: $x) } => min($x, 10, max(1, $x / 2)) + log2($x);",
+        "query { a(x: $x) } when $x > 1 && ($x < 5 || $x == 10) => -2 * $x;",
+        "query { a(x: $x) } when ($x >= 1 || $x <= -1) && $flag => $x;",
+        "query { a(t: $t) } when $t == \"say \\\"hi\\\"\" || $t != \"\" => 1;",
+        "query { a(n: $n) } when $n in [\"mainnet\", \"rinkeby\"] => 1;",
+        "query { a(n: $n) } when $n in [1, 2.5, -3] => 1;",
+        "query { a(n: $n) } when $n in [] => 1;",
+        "query { a(x: $x) } when !($x > 1) && !!$flag || !true => 1;",
+        "query { a(where: { b_gt: 1, c: [1, 2] }) { b c { d } } } when true => 1;",
+        "default => $x + 1 / 0;",
+        "query { a(x: $x) } => -$x + -abs($x) - -(1 + $x) * -($x ^ 2);",
+        "query { a { ...on B { c } } } => 1; default => 2;",
+        "query ($first: Int = 100, $ids: [ID!] = [\"a\"]) { a(first: $first, ids: $ids) } => $first;",
+        "query { a(x: $x) } when is_null($x) || !is_null($y) => 1;",
+        "query { a(x: $x) } when defined($x) && !defined($y) => 1;",
+        "query { a(x: $x) } when $x & 3 | 4 > 0 && ($x << 2) >> 1 == 2 => ($x | 1) & 7;",
+        "query { a(x: $x) } => $x > 1 && !$y ? $x > 2 ? 3 : 2 : 1 / $x;",
+        "let page = $first * 2; query { a(first: $first) } => $page + 1;",
+        "query { a(x: $x) } when $x > 1 += $x; default => 1;",
+        "query { a(first: > 100, skip: <= 10) } when $flag => 1;",
+        "let a = 1; scope s { let a = 2; query { a } => $a; } default => $a; scope t { let b = 3; }",
+        "query { a(where: { b: >= 0.5, c: [1, 2] }) { b } } => 1;",
+        "query { a(id: $id) } when len($id) > 42 => -len($id) + len(\"ab\");",
+        "query { a(ids: $ids) } when count($ids) > 2 => -sum($ids) * 2;",
+    ];
+    for model in models.iter() {
+        let document = parser::parse_document(model).unwrap();
+        let printed = format!("{}", document);
+        let reparsed = parser::parse_document(&printed).unwrap_or_else(|e| {
+            panic!(
+                "Failed to reparse {:?} printed from {:?}: {}",
+                printed, model, e
+            )
+        });
+
+        // GraphQL positions differ after printing, so matches are compared by their text.
+        assert_eq!(printed, format!("{}", reparsed));
+        assert_eq!(document.statements.len(), reparsed.statements.len());
+        for (statement, reparsed) in document.statements.iter().zip(reparsed.statements.iter()) {
+            assert_eq!(statement.cost_expr, reparsed.cost_expr, "{}", printed);
+            assert_eq!(
+                statement.predicate.when_clause, reparsed.predicate.when_clause,
+                "{}",
+                printed
+            );
+        }
+        // Which also compares the matches, ignoring their positions
+        assert!(document.canonical_eq(&reparsed), "{}", printed);
+    }
+}
+
+#[test]
+fn display_round_trip_mod() {
+    let query = "{ a(x: 19, y: 5, z: 3) }";
+    for (expr, cost) in [
+        ("$x % $y * $z", 4u64),
+        ("($x % $y) * $z", 12),
+        ("$x * $y % $z", 2),
+        ("$x * ($y % $z)", 38),
+        ("$x - $y % $z", 17),
+        ("($x - $y) % $z", 2),
+    ]
+    .iter()
+    {
+        let model = format!("query {{ a(x: $x, y: $y, z: $z) }} => {};", expr);
+        let printed = parser::parse_document(&model).unwrap().to_string();
+        test(model.as_str(), query, *cost);
+        test(printed.as_str(), query, *cost);
+    }
+}
+
+#[test]
+fn canonical_eq() {
+    let canonical_eq = |a: &str, b: &str| {
+        let a = parser::parse_document(a).unwrap();
+        let b = parser::parse_document(b).unwrap();
+        a.canonical_eq(&b)
+    };
+
+    // Whitespace, comments and the order of arguments and directives
+    assert!(canonical_eq(
+        "query { pairs(first: $first, skip: 10) { id } } => $first;",
+        "# Pairs\nquery {\n  pairs(skip: 10, first: $first) {\n    id\n  }\n} => $first;",
+    ));
+    assert!(canonical_eq(
+        "query ($a: Int = 1, $b: Int = 2) { a(a: $a, b: $b) @x(y: 1, z: 2) @w } => 1;",
+        "query ($b: Int = 2, $a: Int = 1) { a(b: $b, a: $a) @w @x(z: 2, y: 1) } => 1;",
+    ));
+    // Associativity and constants
+    assert!(canonical_eq(
+        "query { a(x: $x, y: $y) } => $x + ($y + 1) + 2;",
+        "query { a(x: $x, y: $y) } => 3 + $x + $y;",
+    ));
+    assert!(canonical_eq(
+        "query { a(x: $x) } when $x > 1 && ($x < 5 && $flag) => 2 * ($x * 3);",
+        "query { a(x: $x) } when ($x > 1 && $x < 5) && $flag => 6 * $x;",
+    ));
+
+    // The order of operands which may fail is kept
+    assert!(!canonical_eq(
+        "query { a(x: $x, y: $y) } => $x + $y;",
+        "query { a(x: $x, y: $y) } => $y + $x;",
+    ));
+    assert!(!canonical_eq(
+        "query { a(x: $x) } => $x - ($x - 1);",
+        "query { a(x: $x) } => $x - $x - 1;",
+    ));
+    assert!(!canonical_eq(
+        "query { a(x: [1, 2]) } => 1;",
+        "query { a(x: [2, 1]) } => 1;",
+    ));
+    assert!(!canonical_eq(
+        "@name(\"a\") default => 1;",
+        "@name(\"b\") default => 1;",
+    ));
+    assert!(!canonical_eq("default => 1;", "default += 1;"));
+}
+
+#[test]
+fn default() {
+    let query = "query { nonsense }";
+    let model = "query { abc } => 2; default => 10;";
+    test(model, query, 10);
+}
+
+#[test]
+fn default_is_fallback() {
+    let without_default = "query { a } => 1;";
+    test(without_default, "{ a }", 1);
+    test(without_default, "{ b }", CostError::QueryNotCosted);
+
+    let with_default = "query { a } => 1; default => 100;";
+    test(with_default, "{ a }", 1);
+    test(with_default, "{ b }", 100);
+}
+
+#[test]
+fn string_captures() {
+    let model = "
+        query { pairs(where: { token: $token }) } when $token == \"USDC\" => 1;
+        query { pairs(where: { token: $token }) } when $token != \"DAI\" => 2;
+        default => 3;
+    ";
+
+    test(model, "query { pairs(where: { token: \"USDC\" }) }", 1);
+    test(model, "query { pairs(where: { token: \"WETH\" }) }", 2);
+    test(model, "query { pairs(where: { token: \"DAI\" }) }", 3);
+}
+
+#[test]
+fn string_ranges() {
+    let model = "
+        query { pair(id: $id) } when $id >= \"0x0\" && $id < \"0x8\" => 1;
+        query { pair(id: $id) } when $id >= \"0x8\" => 2;
+        default => 3;
+    ";
+
+    test(model, "{ pair(id: \"0x12ab\") }", 1);
+    test(model, "{ pair(id: \"0x8\") }", 2);
+    test(model, "{ pair(id: \"0xff\") }", 2);
+    test(model, "{ pair(id: \"-1\") }", 3);
+    test(
+        model,
+        "{ pair(id: 5) }",
+        CostError::StatementFail {
+            statement: 0,
+            error: EvalError::TypeMismatch {
+                name: "id".to_owned(),
+            },
+        },
+    );
+}
+
+#[test]
+fn enum_arguments() {
+    let model = "
+        query { pairs(orderBy: DESC, first: $first) } => $first;
+        query { pairs(orderBy: $orderBy) } when $orderBy == \"ASC\" => 1;
+        query { pairs(orderBy: $orderBy) } when $orderBy in [\"NAME\", \"ID\"] => 2;
+        default => 3;
+    ";
+
+    test(model, "query { pairs(orderBy: DESC, first: 10) }", 10);
+    test(model, "query { pairs(orderBy: ASC) }", 1);
+    test(model, "query { pairs(orderBy: ASC, first: 10) }", 1);
+    test(model, "query { pairs(orderBy: ID) }", 2);
+    test(model, "query { pairs(orderBy: VOLUME) }", 3);
+    // An enum in a query variable arrives as a string
+    test(
+        model,
+        (
+            "query Pairs($orderBy: OrderBy) { pairs(orderBy: $orderBy) }",
+            "{\"orderBy\": \"ASC\"}",
+        ),
+        1,
+    );
+}
+
+#[test]
+fn flags() {
+    let model = "
+        query { pairs(flags: $flags) } when ($flags & 4) > 0 && $flags & 1 == 1 => 1;
+        query { pairs(flags: $flags) } when ($flags & 4) > 0 => 2;
+        query { pairs(flags: $flags, shift: $shift) } => $flags >> $shift | 1 << $shift;
+        default => 100;
+    ";
+
+    test(model, "{ pairs(flags: 5) }", 1);
+    test(model, "{ pairs(flags: 4) }", 2);
+    test(model, "{ pairs(flags: 8, shift: 2) }", 6);
+    test(model, "{ pairs(flags: 3) }", 100);
+    test(
+        model,
+        "{ pairs(flags: 3, shift: -1) }",
+        CostError::StatementFail {
+            statement: 2,
+            error: EvalError::InvalidArgument,
+        },
+    );
+}
+
+#[test]
+fn conditional_costs() {
+    let model = "
+        query { pairs(first: $first) } => $first > 100 ? $first * 2 : $first;
+        query { tokens(n: $n) } => ($n == 0 ? 1 : 10 / $n);
+        default => $__selections > 1 ? 5 : 2;
+    ";
+
+    test(model, "{ pairs(first: 200) }", 400);
+    test(model, "{ pairs(first: 50) }", 50);
+    // The untaken branch would divide by zero
+    test(model, "{ tokens(n: 0) }", 1);
+    test(model, "{ tokens(n: 5) }", 2);
+    test(model, "{ a }", 2);
+    test(model, "{ a b }", 10);
+}
+
+#[test]
+fn directive_arguments() {
+    let model = "
+        query { pairs @paginate(limit: $limit) } when $limit > 100 => $limit * 2;
+        query { pairs @paginate(limit: $limit) } => $limit;
+        query { tokens @cached(ttl: 60) } => 1;
+        default => 1000;
+    ";
+
+    test(model, "{ pairs @paginate(limit: 10) { id } }", 10);
+    test(model, "{ pairs @paginate(limit: 200) { id } }", 400);
+    test(
+        model,
+        (
+            "query ($limit: Int) { pairs @paginate(limit: $limit) { id } }",
+            "{\"limit\": 20}",
+        ),
+        20,
+    );
+    test(model, "{ pairs { id } }", 1000);
+    test(model, "{ tokens @cached(ttl: 60) }", 1);
+    test(model, "{ tokens @cached(ttl: 30) }", 1000);
+    // Standard directives still apply
+    let skipped = "{ pairs @paginate(limit: 10) @skip(if: true) { id } tokens }";
+    test(model, skipped, 1000);
+}
+
+#[test]
+fn nested_field_directives() {
+    let model = "
+        query { users @cached } => 1;
+        query { users { posts @cached(ttl: $ttl) } } => $ttl;
+        default => 1000;
+    ";
+
+    test(model, "{ users @cached { id } }", 1);
+    test(model, "{ users { id } }", 1000);
+    // Directives of selections within the field are matched too
+    test(model, "{ users { posts @cached(ttl: 60) { id } } }", 60);
+    test(model, "{ users { posts { id } } }", 1000);
+    test(
+        model,
+        "{ users { ...Posts } } fragment Posts on User { posts @cached(ttl: 5) { id } }",
+        5,
+    );
+    // A directive on a different selection does not count
+    test(
+        model,
+        "{ users { posts { id } comments @cached(ttl: 60) } }",
+        1000,
+    );
+}
+
+#[test]
+fn incremental_delivery() {
+    let model = "
+        query { users @stream(initialCount: $n) } => $n;
+        query { users } when $__deferred => 100;
+        query { users } => 10;
+        default when $__deferred => 1000;
+        default => 1;
+    ";
+
+    test(model, "{ users @stream(initialCount: 5) { id } }", 5);
+    test(model, "{ users @stream { id } }", 100);
+    test(model, "{ users { id } }", 10);
+    test(model, "{ tokens @stream }", 1000);
+    // A directive which is turned off does not defer the field
+    test(model, "{ users @stream(if: false) { id } }", 10);
+    test(
+        model,
+        (
+            "query q($s: Boolean) { users @stream(if: $s) { id } }",
+            "{ \"s\": false }",
+        ),
+        10,
+    );
+    test(
+        model,
+        "query q($s: Boolean) { users @stream(if: $s) { id } }",
+        100,
+    );
+    // Deferred fragments are expanded like any other, and only the directives
+    // of the field itself set `$__deferred`
+    test(model, "{ ... @defer { users { id } } tokens }", 11);
+}
+
+#[test]
+fn argument_aliases() {
+    let text = "
+        query { tokens(first: $first) } => $first;
+        query { swaps(where: { first: $first }) } => $first;
+        query ($first: Int = 100) { pairs(first: $first) } => $first;
+        default => 1;
+    ";
+    let model = || {
+        CostModel::compile(text, "")
+            .unwrap()
+            .with_argument_alias("limit", "first")
+    };
+
+    test(model(), "{ tokens(first: 5) }", 5);
+    test(model(), "{ tokens(limit: 5) }", 5);
+    test(
+        model(),
+        ("query q($n: Int) { tokens(limit: $n) }", "{ \"n\": 7 }"),
+        7,
+    );
+    // An argument which is renamed is no longer left out
+    test(model(), "{ pairs(limit: 20) }", 20);
+    test(model(), "{ pairs }", 100);
+
+    // Only the names of arguments are renamed, not of object fields
+    test(model(), "{ swaps(where: { first: 5 }) }", 5);
+    test(model(), "{ swaps(where: { limit: 5 }) }", 1);
+    // Without the alias, the query does not match
+    test(text, "{ tokens(limit: 5) }", 1);
+
+    // Aliases are not chained
+    let chained = CostModel::compile(text, "")
+        .unwrap()
+        .with_argument_alias("limit", "count")
+        .with_argument_alias("count", "first");
+    test(chained, "{ tokens(limit: 5) }", 1);
+}
+
+#[test]
+fn reordered_arguments() {
+    let model = "
+        query { pairs(first: $first, skip: 10, orderBy: id) } => $first;
+        query { tokens @paginate(limit: $limit, offset: 5) } => $limit * 2;
+        query { swaps @a(x: 1) @b(y: $y, z: 2) } => $y * 3;
+        default => 1000;
+    ";
+
+    // Arguments are matched by name, regardless of their position in the query
+    test(model, "{ pairs(first: 7, skip: 10, orderBy: id) }", 7);
+    test(model, "{ pairs(orderBy: id, skip: 10, first: 7) }", 7);
+    test(
+        model,
+        "{ pairs(skip: 10, where: {}, first: 7, orderBy: id) }",
+        7,
+    );
+    test(model, "{ pairs(orderBy: id, skip: 11, first: 7) }", 1000);
+
+    test(model, "{ tokens @paginate(limit: 10, offset: 5) }", 20);
+    test(model, "{ tokens @paginate(offset: 5, limit: 10) }", 20);
+    test(model, "{ tokens @paginate(offset: 6, limit: 10) }", 1000);
+
+    // So are the directives themselves
+    test(model, "{ swaps @b(z: 2, y: 4) @a(x: 1) }", 12);
+    test(model, "{ swaps @a(x: 1) @b(z: 3, y: 4) }", 1000);
+}
+
+#[test]
+fn captured_variables() {
+    let captured = |model: &str| {
+        let document = parser::parse_document(model).unwrap();
+        let mut names: Vec<_> = document.statements[0]
+            .captured_variables()
+            .into_iter()
+            .collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(
+        captured("query { pairs(first: $first, skip: $skip, orderBy: id) } => 1;"),
+        vec!["first", "skip"]
+    );
+    assert_eq!(
+        captured("query { a(where: { b: $b, c: [$c, 1] }) { d(e: $e) } } => 1;"),
+        vec!["b", "c", "e"]
+    );
+    assert_eq!(
+        captured("query { pairs(first: $first) @paginate(limit: $limit, offset: 5) } => 1;"),
+        vec!["first", "limit"]
+    );
+    assert_eq!(
+        captured("query ($first: Int = 100) { pairs(first: $first) } => 1;"),
+        vec!["first"]
+    );
+    // Variables used only in the when clause or cost are not captured
+    assert_eq!(
+        captured("query { pairs(first: $first) } when $LOAD > 1 => $first * $__nodes;"),
+        vec!["first"]
+    );
+    assert!(captured("query { pairs { id } } => 1;").is_empty());
+    assert!(captured("default => $x;").is_empty());
+}
+
+#[test]
+fn boolean_arguments() {
+    let model = "
+        query { users(active: true) } => 1;
+        query { users(active: false) } => 2;
+        query { tokens(verified: $verified) } when $verified => 10;
+        query { tokens(verified: $verified) } when !$verified => 20;
+        default => 1000;
+    ";
+
+    // A boolean literal only matches the same boolean
+    test(model, "{ users(active: true) }", 1);
+    test(model, "{ users(active: false) }", 2);
+    test(model, "{ users(active: \"true\") }", 1000);
+    test(model, "{ users(active: 1) }", 1000);
+    test(model, "{ users }", 1000);
+    test(
+        model,
+        (
+            "query ($a: Boolean) { users(active: $a) }",
+            "{\"a\": false}",
+        ),
+        2,
+    );
+
+    // A captured boolean can be used as a condition
+    test(model, "{ tokens(verified: true) }", 10);
+    test(model, "{ tokens(verified: false) }", 20);
+    test(
+        model,
+        (
+            "query ($v: Boolean) { tokens(verified: $v) }",
+            "{\"v\": true}",
+        ),
+        10,
+    );
+}
+
+#[test]
+fn range_patterns() {
+    let model = "
+        query { users(first: > 100) } => 1;
+        query { users(first: >= 50, skip: <= 10) } when $__range0 != 60 => 2;
+        query { pairs(first: <10) } when $__range0 > 1 => 3;
+        query { pairs(where: { amount: > 0.5 }, first: $first) } => $first;
+        default => 1000;
+    ";
+
+    test(model, "{ users(first: 150) }", 1);
+    test(model, "{ users(first: 101) }", 1);
+    test(model, "{ users(first: 50) }", 2);
+    test(model, "{ users(first: 100, skip: 10) }", 2);
+    test(model, "{ users(first: 60) }", 1000);
+    test(model, "{ users(first: 100, skip: 11) }", 1000);
+    test(model, "{ users(first: 49) }", 1000);
+    test(model, "{ users }", 1000);
+    test(
+        model,
+        ("query ($f: Int) { users(first: $f) }", "{\"f\": 200}"),
+        1,
+    );
+
+    // The pattern is tested before the when clause
+    test(model, "{ pairs(first: 5) }", 3);
+    test(model, "{ pairs(first: 1) }", 1000);
+    test(model, "{ pairs(first: 10) }", 1000);
+
+    // Patterns may be nested in objects
+    test(model, "{ pairs(where: { amount: 0.75 }, first: 7) }", 7);
+    test(model, "{ pairs(where: { amount: 0.25 }, first: 7) }", 1000);
+
+    // A comparison is not a pattern outside of the arguments
+    assert!(CostModel::compile("query ($x: Int = > 1) { users(first: $x) } => 1;", "").is_err());
+    assert!(Predicate::query("query { users(first: > 100) }")
+        .unwrap()
+        .when_clause
+        .is_some());
+}
+
+#[test]
+fn definitions() {
+    let model = "
+        query { pairs(first: $first) } => $page_cost;
+        let page_cost = $first * 2;
+        let discounted = $page_cost - $DISCOUNT;
+        query { tokens(first: $first) } when $discounted > 10 => $discounted;
+        # Captures shadow definitions
+        query { swaps(page_cost: $page_cost) } => $page_cost;
+        default => 1;
+    ";
+    let model = (model, "{ \"DISCOUNT\": 5 }");
+
+    test(model, "{ pairs(first: 10) }", 20);
+    test(model, "{ tokens(first: 10) }", 15);
+    test(model, "{ tokens(first: 5) }", 1);
+    test(model, "{ swaps(page_cost: 3) }", 3);
+
+    let cyclic = |model: &str| match CostModel::compile(model, "{}") {
+        Err(CompileError::CyclicDefinition(name)) => Some(name),
+        _ => None,
+    };
+    assert_eq!(cyclic("let a = $a; default => 1;"), Some("a".to_owned()));
+    assert_eq!(
+        cyclic("let a = $b + 1; let b = max($c, 1); let c = $a; default => $a;"),
+        Some("a".to_owned())
+    );
+    assert_eq!(cyclic("let a = $b; let b = 1; default => $a;"), None);
+}
+
+#[test]
+fn doubling_definitions() {
+    // Each definition refers to the previous one twice, so substituting them copies
+    // the first one 2^n times.
+    let chain = |n: usize| {
+        let mut model = "let a0 = 1;".to_owned();
+        for i in 0..n {
+            model.push_str(&format!(" let a{} = $a{} + $a{};", i + 1, i, i));
+        }
+        model.push_str(&format!(" default => $a{};", n));
+        model
+    };
+
+    test(chain(10).as_str(), "{ a }", 1024);
+
+    assert!(matches!(
+        CostModel::compile(chain(64), "{}"),
+        Err(CompileError::TooManySubstitutions(_))
+    ));
+}
+
+#[test]
+fn scopes() {
+    let model = "
+        let rate = 1;
+        let fee = $rate * 10;
+        scope tokens {
+            let page = $first * 2;
+            # Hides the definition of the document
+            let rate = 5;
+            query { tokens(first: $first) } => $page + $rate;
+            query { owners } => $fee;
+        }
+        scope pairs {
+            let page = $first * 3;
+            query { pairs(first: $first) } => $page + $rate;
+        }
+        query { swaps(first: $first) } => $page;
+        default => 1000;
+    ";
+
+    // Each scope has its own definition of $page
+    test(model, "{ tokens(first: 10) }", 25);
+    test(model, "{ pairs(first: 10) }", 31);
+    // A definition of the document refers to the definitions of the document,
+    // even when used from within a scope
+    test(model, "{ owners }", 10);
+    // Statements outside of the scopes can't see their definitions
+    test(
+        model,
+        "{ swaps(first: 10) }",
+        CostError::StatementFail {
+            statement: 3,
+            error: EvalError::MissingVariable("page".to_owned()),
+        },
+    );
+    // Statements of every scope are matched in order
+    test(model, "{ tokens(first: 1) pairs(first: 1) uniswap }", 1011);
+
+    let document = parser::parse_document(model).unwrap();
+    let scopes: Vec<_> = document
+        .statements
+        .iter()
+        .map(|statement| statement.scope.as_deref())
+        .collect();
+    assert_eq!(
+        scopes,
+        vec![Some("tokens"), Some("tokens"), Some("pairs"), None, None]
+    );
+
+    let built = DocumentBuilder::new()
+        .scope(
+            "tokens",
+            DocumentBuilder::new()
+                .definition("page", builder::mul(builder::var("first"), builder::lit(2)))
+                .statement(
+                    Predicate::query("query { tokens(first: $first) }").unwrap(),
+                    builder::var("page"),
+                ),
+        )
+        .build()
+        .unwrap();
+    let built = CostModel::from_document(built, "").unwrap();
+    test(built, "{ tokens(first: 4) }", 8);
+
+    let fails = |model: &str| CostModel::compile(model, "").is_err();
+    // A scope's definition which refers to its own name refers to itself
+    assert!(fails(
+        "let a = 1; scope s { let a = $a + 1; default => $a; }"
+    ));
+    assert!(fails("scope s { default => 1;"));
+    assert!(fails("scope s { scope t { default => 1; } }"));
+    assert!(fails("default => 1; }"));
+    assert!(!fails("scope s { } default => 1;"));
+}
+
+#[test]
+fn field_base_cost() {
+    let model = "
+        query { pairs { id token0 { id } } } => 10;
+        default => 0;
+    ";
+    let base = |count_introspection| {
+        CostModel::compile(model, "")
+            .unwrap()
+            .with_field_base_cost(wei_to_grt(), count_introspection)
+    };
+
+    test(base(false), "{ pairs { id token0 { id } } }", 14);
+    test(
+        base(false),
+        "{ tokens { id } pairs { id token0 { id } } }",
+        16,
+    );
+    test(
+        base(false),
+        "{ tokens { ...Fields } } fragment Fields on Token { id symbol }",
+        3,
+    );
+    test(base(false), "{ tokens { id __typename } }", 2);
+    test(base(false), "{ __schema { types { name } } }", 0);
+    test(base(true), "{ tokens { id __typename } }", 3);
+    test(base(true), "{ __schema { types { name } } }", 3);
+}
+
+#[test]
+fn float_arguments() {
+    let model = "
+        query { swaps(minAmount: $min) } when $min > 1.2 => $min * 2;
+        query { tokens(share: $share) } => $share * 30;
+        default => 1;
+    ";
+
+    test(model, "{ swaps(minAmount: 1.5) }", 3);
+    test(model, "{ swaps(minAmount: 1.0) }", 1);
+    // Floats are converted exactly as written, rather than by their binary value
+    test(model, "{ tokens(share: 0.1) }", 3);
+    test(
+        model,
+        ("query ($s: Float) { tokens(share: $s) }", "{\"s\": 0.7}"),
+        21,
+    );
+}
+
+#[test]
+fn arithmetic_conditions() {
+    let model = "
+        query { pairs(first: $first, pages: $pages) } when $first * $pages > 1000 => 10;
+        query { pairs(first: $first, skip: $skip) } when $skip >= $first * 2 + 1 => 5;
+        default => 1;
+    ";
+
+    test(model, "{ pairs(first: 100, pages: 11) }", 10);
+    test(model, "{ pairs(first: 100, pages: 10) }", 1);
+    test(model, "{ pairs(first: 10, skip: 21) }", 5);
+    test(model, "{ pairs(first: 10, skip: 20) }", 1);
+}
+
+#[test]
+fn negated_conditions() {
+    let model = "
+        query { pairs(first: $first) } when !($first > 100) => 1;
+        query { pairs(network: $network) } when !($network in [\"mainnet\"]) => 2;
+        default => 3;
+    ";
+
+    test(model, "query { pairs(first: 10) }", 1);
+    test(model, "query { pairs(first: 1000) }", 3);
+    test(model, "query { pairs(network: \"rinkeby\") }", 2);
+    test(model, "query { pairs(network: \"mainnet\") }", 3);
+}
+
+#[test]
+fn list_membership() {
+    let model = "
+        query { pairs(network: $network) } when $network in [\"mainnet\", \"arbitrum\"] => 1;
+        query { pairs(first: $first) } when $first in [] => 2;
+        default => 3;
+    ";
+
+    test(model, "query { pairs(network: \"arbitrum\") }", 1);
+    test(model, "query { pairs(network: \"rinkeby\") }", 3);
+    test(model, "query { pairs(first: 10) }", 3);
+}
+
+#[test]
+fn wildcard_fields() {
+    let model = "
+        query { _(first: $first) } when $first > 100 => $first;
+        query { pairs { _ { id } } } => 2;
+        query { _ } => 10;
+    ";
+
+    test(model, "{ tokens(first: 1000) }", 1000);
+    test(model, "{ pairs(first: 1000) { id } }", 1000);
+    test(model, "{ tokens(first: 5) }", 10);
+    test(model, "{ pairs { token0 { id } } }", 2);
+    test(model, "{ pairs { token0 { symbol } } }", 10);
+    test(model, "{ a b }", 20);
+}
+
+#[test]
+fn selections_builtin() {
+    let model = "
+        query { a } when $__selections > 2 => 10;
+        query { a } => $__selections;
+        default => 1;
+    ";
+
+    test(model, "{ a }", 1);
+    test(model, "{ a b }", 3);
+    test(model, "{ a b c }", 12);
+    test(model, "{ ...f } fragment f on Query { a b }", 3);
+}
+
+#[test]
+fn operation_types() {
+    let model = "
+        query { users } when $__operation == \"mutation\" => 1000;
+        query { users } when $__operation == \"subscription\" => 100;
+        query { users } => 1;
+    ";
+
+    test(model, "{ users { id } }", 1);
+    test(model, "query { users { id } }", 1);
+    test(model, "query named { users { id } }", 1);
+    test(model, "mutation { users { id } }", 1000);
+    test(model, "mutation named { users { id } }", 1000);
+    test(model, "subscription { users { id } }", 100);
+    test(
+        model,
+        "query a { users { id } } mutation b { users { id } }",
+        1001,
+    );
+}
+
+#[test]
+fn depth() {
+    let model = "query { tokens } => $__depth * 10; default => $__depth;";
+
+    test(model, "{ tokens }", 10);
+    test(model, "{ tokens { id } }", 20);
+    test(model, "{ tokens { owner { id } } }", 30);
+    // The depth is of the whole query, not of each field
+    test(
+        model,
+        "{ tokens { id } pairs { token0 { owner { id } } } }",
+        44,
+    );
+    // Fragments do not add to the depth, but their contents do
+    test(
+        model,
+        "{ pairs { ...Tokens ... on Pair { id } } } fragment Tokens on Pair { token0 { id } }",
+        3,
+    );
+
+    // Also when only a when clause uses it
+    let model = "query { tokens } when $__depth > 2 => 2; default => 1;";
+    test(model, "{ tokens { owner { id } } }", 2);
+    test(model, "{ tokens { id } }", 1);
+}
+
+#[test]
+fn nodes() {
+    let model = "default => $__nodes;";
+
+    test(
+        model,
+        "{ users(first: 10) { id tokens(first: 5) { id } } }",
+        60,
+    );
+    // Sibling fields are summed
+    test(
+        model,
+        "{ users(first: 2) { a(first: 3) { id } b(first: 4) { id } } }",
+        16,
+    );
+    // Each top-level field has its own count
+    test(
+        model,
+        "{ users(first: 2) { id } pairs(first: 3) { id } }",
+        5,
+    );
+    // Fields without a selection set are not nodes
+    test(model, "{ users }", 0);
+    test(model, "{ users { id owner { id } } }", 2);
+    test(
+        model,
+        "{ users(first: 10) { ...Tokens } } fragment Tokens on User { tokens(first: 2) { id } }",
+        30,
+    );
+    test(
+        model,
+        ("query($n: Int) { users(first: $n) { id } }", "{\"n\": 3}"),
+        3,
+    );
+
+    let model = CostModel::compile(model, "")
+        .unwrap()
+        .with_default_fan_out(100);
+    test(model, "{ users { tokens(first: 5) { id } } }", 600);
+
+    // Also when only a when clause uses it
+    let model = "query { users } when $__nodes > 5 => 2; default => 1;";
+    test(model, "{ users(first: 10) { id } }", 2);
+    test(model, "{ users(first: 2) { id } }", 1);
+}
+
+#[test]
+fn max_fan_out() {
+    let text = "query { users } => $__nodes;";
+    let model = || CostModel::compile(text, "").unwrap().with_max_fanout(1000);
+
+    // A huge page is clamped at each level before multiplying down the tree
+    test(model(), "{ users(first: 1000000) { id } }", 1000);
+    test(
+        model(),
+        "{ users(first: 1000000) { id tokens(first: 1000000) { id } } }",
+        1001000,
+    );
+    test(
+        model(),
+        "{ users(first: 10) { id tokens(first: 1000000) { id } } }",
+        10010,
+    );
+    // Pages under the cap are unchanged
+    test(
+        model(),
+        "{ users(first: 10) { tokens(first: 5) { id } } }",
+        60,
+    );
+    test(text, "{ users(first: 1000000) { id } }", 1000000);
+    // Without a cap, counts larger than an Int are exact
+    test(
+        text,
+        "{ users(first: 1000000) { id tokens(first: 1000000) { id } } }",
+        1000001000000u64,
+    );
+
+    // The default fan-out is capped as well
+    let model = CostModel::compile(text, "")
+        .unwrap()
+        .with_default_fan_out(100)
+        .with_max_fanout(20);
+    test(model, "{ users { tokens { id } } }", 420);
+}
+
+#[test]
+fn multiplier() {
+    let text = "
+        query { pairs } => 10;
+        query { tokens } => 0.000000000000000003;
+    ";
+    let model = |multiplier: BigFraction| {
+        CostModel::compile(text, "")
+            .unwrap()
+            .with_multiplier(multiplier)
+    };
+    let wei = |wei: u32| BigUint::from(wei);
+
+    test(model(BigFraction::from(2)), "{ pairs }", 20);
+    test(
+        model(BigFraction::from(2)),
+        "{ pairs tokens }",
+        BigUint::from(20u32) * wei_to_grt() + wei(6),
+    );
+    test(model(BigFraction::new(1u8, 2u8)), "{ pairs }", 5);
+    // 1.5 wei is rounded up
+    test(model(BigFraction::new(1u8, 2u8)), "{ tokens }", wei(2));
+    test(model(BigFraction::new(3u8, 2u8)), "{ tokens }", wei(5));
+    test(text, "{ tokens }", wei(3));
+
+    // The maximum cost and budgets apply to the scaled cost
+    let capped = model(BigFraction::from(2)).with_max_cost(15u32 * wei_to_grt());
+    test(capped, "{ pairs }", 15);
+    let ten = 10u32 * wei_to_grt();
+    assert_eq!(
+        model(BigFraction::from(2)).within_budget("{ pairs }", "", &ten),
+        Ok(false)
+    );
+    assert_eq!(
+        model(BigFraction::new(1u8, 2u8)).within_budget("{ pairs }", "", &ten),
+        Ok(true)
+    );
+}
+
+#[test]
+fn introspection() {
+    let model = "
+        default when $__introspection => 1;
+        query { __typename } => 5;
+        default => 10;
+    ";
+
+    test(model, "{ __schema { types { name } } }", 1);
+    test(model, "{ __type(name: \"Pair\") { fields { name } } }", 1);
+    test(model, "{ pairs { id } }", 10);
+    test(model, "{ __schema { types { name } } pairs { id } }", 11);
+}
+
+#[test]
+fn default_captures() {
+    let model = "
+        query ($first: Int = 100) { users(first: $first) } when $first > 50 => $first;
+        query { users(skip: $skip) } => $skip;
+        default => 1;
+    ";
+
+    test(model, "{ users(first: 60) { id } }", 60);
+    test(model, "{ users { id } }", 100);
+    // Arguments without a default are still required
+    test(model, "{ users(first: 10) { id } }", 1);
+    test(
+        "query ($where: Boolean = true) { users { tokens(where: $where) } } when $where => 2;",
+        "{ users { tokens { id } } }",
+        2,
+    );
+}
+
+#[test]
+fn null_arguments() {
+    let model = "
+        query { users(first: null) } => 1;
+        query ($first: Int = 50) { users(first: $first) } when is_null($first) => 2;
+        query ($first: Int = 50) { users(first: $first) } when !is_null($first) => $first;
+    ";
+
+    test(model, "{ users(first: null) { id } }", 1);
+    test(model, "{ users { id } }", 50);
+    test(model, "{ users(first: 0) { id } }", 0);
+    test(model, "{ users(first: 5) { id } }", 5);
+    test(
+        "query { users(first: $first) } when is_null($first) => 2; default => 3;",
+        "{ users(first: null) { id } }",
+        2,
+    );
+    test(
+        "query { users(first: $first) } when is_null($first) => 2; default => 3;",
+        (
+            "query users($n: Int) { users(first: $n) { id } }",
+            "{\"n\": null}",
+        ),
+        2,
+    );
+    test(
+        "query { users(first: $first) } when is_null($first) => 2; default => 3;",
+        "{ users(first: 0) { id } }",
+        3,
+    );
+}
+
+#[test]
+fn defined_variables() {
+    let model = "
+        query { a } when defined($x) && $x > 5 => $x;
+        query { a } when !defined($x) || $x <= 5 => 2;
+        query { b(y: $y) } when defined($y) => 3;
+    ";
+
+    // The comparisons are not evaluated when $x is missing
+    test(model, "{ a }", 2);
+    test((model, "{\"x\": 10}"), "{ a }", 10);
+    test((model, "{\"x\": 1}"), "{ a }", 2);
+    // A capture is defined even if it is null
+    test(model, "{ b(y: null) }", 3);
+    test(model, "{ b(y: \"y\") }", 3);
+
+    // Without short circuiting, the missing variable is an error
+    test(
+        "query { a } when $x > 5 && defined($x) => $x;",
+        "{ a }",
+        CostError::StatementFail {
+            statement: 0,
+            error: EvalError::MissingVariable("x".to_owned()),
+        },
+    );
+}
+
+#[test]
+fn nested_selections() {
+    let model = "
+        query { users { tokens { id } } } => 1;
+        default => 2;
+    ";
+
+    test(model, "{ users { tokens { id } } }", 1);
+    // Fields which are not in the predicate do not prevent a match, at any depth
+    test(model, "{ users { name tokens { id symbol } } }", 1);
+    test(model, "{ users { tokens { id owner { id } } } }", 1);
+    // Every field of the predicate must be present, at the same depth
+    test(model, "{ users { tokens { symbol } } }", 2);
+    test(model, "{ users { tokens } }", 2);
+    test(model, "{ users { id } tokens { id } }", 4);
+    test(model, "{ users { balances { tokens { id } } } }", 2);
+}
+
+#[test]
+fn repeated_fields() {
+    let model = "
+        query { users } when $__count >= 3 => 100;
+        query { users } => $__count;
+        default => 1;
+    ";
+
+    test(model, "{ users { id } }", 1);
+    test(model, "{ a: users { id } b: users { id } }", 4);
+    test(
+        model,
+        "{ a: users { id } b: users { id } c: users { id } tokens }",
+        301,
+    );
+    // Fields are counted after expanding fragments, whether or not they are aliased
+    test(
+        model,
+        "fragment f on Query { users { name } } { users { id } ...f cheap: users { id } }",
+        300,
+    );
+}
+
+#[test]
+fn total_first() {
+    let model = "
+        query { users } when $__total_first > 100 => 100;
+        query { users } => $__total_first;
+        default => 1;
+    ";
+
+    test(model, "{ users(first: 10) { id } }", 10);
+    test(
+        model,
+        "{ a: users(first: 10) { id } b: users(first: 5) { id } }",
+        30,
+    );
+    // Fields without a `first` argument add nothing, and other fields are not summed
+    test(
+        model,
+        "{ a: users(first: 10) { id } b: users(first: 5) { id } c: users { id } tokens(first: 100) }",
+        46,
+    );
+    test(
+        model,
+        (
+            "query q($n: Int) { a: users(first: $n) { id } ...f } fragment f on Query { users(first: 5) { id } }",
+            "{ \"n\": 10 }",
+        ),
+        30,
+    );
+    test(
+        model,
+        "{ a: users(first: 100) { id } b: users(first: 1) { id } }",
+        200,
+    );
+    // Totals larger than an Int are exact
+    test(
+        "query { users } => $__total_first;",
+        "{ a: users(first: 2000000000) { id } b: users(first: 2000000000) { id } }",
+        4000000000u64,
+    );
+
+    let model = CostModel::compile(model, "").unwrap();
+    let explanation = model
+        .explain(
+            "{ a: users(first: 10) { id } b: users(first: 5) { id } }",
+            "",
+        )
+        .unwrap();
+    assert_eq!(explanation.matches[0].captures["__total_first"], "15");
+}
+
+#[test]
+fn aliases() {
+    let model = "
+        query { cheap: users } => 1;
+        query { users } when $__alias == \"expensive\" => 100;
+        query { users { id } } => 10;
+        default => 1000;
+    ";
+
+    test(model, "{ cheap: users { id } }", 1);
+    test(model, "{ expensive: users { id } }", 100);
+    test(model, "{ other: users { id } }", 10);
+    test(model, "{ users { id } }", 10);
+    test(model, "{ users }", 1000);
+    test(model, "{ cheap: tokens }", 1000);
+    // Nested aliases are matched in the same way
+    test(
+        "query { users { short: name } } => 1; default => 2;",
+        "{ users { short: name } }",
+        1,
+    );
+    test(
+        "query { users { short: name } } => 1; default => 2;",
+        "{ users { name } }",
+        2,
+    );
+}
+
+#[test]
+fn fragment_chains() {
+    let model = "query { users { id name } } => 1; default => 2;";
+
+    test(
+        model,
+        "{ users { ...UserFields } } \
+        fragment UserFields on User { id ...Named } \
+        fragment Named on User { name }",
+        1,
+    );
+    test(
+        model,
+        "{ users { ...UserFields } } fragment UserFields on User { id }",
+        2,
+    );
+}
+
+#[test]
+fn fragment_cycles() {
+    let model = "query { users { id name } } => 1; default => 2;";
+
+    test(
+        model,
+        "{ users { ...A } } \
+        fragment A on User { id ...B } \
+        fragment B on User { ...A }",
+        CostError::FragmentCycle("A".into()),
+    );
+    test(
+        model,
+        "{ ...A } fragment A on Query { users ...A }",
+        CostError::FragmentCycle("A".into()),
+    );
+}
+
+#[test]
+fn doubling_fragment_chains() {
+    // Each fragment spreads the next one twice, so expanding every spread in turn
+    // would select 2^40 copies of the last fragment.
+    let mut query = "{ users(first: 2) { ...F0 } }".to_owned();
+    for i in 0..40 {
+        query += &format!(" fragment F{} on User {{ ...F{} ...F{} }}", i, i + 1, i + 1);
+    }
+    query += " fragment F40 on User { id tokens(first: 3) { id } }";
+
+    let model = || {
+        CostModel::compile("query { users } => $__depth;", "")
+            .unwrap()
+            .with_max_depth(10)
+    };
+    let grt = |grt: u64| Ok(BigUint::from(grt) * wei_to_grt());
+    assert_eq!(model().cost(&query, ""), grt(3));
+    // 2 users, each with 3 tokens for each of the 2^40 copies
+    let model = || {
+        CostModel::compile("query { users } => $__nodes;", "")
+            .unwrap()
+            .with_max_nodes(u64::MAX)
+    };
+    assert_eq!(model().cost(&query, ""), grt(2 + 2 * 3 * (1u64 << 40)));
+
+    // The chain is checked for cycles and undefined fragments once
+    let cyclic = query.replace("fragment F40 on User {", "fragment F40 on User { ...F0");
+    assert_eq!(
+        model().cost(&cyclic, ""),
+        Err(CostError::FragmentCycle("F0".into()))
+    );
+}
+
+#[test]
+fn undefined_fragments() {
+    let model = "query { users { id name } } => 1; default => 2;";
+
+    // Fragments which are not spread are ignored
+    test(
+        model,
+        "{ users { id name } } fragment Unused on User { id ...Missing }",
+        1,
+    );
+    test(model, "{ users { ... { id } ... on User { name } } }", 1);
+
+    test(
+        model,
+        "{ ...Missing }",
+        CostError::UndefinedFragment("Missing".to_owned()),
+    );
+    // Even where no statement would expand the fragment
+    test(
+        model,
+        "{ tokens { id ...Missing } }",
+        CostError::UndefinedFragment("Missing".to_owned()),
+    );
+    test(
+        model,
+        "{ users { id name } pairs { ...Pair } } fragment Pair on Pair { ...Missing }",
+        CostError::UndefinedFragment("Missing".to_owned()),
+    );
+    assert_eq!(
+        format!("{}", CostError::UndefinedFragment("Missing".to_owned())),
+        "Undefined fragment Missing"
+    );
+}
+
+#[test]
+fn matching_object() {
+    let model = "
+        query { a(where: { age_gt: 18 }) } => 1;
+        query { a(where: $where) } => 2;
+        default => 3;
+    ";
+
+    test(model, "query { a(where: { age_gt: 18, non: 1 }) }", 1);
+    test(model, "query { a(where: { age_gt: 21 }) }", 2);
+    test(model, "query { a }", 3);
+}
+
+#[test]
+fn nested_object_captures() {
+    let model = "
+        query { pairs(where: { first: $n, status: ACTIVE }) } => $n * 2;
+        query { pairs(where: { token: { symbol: $symbol } }) } when $symbol == \"DAI\" => 5;
+        default => 1;
+    ";
+
+    test(
+        model,
+        "query { pairs(where: { status: ACTIVE, first: 10 }) }",
+        20,
+    );
+    test(
+        model,
+        "query { pairs(where: { first: 10, status: ACTIVE, skip: 5 }) }",
+        20,
+    );
+    test(
+        model,
+        "query { pairs(where: { token: { symbol: \"DAI\", id: 1 } }) }",
+        5,
+    );
+    // Missing keys
+    test(model, "query { pairs(where: { first: 10 }) }", 1);
+    test(model, "query { pairs(where: { token: { id: 1 } }) }", 1);
+    // Mismatched keys
+    test(
+        model,
+        "query { pairs(where: { first: 10, status: PAUSED }) }",
+        1,
+    );
+    test(model, "query { pairs(where: { token: \"DAI\" }) }", 1);
+}
+
+#[test]
+fn argument_presence() {
+    let model = "
+        query { tokens(where: _, first: $first) } => $first;
+        query { tokens(where: 1) } => 3;
+        query { tokens(where: _) } => 2;
+        query { pairs(where: { token: _ }) } => 4;
+        default => 5;
+    ";
+
+    test(
+        model,
+        "{ tokens(where: { owner: \"0x1\" }, first: 10) }",
+        10,
+    );
+    test(model, "{ tokens(where: { owner: \"0x1\" }) }", 2);
+    test(model, "{ tokens(where: null) }", 2);
+    test(model, "{ tokens(where: [1, 2]) }", 2);
+    test(model, "{ tokens(where: 1) }", 3);
+    test(model, "{ tokens(first: 10) }", 5);
+    test(model, "{ tokens }", 5);
+    test(model, "{ pairs(where: { token: \"DAI\", id: 1 }) }", 4);
+    test(model, "{ pairs(where: { id: 1 }) }", 5);
+    // The value is not captured
+    test(
+        "query { tokens(where: _) } => $where;",
+        "{ tokens(where: 1) }",
+        CostError::StatementFail {
+            statement: 0,
+            error: EvalError::MissingVariable("where".to_owned()),
+        },
+    );
+}
+
+#[test]
+fn matching_list() {
+    let model = "
+        query { a(val_in: [1, 2]) } => 1;
+        query { a(val_in: $in) } => 2;
+        default => 3;
+    ";
+
+    test(model, "query { a(val_in: [1, 2]) }", 1);
+    test(model, "query { a(val_in: [2, 3]) }", 2);
+    test(model, "query { a }", 3);
+}
+
+#[test]
+fn list_lengths() {
+    let model = "
+        query { tokens(ids: $ids) } when $ids > 100 => 1000;
+        query { tokens(ids: $ids) } => 0.5 * $ids;
+    ";
+
+    test(
+        model,
+        "query { tokens(ids: [\"x\", \"y\", \"z\"]) }",
+        BigUint::from(1500000000000000000u64),
+    );
+    test(model, "query { tokens(ids: []) }", 0);
+    test(model, "query { tokens(ids: [[1, 2], [3]]) }", 1);
+}
+
+#[test]
+fn fragments() {
+    let model = "
+        query { pairs(skip: $skip) { id reserveUSD } } => 1;
+        query { pairs(skip: $skip) { id } } => 2;
+        default => 3;
+    ";
+
+    let query_1 = "
+        {
+            pairs(skip: 1) { ...fields }
+        }
+        fragment fields on Name {
+            id, reserveUSD
+        }
+    ";
+
+    let query_2 = "
+        {
+            pairs(skip: 1) { ...fields }
+        }
+        fragment fields on Name {
+            id
+        }
+    ";
+
+    let query_3 = "
+        {
+            pairs(skip: 1) { ...fields }
+        }
+        fragment fields on Name {
+            reserveUSD
+        }
+    ";
+
+    test(model, query_1, 1);
+    test(model, query_2, 2);
+    test(model, query_3, 3);
+}
+
+#[test]
+fn invalid_query() {
+    test("default => 1;", "blah", CostError::FailedToParseQuery);
+}
+
+#[test]
+fn invalid_variables() {
+    test(
+        "default => 1;",
+        ("query { a }", "blah"),
+        CostError::FailedToParseVariables,
+    );
+}
+
+#[test]
+fn invalid_model() {
+    for case in &[
+        // Missing semicolon between 2 statements
+        "query { a } => 1 query { b } => 2;",
+        // Garbage data after valid statement
+        "query { a } => 1; garbage",
+    ] {
+        assert!(CostModel::compile(*case, "").is_err());
+    }
+}
+
+#[test]
+fn nested_query() {
+    let model = "
+        query { users { id tokens { id } } } => 1;
+        query { users { id } } => 2;
+        default => 3;
+    ";
+    test(model, "query { users { id tokens { id } } }", 1);
+    test(model, "query { users { id tokens { id and } } }", 1);
+    test(model, "query { users { id tokens { and } } }", 2);
+    test(
+        model,
+        "query { we { are { the { knights { who { say { ni } } } } } } }",
+        3,
+    );
+}
+
+#[test]
+fn query_not_costed() {
+    test("query { a } => 2;", "{ b }", CostError::QueryNotCosted);
+}
+
+#[test]
+fn div_by_zero_does_not_panic() {
+    test(
+        "default => 1 / 0;",
+        "{ a }",
+        CostError::StatementFail {
+            statement: 0,
+            error: EvalError::DivideByZero,
+        },
+    );
+}
+
+#[test]
+fn lossless_math() {
+    // If the cost model were implemented by truncating at each operation,
+    // the result would be 0.
+    test("default => 100 * (1 / 2);", "{ a }", 50);
+
+    // Underflows (below 0) temporarily
+    test(
+        "default => ((-1 / 2) * 5) + 5;",
+        "{ a }",
+        BigUint::from(2500000000000000000u64),
+    );
+}
+
+#[test]
+fn fractional_costs() {
+    // Fractions are kept until the final cost, so halves sum back to the whole.
+    let model = "query { a(first: $first) } => 0.5 * $first + 0.5 * $first;";
+    test(model, "{ a(first: 3) }", 3);
+    test(model, "{ a(first: 1) }", 1);
+
+    // Only the final cost is rounded, and it rounds up to the nearest wei.
+    test(
+        "default => 1 / 3;",
+        "{ a }",
+        BigUint::from(333333333333333334u64),
+    );
+}
+
+#[test]
+fn overflow_clamp() {
+    // Underflow
+    test("default => 100 - 200;", "{ a }", 0);
+    test("default => 115792089237316195423570985008687907853269984665640564039457584007913129639931 + 10;", "{ a }", MAX_COST.clone());
+}
+
+#[test]
+fn max_cost() {
+    let max_cost = BigUint::from(10u32) * wei_to_grt();
+    let model = || {
+        let text = "query { a(depth: $depth) } => 2 ^ $depth; default => 3;";
+        CostModel::compile(text, "")
+            .unwrap()
+            .with_max_cost(max_cost.clone())
+    };
+
+    test(model(), "{ a(depth: 200) }", 10);
+    test(model(), "{ a(depth: 3) }", 8);
+    test(model(), "{ b }", 3);
+    // The cap applies to the sum of all fields.
+    test(model(), "{ a(depth: 3) b }", 10);
+}
+
+#[test]
+fn arithmetic_mode() {
+    let ceiling = BigUint::from(u64::MAX);
+    let model = |mode| {
+        let text = "query { a(n: $n) } => $n * $n; default => 3;";
+        CostModel::compile(text, "")
+            .unwrap()
+            .with_max_cost(ceiling.clone())
+            .with_arithmetic_mode(mode)
+    };
+
+    // u64::MAX wei is a little over 18 GRT
+    test(model(ArithmeticMode::Checked), "{ a(n: 4) }", 16);
+    test(
+        model(ArithmeticMode::Checked),
+        "{ a(n: 5) }",
+        CostError::Overflow,
+    );
+    test(model(ArithmeticMode::Saturating), "{ a(n: 4) }", 16);
+    test(
+        model(ArithmeticMode::Saturating),
+        "{ a(n: 5) }",
+        ceiling.clone(),
+    );
+    test(model(ArithmeticMode::Checked), "{ b }", 3);
+}
+
+#[test]
+fn cost_u64() {
+    let model: CostModel = "
+        query { max } => 18446744073709551615 / 1000000000000000000;
+        query { over } => 18446744073709551616 / 1000000000000000000;
+        query { negative } => 1 - 2;
+        default => 1;
+    "
+    .parse::<CostModel>()
+    .unwrap()
+    .with_negative_cost_policy(NegativeCostPolicy::Allow);
+
+    assert_eq!(model.cost_u64("{ max }", ""), Ok(u64::MAX));
+    assert_eq!(model.cost_u64("{ over }", ""), Err(CostError::Overflow));
+    assert_eq!(model.cost_u64("{ negative }", ""), Err(CostError::Negative));
+    assert_eq!(model.cost_u64("{ a }", ""), Ok(1000000000000000000));
+    // The total is checked, rather than each field
+    assert_eq!(
+        model.cost_u64("{ negative a b }", ""),
+        Ok(1000000000000000000)
+    );
+    // The full cost is still available
+    assert_eq!(
+        model.cost("{ over }", ""),
+        Ok(BigUint::from(u64::MAX) + 1u32)
+    );
+    assert_eq!(model.cost("{ negative }", ""), Ok(BigUint::from(0u32)));
+}
+
+#[test]
+fn negative_cost_policy() {
+    let text = "
+        query { refund(amount: $amount) } => 1 - $amount;
+        query { pairs } => 2;
+        query { pairs } += -0.5;
+    ";
+    let model = |policy| {
+        CostModel::compile(text, "")
+            .unwrap()
+            .with_negative_cost_policy(policy)
+            .with_multiplier(BigFraction::from(2))
+    };
+    let grt = |grt: u64| BigUint::from(grt) * wei_to_grt();
+
+    // Clamped by default
+    let default = CostModel::compile(text, "").unwrap();
+    assert_eq!(
+        default.cost("{ refund(amount: 4) pairs }", ""),
+        Ok(grt(3) / 2u32)
+    );
+
+    let allow = model(NegativeCostPolicy::Allow);
+    // (1 - 2 + 1.5) * 2
+    assert_eq!(allow.cost("{ refund(amount: 2) pairs }", ""), Ok(grt(1)));
+    assert_eq!(allow.cost("{ refund(amount: 4) pairs }", ""), Ok(grt(0)));
+
+    let clamp = model(NegativeCostPolicy::ClampToZero);
+    assert_eq!(clamp.cost("{ refund(amount: 4) pairs }", ""), Ok(grt(3)));
+    assert_eq!(clamp.cost("{ refund(amount: 4) }", ""), Ok(grt(0)));
+    assert_eq!(clamp.cost("{ refund(amount: 1) }", ""), Ok(grt(0)));
+
+    let error = model(NegativeCostPolicy::Error);
+    assert_eq!(
+        error.cost("{ pairs refund(amount: 4) }", ""),
+        Err(CostError::Negative)
+    );
+    // Zero is not negative, and additive statements may be discounts
+    assert_eq!(error.cost("{ refund(amount: 1) pairs }", ""), Ok(grt(3)));
+}
+
+#[test]
+fn explain() {
+    let model: CostModel = "
+        @name(\"pages\") query { pairs(first: $first) } when $first > 10 => $first;
+        query { pairs } => 1;
+        default => 2;
+    "
+    .parse()
+    .unwrap();
+    let query = "{ pairs(first: 20) tokens }";
+
+    let explanation = model.explain(query, "").unwrap();
+    assert_eq!(explanation.cost, model.cost(query, "").unwrap());
+    let statements: Vec<_> = explanation
+        .matches
+        .iter()
+        .map(|m| (m.statement, m.name.as_deref()))
+        .collect();
+    assert_eq!(statements, vec![(0, Some("pages")), (2, None)]);
+    let pages = &explanation.matches[0];
+    assert_eq!(pages.cost_expr, "$first");
+    assert_eq!(pages.cost, BigFraction::from(20));
+    assert_eq!(pages.captures.get("first").map(String::as_str), Some("20"));
+    assert_eq!(
+        pages.captures.get("__alias").map(String::as_str),
+        Some("\"pairs\"")
+    );
+    assert_eq!(explanation.matches[1].captures.get("first"), None);
+
+    let report = explanation.to_string();
+    assert!(report.contains("Statement 0 (\"pages\") matched field pairs\n"));
+    assert!(report.contains("  cost: $first => 20 GRT\n"));
+    assert!(report.contains("  $first = 20\n"));
+    assert!(report.contains("Statement 2 matched field tokens\n"));
+    assert!(report.ends_with("Total: 22000000000000000000 wei"));
+
+    // Each statement which contributed is included
+    let explanation = model.explain_additive(query, "").unwrap();
+    let statements: Vec<_> = explanation.matches.iter().map(|m| m.statement).collect();
+    assert_eq!(statements, vec![0, 1, 2, 2]);
+    assert_eq!(explanation.cost, model.cost_additive(query, "").unwrap());
+
+    // The variables of the query are captured like they are when costing it
+    let query = "query pairs($first: Int) { pairs(first: $first) }";
+    let variables = "{ \"first\": 30 }";
+    let explanation = model.explain(query, variables).unwrap();
+    assert_eq!(explanation.cost, model.cost(query, variables).unwrap());
+    assert_eq!(explanation.matches[0].captures["first"], "30");
+    let explanation = model.explain_additive(query, variables).unwrap();
+    assert_eq!(
+        explanation.cost,
+        model.cost_additive(query, variables).unwrap()
+    );
+
+    assert_eq!(model.explain("{", ""), Err(CostError::FailedToParseQuery));
+    assert_eq!(
+        model.explain(query, "blah"),
+        Err(CostError::FailedToParseVariables)
+    );
+}
+
+#[test]
+fn cost_operation() {
+    let model: CostModel = "query { pairs } => 1; query { tokens } => 10; default => 100;"
+        .parse()
+        .unwrap();
+    let document = "
+        query Pairs { pairs { ...Id } }
+        query Tokens { tokens { ...Id } swaps { id } }
+        fragment Id on Entity { id }
+    ";
+    let cost = |name| model.cost_operation(document, name);
+    let grt = |n: u64| -> Result<BigUint, CostError> { Ok(BigUint::from(n) * wei_to_grt()) };
+
+    assert_eq!(cost(Some("Pairs")), grt(1));
+    assert_eq!(cost(Some("Tokens")), grt(110));
+    assert_eq!(cost(Some("Swaps")), Err(CostError::OperationNotFound));
+    assert_eq!(cost(None), Err(CostError::AmbiguousOperation));
+    // The only operation need not be named
+    assert_eq!(model.cost_operation("{ tokens }", None), grt(10));
+    assert_eq!(
+        model.cost_operation("{ tokens }", Some("Tokens")),
+        Err(CostError::OperationNotFound)
+    );
+    assert_eq!(
+        model.cost_operation("fragment Id on Entity { id }", None),
+        Err(CostError::OperationNotFound)
+    );
+}
+
+#[test]
+fn cost_all() {
+    let model: CostModel = "
+        query { pairs(first: $first) } => $first;
+        query { pairs } => 5;
+        query { tokens } when $__selections > 5 => 100;
+        query { tokens } => 2;
+        default => 1;
+    "
+    .parse()
+    .unwrap();
+    let grt = |n: u64| Some(BigUint::from(n) * wei_to_grt());
+
+    assert_eq!(
+        model.cost_all("{ pairs(first: 3) tokens swaps }", ""),
+        Ok(vec![grt(3), grt(5), None, grt(2), grt(3)])
+    );
+
+    // Fields which no statement matches are not an error
+    let model: CostModel = "query { pairs } => 5;".parse().unwrap();
+    assert_eq!(model.cost_all("{ tokens }", ""), Ok(vec![None]));
+    assert_eq!(model.cost_all("{ pairs tokens }", ""), Ok(vec![grt(5)]));
+}
+
+#[test]
+fn most_specific() {
+    let model: CostModel = "
+        query { pairs } => 1;
+        default => 100;
+        query { pairs(first: $first) { id } } => 3;
+        query { pairs(first: $first) } when $first > 10 => 4;
+        query { pairs(first: $first) } => 2;
+        query { _(first: $first) } => 5;
+    "
+    .parse()
+    .unwrap();
+
+    let cost = |query| model.cost_most_specific(query, "");
+    let grt = |n: u64| -> Result<BigUint, CostError> { Ok(BigUint::from(n) * wei_to_grt()) };
+
+    assert_eq!(cost("{ pairs }"), grt(1));
+    assert_eq!(cost("{ tokens }"), grt(100));
+    assert_eq!(cost("{ pairs(first: 5) { id } }"), grt(3));
+    assert_eq!(cost("{ pairs(first: 50) }"), grt(4));
+    // The when clause and the selection are equally specific, so the first wins
+    assert_eq!(cost("{ pairs(first: 50) { id } }"), grt(3));
+    assert_eq!(cost("{ pairs(first: 5) }"), grt(2));
+    assert_eq!(cost("{ tokens(first: 5) }"), grt(5));
+    // Versus the first match
+    assert_eq!(model.cost("{ pairs(first: 5) { id } }", ""), grt(1));
+
+    assert_eq!(model.document().statements[0].specificity(), 1);
+    assert_eq!(model.document().statements[1].specificity(), 0);
+    assert_eq!(model.document().statements[2].specificity(), 3);
+    assert_eq!(model.document().statements[5].specificity(), 1);
+}
+
+#[test]
+fn most_specific_ties() {
+    let text = "
+        query { pairs(first: $first) } => 1;
+        query { pairs(skip: $skip) } => 2;
+        query { _(first: $first, skip: $skip) } => 3;
+        query { pairs } when true => 4;
+        default => 100;
+    ";
+    let grt = |n: u64| -> Result<BigUint, CostError> { Ok(BigUint::from(n) * wei_to_grt()) };
+
+    // The first of the equally specific statements is chosen, however many
+    // times the model is compiled and costed
+    for _ in 0..20 {
+        let model = CostModel::compile(text, "").unwrap();
+        for _ in 0..5 {
+            assert_eq!(
+                model.cost_most_specific("{ pairs(first: 1, skip: 2) }", ""),
+                grt(1)
+            );
+            assert_eq!(model.cost_most_specific("{ pairs(skip: 2) }", ""), grt(2));
+            assert_eq!(model.cost_most_specific("{ pairs }", ""), grt(4));
+        }
+    }
+
+    let model = CostModel::compile(text, "").unwrap();
+    let statements = &model.document().statements;
+    assert_eq!(
+        statements[0].cmp_specificity(&statements[1]),
+        Ordering::Equal
+    );
+    assert_eq!(
+        statements[0].cmp_specificity(&statements[3]),
+        Ordering::Equal
+    );
+    assert_eq!(
+        statements[2].cmp_specificity(&statements[0]),
+        Ordering::Equal
+    );
+    assert_eq!(
+        statements[4].cmp_specificity(&statements[0]),
+        Ordering::Less
+    );
+    assert_eq!(
+        statements[0].cmp_specificity(&statements[4]),
+        Ordering::Greater
+    );
+}
+
+#[test]
+fn max_depth() {
+    let model = || {
+        CostModel::compile("query { tokens } => 1; default => 2;", "")
+            .unwrap()
+            .with_max_depth(3)
+    };
+    let exceeded = |depth| CostError::DepthExceeded { depth, limit: 3 };
+
+    test(model(), "{ tokens { owner { id } } }", 1);
+    test(
+        model(),
+        "{ tokens { owner { tokens { id } } } }",
+        exceeded(4),
+    );
+    // The depth is checked before costing, so fields which are not costed don't matter
+    test(model(), "{ a { b { c { d } } } }", exceeded(4));
+    // Fragments are expanded
+    test(
+        model(),
+        "{ tokens { ...Owner } } fragment Owner on Token { owner { tokens { id } } }",
+        exceeded(4),
+    );
+    test(model(), "{ tokens { owner { id } } pairs { id } }", 3);
+    // An operation which is too deep rejects the whole query
+    test(
+        model(),
+        "query A { tokens } query B { a { b { c { d } } } }",
+        exceeded(4),
+    );
+}
+
+#[test]
+fn assess() {
+    let model = CostModel::compile(
+        "query { tokens } => 1; query { pairs } when $n => 1; default => 2;",
+        "",
+    )
+    .unwrap()
+    .with_max_depth(3)
+    .with_max_nodes(100)
+    .with_max_cost(BigUint::from(4u32) * wei_to_grt());
+
+    assert_eq!(
+        model.assess("{ tokens { id } a }"),
+        Ok(BigUint::from(3u32) * wei_to_grt())
+    );
+    assert_eq!(
+        model.assess("{ tokens { owner { tokens { id } } } }"),
+        Err(RejectReason::DepthExceeded { depth: 4, limit: 3 })
+    );
+    // 10 tokens, and 10 owners for each
+    assert_eq!(
+        model.assess("{ tokens(first: 10) { owner(first: 10) { id } } }"),
+        Err(RejectReason::NodesExceeded {
+            nodes: 110,
+            limit: 100
+        })
+    );
+    assert_eq!(
+        model.assess("{ a { id } b { id } c { id } }"),
+        Err(RejectReason::CostExceeded {
+            limit: BigUint::from(4u32) * wei_to_grt()
+        })
+    );
+    // Costing stops once over the maximum, so the failing statement is not evaluated
+    assert_eq!(
+        model.assess("{ a b c pairs }"),
+        Err(RejectReason::CostExceeded {
+            limit: BigUint::from(4u32) * wei_to_grt()
+        })
+    );
+    assert_eq!(model.assess("{ tokens"), Err(RejectReason::ParseError));
+    assert!(matches!(
+        model.assess("{ pairs }"),
+        Err(RejectReason::CostError(CostError::StatementFail { .. }))
+    ));
+
+    // The limits also apply to cost, which caps the cost rather than rejecting it
+    assert_eq!(
+        model.cost("{ tokens(first: 10) { owner(first: 10) { id } } }", ""),
+        Err(CostError::NodesExceeded {
+            nodes: 110,
+            limit: 100,
+        })
+    );
+    assert_eq!(
+        model.cost("{ a b c }", ""),
+        Ok(BigUint::from(4u32) * wei_to_grt())
+    );
+}
+
+#[test]
+fn statement_iter() {
+    let mut text = String::new();
+    for i in 1..500 {
+        text.push_str(&format!("query {{ a(id: {}) }} => {};\n", i, i));
+    }
+    text.push_str("query { a } => ;\n");
+    text.push_str("default => 1;\n");
+
+    let mut statements = StatementIter::new(&text);
+    for _ in 1..500 {
+        assert!(matches!(statements.next(), Some(Ok(_))));
+    }
+    let error = statements.next().unwrap().unwrap_err();
+    // The error is reported against the whole model
+    let span = error.span().unwrap();
+    assert_eq!(&text[span.start..], "query { a } => ;\ndefault => 1;\n");
+    assert!(statements.next().is_none());
+
+    assert!(StatementIter::new("").next().is_none());
+}
+
+#[test]
+fn from_sources() {
+    let sources = |second: &'static str| {
+        vec![
+            (
+                "prices.agora".to_owned(),
+                "let page = 2;\nquery { a } => $page;",
+            ),
+            ("tokens.agora".to_owned(), second),
+            ("default.agora".to_owned(), "default => 1;"),
+        ]
+    };
+
+    let model = CostModel::from_sources(&sources("query { b } => $page * 3;")).unwrap();
+    let origins: Vec<_> = model
+        .document()
+        .statements
+        .iter()
+        .map(|s| s.source.as_deref())
+        .collect();
+    assert_eq!(
+        origins,
+        vec![
+            Some("prices.agora"),
+            Some("tokens.agora"),
+            Some("default.agora")
+        ]
+    );
+    test(model, "{ a b c }", 9);
+
+    let second = "query { b } => 1;\nquery { c } => ;";
+    let error = match CostModel::from_sources(&sources(second)) {
+        Err(CompileError::InSource(source, error)) => {
+            assert_eq!(source, "tokens.agora");
+            error
+        }
+        _ => panic!("Expected an error in tokens.agora"),
+    };
+    // The span is within the text of the source
+    let span = error.span().unwrap();
+    assert_eq!(&second[span.start..], "query { c } => ;");
+}
+
+#[test]
+fn validate() {
+    let unbound = |statement, name: &str| ValidationError::UnboundVariable {
+        statement,
+        name: name.to_owned(),
+    };
+
+    let model = CostModel::compile(
+        "
+        query { a(first: $first) } when $first > $LIMIT => $first;
+        query { b(first: $first) } when $missing > 0 || !$flag => $first * $other;
+        default => $__selections + $missing;
+        ",
+        "{ \"LIMIT\": 100 }",
+    )
+    .unwrap();
+    assert_eq!(
+        model.validate(),
+        Err(vec![
+            unbound(1, "missing"),
+            unbound(1, "flag"),
+            unbound(1, "other"),
+            unbound(2, "missing"),
+        ])
+    );
+
+    let model = CostModel::compile(
+        "query { a(first: $first, name: $name) } when $name == \"x\" => $first * $PRICE;",
+        "{ \"PRICE\": 2 }",
+    )
+    .unwrap();
+    assert_eq!(model.validate(), Ok(()));
+}
+
+#[test]
+fn within_budget() {
+    let model: CostModel = "
+        query { pairs(first: $first) } => $first;
+        query { tokens } => 5;
+    "
+    .parse()
+    .unwrap();
+    let within = |query: &str, grt: u64| {
+        model.within_budget(query, "", &(BigUint::from(grt) * wei_to_grt()))
+    };
+
+    assert_eq!(within("{ pairs(first: 10) }", 10), Ok(true));
+    assert_eq!(within("{ pairs(first: 11) }", 10), Ok(false));
+    assert_eq!(within("{ pairs(first: 5) tokens }", 10), Ok(true));
+    assert_eq!(within("{ pairs(first: 5) tokens tokens }", 10), Ok(false));
+    // Costing stops once the query is over budget, so the uncosted field is never reached.
+    assert_eq!(within("{ pairs(first: 100) swaps }", 10), Ok(false));
+    assert_eq!(
+        within("{ pairs(first: 1) swaps }", 10),
+        Err(CostError::QueryNotCosted)
+    );
+}
+
+#[test]
+fn normalize_queries() {
+    let a = "
+        query Pairs {
+            # The first page
+            first: pairs(skip: 0, first: 10, where: { b: 1, a: 2 }) { id }
+        }
+    ";
+    let b = "{ pairs(first: 10, where: { a: 2, b: 1 }, skip: 0) { id } }";
+    assert_eq!(normalize_query(a), normalize_query(b));
+    assert_ne!(
+        normalize_query(a),
+        normalize_query("{ pairs(first: 10) { id } }")
+    );
+    assert_eq!(normalize_query("{"), Err(CostError::FailedToParseQuery));
+}
+
+#[test]
+fn query_fingerprints() {
+    let fingerprint = |query: &str| query_fingerprint(query, None).unwrap();
+
+    // Formatting, the order of arguments, aliases and the names of operations and
+    // variables are ignored
+    let same = [
+        "{ pairs(first: 10, skip: 0) { id } }",
+        "query Pairs { page: pairs(skip: 0, first: 10) { id } }",
+        "
+        # The first page
+        {
+            pairs(first: 10, skip: 0) {
+                id
+            }
+        }
+        ",
+    ];
+    for query in same.iter() {
+        assert_eq!(fingerprint(*query), fingerprint(same[0]), "{}", query);
+    }
+    assert_eq!(
+        fingerprint("query q($a: Int, $b: Int) { pairs(first: $a, skip: $b) { id } }"),
+        fingerprint("query r($x: Int, $y: Int) { pairs(first: $x, skip: $y) { id } }"),
+    );
+
+    let different = [
+        "{ pairs(first: 10, skip: 0) { id } }",
+        "{ pairs(first: 11, skip: 0) { id } }",
+        "{ pairs(first: 10, skip: 0) { name } }",
+        "{ pairs(first: 10) { id } }",
+        "{ pairs(first: \"10\", skip: 0) { id } }",
+        "{ tokens(first: 10, skip: 0) { id } }",
+        "{ pairs(first: 10, skip: 0) { id } tokens }",
+        "{ pairs(first: 10, skip: 0) { id name } }",
+        "{ pairs(first: 10, skip: 0) { id @skip(if: true) } }",
+        "mutation { pairs(first: 10, skip: 0) { id } }",
+        "query q($a: Int, $b: Int) { pairs(first: $a, skip: $b) { id } }",
+        "query q($a: Int) { pairs(first: $a, skip: $a) { id } }",
+    ];
+    let mut fingerprints: Vec<_> = different.iter().map(|query| fingerprint(*query)).collect();
+    fingerprints.sort_unstable();
+    fingerprints.dedup();
+    assert_eq!(fingerprints.len(), different.len());
+
+    // The values of variables are only used if they are given
+    let query = "query q($n: Int) { pairs(first: $n) { id } }";
+    assert_eq!(
+        query_fingerprint(query, Some("{ \"n\": 10 }")),
+        query_fingerprint("{ pairs(first: 10) { id } }", None)
+    );
+    assert_ne!(
+        query_fingerprint(query, Some("{ \"n\": 10 }")),
+        query_fingerprint(query, Some("{ \"n\": 11 }"))
+    );
+    assert_eq!(
+        query_fingerprint("{", None),
+        Err(CostError::FailedToParseQuery)
+    );
+    assert_eq!(
+        query_fingerprint(query, Some("{")),
+        Err(CostError::FailedToParseVariables)
+    );
+}
+
+#[test]
+fn cache() {
+    let model: CostModel = "query { pairs(first: $first) } => $first;"
+        .parse::<CostModel>()
+        .unwrap()
+        .with_cache(1);
+    let expect = |grt: u64| Ok(BigUint::from(grt) * wei_to_grt());
+    let stats = |hits, misses| Some(CacheStats { hits, misses });
+
+    assert_eq!(model.cost("{ pairs(first: 5) { id } }", ""), expect(5));
+    assert_eq!(model.cache_stats(), stats(0, 1));
+
+    // Formatting, and the names of operations and variables, are ignored.
+    assert_eq!(model.cost("{pairs(first:5){id}}", ""), expect(5));
+    assert_eq!(
+        model.cost("query a($x: Int) { pairs(first: $x) { id } }", "{\"x\": 5}"),
+        expect(5)
+    );
+    assert_eq!(
+        model.cost("query b($y: Int) { pairs(first: $y) { id } }", "{\"y\": 5}"),
+        expect(5)
+    );
+    assert_eq!(model.cache_stats(), stats(3, 1));
+
+    // Different values for the variables are different queries.
+    assert_eq!(
+        model.cost("query a($x: Int) { pairs(first: $x) { id } }", "{\"x\": 6}"),
+        expect(6)
+    );
+    assert_eq!(model.cache_stats(), stats(3, 2));
+
+    // With a capacity of 1, the first query was evicted.
+    assert_eq!(model.cost("{ pairs(first: 5) { id } }", ""), expect(5));
+    assert_eq!(model.cache_stats(), stats(3, 3));
+
+    // Failures are remembered too, but queries which fail to parse are not.
+    assert_eq!(model.cost("{ tokens }", ""), Err(CostError::QueryNotCosted));
+    assert_eq!(model.cost("{ tokens }", ""), Err(CostError::QueryNotCosted));
+    assert_eq!(model.cost("{", ""), Err(CostError::FailedToParseQuery));
+    assert_eq!(model.cache_stats(), stats(4, 4));
+
+    // Arguments may be given in any order.
+    assert_eq!(
+        model.cost("{ pairs(skip: 1, first: 5) { id } }", ""),
+        expect(5)
+    );
+    assert_eq!(
+        model.cost("{ pairs(first: 5, skip: 1) { id } }", ""),
+        expect(5)
+    );
+    assert_eq!(model.cache_stats(), stats(5, 5));
+
+    // Variables without values keep their names, which the errors report.
+    assert_eq!(
+        model.cost("query a($x: Int) { pairs(first: $x) { id } }", ""),
+        Err(CostError::MissingVariable("x".to_owned()))
+    );
+    assert_eq!(
+        model.cost("query a($y: Int) { pairs(first: $y) { id } }", ""),
+        Err(CostError::MissingVariable("y".to_owned()))
+    );
+    assert_eq!(model.cache_stats(), stats(5, 7));
+
+    let uncached: CostModel = "default => 1;".parse().unwrap();
+    assert_eq!(uncached.cache_stats(), None);
+}
+
+#[test]
+fn capture_types() {
+    let mut captures = Captures::new();
+    captures.insert("first", 10);
+    captures.insert("symbol", "T1".to_owned());
+
+    assert_eq!(
+        captures.get_as::<String>("symbol"),
+        Ok(Some("T1".to_owned()))
+    );
+    assert_eq!(captures.get_as::<bool>("missing"), Ok(None));
+    let err = captures.get_as::<String>("first").unwrap_err();
+    assert_eq!(err.name, "first");
+    assert_eq!(err.found, "Int");
+    assert_eq!(err.expected, std::any::type_name::<String>());
+
+    let first = captures.get_cloned("first").unwrap();
+    assert_eq!(format!("{}", first), "10");
+    assert_eq!(captures.get_cloned("missing"), None);
+}
+
+#[test]
+fn shared_context() {
+    let models: Vec<CostModel> = vec![
+        "query { pairs(first: $first) { token0 { id } } } => $first; default => 1;",
+        "query { pairs { id } } => 2; query { tokens { id } } => 5; default => 3;",
+    ]
+    .into_iter()
+    .map(|m| m.parse().unwrap())
+    .collect();
+    let query = "
+        { pairs(first: 10) { ...Pair } tokens { ...Token } }
+        fragment Pair on Pair { id token0 { ...Token } }
+        fragment Token on Token { id }
+    ";
+
+    // The query and its fragments are parsed once for all of the models
+    let mut context: Context<&str> = Context::new(query, "").unwrap();
+    for _ in 0..2 {
+        let costs: Vec<_> = models
+            .iter()
+            .map(|model| model.cost_with_context(&mut context).unwrap())
+            .collect();
+        assert_eq!(costs, vec![wei_to_grt() * 11u32, wei_to_grt() * 7u32]);
+    }
+    for model in models.iter() {
+        assert_eq!(model.cost(query, ""), model.cost_with_context(&mut context));
+    }
+
+    // Fragments which are out of order are still found
+    context.fragments.reverse();
+    for model in models.iter() {
+        assert_eq!(model.cost(query, ""), model.cost_with_context(&mut context));
+    }
+}
+
+#[test]
+fn captures() {
+    let model: CostModel = "
+        query { pairs(first: $first, where: { name: $name, ids: $ids }) } => 1;
+    "
+    .parse()
+    .unwrap();
+    let mut context: Context<&str> = Context::new(
+        "{ pairs(first: 10, where: { name: \"a\", ids: [1, 2] }) { id } }",
+        "",
+    )
+    .unwrap();
+    model.cost_with_context(&mut context).unwrap();
+
+    let captures: BTreeMap<_, _> = context.captures().iter().collect();
+    assert_eq!(captures.get("first"), Some(&CaptureValue::Int(10)));
+    assert_eq!(captures.get("name"), Some(&CaptureValue::String("a")));
+    assert_eq!(
+        captures.get("ids"),
+        Some(&CaptureValue::List(vec![
+            CaptureValue::Int(1),
+            CaptureValue::Int(2)
+        ]))
+    );
+    assert_eq!(
+        captures.get("__alias"),
+        Some(&CaptureValue::String("pairs"))
+    );
 
-    test(model, (query, variables), 1);
+    let snapshot = context.captures().debug_snapshot();
+    let names: Vec<_> = snapshot.keys().map(String::as_str).collect();
+    assert_eq!(
+        names,
+        [
+            "__alias",
+            "__deferred",
+            "__introspection",
+            "__operation",
+            "__selections",
+            "first",
+            "ids",
+            "name"
+        ]
+    );
+    assert_eq!(snapshot["first"], "10");
+    assert_eq!(snapshot["name"], "\"a\"");
+    assert_eq!(snapshot["ids"], "[1, 2]");
 }
 
 #[test]
-fn default() {
-    let query = "query { nonsense }";
-    let model = "query { abc } => 2; default => 10;";
-    test(model, query, 10);
+fn cost_batch() {
+    let model: CostModel = "
+        query { pairs(first: $first) } => $first;
+        query { tokens } => 5;
+    "
+    .parse()
+    .unwrap();
+    let queries: Vec<String> = (0..100)
+        .map(|i| match i % 3 {
+            0 => format!("{{ pairs(first: {}) }}", i),
+            1 => "{ tokens }".to_owned(),
+            _ => "{ swaps }".to_owned(),
+        })
+        .collect();
+    let queries: Vec<&str> = queries.iter().map(String::as_str).collect();
+
+    let sequential: Vec<_> = queries.iter().map(|query| model.cost(query, "")).collect();
+    assert_eq!(model.cost_batch(&queries), sequential);
 }
 
 #[test]
-fn matching_object() {
-    let model = "
-        query { a(where: { age_gt: 18 }) } => 1;
-        query { a(where: $where) } => 2;
-        default => 3;
+fn cost_fast() {
+    let text = "
+        query { pairs(ids: $ids) } => 10;
+        query { tokens(first: $first) } => $first;
+        default => 1;
     ";
+    let model = CostModel::compile(text, "").unwrap();
+    let fast = CostModel::compile(text, "")
+        .unwrap()
+        .with_fast_path(1, wei_to_grt());
+
+    // The fixed cost is that of the default, so both paths agree either side of the threshold
+    for query in &[
+        "{ swaps }",
+        "{ a: swaps b: swaps }",
+        "query { ...F } fragment F on Query { swaps }",
+    ] {
+        assert_eq!(fast.cost_fast(query), model.cost(query, ""), "{}", query);
+    }
+    // But it does not match statements
+    assert_eq!(fast.cost_fast("{ tokens(first: 5) }"), Ok(wei_to_grt()));
+    assert_eq!(
+        model.cost("{ tokens(first: 5) }", ""),
+        Ok(BigUint::from(5u32) * wei_to_grt())
+    );
 
-    test(model, "query { a(where: { age_gt: 18, non: 1 }) }", 1);
-    test(model, "query { a(where: { age_gt: 21 }) }", 2);
-    test(model, "query { a }", 3);
+    // Queries over the threshold, or with list arguments, are costed in full
+    for query in &[
+        "{ tokens(first: 5) { id } }",
+        "{ pairs(ids: [1, 2]) }",
+        "{ swaps(where: { id_in: [1] }) }",
+        "query { ...F } fragment F on Query { pairs(ids: [1]) }",
+    ] {
+        assert_eq!(fast.cost_fast(query), model.cost(query, ""), "{}", query);
+    }
+    assert_eq!(
+        fast.cost_fast("{ pairs(ids: [1, 2]) }"),
+        Ok(BigUint::from(10u32) * wei_to_grt())
+    );
+
+    // Without a fast path, every query is costed in full
+    assert_eq!(
+        model.cost_fast("{ tokens(first: 5) }"),
+        Ok(BigUint::from(5u32) * wei_to_grt())
+    );
+    assert_eq!(
+        fast.cost_fast("{ swaps"),
+        Err(CostError::FailedToParseQuery)
+    );
 }
 
 #[test]
-fn matching_list() {
+fn lint() {
+    let lint = |model: &str| CostModel::compile(model, "").unwrap().lint();
+    let unreachable = |index, shadowed_by| LintWarning::UnreachableStatement { index, shadowed_by };
+
+    let ordered = "
+        query { tokens(first: 10) { id } } => 1;
+        query { tokens(first: $first) } when $first > 100 => 2;
+        query { tokens(first: $first) } => 3;
+        query { tokens } => 4;
+        query { pairs @paginate(limit: $limit) } => $limit;
+        query { pairs } => 5;
+        query { pairs } += 1;
+        default => 6;
+    ";
+    assert_eq!(lint(ordered), vec![]);
+
     let model = "
-        query { a(val_in: [1, 2]) } => 1;
-        query { a(val_in: $in) } => 2;
-        default => 3;
+        query { tokens(first: $first) } => 1;
+        query { tokens(first: 10, skip: 5) { id } } => 2;
+        query { tokens(first: $first) } when $first > 100 => 3;
+        query { _(where: _) } => 4;
+        query { pairs(where: { id: 1 }) @paginate(limit: 5) } => 5;
+        query { tokens } => 6;
+        query { users } += 1;
+        default => 7;
+        query { users } => 8;
     ";
+    assert_eq!(
+        lint(model),
+        vec![
+            unreachable(1, 0),
+            unreachable(2, 0),
+            unreachable(4, 3),
+            unreachable(8, 7),
+        ]
+    );
 
-    test(model, "query { a(val_in: [1, 2]) }", 1);
-    test(model, "query { a(val_in: [2, 3]) }", 2);
-    test(model, "query { a }", 3);
+    // A capture with a default also matches fields which leave out the argument
+    let defaults = "
+        query { tokens(first: $first) } => 1;
+        query ($first: Int = 10) { tokens(first: $first) } => 2;
+        query ($n: Int = 10) { tokens(first: $n) } => 3;
+    ";
+    assert_eq!(lint(defaults), vec![unreachable(2, 1)]);
+
+    assert_eq!(
+        format!("{}", unreachable(2, 0)),
+        "Statement 2 is unreachable, because statement 0 matches every field that it does"
+    );
 }
 
 #[test]
-fn fragments() {
-    let model = "
-        query { pairs(skip: $skip) { id reserveUSD } } => 1;
-        query { pairs(skip: $skip) { id } } => 2;
-        default => 3;
+fn strict() {
+    let text = "
+        query { tokens(first: $first) } when $first <= 100 => 1;
+        query { pairs } => 2;
+        default => 10;
     ";
+    let strict = || CostModel::compile(text, "").unwrap().strict();
+    let not_allowed = |field: &str| CostError::NotAllowed(field.to_owned());
 
-    let query_1 = "
-        {
-            pairs(skip: 1) { ...fields }
-        }
-        fragment fields on Name {
-            id, reserveUSD
-        }
-    ";
+    test(strict(), "{ pairs }", 2);
+    test(strict(), "{ tokens(first: 10) pairs }", 3);
+    // Only matched by the default statement
+    test(strict(), "{ tokens(first: 1000) }", not_allowed("tokens"));
+    test(
+        strict(),
+        "{ pairs big: tokens(first: 1000) }",
+        not_allowed("big"),
+    );
+    test(strict(), "{ swaps }", not_allowed("swaps"));
+    test(text, "{ swaps }", 10);
 
-    let query_2 = "
-        {
-            pairs(skip: 1) { ...fields }
-        }
-        fragment fields on Name {
-            id
-        }
-    ";
+    // Without a default statement
+    let text = "query { pairs } => 2;";
+    test(
+        CostModel::compile(text, "").unwrap().strict(),
+        "{ swaps }",
+        not_allowed("swaps"),
+    );
+    test(text, "{ swaps }", CostError::QueryNotCosted);
+
+    // Additive default statements add to the cost of allowed fields
+    let model = || {
+        CostModel::compile("query { pairs } => 2; default += 1;", "")
+            .unwrap()
+            .strict()
+    };
+    test(model(), "{ pairs }", 3);
+    test(model(), "{ swaps }", not_allowed("swaps"));
+
+    // The fast path does not bypass the allowlist
+    let model = strict().with_fast_path(10, wei_to_grt());
+    assert_eq!(model.cost_fast("{ swaps }"), Err(not_allowed("swaps")));
+    assert_eq!(
+        model.cost_fast("{ pairs }"),
+        Ok(BigUint::from(2u32) * wei_to_grt())
+    );
+}
 
-    let query_3 = "
-        {
-            pairs(skip: 1) { ...fields }
-        }
-        fragment fields on Name {
-            reserveUSD
-        }
-    ";
+#[cfg(feature = "tokio")]
+#[test]
+fn cost_spawn_blocking() {
+    let model =
+        std::sync::Arc::new(CostModel::compile("query { pairs } => 2; default => 1;", "").unwrap());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let costs = runtime.block_on(async {
+        let pairs = model.clone().cost_spawn_blocking("{ pairs }".to_owned());
+        let tokens = model.clone().cost_spawn_blocking("{ tokens }".to_owned());
+        (pairs.await, tokens.await)
+    });
+    assert_eq!(
+        costs,
+        (Ok(BigUint::from(2u32) * wei_to_grt()), Ok(wei_to_grt()))
+    );
+    assert_eq!(
+        runtime.block_on(model.cost_spawn_blocking("{ pairs".to_owned())),
+        Err(CostError::FailedToParseQuery)
+    );
+}
 
-    test(model, query_1, 1);
-    test(model, query_2, 2);
-    test(model, query_3, 3);
+#[test]
+fn send_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<CostModel>();
+    assert_send_sync::<Document<'static>>();
+    assert_send_sync::<Context<'static, String>>();
+    assert_send_sync::<CostError>();
+    assert_send_sync::<CompileError>();
+    assert_send_sync::<RejectReason>();
+    assert_send_sync::<CostExplanation>();
+    assert_send_sync::<CacheStats>();
 }
 
 #[test]
-fn invalid_query() {
-    test("default => 1;", "blah", CostError::FailedToParseQuery);
+fn query_variables() {
+    let model: CostModel = "
+        query { pairs(first: $first) } when $first > 100 => 10;
+        query { pairs } => 1;
+    "
+    .parse()
+    .unwrap();
+    let query = "query pairs($first: Int) { pairs(first: $first) { id } }";
+    let cost = |variables: serde_json::Value| model.cost_with_variables(query, &variables);
+    let expect = |grt: u64| Ok(BigUint::from(grt) * wei_to_grt());
+
+    assert_eq!(cost(serde_json::json!({ "first": 10 })), expect(1));
+    assert_eq!(cost(serde_json::json!({ "first": 1000 })), expect(10));
+    assert_eq!(
+        cost(serde_json::Value::Null),
+        Err(CostError::MissingVariable("first".to_owned()))
+    );
+    assert_eq!(
+        cost(serde_json::json!([1000])),
+        Err(CostError::FailedToParseVariables)
+    );
 }
 
 #[test]
-fn invalid_variables() {
-    test(
-        "default => 1;",
-        ("query { a }", "blah"),
-        CostError::FailedToParseVariables,
+fn query_variable_numbers() {
+    let model: CostModel = "query { pairs(first: $first) } => $first;".parse().unwrap();
+    let query = "query pairs($first: Int) { pairs(first: $first) { id } }";
+    let cost = |variables: serde_json::Value| model.cost_with_variables(query, &variables);
+    let expect = |grt: u64| Ok(BigUint::from(grt) * wei_to_grt());
+
+    assert_eq!(cost(serde_json::json!({ "first": 10 })), expect(10));
+    // Numbers which do not fit in an Int are a Float
+    assert_eq!(
+        cost(serde_json::json!({ "first": 3000000000u64 })),
+        expect(3000000000)
+    );
+    assert_eq!(
+        cost(serde_json::json!({ "first": 1.5 })),
+        Ok(BigUint::from(15u32) * wei_to_grt() / BigUint::from(10u32))
+    );
+    assert_eq!(
+        model.cost(query, "{ \"first\": 3000000000 }"),
+        expect(3000000000)
     );
 }
 
 #[test]
-fn invalid_model() {
-    for case in &[
-        // Missing semicolon between 2 statements
-        "query { a } => 1 query { b } => 2;",
-        // Garbage data after valid statement
-        "query { a } => 1; garbage",
-    ] {
-        assert!(CostModel::compile(*case, "").is_err());
-    }
+fn query_variable_types() {
+    let model: CostModel = "
+        query { pairs(first: $first) } => $first * 2;
+        query { tokens(symbol: $symbol) } when $symbol == \"USDC\" => 1;
+    "
+    .parse()
+    .unwrap();
+    let cost =
+        |query: &str, variables: serde_json::Value| model.cost_with_variables(query, &variables);
+    let pairs = "query pairs($n: Int) { pairs(first: $n) }";
+    let tokens = "query tokens($s: String) { tokens(symbol: $s) }";
+
+    assert_eq!(
+        cost(pairs, serde_json::json!({ "n": "10" })),
+        Ok(BigUint::from(20u32) * wei_to_grt())
+    );
+    assert_eq!(
+        cost(pairs, serde_json::json!({ "n": "ten" })),
+        Err(CostError::VariableTypeMismatch {
+            name: "n".to_owned(),
+            expected: "Number".to_owned(),
+            got: "String".to_owned(),
+        })
+    );
+    assert_eq!(
+        cost(tokens, serde_json::json!({ "s": 5 })),
+        Err(CostError::VariableTypeMismatch {
+            name: "s".to_owned(),
+            expected: "String".to_owned(),
+            got: "Int".to_owned(),
+        })
+    );
+    // Values written in the query are not from variables
+    assert_eq!(
+        cost("{ pairs(first: \"ten\") }", serde_json::Value::Null),
+        Err(CostError::StatementFail {
+            statement: 0,
+            error: EvalError::TypeMismatch {
+                name: "first".to_owned()
+            },
+        })
+    );
 }
 
 #[test]
-fn nested_query() {
+fn string_length() {
     let model = "
-        query { users { id tokens { id } } } => 1;
-        query { users { id } } => 2;
-        default => 3;
+        query { token(id: $id) } when len($id) > 42 => 10;
+        query { token(id: $id) } => len($id);
     ";
-    test(model, "query { users { id tokens { id } } }", 1);
-    test(model, "query { users { id tokens { id and } } }", 1);
-    test(model, "query { users { id tokens { and } } }", 2);
+    // 42 characters
     test(
         model,
-        "query { we { are { the { knights { who { say { ni } } } } } } }",
-        3,
+        "{ token(id: \"0x6b175474e89094c44da98b954eedeac495271d0f\") }",
+        42,
     );
-}
+    test(
+        model,
+        "{ token(id: \"0x6b175474e89094c44da98b954eedeac495271d0f0\") }",
+        10,
+    );
+    // Characters, not bytes
+    test(model, "{ token(id: \"ñ\") }", 1);
+    test(model, "{ token(id: \"\") }", 0);
 
-#[test]
-fn query_not_costed() {
-    test("query { a } => 2;", "{ b }", CostError::QueryNotCosted);
+    test(
+        model,
+        "{ token(id: 5) }",
+        CostError::StatementFail {
+            statement: 0,
+            error: EvalError::TypeMismatch {
+                name: "id".to_owned(),
+            },
+        },
+    );
+    test(
+        model,
+        ("query t($id: ID) { token(id: $id) }", "{ \"id\": 5 }"),
+        CostError::VariableTypeMismatch {
+            name: "id".to_owned(),
+            expected: "String".to_owned(),
+            got: "Int".to_owned(),
+        },
+    );
 }
 
 #[test]
-fn div_by_zero_does_not_panic() {
-    test("default => 1 / 0;", "{ a }", CostError::CostModelFail);
-}
+fn list_folds() {
+    let model = "
+        query { transfers(amounts: $amounts) } when count($amounts) > 3 => 100;
+        query { transfers(amounts: $amounts) } => sum($amounts);
+        query { pairs(ids: $ids) } => count($ids) * 2;
+    ";
+    test(model, "{ transfers(amounts: [1, 2, 3]) }", 6);
+    test(model, "{ transfers(amounts: [1, 2, 3, 4]) }", 100);
+    test(model, "{ transfers(amounts: [\"10\", 0.5, 1.5]) }", 12);
+    test(model, "{ transfers(amounts: []) }", 0);
+    test(model, "{ pairs(ids: [\"a\", \"b\", \"c\"]) }", 6);
+    test(
+        model,
+        (
+            "query q($a: [Int]) { transfers(amounts: $a) }",
+            "{ \"a\": [5, 10, 15] }",
+        ),
+        30,
+    );
 
-#[test]
-fn lossless_math() {
-    // If the cost model were implemented by truncating at each operation,
-    // the result would be 0.
-    test("default => 100 * (1 / 2);", "{ a }", 50);
+    test(
+        model,
+        "{ transfers(amounts: 5) }",
+        CostError::StatementFail {
+            statement: 0,
+            error: EvalError::TypeMismatch {
+                name: "amounts".to_owned(),
+            },
+        },
+    );
+    test(
+        model,
+        "{ transfers(amounts: [1, \"a\"]) }",
+        CostError::StatementFail {
+            statement: 1,
+            error: EvalError::TypeMismatch {
+                name: "amounts".to_owned(),
+            },
+        },
+    );
+    test(
+        model,
+        (
+            "query q($a: [String]) { transfers(amounts: $a) }",
+            "{ \"a\": [\"x\"] }",
+        ),
+        CostError::VariableTypeMismatch {
+            name: "a".to_owned(),
+            expected: "Number".to_owned(),
+            got: "String".to_owned(),
+        },
+    );
 
-    // Underflows (below 0) temporarily
+    // Globals which are lists are folded when compiling
     test(
-        "default => ((-1 / 2) * 5) + 5;",
+        ("default => sum($FEES);", "{ \"FEES\": [1, 2, 3] }"),
         "{ a }",
-        BigUint::from(2500000000000000000u64),
+        6,
     );
 }
 
-#[test]
-fn overflow_clamp() {
-    // Underflow
-    test("default => 100 - 200;", "{ a }", 0);
-    test("default => 115792089237316195423570985008687907853269984665640564039457584007913129639931 + 10;", "{ a }", MAX_COST.clone());
-}
-
 #[test]
 fn decimals() {
     test(
@@ -278,12 +3051,37 @@ fn decimals() {
     );
 }
 
+#[test]
+fn exponent_of_capture() {
+    let model = "query { a(depth: $depth) } => 2 ^ $depth;";
+    test(model, "{ a(depth: 3) }", 8);
+    test(
+        model,
+        "{ a(depth: -3) }",
+        CostError::StatementFail {
+            statement: 0,
+            error: EvalError::Overflow,
+        },
+    );
+}
+
+#[test]
+fn min_max_calls() {
+    let model = "query { a(first: $first) } => max(min($first, 500), 10) * 0.1;";
+    test(model, "{ a(first: 100) }", 10);
+    test(model, "{ a(first: 1000) }", 50);
+    test(model, "{ a(first: 0) }", 1);
+}
+
 #[test]
 fn infinity_cancel_is_err() {
     test(
         "default => (1 / 0) + (-1 / 0);",
         "{ a }",
-        CostError::CostModelFail,
+        CostError::StatementFail {
+            statement: 0,
+            error: EvalError::DivideByZero,
+        },
     );
 }
 
@@ -338,7 +3136,68 @@ fn capture_shadows_global() {
 #[test]
 fn ban() {
     let model = "default => $BAN;";
-    test(model, "{ a }", CostError::CostModelFail);
+    test(
+        model,
+        "{ a }",
+        CostError::StatementFail {
+            statement: 0,
+            error: EvalError::MissingVariable("BAN".to_owned()),
+        },
+    );
+}
+
+#[test]
+fn negative_captures() {
+    let model = "
+        query { pairs(first: $first, skip: $skip) } => abs($first - $skip);
+        query { tokens(delta: $delta) } => 10 + -$delta;
+    ";
+
+    test(model, "{ pairs(first: 2, skip: 10) }", 8);
+    test(model, "{ pairs(first: 10, skip: 2) }", 8);
+    test(model, "{ tokens(delta: -5) }", 15);
+    test(model, "{ tokens(delta: 5) }", 5);
+}
+
+#[test]
+fn compare_captures() {
+    let model = "
+        query { pairs(first: $first, skip: $skip) } when $first > $skip => 1;
+        query { pairs(first: $first, skip: $skip) } when $first == $skip => 2;
+        query { pairs(first: $first, skip: $skip) } when $first <= $skip => 3;
+        query { tokens(first: $first) } when $first > $LIMIT => 4;
+    ";
+
+    test(model, "{ pairs(first: 10, skip: 5) }", 1);
+    test(model, "{ pairs(first: 5, skip: 5) }", 2);
+    test(model, "{ pairs(first: 1, skip: 5) }", 3);
+    // A side which is neither captured nor a global is an error
+    test(
+        model,
+        "{ tokens(first: 1) }",
+        CostError::StatementFail {
+            statement: 3,
+            error: EvalError::MissingVariable("LIMIT".to_owned()),
+        },
+    );
+}
+
+#[test]
+fn statement_fail_index() {
+    let model = "query { a } => 1; query { b(x: $x) } => 1 / $x; default => 2;";
+    test(
+        model,
+        "{ a b(x: 2) }",
+        BigUint::from(1500000000000000000u64),
+    );
+    test(
+        model,
+        "{ a b(x: 0) }",
+        CostError::StatementFail {
+            statement: 1,
+            error: EvalError::DivideByZero,
+        },
+    );
 }
 
 mod global_when_to_bool {
@@ -482,6 +3341,26 @@ mod standard_directives {
             1000,
         );
     }
+
+    #[test]
+    fn unresolved_variables() {
+        let query = "query Q($s: Boolean) { c { c1 c2 @skip(if: $s) } b @include(if: $b) }";
+        let model = |unresolved| {
+            CostModel::compile(MODEL, "")
+                .unwrap()
+                .with_unresolved_directives(unresolved)
+        };
+
+        // By default, the fields are kept
+        test(MODEL, query, 110);
+        test(model(UnresolvedDirectives::Keep), query, 110);
+        test(model(UnresolvedDirectives::Exclude), query, 1000);
+        test(
+            model(UnresolvedDirectives::Fail),
+            query,
+            CostError::MissingVariable("b".to_owned()),
+        );
+    }
 }
 
 #[test]
@@ -506,6 +3385,211 @@ fn root_fragment() {
     test(model, query, 111);
 }
 
+#[test]
+fn wasm_cost() {
+    let model = "query { a(first: $first) } => $first * 1000000000000; default => 1;";
+
+    // The cost is written in full, since it may not fit in a JavaScript number
+    assert_eq!(
+        crate::wasm::cost(model, "{ a(first: 9000000) }"),
+        Ok("9000000000000000000".to_owned())
+    );
+    assert_eq!(crate::wasm::cost(model, "{ b }"), Ok("1".to_owned()));
+    assert_eq!(
+        crate::wasm::cost(model, "{ a"),
+        Err(format!("{}", CostError::FailedToParseQuery))
+    );
+    assert!(crate::wasm::cost("query {", "{ a }").is_err());
+}
+
+#[test]
+fn document_builder() {
+    use crate::builder::*;
+
+    let text = "
+        let page = max($first, 1);
+        query { pairs(first: $first) } when $first > 100 && !$discount => $page * 2;
+        query { pairs(first: $first) } => $page / 2;
+        query { tokens(skip: $skip) } when defined($skip) => $skip % 3 + $LOAD;
+        default => -1 + 2;
+    ";
+    let parsed = CostModel::compile(text, "{\"LOAD\": 5, \"discount\": false}").unwrap();
+
+    let document = DocumentBuilder::new()
+        .definition("page", call(BuiltinFn::Max, vec![var("first"), lit(1)]))
+        .statement(
+            Predicate::query("query { pairs(first: $first) }")
+                .unwrap()
+                .when(and(gt(var("first"), lit(100)), not(flag("discount")))),
+            mul(var("page"), lit(2)),
+        )
+        .statement(
+            Predicate::query("query { pairs(first: $first) }").unwrap(),
+            div(var("page"), lit(2)),
+        )
+        .statement(
+            Predicate::query("query { tokens(skip: $skip) }")
+                .unwrap()
+                .when(defined("skip")),
+            add(rem(var("skip"), lit(3)), var("LOAD")),
+        )
+        .default(add(lit(-1), lit(2)))
+        .build()
+        .unwrap();
+    assert_eq!(
+        format!("{}", document),
+        format!("{}", parser::parse_document(text).unwrap())
+    );
+    let built = CostModel::from_document(document, "{\"LOAD\": 5, \"discount\": false}").unwrap();
+
+    let queries = [
+        "{ pairs(first: 7) }",
+        "{ pairs(first: 1000) }",
+        "{ pairs(first: 0) }",
+        "{ pairs }",
+        "{ tokens(skip: 10) }",
+        "{ tokens }",
+        "{ swaps }",
+    ];
+    for query in queries.iter() {
+        assert_eq!(parsed.cost(query, ""), built.cost(query, ""), "{}", query);
+    }
+
+    assert!(Predicate::query("query {").is_err());
+    let cyclic = DocumentBuilder::new()
+        .definition("a", var("a"))
+        .default(var("a"))
+        .build()
+        .unwrap();
+    assert!(matches!(
+        CostModel::from_document(cyclic, ""),
+        Err(CompileError::CyclicDefinition(name)) if name == "a"
+    ));
+}
+
+#[test]
+fn query_statements() {
+    use crate::builder::*;
+
+    // The snippets are built at runtime, and do not outlive the loop
+    let model = || {
+        let mut builder = DocumentBuilder::new();
+        for (i, entity) in ["tokens", "pairs"].iter().enumerate() {
+            let snippet = format!("query {{ {}(first: $first) }} when $first > 10", entity);
+            builder = builder.query_statement(snippet, mul(var("first"), lit(i as u32 + 2)));
+        }
+        CostModel::from_builder(builder.default(lit(1)), "").unwrap()
+    };
+
+    test(model(), "{ tokens(first: 20) }", 40);
+    test(model(), "{ pairs(first: 20) }", 60);
+    test(model(), "{ pairs(first: 5) }", 1);
+    test(model(), "{ swaps(first: 20) }", 1);
+
+    // Statements of a scope keep their predicates
+    let model = || {
+        let scope = DocumentBuilder::new().query_statement("query { b }", lit(2));
+        let builder = DocumentBuilder::new()
+            .query_statement("query { a }", lit(1))
+            .scope("s", scope);
+        CostModel::from_builder(builder, "").unwrap()
+    };
+    test(model(), "{ a }", 1);
+    test(model(), "{ b }", 2);
+
+    // Statements with predicates built from borrowed text keep them too
+    let model = || {
+        let predicate = Predicate::query("query { c(first: $first) }")
+            .unwrap()
+            .when(gt(var("first"), lit(10)));
+        let builder = DocumentBuilder::new()
+            .statement(predicate, var("first"))
+            .query_statement("query { c }", lit(2));
+        CostModel::from_builder(builder.default(lit(1)), "").unwrap()
+    };
+    test(model(), "{ c(first: 20) }", 20);
+    test(model(), "{ c(first: 5) }", 2);
+    test(model(), "{ d }", 1);
+
+    let builder = DocumentBuilder::new().query_statement("query {", lit(1));
+    assert!(CostModel::from_builder(builder, "").is_err());
+
+    let builder = DocumentBuilder::new().query_statement("query { a }", lit(1));
+    assert!(matches!(builder.build(), Err(CompileError::QueryStatement)));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_events() {
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Keeps the fields of every event, written with Debug.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<BTreeMap<String, String>>>>);
+
+    struct Fields(BTreeMap<String, String>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0
+                .insert(field.name().to_owned(), format!("{:?}", value));
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields(BTreeMap::new());
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    let model: CostModel = "
+        @name(\"large\") query { a(first: $first) } when $first > 5 => $first;
+        default => 1;
+    "
+    .parse()
+    .unwrap();
+    let recorder = Recorder::default();
+    let cost =
+        tracing::subscriber::with_default(recorder.clone(), || model.cost("{ a(first: 3) }", ""));
+    assert_eq!(cost, Ok(wei_to_grt()));
+
+    let events = recorder.0.lock().unwrap();
+    let field = |index: usize, name: &str| events[index].get(name).map(String::as_str);
+    assert_eq!(events.len(), 4);
+
+    assert_eq!(field(0, "message"), Some("Tried statement"));
+    assert_eq!(field(0, "statement"), Some("0"));
+    assert_eq!(field(0, "name"), Some("\"large\""));
+    assert_eq!(field(0, "matched"), Some("false"));
+    assert!(field(0, "captures").unwrap().contains("\"first\": \"3\""));
+
+    assert_eq!(field(1, "message"), Some("Tried statement"));
+    assert_eq!(field(1, "statement"), Some("1"));
+    assert_eq!(field(1, "name"), None);
+    assert_eq!(field(1, "matched"), Some("true"));
+
+    assert_eq!(field(2, "message"), Some("Costed field"));
+    assert_eq!(field(2, "cost"), Some("Some(\"1\")"));
+    assert_eq!(field(3, "message"), Some("Costed query"));
+    assert_eq!(field(3, "cost"), Some("1"));
+}
+
 mod inline_fragments {
     use super::*;
 
@@ -529,6 +3613,29 @@ mod inline_fragments {
     fn no_match() {
         test(MODEL, "{ a { b } }", 100);
     }
+
+    #[test]
+    fn flattened() {
+        let model = "query { users { id name } } => 1; default => 2;";
+
+        test(model, "{ users { id ... on User { name } } }", 1);
+        test(model, "{ users { ... { id name } } }", 1);
+        test(model, "{ users { id ... on Admin { role } } }", 2);
+    }
+
+    #[test]
+    fn type_conditions() {
+        let model = "
+            query { users { ... on Admin { role } } } => 1;
+            query { users { role } } => 2;
+            default => 3;
+        ";
+
+        test(model, "{ users { ... on Admin { role } } }", 1);
+        test(model, "{ users { ... on User { role } } }", 2);
+        test(model, "{ users { role } }", 2);
+        test(model, "{ users { id } }", 3);
+    }
 }
 
 mod recursions {
@@ -545,3 +3652,183 @@ mod recursions {
         test((model.as_str(), "{\"a\": 2}"), "{ a }", 5002);
     }
 }
+
+mod never_panics {
+    use super::*;
+    use proptest::prelude::*;
+    use proptest::sample::select;
+
+    const OPERATORS: &[&str] = &["+", "-", "*", "/", "%", "^", "<<", ">>", "&", "|"];
+    const COMPARISONS: &[&str] = &["==", "!=", "<", ">", "<=", ">="];
+    const FUNCTIONS: &[&str] = &["min", "max", "abs", "log2", "log10", "ceil_div"];
+    const VARIABLES: &[&str] = &[
+        "$a",
+        "$b",
+        "$s",
+        "$l",
+        "$g",
+        "$missing",
+        "len($s)",
+        "sum($l)",
+        "count($l)",
+        "$__depth",
+    ];
+    const PREDICATES: &[&str] = &[
+        "default",
+        "query { a }",
+        "query { a(first: $a) }",
+        "query { a(first: $a, skip: $b) { b } }",
+        "query { a(name: $s, ids: $l) }",
+        "query { a(first: > 100) }",
+        "query ($a: Int = 1) { b(first: $a) { c } }",
+        "query { a @skip(if: $b) }",
+    ];
+    const BOOLEAN_OPERATORS: &[&str] = &["&&", "||"];
+    const STATEMENT_OPERATORS: &[&str] = &["=>", "+="];
+    const FIELDS: &[&str] = &["a", "b", "c", "__typename"];
+    const OPERATIONS: &[&str] = &["query", "query ($v: Int)", "mutation", "{"];
+    const GLOBALS: &[&str] = &["", "{\"g\": 2}", "{\"g\": [1, 2]}", "{\"g\": \"x\"}"];
+    const QUERY_VARIABLES: &[&str] = &[
+        "",
+        "{\"v\": 3}",
+        "{\"v\": -3}",
+        "{\"v\": \"x\"}",
+        "{\"v\": null}",
+        "{\"v\": 99999999999}",
+    ];
+
+    fn linear_expression() -> BoxedStrategy<String> {
+        let leaf = prop_oneof![
+            "-?[0-9]{1,80}(\\.[0-9]{1,10})?",
+            select(VARIABLES).prop_map(String::from),
+        ];
+        leaf.prop_recursive(6, 64, 3, |inner| {
+            let call = (
+                select(FUNCTIONS),
+                prop::collection::vec(inner.clone(), 1..3),
+            )
+                .prop_map(|(func, args)| format!("{}({})", func, args.join(", ")));
+            prop_oneof![
+                (inner.clone(), select(OPERATORS), inner.clone())
+                    .prop_map(|(lhs, op, rhs)| format!("({} {} {})", lhs, op, rhs)),
+                inner.clone().prop_map(|expr| format!("-({})", expr)),
+                call,
+                (condition(inner.clone()), inner.clone(), inner).prop_map(
+                    |(cond, then, otherwise)| format!("({} ? {} : {})", cond, then, otherwise)
+                ),
+            ]
+        })
+        .boxed()
+    }
+
+    fn condition(expr: BoxedStrategy<String>) -> BoxedStrategy<String> {
+        let leaf = prop_oneof![
+            Just("true".to_owned()),
+            Just("$b".to_owned()),
+            Just("defined($a)".to_owned()),
+            Just("is_null($a)".to_owned()),
+            (expr.clone(), select(COMPARISONS), expr)
+                .prop_map(|(lhs, op, rhs)| format!("{} {} {}", lhs, op, rhs)),
+        ];
+        leaf.prop_recursive(3, 16, 2, |inner| {
+            prop_oneof![
+                (inner.clone(), select(BOOLEAN_OPERATORS), inner.clone())
+                    .prop_map(|(lhs, op, rhs)| format!("({} {} {})", lhs, op, rhs)),
+                inner.prop_map(|cond| format!("!({})", cond)),
+            ]
+        })
+        .boxed()
+    }
+
+    fn statement() -> impl Strategy<Value = String> {
+        (
+            select(PREDICATES),
+            prop::option::of(condition(linear_expression())),
+            select(STATEMENT_OPERATORS),
+            linear_expression(),
+        )
+            .prop_map(|(predicate, when, op, expr)| match when {
+                Some(when) => format!("{} when {} {} {};", predicate, when, op, expr),
+                None => format!("{} {} {};", predicate, op, expr),
+            })
+    }
+
+    fn graphql_query() -> impl Strategy<Value = String> {
+        let argument = prop_oneof![
+            "-?[0-9]{1,25}".prop_map(|n| format!("first: {}", n)),
+            "[a-z ]{0,8}".prop_map(|s| format!("name: \"{}\"", s)),
+            prop::collection::vec("-?[0-9]{1,12}", 0..4)
+                .prop_map(|ids| format!("ids: [{}]", ids.join(", "))),
+            Just("ids: [\"x\", null]".to_owned()),
+            Just("skip: $v".to_owned()),
+            Just("first: null".to_owned()),
+        ];
+        let directive = prop_oneof![
+            Just(""),
+            Just(" @skip(if: true)"),
+            Just(" @include(if: $v)"),
+            Just(" @defer"),
+        ];
+        let leaf = (
+            select(FIELDS),
+            prop::collection::vec(argument, 0..3),
+            directive,
+        )
+            .prop_map(|(name, arguments, directive)| {
+                if arguments.is_empty() {
+                    format!("{}{}", name, directive)
+                } else {
+                    format!("{}({}){}", name, arguments.join(", "), directive)
+                }
+            });
+        let selection = leaf.prop_recursive(4, 32, 3, |inner| {
+            prop_oneof![
+                (inner.clone(), prop::collection::vec(inner.clone(), 1..3))
+                    .prop_map(|(field, items)| format!("{} {{ {} }}", field, items.join(" "))),
+                prop::collection::vec(inner, 1..3)
+                    .prop_map(|items| format!("... on T {{ {} }}", items.join(" "))),
+                Just("...F".to_owned()),
+            ]
+        });
+        (
+            select(OPERATIONS),
+            prop::collection::vec(selection, 1..4),
+            prop::bool::ANY,
+        )
+            .prop_map(|(operation, items, fragment)| {
+                let open = if operation == "{" { "" } else { " {" };
+                let fragment = if fragment {
+                    " fragment F on T { a b(first: 1) }"
+                } else {
+                    ""
+                };
+                format!("{}{} {} }}{}", operation, open, items.join(" "), fragment)
+            })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(512))]
+
+        #[test]
+        fn compile_and_cost(
+            statements in prop::collection::vec(statement(), 1..5),
+            globals in select(GLOBALS),
+            query in graphql_query(),
+            variables in select(QUERY_VARIABLES),
+        ) {
+            if let Ok(model) = CostModel::compile(statements.join("\n"), globals) {
+                let _ = model.cost(&query, variables);
+                let _ = model.cost_additive(&query, variables);
+                let _ = model.cost_most_specific(&query, variables);
+                let _ = model.lint();
+            }
+        }
+
+        #[test]
+        fn arbitrary_text(model in ".*", query in ".*", variables in ".*") {
+            if let Ok(model) = CostModel::compile(model, "") {
+                let _ = model.cost(&query, &variables);
+            }
+        }
+    }
+}
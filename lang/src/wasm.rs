@@ -0,0 +1,32 @@
+//! An entry point for costing queries from JavaScript, when the crate is built
+//! for WebAssembly with the `wasm` feature:
+//!
+//! cargo rustc -p cost-model --release --target wasm32-unknown-unknown --features wasm --crate-type cdylib
+//!
+//! The entry point takes and returns strings, and does not use threads, clocks
+//! or the filesystem. The `rayon` feature should not be enabled with it.
+
+use crate::prelude::*;
+use crate::CostModel;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+/// Compiles the model without globals and costs the query without variables,
+/// returning the cost in wei as a decimal string.
+pub(crate) fn cost(model: &str, query: &str) -> Result<String, String> {
+    profile_fn!(cost);
+
+    let model: CostModel = model.parse().map_err(|e| format!("{}", e))?;
+    let cost = model.cost(query, "").map_err(|e| format!("{}", e))?;
+    Ok(cost.to_str_radix(10))
+}
+
+/// Costs the query with the model. The cost is in wei, and is a decimal string
+/// since it may be too large for a JavaScript number.
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn cost_model_cost(model: &str, query: &str) -> Result<String, JsValue> {
+    profile_fn!(cost_model_cost);
+
+    cost(model, query).map_err(|e| JsValue::from_str(&e))
+}